@@ -0,0 +1,529 @@
+use crate::metrics;
+use crate::utils::agent::{spawn_nonblocking_loop, Agent, Context, DispatchFn, Handler, Message};
+use crate::utils::BoxError;
+use headers::{CacheControl, HeaderMapExt};
+use http::header::{
+    HeaderMap, HeaderName, CACHE_CONTROL, ETAG, IF_MODIFIED_SINCE, IF_NONE_MATCH, LAST_MODIFIED,
+    LOCATION,
+};
+use http::{HeaderValue, Method, Request, StatusCode};
+use hyper::body::HttpBody;
+use hyper::client::Client;
+use hyper::{Body, Response};
+use prometheus::Histogram;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::error::Error as StdError;
+use std::time::Duration;
+use thiserror::Error;
+use tokio::sync::mpsc;
+use url::Url;
+
+pub mod proxy;
+pub use self::proxy::{ConnectTimeout, Connector, ParseProxyError, Proxy};
+
+pub mod resolve;
+pub use self::resolve::IpFilter;
+
+pub mod tls;
+pub use self::tls::{LoadTlsError, TlsFiles, TlsOptions};
+
+#[derive(Debug, Error)]
+pub enum FetchError {
+    #[error("HTTP request failed: {0}")]
+    Hyper(hyper::Error),
+    #[error("connection timed out")]
+    ConnectTimeout,
+    #[error("HTTP request timed out")]
+    Timeout,
+    #[error("unexpected HTTP status code: {0}")]
+    BadStatus(StatusCode),
+    #[error("redirect not followed, limit of {0} redirects reached")]
+    RedirectLimit(usize),
+    #[error("invalid redirect: {0}")]
+    InvalidRedirect(String),
+    #[error("HTTP response body exceeds {0} bytes")]
+    TooLarge(usize),
+    #[error("could not read HTTP response body: {0}")]
+    Read(BoxError),
+    #[error("invalid UTF-8 in HTTP response body: {0}")]
+    Utf8(#[from] std::string::FromUtf8Error),
+}
+
+impl From<hyper::Error> for FetchError {
+    fn from(err: hyper::Error) -> Self {
+        let mut source = err.source();
+        while let Some(cause) = source {
+            if cause.is::<ConnectTimeout>() {
+                return FetchError::ConnectTimeout;
+            }
+            source = cause.source();
+        }
+        FetchError::Hyper(err)
+    }
+}
+
+impl FetchError {
+    /// Count this error in metrics.
+    pub fn apply_metric(&self) {
+        match self {
+            Self::Hyper(_) => metrics::FETCH_ERROR_HTTP.inc(),
+            Self::ConnectTimeout => metrics::FETCH_ERROR_CONNECT_TIMEOUT.inc(),
+            Self::Timeout => metrics::FETCH_ERROR_TIMEOUT.inc(),
+            Self::BadStatus(_) => metrics::FETCH_ERROR_BAD_STATUS.inc(),
+            Self::RedirectLimit(_) => metrics::FETCH_ERROR_REDIRECT_LIMIT.inc(),
+            Self::InvalidRedirect(_) => metrics::FETCH_ERROR_INVALID_REDIRECT.inc(),
+            Self::TooLarge(_) => metrics::FETCH_ERROR_TOO_LARGE.inc(),
+            Self::Read(_) | Self::Utf8(_) => metrics::FETCH_ERROR_INVALID_BODY.inc(),
+        }
+    }
+}
+
+/// What a URL is fetched for. Determines the latency metric and maximum response size.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum FetchPurpose {
+    /// `WebFinger` queries.
+    Webfinger,
+    /// OpenID Connect configuration documents.
+    OidcConfig,
+    /// OpenID Connect JWKs.
+    OidcKeys,
+    /// Requests to email sending APIs.
+    MailApi,
+}
+
+impl FetchPurpose {
+    /// The latency metric for this kind of fetch.
+    pub fn metric(self) -> &'static Histogram {
+        match self {
+            Self::Webfinger => &metrics::AUTH_WEBFINGER_DURATION,
+            Self::OidcConfig => &metrics::AUTH_OIDC_FETCH_CONFIG_DURATION,
+            Self::OidcKeys => &metrics::AUTH_OIDC_FETCH_JWKS_DURATION,
+            Self::MailApi => &metrics::AUTH_EMAIL_SEND_DURATION,
+        }
+    }
+}
+
+/// Maximum response body sizes in bytes, per `FetchPurpose`.
+#[derive(Clone, Copy, Debug)]
+pub struct FetchSizeLimits {
+    pub webfinger: usize,
+    pub oidc_config: usize,
+    pub oidc_keys: usize,
+    pub mail_api: usize,
+}
+
+impl FetchSizeLimits {
+    pub fn get(&self, purpose: FetchPurpose) -> usize {
+        match purpose {
+            FetchPurpose::Webfinger => self.webfinger,
+            FetchPurpose::OidcConfig => self.oidc_config,
+            FetchPurpose::OidcKeys => self.oidc_keys,
+            FetchPurpose::MailApi => self.mail_api,
+        }
+    }
+}
+
+impl Default for FetchSizeLimits {
+    fn default() -> Self {
+        FetchSizeLimits {
+            webfinger: 16_384,
+            oidc_config: 65_536,
+            oidc_keys: 65_536,
+            mail_api: 16_384,
+        }
+    }
+}
+
+/// Response headers used to make conditional requests.
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct Validators {
+    /// The `ETag` header.
+    pub etag: Option<String>,
+    /// The `Last-Modified` header.
+    pub last_modified: Option<String>,
+}
+
+impl Validators {
+    /// Read validators from response headers.
+    fn from_headers(headers: &HeaderMap) -> Self {
+        let get = |name: HeaderName| {
+            headers
+                .get(name)
+                .and_then(|value| value.to_str().ok())
+                .map(ToOwned::to_owned)
+        };
+        Validators {
+            etag: get(ETAG),
+            last_modified: get(LAST_MODIFIED),
+        }
+    }
+}
+
+/// The result of fetching a URL.
+pub struct FetchUrlResult {
+    /// Data from the response, or `None` if the server responded with `304 Not Modified`.
+    pub data: Option<String>,
+    /// Public caching age from  `Cache-Control` header, or 0.
+    pub max_age: Duration,
+    /// The `stale-while-revalidate` directive from the `Cache-Control` header, or 0.
+    pub stale_while_revalidate: Duration,
+    /// The `stale-if-error` directive from the `Cache-Control` header, or 0.
+    pub stale_if_error: Duration,
+    /// Validators for revalidating the response later.
+    pub validators: Validators,
+}
+
+/// Message containing an HTTP request to make.
+pub struct FetchUrl {
+    /// The request to make.
+    pub request: Request<Body>,
+    /// What the request is for.
+    pub purpose: FetchPurpose,
+}
+impl Message for FetchUrl {
+    type Reply = Result<FetchUrlResult, FetchError>;
+}
+impl FetchUrl {
+    /// Create a simple GET request message.
+    pub fn get(url: &Url, purpose: FetchPurpose) -> Self {
+        let hyper_uri: hyper::Uri = url
+            .as_str()
+            .parse()
+            .expect("could not convert Url to Hyper Url");
+        let request = Request::get(hyper_uri)
+            .body(Body::empty())
+            .expect("could not build GET request");
+        FetchUrl { request, purpose }
+    }
+
+    /// Create a conditional GET request message, to revalidate a cached response.
+    pub fn revalidate(url: &Url, validators: &Validators, purpose: FetchPurpose) -> Self {
+        let mut message = Self::get(url, purpose);
+        let headers = message.request.headers_mut();
+        let conditions = [
+            (IF_NONE_MATCH, &validators.etag),
+            (IF_MODIFIED_SINCE, &validators.last_modified),
+        ];
+        for (name, value) in &conditions {
+            if let Some(value) = value.as_ref().and_then(|v| HeaderValue::from_str(v).ok()) {
+                headers.insert(name.clone(), value);
+            }
+        }
+        message
+    }
+}
+
+/// Get a `Cache-Control` directive that has a value in seconds.
+///
+/// The `headers` crate only parses standard directives, so this is used for extensions.
+fn cache_control_secs(headers: &HeaderMap, name: &str) -> Option<Duration> {
+    headers
+        .get_all(CACHE_CONTROL)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .find_map(|directive| {
+            let mut parts = directive.splitn(2, '=');
+            let key = parts.next()?.trim();
+            let value = parts.next()?.trim().trim_matches('"');
+            if key.eq_ignore_ascii_case(name) {
+                value.parse().ok()
+            } else {
+                None
+            }
+        })
+        .map(Duration::from_secs)
+}
+
+/// Options for `FetchAgent`.
+#[derive(Clone)]
+pub struct FetchOptions {
+    /// Proxy to send requests through.
+    pub proxy: Option<Proxy>,
+    /// Addresses requests may connect to.
+    pub ip_filter: IpFilter,
+    /// Time allowed to establish a connection.
+    pub connect_timeout: Duration,
+    /// Time allowed for the entire request, including redirects and reading the response.
+    pub timeout: Duration,
+    /// Maximum number of redirects to follow for GET requests.
+    pub max_redirects: usize,
+    /// Maximum response body sizes.
+    pub max_sizes: FetchSizeLimits,
+    /// TLS settings for all connections.
+    pub tls: TlsOptions,
+    /// TLS settings for specific hosts and ports, combined with `tls`.
+    pub origin_tls: HashMap<(String, u16), TlsOptions>,
+}
+
+impl Default for FetchOptions {
+    fn default() -> Self {
+        FetchOptions {
+            proxy: None,
+            ip_filter: IpFilter::default(),
+            connect_timeout: Duration::from_secs(10),
+            timeout: Duration::from_secs(30),
+            max_redirects: 0,
+            max_sizes: FetchSizeLimits::default(),
+            tls: TlsOptions::default(),
+            origin_tls: HashMap::new(),
+        }
+    }
+}
+
+/// Agent that fetches URLs.
+pub struct FetchAgent {
+    client: Client<Connector>,
+    user_agent: HeaderValue,
+    timeout: Duration,
+    max_redirects: usize,
+    max_sizes: FetchSizeLimits,
+}
+
+impl FetchAgent {
+    pub fn new(options: FetchOptions) -> Self {
+        let timeout = options.timeout;
+        let max_redirects = options.max_redirects;
+        let max_sizes = options.max_sizes;
+        let connector = Connector::new(options).expect("Could not initialize TLS");
+        FetchAgent {
+            client: Client::builder().build(connector),
+            user_agent: HeaderValue::from_str(&format!("portier.io/{}", env!("CARGO_PKG_VERSION")))
+                .expect("Could not prepare User-Agent header"),
+            timeout,
+            max_redirects,
+            max_sizes,
+        }
+    }
+}
+
+/// Make a request, following redirects for GET requests up to `max_redirects`.
+///
+/// Redirects to other schemes than HTTP(S), and from HTTPS to HTTP, are refused.
+async fn request_following(
+    client: Client<Connector>,
+    request: Request<Body>,
+    max_redirects: usize,
+) -> Result<Response<Body>, FetchError> {
+    let method = request.method().clone();
+    let headers = request.headers().clone();
+    let mut uri = request.uri().clone();
+    let mut request = request;
+    let mut redirects = 0;
+    loop {
+        let res = client.request(request).await?;
+        let status = res.status();
+        if !status.is_redirection() || status == StatusCode::NOT_MODIFIED {
+            return Ok(res);
+        }
+        if method != Method::GET {
+            return Err(FetchError::BadStatus(status));
+        }
+        if redirects == max_redirects {
+            return Err(FetchError::RedirectLimit(max_redirects));
+        }
+        redirects += 1;
+
+        let location = res
+            .headers()
+            .get(LOCATION)
+            .and_then(|value| value.to_str().ok())
+            .ok_or_else(|| FetchError::InvalidRedirect("missing Location header".to_owned()))?;
+        let current: Url = uri
+            .to_string()
+            .parse()
+            .expect("could not convert Hyper Uri to Url");
+        let next = current
+            .join(location)
+            .map_err(|err| FetchError::InvalidRedirect(format!("{}: {}", location, err)))?;
+        match (current.scheme(), next.scheme()) {
+            ("https", "https") | ("http", "http") | ("http", "https") => {}
+            _ => return Err(FetchError::InvalidRedirect(format!("refused {}", next))),
+        }
+        uri = next
+            .as_str()
+            .parse()
+            .expect("could not convert Url to Hyper Uri");
+
+        request = Request::get(uri.clone())
+            .body(Body::empty())
+            .expect("could not build GET request");
+        *request.headers_mut() = headers.clone();
+    }
+}
+
+/// Read a response body, up to `limit` bytes.
+async fn read_limited(body: &mut Body, limit: usize) -> Result<Vec<u8>, FetchError> {
+    let mut data = Vec::new();
+    while let Some(chunk) = body.data().await {
+        let chunk = chunk.map_err(|err| FetchError::Read(Box::new(err)))?;
+        if data.len() + chunk.len() > limit {
+            return Err(FetchError::TooLarge(limit));
+        }
+        data.extend_from_slice(&chunk);
+    }
+    Ok(data)
+}
+
+impl Agent for FetchAgent {
+    fn spawn_loop(self, rx: mpsc::Receiver<DispatchFn<Self>>) {
+        // Requests are made in separate tasks, so handlers never block.
+        spawn_nonblocking_loop(self, rx);
+    }
+}
+
+impl Handler<FetchUrl> for FetchAgent {
+    fn handle(&mut self, mut message: FetchUrl, cx: Context<Self, FetchUrl>) {
+        message
+            .request
+            .headers_mut()
+            .insert("User-Agent", self.user_agent.clone());
+
+        let timer = message.purpose.metric().start_timer();
+        let future = request_following(self.client.clone(), message.request, self.max_redirects);
+        let max_size = self.max_sizes.get(message.purpose);
+        let fetch = async move {
+            let mut res = future.await?;
+            let data = if res.status() == StatusCode::NOT_MODIFIED {
+                None
+            } else if res.status().is_success() {
+                let data = read_limited(res.body_mut(), max_size).await?;
+                Some(String::from_utf8(data)?)
+            } else {
+                return Err(FetchError::BadStatus(res.status()));
+            };
+            timer.observe_duration();
+
+            // Grab the max-age directive from the Cache-Control header.
+            let headers = res.headers();
+            let max_age = headers
+                .typed_get()
+                .and_then(|header: CacheControl| header.max_age())
+                .unwrap_or_else(|| Duration::from_secs(0));
+            let stale_while_revalidate = cache_control_secs(headers, "stale-while-revalidate")
+                .unwrap_or_else(|| Duration::from_secs(0));
+            let stale_if_error = cache_control_secs(headers, "stale-if-error")
+                .unwrap_or_else(|| Duration::from_secs(0));
+
+            Ok(FetchUrlResult {
+                data,
+                max_age,
+                stale_while_revalidate,
+                stale_if_error,
+                validators: Validators::from_headers(headers),
+            })
+        };
+        let timeout = self.timeout;
+        cx.reply_later(async move {
+            let result = tokio::time::timeout(timeout, fetch)
+                .await
+                .unwrap_or(Err(FetchError::Timeout));
+            if let Err(ref err) = result {
+                err.apply_metric();
+            }
+            result
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::agent::{spawn_agent, Addr};
+    use hyper::service::{make_service_fn, service_fn};
+    use hyper::Server;
+    use std::convert::Infallible;
+
+    async fn serve(req: Request<Body>) -> Result<Response<Body>, Infallible> {
+        let res = match req.uri().path() {
+            "/ok" => Response::new(Body::from("ok")),
+            "/large" => Response::new(Body::from(vec![b'x'; 20_000])),
+            "/slow" => {
+                tokio::time::sleep(Duration::from_secs(5)).await;
+                Response::new(Body::from("slow"))
+            }
+            "/redirect" => Response::builder()
+                .status(StatusCode::FOUND)
+                .header(LOCATION, "ok")
+                .body(Body::empty())
+                .unwrap(),
+            "/redirect-loop" => Response::builder()
+                .status(StatusCode::FOUND)
+                .header(LOCATION, "/redirect-loop")
+                .body(Body::empty())
+                .unwrap(),
+            "/redirect-file" => Response::builder()
+                .status(StatusCode::FOUND)
+                .header(LOCATION, "file:///etc/passwd")
+                .body(Body::empty())
+                .unwrap(),
+            _ => Response::builder()
+                .status(StatusCode::NOT_FOUND)
+                .body(Body::empty())
+                .unwrap(),
+        };
+        Ok(res)
+    }
+
+    async fn setup(options: FetchOptions) -> (Addr<FetchAgent>, Url) {
+        let server = Server::bind(&([127, 0, 0, 1], 0).into()).serve(make_service_fn(|_| async {
+            Ok::<_, Infallible>(service_fn(serve))
+        }));
+        let url = format!("http://{}/", server.local_addr()).parse().unwrap();
+        tokio::spawn(server);
+        (spawn_agent(FetchAgent::new(options)).await, url)
+    }
+
+    async fn fetch(
+        fetcher: &Addr<FetchAgent>,
+        url: &Url,
+        path: &str,
+    ) -> Result<String, FetchError> {
+        let message = FetchUrl::get(&url.join(path).unwrap(), FetchPurpose::Webfinger);
+        let result = fetcher.send(message).await?;
+        Ok(result.data.unwrap())
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_redirects() {
+        let (fetcher, url) = setup(FetchOptions::default()).await;
+        match fetch(&fetcher, &url, "redirect").await {
+            Err(FetchError::RedirectLimit(0)) => {}
+            res => panic!("unexpected result: {:?}", res),
+        }
+
+        let (fetcher, url) = setup(FetchOptions {
+            max_redirects: 3,
+            ..FetchOptions::default()
+        })
+        .await;
+        assert_eq!(fetch(&fetcher, &url, "redirect").await.unwrap(), "ok");
+        match fetch(&fetcher, &url, "redirect-loop").await {
+            Err(FetchError::RedirectLimit(3)) => {}
+            res => panic!("unexpected result: {:?}", res),
+        }
+        match fetch(&fetcher, &url, "redirect-file").await {
+            Err(FetchError::InvalidRedirect(_)) => {}
+            res => panic!("unexpected result: {:?}", res),
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_limits() {
+        let (fetcher, url) = setup(FetchOptions {
+            timeout: Duration::from_secs(1),
+            ..FetchOptions::default()
+        })
+        .await;
+        assert_eq!(fetch(&fetcher, &url, "ok").await.unwrap(), "ok");
+        match fetch(&fetcher, &url, "large").await {
+            Err(FetchError::TooLarge(16_384)) => {}
+            res => panic!("unexpected result: {:?}", res),
+        }
+        match fetch(&fetcher, &url, "slow").await {
+            Err(FetchError::Timeout) => {}
+            res => panic!("unexpected result: {:?}", res),
+        }
+    }
+}
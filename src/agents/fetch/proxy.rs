@@ -0,0 +1,538 @@
+use super::resolve::{FilteredResolver, ForbiddenAddress, IpFilter};
+use super::FetchOptions;
+use crate::utils::{BoxError, BoxFuture};
+use http::uri::Scheme;
+use hyper::client::HttpConnector;
+use hyper::service::Service;
+use hyper::Uri;
+use hyper_tls::MaybeHttpsStream;
+use percent_encoding::percent_decode_str;
+use std::collections::HashMap;
+use std::io::Error as IoError;
+use std::net::IpAddr;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::Duration;
+use thiserror::Error;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tokio_native_tls::TlsConnector;
+use url::Url;
+
+/// Maximum size of the response head to a CONNECT request.
+const MAX_CONNECT_RESPONSE: usize = 8192;
+
+#[derive(Debug, Error)]
+pub enum ParseProxyError {
+    #[error("invalid URL: {0}")]
+    Url(#[from] url::ParseError),
+    #[error("unsupported proxy scheme: {0}")]
+    UnsupportedScheme(String),
+    #[error("proxy URL has no host")]
+    MissingHost,
+    #[error("SOCKS5 proxy username and password must be at most 255 bytes")]
+    CredentialsTooLong,
+}
+
+#[derive(Debug, Error)]
+#[error("connection timed out")]
+pub struct ConnectTimeout;
+
+#[derive(Debug, Error)]
+pub enum TunnelError {
+    #[error("proxy connection failed: {0}")]
+    Io(#[from] IoError),
+    #[error("proxy refused tunnel: {0}")]
+    Refused(String),
+    #[error("invalid proxy response")]
+    InvalidResponse,
+    #[error("host name is too long for a SOCKS5 proxy")]
+    HostTooLong,
+}
+
+/// Protocol used to talk to a proxy.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum ProxyScheme {
+    /// HTTP proxy, using CONNECT tunnelling.
+    Http,
+    /// SOCKS5 proxy, which can resolve host names.
+    Socks5,
+}
+
+/// An outbound proxy, and the hosts that bypass it.
+#[derive(Clone, Debug)]
+pub struct Proxy {
+    scheme: ProxyScheme,
+    /// Proxy address, as a URI for `HttpConnector`.
+    uri: Uri,
+    /// Username and password.
+    credentials: Option<(String, String)>,
+    /// Lowercase host names and domains that bypass the proxy.
+    bypass: Vec<String>,
+}
+
+impl Proxy {
+    /// Parse a proxy URL, as found in `HTTPS_PROXY`.
+    ///
+    /// A URL without scheme is treated as an HTTP proxy. Entries in `no_proxy` match a host and
+    /// its subdomains, or all hosts if `*`.
+    pub fn new(url: &str, no_proxy: &[String]) -> Result<Self, ParseProxyError> {
+        let url: Url = if url.contains("://") {
+            url.parse()?
+        } else {
+            format!("http://{}", url).parse()?
+        };
+        let (scheme, default_port) = match url.scheme() {
+            "http" => (ProxyScheme::Http, 80),
+            "socks5" | "socks5h" => (ProxyScheme::Socks5, 1080),
+            other => return Err(ParseProxyError::UnsupportedScheme(other.to_owned())),
+        };
+        let host = url.host_str().ok_or(ParseProxyError::MissingHost)?;
+        let port = url.port().unwrap_or(default_port);
+        let uri = format!("http://{}:{}", host, port)
+            .parse()
+            .map_err(|_| ParseProxyError::MissingHost)?;
+        let decode = |value: &str| percent_decode_str(value).decode_utf8_lossy().into_owned();
+        let credentials = if url.username().is_empty() {
+            None
+        } else {
+            Some((
+                decode(url.username()),
+                decode(url.password().unwrap_or_default()),
+            ))
+        };
+        // SOCKS5 sends the length of each of these in a single byte.
+        if let (ProxyScheme::Socks5, Some((username, password))) = (scheme, &credentials) {
+            if username.len() > 255 || password.len() > 255 {
+                return Err(ParseProxyError::CredentialsTooLong);
+            }
+        }
+        let bypass = no_proxy
+            .iter()
+            .map(|entry| entry.trim().trim_start_matches('.').to_ascii_lowercase())
+            .filter(|entry| !entry.is_empty())
+            .collect();
+        Ok(Proxy {
+            scheme,
+            uri,
+            credentials,
+            bypass,
+        })
+    }
+
+    /// Whether connections to the given host go through the proxy.
+    fn applies_to(&self, host: &str) -> bool {
+        let host = host.trim_end_matches('.').to_ascii_lowercase();
+        !self.bypass.iter().any(|entry| {
+            entry == "*"
+                || *entry == host
+                || (host.ends_with(entry.as_str())
+                    && host[..host.len() - entry.len()].ends_with('.'))
+        })
+    }
+
+    /// Ask the proxy on the other end of the stream to connect to the given target.
+    ///
+    /// The target is either the host itself, or an address it resolved to. The host is still sent
+    /// in the `Host` header of an HTTP proxy.
+    async fn tunnel(
+        &self,
+        stream: &mut TcpStream,
+        target: &str,
+        host: &str,
+        port: u16,
+    ) -> Result<(), TunnelError> {
+        match self.scheme {
+            ProxyScheme::Http => self.tunnel_http(stream, target, host, port).await,
+            ProxyScheme::Socks5 => self.tunnel_socks5(stream, target, port).await,
+        }
+    }
+
+    async fn tunnel_http(
+        &self,
+        stream: &mut TcpStream,
+        target: &str,
+        host: &str,
+        port: u16,
+    ) -> Result<(), TunnelError> {
+        let authority = |host: &str| {
+            if host.contains(':') {
+                format!("[{}]:{}", host, port)
+            } else {
+                format!("{}:{}", host, port)
+            }
+        };
+        let authorization = match self.credentials {
+            Some((ref username, ref password)) => format!(
+                "Proxy-Authorization: Basic {}\r\n",
+                base64::encode(format!("{}:{}", username, password))
+            ),
+            None => String::new(),
+        };
+        let request = format!(
+            "CONNECT {} HTTP/1.1\r\nHost: {}\r\n{}\r\n",
+            authority(target),
+            authority(host),
+            authorization
+        );
+        stream.write_all(request.as_bytes()).await?;
+
+        // The proxy sends nothing after the response head until we start talking through the
+        // tunnel, so it's safe to read byte-by-byte up to the empty line.
+        let mut head = Vec::new();
+        while !head.ends_with(b"\r\n\r\n") {
+            if head.len() >= MAX_CONNECT_RESPONSE {
+                return Err(TunnelError::InvalidResponse);
+            }
+            head.push(stream.read_u8().await?);
+        }
+        let head = String::from_utf8_lossy(&head);
+        let status_line = head.lines().next().unwrap_or_default();
+        match status_line.split_whitespace().nth(1) {
+            Some(code) if code.starts_with('2') => Ok(()),
+            Some(_) => Err(TunnelError::Refused(status_line.to_owned())),
+            None => Err(TunnelError::InvalidResponse),
+        }
+    }
+
+    async fn tunnel_socks5(
+        &self,
+        stream: &mut TcpStream,
+        host: &str,
+        port: u16,
+    ) -> Result<(), TunnelError> {
+        // Host names are sent with their length in a single byte.
+        if host.len() > 255 {
+            return Err(TunnelError::HostTooLong);
+        }
+
+        // Greeting, offering no authentication, and username/password if we have credentials.
+        if self.credentials.is_some() {
+            stream.write_all(&[5, 2, 0, 2]).await?;
+        } else {
+            stream.write_all(&[5, 1, 0]).await?;
+        }
+        let mut reply = [0; 2];
+        stream.read_exact(&mut reply).await?;
+        match (reply, &self.credentials) {
+            ([5, 0], _) => {}
+            ([5, 2], Some((username, password))) => {
+                let mut auth = vec![1, username.len() as u8];
+                auth.extend_from_slice(username.as_bytes());
+                auth.push(password.len() as u8);
+                auth.extend_from_slice(password.as_bytes());
+                stream.write_all(&auth).await?;
+                stream.read_exact(&mut reply).await?;
+                if reply[1] != 0 {
+                    return Err(TunnelError::Refused("authentication failed".to_owned()));
+                }
+            }
+            ([5, 0xff], _) => {
+                return Err(TunnelError::Refused(
+                    "no acceptable authentication method".to_owned(),
+                ))
+            }
+            _ => return Err(TunnelError::InvalidResponse),
+        }
+
+        // Connect request.
+        let mut request = vec![5, 1, 0];
+        match host.parse() {
+            Ok(IpAddr::V4(addr)) => {
+                request.push(1);
+                request.extend_from_slice(&addr.octets());
+            }
+            Ok(IpAddr::V6(addr)) => {
+                request.push(4);
+                request.extend_from_slice(&addr.octets());
+            }
+            Err(_) => {
+                request.push(3);
+                request.push(host.len() as u8);
+                request.extend_from_slice(host.as_bytes());
+            }
+        }
+        request.extend_from_slice(&port.to_be_bytes());
+        stream.write_all(&request).await?;
+
+        let mut reply = [0; 4];
+        stream.read_exact(&mut reply).await?;
+        if reply[0] != 5 {
+            return Err(TunnelError::InvalidResponse);
+        }
+        if reply[1] != 0 {
+            return Err(TunnelError::Refused(format!("SOCKS5 error {}", reply[1])));
+        }
+        // Skip the bound address and port.
+        let addr_len = match reply[3] {
+            1 => 4,
+            4 => 16,
+            3 => stream.read_u8().await? as usize,
+            _ => return Err(TunnelError::InvalidResponse),
+        };
+        let mut bound = vec![0; addr_len + 2];
+        stream.read_exact(&mut bound).await?;
+        Ok(())
+    }
+}
+
+/// Connector for `hyper::Client` that supports HTTPS, optionally through a proxy.
+///
+/// Connections are only made to addresses permitted by the `IpFilter`. When the filter restricts
+/// addresses and a proxy is used, host names are resolved and checked here, and the proxy is asked
+/// to connect to the resulting address instead.
+#[derive(Clone)]
+pub struct Connector {
+    http: HttpConnector<FilteredResolver>,
+    resolver: FilteredResolver,
+    proxy_http: HttpConnector,
+    tls: TlsConnector,
+    /// TLS connectors for specific hosts and ports.
+    origin_tls: Arc<HashMap<(String, u16), TlsConnector>>,
+    proxy: Option<Arc<Proxy>>,
+    filter: Arc<IpFilter>,
+    /// Time allowed to establish a connection, including any tunnel and TLS handshake.
+    timeout: Duration,
+}
+
+impl Connector {
+    pub fn new(options: FetchOptions) -> Result<Self, native_tls::Error> {
+        let filter = Arc::new(options.ip_filter);
+        let resolver = FilteredResolver::new(filter.clone());
+        let mut http = HttpConnector::new_with_resolver(resolver.clone());
+        http.enforce_http(false);
+        let tls = options.tls;
+        let origin_tls = options
+            .origin_tls
+            .into_iter()
+            .map(|(origin, origin_tls)| Ok((origin, tls.merge(&origin_tls).connector()?.into())))
+            .collect::<Result<_, native_tls::Error>>()?;
+        Ok(Connector {
+            http,
+            resolver,
+            proxy_http: HttpConnector::new(),
+            tls: tls.connector()?.into(),
+            origin_tls: Arc::new(origin_tls),
+            proxy: options.proxy.map(Arc::new),
+            filter,
+            timeout: options.connect_timeout,
+        })
+    }
+}
+
+impl Service<Uri> for Connector {
+    type Response = MaybeHttpsStream<TcpStream>;
+    type Error = BoxError;
+    type Future = BoxFuture<Result<Self::Response, BoxError>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), BoxError>> {
+        self.http.poll_ready(cx).map_err(Into::into)
+    }
+
+    fn call(&mut self, dst: Uri) -> Self::Future {
+        let mut http = self.http.clone();
+        let mut resolver = self.resolver.clone();
+        let mut proxy_http = self.proxy_http.clone();
+        let tls = self.tls.clone();
+        let origin_tls = self.origin_tls.clone();
+        let proxy = self.proxy.clone();
+        let filter = self.filter.clone();
+        let timeout = self.timeout;
+        let connect = async move {
+            let host = dst
+                .host()
+                .ok_or("URI has no host")?
+                .trim_start_matches('[')
+                .trim_end_matches(']')
+                .to_owned();
+            let is_https = dst.scheme() == Some(&Scheme::HTTPS);
+            let port = dst.port_u16().unwrap_or(if is_https { 443 } else { 80 });
+            // `HttpConnector` does not use the resolver for IP address hosts.
+            if let Ok(ip) = host.parse() {
+                if !filter.permits(ip) {
+                    return Err(ForbiddenAddress(host).into());
+                }
+            }
+            let stream = match proxy.filter(|proxy| proxy.applies_to(&host)) {
+                Some(proxy) => {
+                    // Without restrictions, leave resolving to the proxy.
+                    let target = if filter.public_only && host.parse::<IpAddr>().is_err() {
+                        let mut addrs = resolver.call(host.parse()?).await?;
+                        let addr = addrs.next().ok_or_else(|| ForbiddenAddress(host.clone()))?;
+                        addr.ip().to_string()
+                    } else {
+                        host.clone()
+                    };
+                    let mut stream = proxy_http.call(proxy.uri.clone()).await?;
+                    proxy.tunnel(&mut stream, &target, &host, port).await?;
+                    stream
+                }
+                None => http.call(dst).await?,
+            };
+            if is_https {
+                let tls = origin_tls
+                    .get(&(host.to_ascii_lowercase(), port))
+                    .unwrap_or(&tls);
+                Ok(MaybeHttpsStream::Https(tls.connect(&host, stream).await?))
+            } else {
+                Ok(MaybeHttpsStream::Http(stream))
+            }
+        };
+        Box::pin(async move {
+            tokio::time::timeout(timeout, connect)
+                .await
+                .unwrap_or_else(|_| Err(ConnectTimeout.into()))
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::net::TcpListener;
+
+    fn no_proxy(entries: &[&str]) -> Vec<String> {
+        entries.iter().map(|&entry| entry.to_owned()).collect()
+    }
+
+    #[test]
+    fn test_parse() {
+        let proxy = Proxy::new("proxy.example.com:3128", &[]).unwrap();
+        assert_eq!(proxy.scheme, ProxyScheme::Http);
+        assert_eq!(proxy.uri, "http://proxy.example.com:3128/");
+        assert!(proxy.credentials.is_none());
+
+        let proxy = Proxy::new("socks5://us%40r:p%3Ass@[::1]", &[]).unwrap();
+        assert_eq!(proxy.scheme, ProxyScheme::Socks5);
+        assert_eq!(proxy.uri, "http://[::1]:1080/");
+        assert_eq!(
+            proxy.credentials,
+            Some(("us@r".to_owned(), "p:ss".to_owned()))
+        );
+
+        assert!(Proxy::new("ftp://proxy.example.com", &[]).is_err());
+        let long = "x".repeat(256);
+        assert!(matches!(
+            Proxy::new(&format!("socks5://{}:pass@[::1]", long), &[]),
+            Err(ParseProxyError::CredentialsTooLong)
+        ));
+        assert!(matches!(
+            Proxy::new(&format!("socks5://user:{}@[::1]", long), &[]),
+            Err(ParseProxyError::CredentialsTooLong)
+        ));
+        assert!(Proxy::new(&format!("http://{}:pass@[::1]", long), &[]).is_ok());
+    }
+
+    #[test]
+    fn test_no_proxy() {
+        let proxy = Proxy::new("proxy:3128", &no_proxy(&["localhost", ".Internal"])).unwrap();
+        assert!(!proxy.applies_to("localhost"));
+        assert!(!proxy.applies_to("internal"));
+        assert!(!proxy.applies_to("idp.internal"));
+        assert!(!proxy.applies_to("IDP.INTERNAL."));
+        assert!(proxy.applies_to("notinternal"));
+        assert!(proxy.applies_to("example.com"));
+
+        let proxy = Proxy::new("proxy:3128", &no_proxy(&["*"])).unwrap();
+        assert!(!proxy.applies_to("example.com"));
+    }
+
+    /// Accept one connection, then check each message the client sends and respond to it.
+    async fn fake_proxy(exchanges: Vec<(&'static [u8], &'static [u8])>) -> Proxy {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            for (expect, respond) in exchanges {
+                let mut received = vec![0; expect.len()];
+                stream.read_exact(&mut received).await.unwrap();
+                assert_eq!(received, expect);
+                stream.write_all(respond).await.unwrap();
+            }
+            stream.write_all(b"tunnelled").await.unwrap();
+        });
+        Proxy::new(&format!("http://user:pass@{}", addr), &[]).unwrap()
+    }
+
+    async fn check_tunnel(proxy: Proxy, target: &str) -> Result<(), TunnelError> {
+        let mut stream = TcpStream::connect(proxy.uri.authority().unwrap().as_str())
+            .await
+            .unwrap();
+        proxy
+            .tunnel(&mut stream, target, "example.com", 443)
+            .await?;
+        let mut data = String::new();
+        stream.read_to_string(&mut data).await.unwrap();
+        assert_eq!(data, "tunnelled");
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_forbidden_address() {
+        // The proxy is never contacted, because addresses are checked first.
+        for proxy in vec![None, Some(Proxy::new("127.0.0.1:9", &[]).unwrap())] {
+            let options = FetchOptions {
+                ip_filter: IpFilter {
+                    public_only: true,
+                    allowed: Vec::new(),
+                },
+                proxy,
+                ..FetchOptions::default()
+            };
+            let mut connector = Connector::new(options).unwrap();
+            for uri in &[
+                "http://127.0.0.1/",
+                "https://[::1]:8443/",
+                "http://localhost/",
+            ] {
+                let err = match connector.call(uri.parse().unwrap()).await {
+                    Ok(_) => panic!("connected to {}", uri),
+                    Err(err) => err.to_string(),
+                };
+                assert!(err.contains("not a public IP address"), "{}: {}", uri, err);
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_http_connect() {
+        let expect = b"CONNECT example.com:443 HTTP/1.1\r\nHost: example.com:443\r\n\
+            Proxy-Authorization: Basic dXNlcjpwYXNz\r\n\r\n";
+        let proxy = fake_proxy(vec![(expect, b"HTTP/1.1 200 OK\r\n\r\n")]).await;
+        check_tunnel(proxy, "example.com").await.unwrap();
+
+        let proxy = fake_proxy(vec![(expect, b"HTTP/1.1 403 Forbidden\r\n\r\n")]).await;
+        assert!(matches!(
+            check_tunnel(proxy, "example.com").await,
+            Err(TunnelError::Refused(_))
+        ));
+
+        let expect = b"CONNECT [2001:db8::1]:443 HTTP/1.1\r\nHost: example.com:443\r\n\
+            Proxy-Authorization: Basic dXNlcjpwYXNz\r\n\r\n";
+        let proxy = fake_proxy(vec![(expect, b"HTTP/1.1 200 OK\r\n\r\n")]).await;
+        check_tunnel(proxy, "2001:db8::1").await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_socks5() {
+        let mut proxy = fake_proxy(vec![
+            (b"\x05\x02\x00\x02", b"\x05\x02"),
+            (b"\x01\x04user\x04pass", b"\x01\x00"),
+            (
+                b"\x05\x01\x00\x03\x0bexample.com\x01\xbb",
+                b"\x05\x00\x00\x01\x7f\x00\x00\x01\x00\x50",
+            ),
+        ])
+        .await;
+        proxy.scheme = ProxyScheme::Socks5;
+        check_tunnel(proxy, "example.com").await.unwrap();
+
+        let mut proxy = fake_proxy(vec![]).await;
+        proxy.scheme = ProxyScheme::Socks5;
+        let host = format!("{}.example.com", "x".repeat(250));
+        assert!(matches!(
+            check_tunnel(proxy, &host).await,
+            Err(TunnelError::HostTooLong)
+        ));
+    }
+}
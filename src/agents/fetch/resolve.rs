@@ -0,0 +1,108 @@
+use crate::utils::{is_public_ip, BoxError, BoxFuture};
+use hyper::client::connect::dns::{GaiResolver, Name};
+use hyper::service::Service;
+use ipnetwork::IpNetwork;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::vec::IntoIter;
+use thiserror::Error;
+
+#[derive(Debug, Error)]
+#[error("refusing to connect to {0}: not a public IP address")]
+pub struct ForbiddenAddress(pub String);
+
+/// Decides which IP addresses fetches may connect to.
+#[derive(Clone, Debug, Default)]
+pub struct IpFilter {
+    /// Whether to refuse addresses outside the public address space.
+    pub public_only: bool,
+    /// Networks that are allowed regardless of `public_only`.
+    pub allowed: Vec<IpNetwork>,
+}
+
+impl IpFilter {
+    pub fn permits(&self, ip: IpAddr) -> bool {
+        !self.public_only || is_public_ip(&ip) || self.allowed.iter().any(|net| net.contains(ip))
+    }
+}
+
+/// DNS resolver that drops addresses refused by an `IpFilter`.
+#[derive(Clone)]
+pub struct FilteredResolver {
+    inner: GaiResolver,
+    filter: Arc<IpFilter>,
+}
+
+impl FilteredResolver {
+    pub fn new(filter: Arc<IpFilter>) -> Self {
+        FilteredResolver {
+            inner: GaiResolver::new(),
+            filter,
+        }
+    }
+}
+
+impl Service<Name> for FilteredResolver {
+    type Response = IntoIter<SocketAddr>;
+    type Error = BoxError;
+    type Future = BoxFuture<Result<Self::Response, BoxError>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), BoxError>> {
+        self.inner.poll_ready(cx).map_err(Into::into)
+    }
+
+    fn call(&mut self, name: Name) -> Self::Future {
+        let future = self.inner.call(name.clone());
+        let filter = self.filter.clone();
+        Box::pin(async move {
+            let addrs: Vec<_> = future
+                .await?
+                .filter(|addr| filter.permits(addr.ip()))
+                .collect();
+            if addrs.is_empty() {
+                return Err(ForbiddenAddress(name.as_str().to_owned()).into());
+            }
+            Ok(addrs.into_iter())
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_ip_filter() {
+        let filter = IpFilter {
+            public_only: true,
+            allowed: vec!["10.1.0.0/16".parse().unwrap()],
+        };
+        for ip in &[
+            "93.184.216.34",
+            "2606:2800:220:1::248",
+            "10.1.2.3",
+            "64:ff9b::5db8:d822",
+        ] {
+            assert!(filter.permits(ip.parse().unwrap()), "{}", ip);
+        }
+        for ip in &[
+            "127.0.0.1",
+            "10.2.0.1",
+            "192.168.1.1",
+            "169.254.169.254",
+            "100.100.100.200",
+            "0.0.0.0",
+            "::1",
+            "fd00::1",
+            "fe80::1",
+            "::ffff:127.0.0.1",
+            "64:ff9b::7f00:1",
+            "64:ff9b::a02:1",
+            "2001:db8::1",
+        ] {
+            assert!(!filter.permits(ip.parse().unwrap()), "{}", ip);
+        }
+        assert!(IpFilter::default().permits("127.0.0.1".parse().unwrap()));
+    }
+}
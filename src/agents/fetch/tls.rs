@@ -0,0 +1,137 @@
+use crate::utils::pem::{self, ParseError};
+use native_tls::{Certificate, Identity, TlsConnector};
+use std::fs::File;
+use std::io::{BufReader, Error as IoError};
+use std::path::{Path, PathBuf};
+use thiserror::Error;
+
+#[derive(Debug, Error)]
+pub enum LoadTlsError {
+    #[error("could not read {0}: {1}")]
+    Io(PathBuf, IoError),
+    #[error("could not parse {0}: {1}")]
+    Pem(PathBuf, ParseError),
+    #[error("no certificates found in {0}")]
+    NoCertificates(PathBuf),
+    #[error("invalid certificate or identity in {0}: {1}")]
+    Tls(PathBuf, native_tls::Error),
+}
+
+/// Files containing TLS settings for outbound connections.
+#[derive(Clone, Debug, Default)]
+pub struct TlsFiles {
+    /// PEM file with additional trusted CA certificates.
+    pub ca_bundle: Option<PathBuf>,
+    /// PKCS #12 file with a client certificate and key.
+    pub client_identity: Option<PathBuf>,
+    /// Password for `client_identity`.
+    pub client_identity_password: String,
+}
+
+/// TLS settings for outbound connections, in addition to the system trust store.
+#[derive(Clone, Default)]
+pub struct TlsOptions {
+    /// Additional trusted CA certificates.
+    pub ca_certs: Vec<Certificate>,
+    /// Client certificate and key to present.
+    pub identity: Option<Identity>,
+}
+
+impl TlsOptions {
+    /// Load settings from files.
+    pub fn load(files: &TlsFiles) -> Result<Self, LoadTlsError> {
+        let mut options = TlsOptions::default();
+        if let Some(ref path) = files.ca_bundle {
+            options.ca_certs = load_certificates(path)?;
+        }
+        if let Some(ref path) = files.client_identity {
+            let der = std::fs::read(path).map_err(|err| LoadTlsError::Io(path.clone(), err))?;
+            let identity = Identity::from_pkcs12(&der, &files.client_identity_password)
+                .map_err(|err| LoadTlsError::Tls(path.clone(), err))?;
+            options.identity = Some(identity);
+        }
+        Ok(options)
+    }
+
+    /// Combine with settings for a specific origin, which trusts both sets of certificates and
+    /// prefers the origin client identity.
+    pub fn merge(&self, origin: &TlsOptions) -> TlsOptions {
+        TlsOptions {
+            ca_certs: self
+                .ca_certs
+                .iter()
+                .chain(&origin.ca_certs)
+                .cloned()
+                .collect(),
+            identity: origin.identity.clone().or_else(|| self.identity.clone()),
+        }
+    }
+
+    /// Build a connector using these settings.
+    pub fn connector(&self) -> Result<TlsConnector, native_tls::Error> {
+        let mut builder = TlsConnector::builder();
+        for cert in &self.ca_certs {
+            builder.add_root_certificate(cert.clone());
+        }
+        if let Some(ref identity) = self.identity {
+            builder.identity(identity.clone());
+        }
+        builder.build()
+    }
+}
+
+fn load_certificates(path: &Path) -> Result<Vec<Certificate>, LoadTlsError> {
+    let file = File::open(path).map_err(|err| LoadTlsError::Io(path.to_owned(), err))?;
+    let ders = pem::parse_certificates(BufReader::new(file))
+        .map_err(|err| LoadTlsError::Pem(path.to_owned(), err))?;
+    if ders.is_empty() {
+        return Err(LoadTlsError::NoCertificates(path.to_owned()));
+    }
+    ders.iter()
+        .map(|der| Certificate::from_der(der))
+        .collect::<Result<_, _>>()
+        .map_err(|err| LoadTlsError::Tls(path.to_owned(), err))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TEST_CA: &str = "-----BEGIN CERTIFICATE-----
+MIIBezCCASGgAwIBAgIUUgU1l//OWe/0VSQh+js36aghD/IwCgYIKoZIzj0EAwIw
+EjEQMA4GA1UEAwwHVGVzdCBDQTAgFw0yNjEwMTkwMDQzNDZaGA8yMTI2MDkyNTAw
+NDM0NlowEjEQMA4GA1UEAwwHVGVzdCBDQTBZMBMGByqGSM49AgEGCCqGSM49AwEH
+A0IABBkCuJ7+t3vl0j/c6qbXFWGzhBthv2mJfJd50w/tAMitHT9uS75n5xKFFSyz
+D4Ghvc2wgGEaqBSY+QAd/38xofWjUzBRMB0GA1UdDgQWBBRgok8Y6QlLRo6ZQ0XC
+5on0RgASLjAfBgNVHSMEGDAWgBRgok8Y6QlLRo6ZQ0XC5on0RgASLjAPBgNVHRMB
+Af8EBTADAQH/MAoGCCqGSM49BAMCA0gAMEUCIQCxHSzhqYZAZ4MOtlCdTo98gDUZ
+OVRanhq1ImVPocZC/AIgJ6uad+JqglQPiCzcJF0joa06tSwe2EnvrZnoAOhunH8=
+-----END CERTIFICATE-----
+";
+
+    #[test]
+    fn test_load_ca_bundle() {
+        let dir = std::env::temp_dir().join(format!("portier-tls-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let load = |contents: &str| {
+            let path = dir.join("ca.pem");
+            std::fs::write(&path, contents).unwrap();
+            TlsOptions::load(&TlsFiles {
+                ca_bundle: Some(path),
+                ..TlsFiles::default()
+            })
+        };
+
+        let bundle = format!("Test CA\n{}\nTest CA again\n{}", TEST_CA, TEST_CA);
+        let options = load(&bundle).unwrap();
+        assert_eq!(options.ca_certs.len(), 2);
+        assert_eq!(options.merge(&options).ca_certs.len(), 4);
+        assert!(options.connector().is_ok());
+
+        match load("no certificates here") {
+            Err(LoadTlsError::NoCertificates(_)) => {}
+            _ => panic!("expected NoCertificates error"),
+        }
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
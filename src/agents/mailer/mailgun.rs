@@ -1,6 +1,6 @@
+use crate::agents::*;
 use crate::email_address::EmailAddress;
 use crate::utils::agent::*;
-use crate::{agents::*, metrics};
 use http::Request;
 use hyper::Body;
 use url::form_urlencoded;
@@ -57,7 +57,7 @@ impl Handler<SendMail> for MailgunMailer {
 
         let future = self.fetcher.send(FetchUrl {
             request,
-            metric: &*metrics::AUTH_EMAIL_SEND_DURATION,
+            purpose: FetchPurpose::MailApi,
         });
         cx.reply_later(async move {
             match future.await {
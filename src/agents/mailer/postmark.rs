@@ -1,6 +1,6 @@
+use crate::agents::*;
 use crate::email_address::EmailAddress;
 use crate::utils::agent::*;
-use crate::{agents::*, metrics};
 use http::Request;
 use hyper::Body;
 use serde::Deserialize;
@@ -59,11 +59,11 @@ impl Handler<SendMail> for PostmarkMailer {
 
         let future = self.fetcher.send(FetchUrl {
             request,
-            metric: &*metrics::AUTH_EMAIL_SEND_DURATION,
+            purpose: FetchPurpose::MailApi,
         });
         cx.reply_later(async move {
             let data = match future.await {
-                Ok(result) => result.data,
+                Ok(result) => result.data.unwrap_or_default(),
                 Err(err) => {
                     log::error!("Postmark request failed: {}", err);
                     return false;
@@ -0,0 +1,164 @@
+//! Shared logic for caching fetched URLs in stores.
+
+use crate::agents::{FetchAgent, FetchError, FetchPurpose, FetchUrl, Validators};
+use crate::utils::{agent::Addr, unix_timestamp, BoxError};
+use http::StatusCode;
+use serde::{Deserialize, Serialize};
+use std::cmp::max;
+use std::collections::HashSet;
+use std::future::Future;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use url::Url;
+
+/// How long fetched responses are used.
+///
+/// These are minimums; responses can extend them using `Cache-Control` directives.
+#[derive(Clone, Copy, Debug)]
+pub struct CachePolicy {
+    /// Time a response is fresh.
+    pub ttl: Duration,
+    /// Time after `ttl` that a response is used while it is revalidated in the background.
+    pub stale_while_revalidate: Duration,
+    /// Time after `ttl` that a response is used if revalidation fails.
+    pub stale_if_error: Duration,
+}
+
+/// Freshness of a cache entry.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum CacheState {
+    /// The entry can be used as is.
+    Fresh,
+    /// The entry can be used, but should be revalidated in the background.
+    Stale,
+    /// The entry must be revalidated, and can only be used if that fails.
+    Expired,
+}
+
+/// A cached response. Times are Unix timestamps.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct CacheEntry {
+    pub data: String,
+    pub validators: Validators,
+    pub fresh_until: u64,
+    pub stale_until: u64,
+    /// Time after which the entry can no longer be used, even if revalidation fails.
+    pub error_until: u64,
+    /// Time after which the entry can be removed.
+    pub expires: u64,
+}
+
+impl CacheEntry {
+    /// Determine the current freshness of the entry.
+    pub fn state(&self) -> CacheState {
+        let now = unix_timestamp();
+        if now < self.fresh_until {
+            CacheState::Fresh
+        } else if now < self.stale_until {
+            CacheState::Stale
+        } else {
+            CacheState::Expired
+        }
+    }
+
+    /// Whether the entry can still be used when revalidation fails.
+    pub fn is_usable(&self) -> bool {
+        unix_timestamp() < self.error_until
+    }
+
+    /// Time remaining until the entry can be removed.
+    pub fn ttl(&self) -> Duration {
+        Duration::from_secs(self.expires.saturating_sub(unix_timestamp()))
+    }
+}
+
+/// Fetch a URL, revalidating the cached entry if there is one.
+pub async fn fetch_entry(
+    fetcher: &Addr<FetchAgent>,
+    policy: CachePolicy,
+    url: &Url,
+    purpose: FetchPurpose,
+    cached: Option<&CacheEntry>,
+) -> Result<CacheEntry, FetchError> {
+    let message = match cached {
+        Some(entry) => FetchUrl::revalidate(url, &entry.validators, purpose),
+        None => FetchUrl::get(url, purpose),
+    };
+    let result = fetcher.send(message).await?;
+    let (data, validators) = match (result.data, cached) {
+        (Some(data), _) => (data, result.validators),
+        // A `304 Not Modified` response may update validators.
+        (None, Some(entry)) => (
+            entry.data.clone(),
+            Validators {
+                etag: result
+                    .validators
+                    .etag
+                    .or_else(|| entry.validators.etag.clone()),
+                last_modified: result
+                    .validators
+                    .last_modified
+                    .or_else(|| entry.validators.last_modified.clone()),
+            },
+        ),
+        (None, None) => return Err(FetchError::BadStatus(StatusCode::NOT_MODIFIED)),
+    };
+    let lifetime = max(policy.ttl, result.max_age).as_secs();
+    let stale_while_revalidate = max(policy.stale_while_revalidate, result.stale_while_revalidate);
+    let stale_if_error = max(policy.stale_if_error, result.stale_if_error);
+    let fresh_until = unix_timestamp() + lifetime;
+    let stale_until = fresh_until + stale_while_revalidate.as_secs();
+    let error_until = fresh_until + max(stale_while_revalidate, stale_if_error).as_secs();
+    // Entries with validators are kept for another lifetime, so they can be revalidated.
+    let expires = if validators == Validators::default() {
+        error_until
+    } else {
+        max(error_until, fresh_until + lifetime)
+    };
+    Ok(CacheEntry {
+        data,
+        validators,
+        fresh_until,
+        stale_until,
+        error_until,
+        expires,
+    })
+}
+
+/// Fall back to a cached entry after a failed fetch, if it is still usable.
+pub fn stale_on_error(
+    cached: Option<&CacheEntry>,
+    url: &Url,
+    err: FetchError,
+) -> Result<String, FetchError> {
+    match cached {
+        Some(entry) if entry.is_usable() => {
+            log::warn!("Using stale response for {}, fetch failed: {}", url, err);
+            Ok(entry.data.clone())
+        }
+        _ => Err(err),
+    }
+}
+
+/// Tracks background revalidations, so each URL is only revalidated once at a time.
+#[derive(Clone, Default)]
+pub struct Revalidations(Arc<Mutex<HashSet<Url>>>);
+
+impl Revalidations {
+    /// Spawn a task to revalidate a stale entry, unless one is already running for this URL.
+    pub fn spawn<F>(&self, url: Url, future: F)
+    where
+        F: Future<Output = Result<(), BoxError>> + Send + 'static,
+    {
+        if !self.0.lock().unwrap().insert(url.clone()) {
+            return;
+        }
+        let running = self.0.clone();
+        tokio::spawn(async move {
+            if let Err(err) = future.await {
+                log::warn!("Background revalidation of {} failed: {}", url, err);
+            }
+            running.lock().unwrap().remove(&url);
+        });
+    }
+}
@@ -1,12 +1,21 @@
+use crate::agents::store::cache::{fetch_entry, stale_on_error, Revalidations};
 use crate::agents::*;
-use crate::config::LimitConfig;
+use crate::config::{LimitAlgorithm, LimitConfig, LimitExceeded, LimitRules};
 use crate::crypto::SigningAlgorithm;
-use crate::utils::agent::*;
+use crate::utils::{agent::*, unix_duration, unix_timestamp, DomainValidation};
 use crate::web::Session;
-use std::collections::hash_map::{Entry, HashMap};
+use futures_util::future::Future;
+use hashlink::{linked_hash_map::Entry, LruCache};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufReader, BufWriter, ErrorKind};
+use std::path::{Path, PathBuf};
 use std::sync::Arc;
-use std::time::{Duration, Instant};
-use tokio::sync::Mutex;
+use std::time::Duration;
+use tokio::sync::{mpsc, Mutex};
+use tokio::task::spawn_blocking;
+use tokio::time::Instant;
 use url::Url;
 
 /// Combines any type with an `Instant` expiry time.
@@ -34,13 +43,76 @@ impl Message for Gc {
     type Reply = ();
 }
 
+/// Message sent to write a snapshot to disk.
+struct WriteSnapshot;
+impl Message for WriteSnapshot {
+    type Reply = ();
+}
+
+/// A session as stored in a snapshot.
+#[derive(Serialize, Deserialize)]
+struct SnapshotSession {
+    id: String,
+    /// Expiry time as a Unix timestamp.
+    expires: u64,
+    data: Session,
+}
+
+/// A rate limit entry as stored in a snapshot.
+#[derive(Serialize, Deserialize)]
+struct SnapshotLimit {
+    id: String,
+    /// Expiry time as a Unix timestamp.
+    expires: u64,
+    /// Same as the value in `StoreData`.
+    value: i64,
+}
+
+/// Contents of a snapshot file.
+#[derive(Default, Serialize, Deserialize)]
+struct Snapshot {
+    keys: Vec<KeySet>,
+    #[serde(default)]
+    sessions: Vec<SnapshotSession>,
+    /// Only written when importing data.
+    #[serde(default)]
+    limits: Vec<SnapshotLimit>,
+}
+
+/// Options for the memory store.
+#[derive(Clone)]
+pub struct MemoryOptions {
+    /// Maximum number of cache entries, or 0 for unlimited.
+    pub max_cache_entries: usize,
+    /// Maximum number of rate limit entries, or 0 for unlimited.
+    pub max_limit_entries: usize,
+    /// Path to periodically write a snapshot to.
+    pub snapshot_path: Option<PathBuf>,
+    /// Interval between snapshots.
+    pub snapshot_interval: Duration,
+    /// Whether to include sessions in snapshots.
+    pub snapshot_sessions: bool,
+}
+
+/// Create an `LruCache`, where a capacity of 0 means unbounded.
+fn lru_cache<K: Eq + std::hash::Hash, V>(capacity: usize) -> LruCache<K, V> {
+    if capacity == 0 {
+        LruCache::new_unbounded()
+    } else {
+        LruCache::new(capacity)
+    }
+}
+
+/// Rate limit storage. Entries are evicted in LRU order.
+type Limits = LruCache<String, Expiring<usize>>;
+
 /// A slot in the cache `HashMap`.
 ///
 /// We want to lock these individually while a fetch is in progress, so multiple requests for the
 /// same URL result in only one fetch. Therefore, we use an `Arc<Mutex<_>>` to carry slots around,
 /// and within an `Option` which indicates whether cache is actually present (despite the hash map
 /// entry existing or not, which does not indicate anything).
-type CacheSlot = Arc<Mutex<Option<Expiring<String>>>>;
+type CacheSlot = Arc<Mutex<Option<CacheEntry>>>;
 
 /// A slot in the keys `HashMap`.
 ///
@@ -52,49 +124,276 @@ type KeysSlot = Arc<Mutex<KeySet>>;
 pub struct MemoryStore {
     /// TTL of session keys
     expire_sessions: Duration,
-    /// TTL of cache keys
-    expire_cache: Duration,
+    /// Cache timing configuration.
+    cache_policy: CachePolicy,
     /// Rate limit configuration.
-    limit_configs: Vec<LimitConfig>,
+    limit_rules: LimitRules,
+    /// Snapshot configuration.
+    snapshot_path: Option<PathBuf>,
+    snapshot_interval: Duration,
+    snapshot_sessions: bool,
+    /// Number of snapshots built so far.
+    snapshot_generation: u64,
+    /// Generation of the last snapshot written. Locked while writing, so writes are serialized.
+    snapshot_written: Arc<Mutex<u64>>,
     /// The agent used for fetching on cache miss.
     fetcher: Addr<FetchAgent>,
+    /// Background revalidations in progress.
+    revalidations: Revalidations,
     /// Key manager if rotating keys are enabled.
     key_manager: Option<Addr<RotatingKeys>>,
     /// Session storage.
     sessions: HashMap<String, Expiring<Session>>,
+    /// Challenge storage.
+    challenges: HashMap<String, Expiring<String>>,
     /// Cache storage.
-    cache: HashMap<Url, CacheSlot>,
+    cache: LruCache<Url, CacheSlot>,
+    /// Domain validation storage. Shares the cache size limit.
+    domain_validations: LruCache<String, Expiring<DomainValidation>>,
     /// Rate limit storage.
-    limits: HashMap<String, Expiring<usize>>,
+    limits: Limits,
     /// Keys storage.
     keys: HashMap<SigningAlgorithm, KeysSlot>,
+    /// Keys read from a snapshot, used when rotating keys are enabled.
+    snapshot_keys: HashMap<SigningAlgorithm, KeySet>,
 }
 
 impl MemoryStore {
     pub fn new(
+        options: MemoryOptions,
         expire_sessions: Duration,
-        expire_cache: Duration,
-        limit_configs: Vec<LimitConfig>,
+        cache_policy: CachePolicy,
+        limit_rules: LimitRules,
         fetcher: Addr<FetchAgent>,
-    ) -> Self {
-        log::warn!("Storing sessions and keys in memory.");
-        log::warn!("Note that these will be lost on restart!");
-
-        MemoryStore {
+    ) -> io::Result<Self> {
+        let mut store = MemoryStore {
             expire_sessions,
-            expire_cache,
-            limit_configs,
+            cache_policy,
+            limit_rules,
+            snapshot_path: options.snapshot_path,
+            snapshot_interval: options.snapshot_interval,
+            snapshot_sessions: options.snapshot_sessions,
+            snapshot_generation: 0,
+            snapshot_written: Arc::new(Mutex::new(0)),
             fetcher,
+            revalidations: Revalidations::default(),
             key_manager: None,
             sessions: HashMap::new(),
-            cache: HashMap::new(),
-            limits: HashMap::new(),
+            challenges: HashMap::new(),
+            cache: lru_cache(options.max_cache_entries),
+            domain_validations: lru_cache(options.max_cache_entries),
+            limits: lru_cache(options.max_limit_entries),
             keys: HashMap::new(),
+            snapshot_keys: HashMap::new(),
+        };
+
+        match store.snapshot_path {
+            Some(ref path) => {
+                log::warn!("Storing sessions and keys in memory, with snapshots.");
+                if !store.snapshot_sessions {
+                    log::warn!("Note that sessions will be lost on restart!");
+                }
+                let snapshot = match File::open(path) {
+                    Ok(file) => serde_json::from_reader(BufReader::new(file))?,
+                    Err(err) if err.kind() == ErrorKind::NotFound => Snapshot::default(),
+                    Err(err) => return Err(err),
+                };
+                store.load_snapshot(snapshot);
+            }
+            None => {
+                log::warn!("Storing sessions and keys in memory.");
+                log::warn!("Note that these will be lost on restart!");
+            }
+        }
+
+        Ok(store)
+    }
+
+    /// Restore state from a snapshot.
+    fn load_snapshot(&mut self, snapshot: Snapshot) {
+        for key_set in snapshot.keys {
+            self.snapshot_keys.insert(key_set.signing_alg, key_set);
+        }
+        for session in snapshot.sessions {
+            self.insert_session(StoreEntry {
+                id: session.id,
+                value: session.data,
+                expires: Duration::from_secs(session.expires),
+            });
+        }
+        for limit in snapshot.limits {
+            self.insert_limit(StoreEntry {
+                id: limit.id,
+                value: limit.value,
+                expires: Duration::from_secs(limit.expires),
+            });
+        }
+    }
+
+    /// Collect state for a snapshot.
+    ///
+    /// Returns `None` if keys are currently being rotated.
+    fn build_snapshot(&self, include_sessions: bool, include_limits: bool) -> Option<Snapshot> {
+        let keys = self.key_sets()?;
+        let mut sessions = Vec::new();
+        if include_sessions {
+            for entry in self.session_entries() {
+                sessions.push(SnapshotSession {
+                    expires: entry.expires_secs(),
+                    id: entry.id,
+                    data: entry.value,
+                });
+            }
+        }
+        let mut limits = Vec::new();
+        if include_limits {
+            for entry in self.limit_entries() {
+                limits.push(SnapshotLimit {
+                    expires: entry.expires_secs(),
+                    id: entry.id,
+                    value: entry.value,
+                });
+            }
+        }
+
+        Some(Snapshot {
+            keys,
+            sessions,
+            limits,
+        })
+    }
+
+    /// Sessions that have not expired, as copied between stores.
+    fn session_entries(&self) -> Vec<StoreEntry<Session>> {
+        let now = Instant::now();
+        let unix_now = unix_duration();
+        self.sessions
+            .iter()
+            .filter(|(_, entry)| entry.is_alive())
+            .map(|(id, entry)| StoreEntry {
+                id: id.clone(),
+                value: entry.value.clone(),
+                expires: unix_now + entry.expires.saturating_duration_since(now),
+            })
+            .collect()
+    }
+
+    /// Rate limit entries that have not expired, as copied between stores.
+    fn limit_entries(&self) -> Vec<StoreEntry<i64>> {
+        let now = Instant::now();
+        let unix_now = unix_duration();
+        self.limits
+            .iter()
+            .filter(|(_, entry)| entry.is_alive())
+            .map(|(id, entry)| {
+                let expires = unix_now + entry.expires.saturating_duration_since(now);
+                let value = if self.is_token_bucket(id) {
+                    expires.as_micros() as i64
+                } else {
+                    entry.value as i64
+                };
+                StoreEntry {
+                    id: id.clone(),
+                    value,
+                    expires,
+                }
+            })
+            .collect()
+    }
+
+    /// Add a session copied from another store, unless it expired.
+    fn insert_session(&mut self, entry: StoreEntry<Session>) {
+        if let Some(ttl) = entry.ttl() {
+            self.sessions
+                .insert(entry.id, Expiring::from_duration(entry.value, ttl));
+        }
+    }
+
+    /// Add a rate limit entry copied from another store, unless it expired.
+    fn insert_limit(&mut self, entry: StoreEntry<i64>) {
+        if let Some(ttl) = entry.ttl() {
+            let expiring = if self.is_token_bucket(&entry.id) {
+                let tat = Duration::from_micros(entry.value as u64);
+                let wait = tat.checked_sub(unix_duration()).unwrap_or_default();
+                Expiring::from_duration(0, wait)
+            } else {
+                Expiring::from_duration(entry.value as usize, ttl)
+            };
+            self.limits.insert(entry.id, expiring);
         }
     }
+
+    /// Write a snapshot on the blocking thread pool.
+    ///
+    /// Writes are serialized, and the snapshot is skipped if a newer one was already written.
+    fn write_snapshot_later(&mut self, snapshot: Snapshot) -> impl Future<Output = io::Result<()>> {
+        self.snapshot_generation += 1;
+        let generation = self.snapshot_generation;
+        let written = self.snapshot_written.clone();
+        let path = self.snapshot_path.clone().unwrap();
+        async move {
+            let mut written = written.lock().await;
+            if *written > generation {
+                return Ok(());
+            }
+            spawn_blocking(move || write_snapshot(&path, &snapshot))
+                .await
+                .expect("snapshot writer panicked")?;
+            *written = generation;
+            Ok(())
+        }
+    }
+
+    /// Collect all key sets.
+    ///
+    /// Returns `None` if keys are currently being rotated.
+    fn key_sets(&self) -> Option<Vec<KeySet>> {
+        let mut keys = Vec::with_capacity(self.keys.len());
+        for slot in self.keys.values() {
+            keys.push(slot.try_lock().ok()?.clone());
+        }
+        // Keys from a snapshot that were not (yet) enabled are kept.
+        for (signing_alg, key_set) in &self.snapshot_keys {
+            if !self.keys.contains_key(signing_alg) {
+                keys.push(key_set.clone());
+            }
+        }
+        Some(keys)
+    }
+
+    /// Whether a rate limit entry belongs to a token bucket limit.
+    fn is_token_bucket(&self, id: &str) -> bool {
+        id.split('|')
+            .next()
+            .and_then(|id| id.parse::<usize>().ok())
+            .and_then(|id| self.limit_rules.configs.get(id))
+            .map_or(false, |config| {
+                config.algorithm == LimitAlgorithm::TokenBucket
+            })
+    }
+}
+
+/// Write a snapshot file, replacing any existing file.
+fn write_snapshot(path: &Path, snapshot: &Snapshot) -> io::Result<()> {
+    let mut tmp_path = path.as_os_str().to_owned();
+    tmp_path.push(".tmp");
+    let mut open_options = OpenOptions::new();
+    open_options.write(true).create(true).truncate(true);
+    // The snapshot contains private keys.
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut open_options, 0o600);
+    let file = open_options.open(&tmp_path)?;
+    serde_json::to_writer(BufWriter::new(&file), snapshot)?;
+    file.sync_all()?;
+    fs::rename(&tmp_path, path)
 }
 
 impl Agent for MemoryStore {
+    fn spawn_loop(self, rx: mpsc::Receiver<DispatchFn<Self>>) {
+        // Snapshots are written on a separate thread, so handlers never block.
+        spawn_nonblocking_loop(self, rx);
+    }
+
     fn started(&mut self, cx: Context<Self, AgentStarted>) {
         // Start the garbage collection loop.
         let addr = cx.addr().clone();
@@ -107,6 +406,18 @@ impl Agent for MemoryStore {
                 addr.send(Gc).await;
             }
         });
+        // Start the snapshot loop.
+        if self.snapshot_path.is_some() {
+            let addr = cx.addr().clone();
+            let mut interval = tokio::time::interval(self.snapshot_interval);
+            tokio::spawn(async move {
+                interval.tick().await;
+                loop {
+                    interval.tick().await;
+                    addr.send(WriteSnapshot).await;
+                }
+            });
+        }
         cx.reply(());
     }
 }
@@ -118,7 +429,13 @@ impl Handler<Gc> for MemoryStore {
             .drain()
             .filter(|(_, ref entry)| entry.is_alive())
             .collect();
-        self.cache = self
+        self.challenges = self
+            .challenges
+            .drain()
+            .filter(|(_, ref entry)| entry.is_alive())
+            .collect();
+        // Rebuild LRU tables in their existing order.
+        let cache: Vec<_> = self
             .cache
             .drain()
             .filter(|(_, ref slot)| {
@@ -128,22 +445,50 @@ impl Handler<Gc> for MemoryStore {
                     Arc::strong_count(slot) > 1
                         || maybe_entry
                             .as_ref()
-                            .filter(|entry| entry.is_alive())
+                            .filter(|entry| entry.expires > unix_timestamp())
                             .is_some()
                 } else {
                     true
                 }
             })
             .collect();
-        self.limits = self
+        self.cache.extend(cache);
+        let domain_validations: Vec<_> = self
+            .domain_validations
+            .drain()
+            .filter(|(_, ref entry)| entry.is_alive())
+            .collect();
+        self.domain_validations.extend(domain_validations);
+        let limits: Vec<_> = self
             .limits
             .drain()
             .filter(|(_, ref entry)| entry.is_alive())
             .collect();
+        self.limits.extend(limits);
         cx.reply(())
     }
 }
 
+impl Handler<WriteSnapshot> for MemoryStore {
+    fn handle(&mut self, _message: WriteSnapshot, cx: Context<Self, WriteSnapshot>) {
+        let path = self.snapshot_path.clone().unwrap();
+        match self.build_snapshot(self.snapshot_sessions, false) {
+            Some(snapshot) => {
+                let write_fut = self.write_snapshot_later(snapshot);
+                cx.reply_later(async move {
+                    if let Err(err) = write_fut.await {
+                        log::error!("Could not write snapshot to {}: {}", path.display(), err);
+                    }
+                });
+            }
+            None => {
+                log::debug!("Skipping snapshot, because keys are being rotated");
+                cx.reply(());
+            }
+        }
+    }
+}
+
 impl Handler<SaveSession> for MemoryStore {
     fn handle(&mut self, message: SaveSession, cx: Context<Self, SaveSession>) {
         self.sessions.insert(
@@ -172,73 +517,239 @@ impl Handler<DeleteSession> for MemoryStore {
     }
 }
 
+impl Handler<SaveChallenge> for MemoryStore {
+    fn handle(&mut self, message: SaveChallenge, cx: Context<Self, SaveChallenge>) {
+        self.challenges.insert(
+            message.challenge_id,
+            Expiring::from_duration(message.data, self.expire_sessions),
+        );
+        cx.reply(Ok(()));
+    }
+}
+
+impl Handler<TakeChallenge> for MemoryStore {
+    fn handle(&mut self, message: TakeChallenge, cx: Context<Self, TakeChallenge>) {
+        let data = self
+            .challenges
+            .remove(&message.challenge_id)
+            .filter(Expiring::is_alive)
+            .map(|entry| entry.value);
+        cx.reply(Ok(data));
+    }
+}
+
+impl Handler<GetDomainValidation> for MemoryStore {
+    fn handle(&mut self, message: GetDomainValidation, cx: Context<Self, GetDomainValidation>) {
+        let validation = self
+            .domain_validations
+            .get(&message.key)
+            .filter(|entry| entry.is_alive())
+            .map(|entry| entry.value);
+        cx.reply(Ok(validation));
+    }
+}
+
+impl Handler<SaveDomainValidation> for MemoryStore {
+    fn handle(&mut self, message: SaveDomainValidation, cx: Context<Self, SaveDomainValidation>) {
+        self.domain_validations.insert(
+            message.key,
+            Expiring::from_duration(message.validation, message.ttl),
+        );
+        cx.reply(Ok(()));
+    }
+}
+
 impl Handler<FetchUrlCached> for MemoryStore {
     fn handle(&mut self, message: FetchUrlCached, cx: Context<Self, FetchUrlCached>) {
         let fetcher = self.fetcher.clone();
-        let slot = self.cache.entry(message.url.clone()).or_default().clone();
-        let expire_cache = self.expire_cache;
+        let slot = match self.cache.get(&message.url) {
+            Some(slot) => slot.clone(),
+            None => {
+                let slot = CacheSlot::default();
+                self.cache.insert(message.url.clone(), slot.clone());
+                slot
+            }
+        };
+        let policy = self.cache_policy;
+        let revalidations = self.revalidations.clone();
         cx.reply_later(async move {
-            let mut slot = slot.lock().await;
-            if let Some(entry) = slot.as_ref().filter(|entry| entry.is_alive()) {
-                return Ok(entry.value.clone());
+            let mut entry = slot.lock().await;
+            if let Some(cached) = entry.as_ref() {
+                match cached.state() {
+                    CacheState::Fresh => return Ok(cached.data.clone()),
+                    CacheState::Stale => {
+                        let (slot, stale) = (slot.clone(), cached.clone());
+                        let FetchUrlCached { url, purpose } = message;
+                        revalidations.spawn(url.clone(), async move {
+                            let fresh =
+                                fetch_entry(&fetcher, policy, &url, purpose, Some(&stale)).await?;
+                            *slot.lock().await = Some(fresh);
+                            Ok(())
+                        });
+                        return Ok(cached.data.clone());
+                    }
+                    CacheState::Expired => {}
+                }
+            }
+            match fetch_entry(
+                &fetcher,
+                policy,
+                &message.url,
+                message.purpose,
+                entry.as_ref(),
+            )
+            .await
+            {
+                Ok(fresh) => {
+                    let data = fresh.data.clone();
+                    *entry = Some(fresh);
+                    Ok(data)
+                }
+                Err(err) => Ok(stale_on_error(entry.as_ref(), &message.url, err)?),
             }
-            let result = fetcher
-                .send(FetchUrl::get(&message.url, message.metric))
-                .await?;
-            let ttl = std::cmp::max(expire_cache, result.max_age);
-            *slot = Some(Expiring::from_duration(result.data.clone(), ttl));
-            Ok(result.data)
         });
     }
 }
 
+/// Increment a fixed window limit, and test it.
+fn incr_fixed_window(
+    limits: &mut Limits,
+    config: &LimitConfig,
+    key: &str,
+) -> Option<LimitExceeded> {
+    let now = Instant::now();
+    if let Some(expiring) = limits.get_mut(key) {
+        if expiring.expires <= now {
+            *expiring = Expiring::from_duration(1, config.window);
+        } else {
+            if config.extend_window {
+                expiring.expires = now + config.window;
+            }
+            expiring.value = expiring.value.saturating_add(1);
+        }
+    } else {
+        limits.insert(key.to_owned(), Expiring::from_duration(1, config.window));
+    }
+    let expiring = limits.peek(key).unwrap();
+    if expiring.value <= config.max_count {
+        None
+    } else {
+        Some(LimitExceeded::new(config, expiring.expires - now))
+    }
+}
+
+/// Increment a sliding window limit, and test it.
+fn incr_sliding_window(
+    limits: &mut Limits,
+    config: &LimitConfig,
+    key: &str,
+) -> Option<LimitExceeded> {
+    let window = config.sliding_window(unix_duration());
+    let current_key = window.current_key(key, "|");
+    let current = if let Some(expiring) = limits.get_mut(&current_key) {
+        expiring.value = expiring.value.saturating_add(1);
+        expiring.value
+    } else {
+        limits.insert(current_key, Expiring::from_duration(1, window.ttl));
+        1
+    };
+    let previous = limits
+        .get(&window.previous_key(key, "|"))
+        .filter(|entry| entry.is_alive())
+        .map_or(0, |entry| entry.value);
+    if window.count(previous, current) <= config.max_count {
+        None
+    } else {
+        let reset = window.reset(previous, current, config.max_count);
+        Some(LimitExceeded::new(config, reset))
+    }
+}
+
+/// Try to take a token from a token bucket limit.
+///
+/// The expiry time of the entry doubles as the arrival time of the bucket.
+fn take_token_bucket(
+    limits: &mut Limits,
+    config: &LimitConfig,
+    key: String,
+) -> Option<LimitExceeded> {
+    let tat = limits.get(&key).map(|entry| entry.expires);
+    match config.bucket_take(tat, Instant::now()) {
+        Ok(expires) => {
+            limits.insert(key, Expiring { value: 0, expires });
+            None
+        }
+        Err(reset) => Some(LimitExceeded::new(config, reset)),
+    }
+}
+
 impl Handler<IncrAndTestLimits> for MemoryStore {
     fn handle(&mut self, message: IncrAndTestLimits, cx: Context<Self, IncrAndTestLimits>) {
-        let mut ok = true;
-        for config in &self.limit_configs {
-            let key = message.input.build_key(&config, "", "|");
-            let count = match self.limits.entry(key) {
-                Entry::Occupied(mut entry) => {
-                    let mut expiring = entry.get_mut();
-                    let now = Instant::now();
-                    if expiring.expires <= now {
-                        *expiring = Expiring::from_duration(1, config.window);
-                        1
-                    } else {
-                        if config.extend_window {
-                            expiring.expires = now + config.window;
-                        }
-                        expiring.value = expiring.value.saturating_add(1);
-                        expiring.value
-                    }
-                }
-                Entry::Vacant(entry) => {
-                    entry.insert(Expiring::from_duration(1, config.window));
-                    1
+        let mut exceeded = Vec::new();
+        for config in self.limit_rules.select(&message.input) {
+            let key = message.input.build_key(config, "", "|");
+            let result = match config.algorithm {
+                LimitAlgorithm::FixedWindow => incr_fixed_window(&mut self.limits, config, &key),
+                LimitAlgorithm::SlidingWindow => {
+                    incr_sliding_window(&mut self.limits, config, &key)
                 }
+                LimitAlgorithm::TokenBucket => take_token_bucket(&mut self.limits, config, key),
             };
-            ok = ok && count <= config.max_count;
+            exceeded.extend(result);
         }
-        cx.reply(Ok(ok));
+        cx.reply(Ok(LimitExceeded::most_severe(exceeded)));
     }
 }
 
 impl Handler<DecrLimits> for MemoryStore {
     fn handle(&mut self, message: DecrLimits, cx: Context<Self, DecrLimits>) {
-        for config in &self.limit_configs {
+        for config in self.limit_rules.select(&message.input) {
             if !config.decr_complete {
                 continue;
             }
-            let key = message.input.build_key(&config, "", "|");
-            if let Entry::Occupied(mut entry) = self.limits.entry(key) {
-                let Expiring { expires, value } = *entry.get();
-                let now = Instant::now();
-                if expires <= now || value <= 1 {
-                    entry.remove();
-                } else {
-                    entry.get_mut().value -= 1;
+            let key = message.input.build_key(config, "", "|");
+            let now = Instant::now();
+            match config.algorithm {
+                LimitAlgorithm::FixedWindow | LimitAlgorithm::SlidingWindow => {
+                    let keys = if config.algorithm == LimitAlgorithm::FixedWindow {
+                        vec![key]
+                    } else {
+                        let window = config.sliding_window(unix_duration());
+                        vec![
+                            window.current_key(&key, "|"),
+                            window.previous_key(&key, "|"),
+                        ]
+                    };
+                    // Decrement the first counter that is still alive.
+                    for key in keys {
+                        if let Entry::Occupied(mut entry) = self.limits.entry(key) {
+                            entry.to_back();
+                            let Expiring { expires, value } = *entry.get();
+                            if expires <= now {
+                                entry.remove();
+                                continue;
+                            }
+                            if value <= 1 {
+                                entry.remove();
+                            } else {
+                                entry.get_mut().value -= 1;
+                            }
+                            break;
+                        }
+                    }
                 }
-            };
+                LimitAlgorithm::TokenBucket => {
+                    if let Entry::Occupied(mut entry) = self.limits.entry(key) {
+                        entry.to_back();
+                        match config.bucket_return(entry.get().expires, now) {
+                            Some(expires) => entry.get_mut().expires = expires,
+                            None => {
+                                entry.remove();
+                            }
+                        }
+                    }
+                }
+            }
         }
         cx.reply(Ok(()));
     }
@@ -249,7 +760,10 @@ impl Handler<EnableRotatingKeys> for MemoryStore {
         self.key_manager = Some(message.key_manager.clone());
         let mut update_msgs = Vec::with_capacity(message.signing_algs.len());
         for signing_alg in &message.signing_algs {
-            let key_set = KeySet::empty(*signing_alg);
+            let key_set = self
+                .snapshot_keys
+                .remove(signing_alg)
+                .unwrap_or_else(|| KeySet::empty(*signing_alg));
             update_msgs.push(UpdateKeys(key_set.clone()));
             self.keys
                 .insert(*signing_alg, Arc::new(Mutex::new(key_set)));
@@ -266,13 +780,19 @@ impl Handler<RotateKeysLocked> for MemoryStore {
     fn handle(&mut self, message: RotateKeysLocked, cx: Context<Self, RotateKeysLocked>) {
         let slot_rc = self.keys[&message.0].clone();
         let key_manager = self.key_manager.as_ref().unwrap().clone();
+        let addr = self.snapshot_path.as_ref().map(|_| cx.addr().clone());
         cx.reply_later(async move {
             let mut slot = slot_rc
                 .try_lock()
                 .expect("Keys lock should never be locked twice");
             if let Some(key_set) = key_manager.send(RotateKeys(slot.clone())).await {
                 *slot = key_set.clone();
+                drop(slot);
                 key_manager.send(UpdateKeys(key_set)).await;
+                // Persist new keys right away.
+                if let Some(addr) = addr {
+                    addr.send(WriteSnapshot).await;
+                }
             }
         });
     }
@@ -284,4 +804,169 @@ impl Handler<ImportKeySet> for MemoryStore {
     }
 }
 
+impl Handler<ExportData> for MemoryStore {
+    fn handle(&mut self, message: ExportData, cx: Context<Self, ExportData>) {
+        let key_sets = match self.key_sets() {
+            Some(key_sets) => key_sets,
+            None => return cx.reply(Err("Keys are being rotated, try again later".into())),
+        };
+        let sessions = if message.sessions {
+            self.session_entries()
+        } else {
+            vec![]
+        };
+        let limits = if message.limits {
+            self.limit_entries()
+        } else {
+            vec![]
+        };
+
+        cx.reply(Ok(StoreData {
+            key_sets,
+            sessions,
+            limits,
+        }));
+    }
+}
+
+impl Handler<ImportData> for MemoryStore {
+    fn handle(&mut self, message: ImportData, cx: Context<Self, ImportData>) {
+        if self.snapshot_path.is_none() {
+            return cx.reply(Err(
+                "Importing into a memory store without snapshots has no effect".into(),
+            ));
+        }
+        let StoreData {
+            key_sets,
+            sessions,
+            limits,
+        } = message.0;
+
+        let mut update_msgs = Vec::new();
+        for key_set in key_sets {
+            match self.keys.get(&key_set.signing_alg) {
+                Some(slot) => match slot.try_lock() {
+                    Ok(mut slot) => {
+                        *slot = key_set.clone();
+                        update_msgs.push(UpdateKeys(key_set));
+                    }
+                    Err(_) => {
+                        return cx.reply(Err("Keys are being rotated, try again later".into()))
+                    }
+                },
+                None => {
+                    self.snapshot_keys.insert(key_set.signing_alg, key_set);
+                }
+            }
+        }
+        for entry in sessions {
+            self.insert_session(entry);
+        }
+        for entry in limits {
+            self.insert_limit(entry);
+        }
+
+        // Always include sessions and rate limits here, so imported data is not lost, even if
+        // periodic snapshots leave them out.
+        let write_fut = self
+            .build_snapshot(true, true)
+            .map(|snapshot| self.write_snapshot_later(snapshot));
+        let key_manager = self.key_manager.clone();
+        cx.reply_later(async move {
+            let result = match write_fut {
+                Some(write_fut) => write_fut.await.map_err(Into::into),
+                None => Err("Keys are being rotated, try again later".into()),
+            };
+            if let Some(key_manager) = key_manager {
+                for update_msg in update_msgs {
+                    key_manager.send(update_msg).await;
+                }
+            }
+            result
+        });
+    }
+}
+
 impl StoreSender for Addr<MemoryStore> {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::agents::store::tests::{test_cache_policy, test_session, wait_for_jwks};
+    use crate::utils::SecureRandom;
+
+    fn test_options(snapshot_path: Option<PathBuf>) -> MemoryOptions {
+        MemoryOptions {
+            max_cache_entries: 2,
+            max_limit_entries: 2,
+            snapshot_path,
+            snapshot_interval: Duration::from_secs(300),
+            snapshot_sessions: true,
+        }
+    }
+
+    #[test]
+    fn test_limits_eviction() {
+        let config: LimitConfig = "email:1/1h".parse().unwrap();
+        let mut limits: Limits = lru_cache(2);
+        assert!(incr_fixed_window(&mut limits, &config, "a").is_none());
+        assert!(incr_fixed_window(&mut limits, &config, "b").is_none());
+        assert!(incr_fixed_window(&mut limits, &config, "a").is_some());
+        // Evicts "b", which was least recently used.
+        assert!(incr_fixed_window(&mut limits, &config, "c").is_none());
+        assert_eq!(limits.len(), 2);
+        assert!(limits.peek("a").is_some());
+        assert!(limits.peek("b").is_none());
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_snapshot() {
+        let path =
+            std::env::temp_dir().join(format!("portier-snapshot-{}.json", std::process::id()));
+        let rng = SecureRandom::new().await;
+        let fetcher = spawn_agent(FetchAgent::new(FetchOptions::default())).await;
+        let ttl = Duration::from_secs(60);
+        let spawn = || async {
+            let options = test_options(Some(path.clone()));
+            let store = MemoryStore::new(
+                options,
+                ttl,
+                test_cache_policy(ttl),
+                LimitRules::default(),
+                fetcher.clone(),
+            );
+            let store = Arc::new(spawn_agent(store.unwrap()).await);
+            let key_manager = spawn_agent(RotatingKeys::new(
+                store.clone(),
+                Duration::from_secs(86_400),
+                &[SigningAlgorithm::EdDsa],
+                vec![],
+                rng.clone(),
+            ))
+            .await;
+            (store, wait_for_jwks(&key_manager).await)
+        };
+
+        let (store, jwks) = spawn().await;
+        store
+            .send(SaveSession {
+                session_id: "session".to_owned(),
+                data: test_session(),
+            })
+            .await
+            .unwrap();
+        // Concurrent writes must not interleave.
+        futures_util::future::join_all((0..8).map(|_| store.send(WriteSnapshot))).await;
+
+        let (store, restored_jwks) = spawn().await;
+        assert_eq!(restored_jwks, jwks);
+        let session = store
+            .send(GetSession {
+                session_id: "session".to_owned(),
+            })
+            .await
+            .unwrap();
+        assert!(session.is_some());
+        std::fs::remove_file(&path).unwrap();
+    }
+}
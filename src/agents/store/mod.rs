@@ -1,11 +1,13 @@
 use crate::agents::key_manager::rotating::{KeySet, RotatingKeys};
-use crate::config::LimitInput;
+use crate::agents::FetchPurpose;
+use crate::config::{LimitExceeded, LimitInput};
 use crate::crypto::SigningAlgorithm;
 use crate::utils::agent::{Addr, Message, Sender};
-use crate::utils::BoxError;
+use crate::utils::DomainValidation;
+use crate::utils::{unix_duration, BoxError};
 use crate::web::Session;
-use prometheus::Histogram;
 use std::collections::HashSet;
+use std::time::Duration;
 use url::Url;
 
 /// Message requesting a session be saved.
@@ -37,12 +39,56 @@ impl Message for DeleteSession {
     type Reply = Result<(), BoxError>;
 }
 
+/// Message requesting a challenge be saved.
+///
+/// Challenges expire along with sessions.
+pub struct SaveChallenge {
+    /// The challenge ID.
+    pub challenge_id: String,
+    /// Data the challenge is bound to.
+    pub data: String,
+}
+impl Message for SaveChallenge {
+    type Reply = Result<(), BoxError>;
+}
+
+/// Message requesting a challenge be fetched and deleted, so it can only be used once.
+pub struct TakeChallenge {
+    /// The challenge ID.
+    pub challenge_id: String,
+}
+impl Message for TakeChallenge {
+    type Reply = Result<Option<String>, BoxError>;
+}
+
+/// Message requesting the cached DNS validation of a domain be fetched.
+pub struct GetDomainValidation {
+    /// The cache key, see `DomainValidator::cache_key`.
+    pub key: String,
+}
+impl Message for GetDomainValidation {
+    type Reply = Result<Option<DomainValidation>, BoxError>;
+}
+
+/// Message requesting the DNS validation of a domain be cached.
+pub struct SaveDomainValidation {
+    /// The cache key, see `DomainValidator::cache_key`.
+    pub key: String,
+    /// The validation outcome.
+    pub validation: DomainValidation,
+    /// How long to cache the outcome.
+    pub ttl: Duration,
+}
+impl Message for SaveDomainValidation {
+    type Reply = Result<(), BoxError>;
+}
+
 /// Message requesting a URL be fetched, possibly from cache.
 pub struct FetchUrlCached {
     /// The URL to fetch.
     pub url: Url,
-    /// Latency metric to use on cache miss.
-    pub metric: &'static Histogram,
+    /// What the URL is fetched for.
+    pub purpose: FetchPurpose,
 }
 impl Message for FetchUrlCached {
     type Reply = Result<String, BoxError>;
@@ -51,13 +97,14 @@ impl Message for FetchUrlCached {
 /// Message requesting rate limits be increased and tested.
 ///
 /// The configured rate limits are passed to the store when it is created. The store should always
-/// increment all rate limits, even if only the first one fails, for example. The result is `true`
-/// if none of the rate limits were hit.
+/// increment all rate limits, even if only the first one fails, for example. The result is `None`
+/// if none of the rate limits were hit, otherwise it describes the limit that takes the longest to
+/// reset.
 pub struct IncrAndTestLimits {
     pub input: LimitInput,
 }
 impl Message for IncrAndTestLimits {
-    type Reply = Result<bool, BoxError>;
+    type Reply = Result<Option<LimitExceeded>, BoxError>;
 }
 
 /// Message requesting rate limits be decreased.
@@ -107,6 +154,62 @@ impl Message for ImportKeySet {
     type Reply = ();
 }
 
+/// A stored value with an expiry time, as copied between stores.
+pub struct StoreEntry<T> {
+    /// The key of the entry, without any store-specific prefix.
+    pub id: String,
+    pub value: T,
+    /// Expiry time, as a duration since the Unix epoch.
+    pub expires: Duration,
+}
+
+impl<T> StoreEntry<T> {
+    /// Time remaining until expiry, or `None` if already expired.
+    pub fn ttl(&self) -> Option<Duration> {
+        self.expires
+            .checked_sub(unix_duration())
+            .filter(|ttl| *ttl > Duration::from_secs(0))
+    }
+
+    /// Expiry time in whole seconds since the Unix epoch, rounded up.
+    pub fn expires_secs(&self) -> u64 {
+        self.expires.as_secs() + u64::from(self.expires.subsec_nanos() > 0)
+    }
+}
+
+/// Data copied between stores by `migrate-store`.
+///
+/// Rate limit entries use the IDs built by `LimitInput::build_key`. For token buckets, the value
+/// is the arrival time in microseconds since the Unix epoch; otherwise it is a counter.
+#[derive(Default)]
+pub struct StoreData {
+    pub key_sets: Vec<KeySet>,
+    pub sessions: Vec<StoreEntry<Session>>,
+    pub limits: Vec<StoreEntry<i64>>,
+}
+
+/// Message requesting a copy of data in the store.
+///
+/// Key sets are always included. Expired entries are skipped.
+pub struct ExportData {
+    /// Whether to include sessions.
+    pub sessions: bool,
+    /// Whether to include rate limits.
+    pub limits: bool,
+}
+impl Message for ExportData {
+    type Reply = Result<StoreData, BoxError>;
+}
+
+/// Message requesting data exported from another store be written.
+///
+/// Existing entries with the same IDs are replaced, and other workers are notified of key set
+/// changes if possible.
+pub struct ImportData(pub StoreData);
+impl Message for ImportData {
+    type Reply = Result<(), BoxError>;
+}
+
 /// Store abstraction. Combines all message types.
 ///
 /// Downside of this is that it needs to be implemented on the agent side as:
@@ -115,17 +218,31 @@ pub trait StoreSender:
     Sender<SaveSession>
     + Sender<GetSession>
     + Sender<DeleteSession>
+    + Sender<SaveChallenge>
+    + Sender<TakeChallenge>
+    + Sender<GetDomainValidation>
+    + Sender<SaveDomainValidation>
     + Sender<FetchUrlCached>
     + Sender<IncrAndTestLimits>
     + Sender<DecrLimits>
     + Sender<EnableRotatingKeys>
     + Sender<RotateKeysLocked>
     + Sender<ImportKeySet>
+    + Sender<ExportData>
+    + Sender<ImportData>
 {
 }
 
+pub mod cache;
+pub use self::cache::{CacheEntry, CachePolicy, CacheState};
+
 pub mod memory;
-pub use self::memory::MemoryStore;
+pub use self::memory::{MemoryOptions, MemoryStore};
+
+#[cfg(feature = "postgres")]
+pub mod postgres;
+#[cfg(feature = "postgres")]
+pub use self::postgres::PostgresStore;
 
 #[cfg(feature = "redis")]
 pub mod redis;
@@ -135,4 +252,7 @@ pub use self::redis::RedisStore;
 #[cfg(feature = "rusqlite")]
 pub mod rusqlite;
 #[cfg(feature = "rusqlite")]
-pub use self::rusqlite::RusqliteStore;
+pub use self::rusqlite::{RusqliteOptions, RusqliteStore};
+
+#[cfg(test)]
+mod tests;
@@ -0,0 +1,1067 @@
+use crate::agents::store::cache::{fetch_entry, stale_on_error, Revalidations};
+use crate::agents::*;
+use crate::config::{LimitAlgorithm, LimitConfig, LimitExceeded, LimitRules};
+use crate::crypto::SigningAlgorithm;
+use crate::utils::{agent::*, base64url, unix_duration, unix_timestamp, BoxError, SecureRandom};
+use futures_util::future::{self, Future};
+use futures_util::{stream, StreamExt};
+use native_tls::TlsConnector;
+use postgres_native_tls::MakeTlsConnector;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::mpsc;
+use tokio_postgres::{
+    AsyncMessage, Client, Config as PgConfig, Error as PgError, GenericClient, Notification,
+    Transaction,
+};
+use url::Url;
+
+/// Schema version this code works with.
+const SCHEMA_VERSION: i32 = 1;
+
+/// Advisory lock ID used while verifying the schema. 'Prtr' in hex.
+const SCHEMA_LOCK_ID: i64 = 0x5072_7472;
+
+/// Channel used to notify other workers of key set changes.
+const KEYS_CHANNEL: &str = "portier_keys_updated";
+
+/// Message sent at an interval to collect garbage.
+struct Gc;
+impl Message for Gc {
+    type Reply = ();
+}
+
+/// Internal message used to fetch a key set and send an update to the key manager.
+struct RefreshKeys(SigningAlgorithm);
+impl Message for RefreshKeys {
+    type Reply = ();
+}
+
+/// Internal message used to request a new connection.
+///
+/// Contains the generation of the connection that failed.
+struct Reconnect(u64);
+impl Message for Reconnect {
+    type Reply = ();
+}
+
+/// Internal message used to install a new connection.
+struct Connected(Client, NotificationRecv);
+impl Message for Connected {
+    type Reply = ();
+}
+
+/// Receiver for notifications on the main connection.
+///
+/// The channel is closed when the connection is lost.
+type NotificationRecv = mpsc::UnboundedReceiver<Notification>;
+
+/// Store implementation using `tokio-postgres`.
+pub struct PostgresStore {
+    /// A random unique ID for ourselves.
+    id: String,
+    /// The main connection.
+    client: Arc<Client>,
+    /// Notifications received on the main connection, until the agent is started.
+    notifications: Option<NotificationRecv>,
+    /// Incremented every time we reconnect.
+    generation: u64,
+    /// Whether a reconnect is in progress.
+    reconnecting: bool,
+    /// Signing algorithms for which we track key set updates.
+    signing_algs: Vec<SigningAlgorithm>,
+    /// Connection configuration, used to open connections for key rotation.
+    pg_config: PgConfig,
+    /// TLS configuration, used to open connections for key rotation.
+    tls: MakeTlsConnector,
+    /// TTL of session keys
+    expire_sessions: Duration,
+    /// Cache timing configuration.
+    cache_policy: CachePolicy,
+    /// Rate limit configuration.
+    limit_rules: LimitRules,
+    /// The agent used for fetching on cache miss.
+    fetcher: Addr<FetchAgent>,
+    /// Background revalidations in progress.
+    revalidations: Revalidations,
+    /// Key manager if rotating keys are enabled.
+    key_manager: Option<Addr<RotatingKeys>>,
+}
+
+impl PostgresStore {
+    pub async fn new(
+        url: String,
+        expire_sessions: Duration,
+        cache_policy: CachePolicy,
+        limit_rules: LimitRules,
+        fetcher: Addr<FetchAgent>,
+        rng: SecureRandom,
+    ) -> Result<Self, BoxError> {
+        let id = base64url::encode(&rng.generate_async(16).await);
+        let pg_config: PgConfig = url.parse()?;
+        let tls = MakeTlsConnector::new(TlsConnector::new()?);
+        let (mut client, notifications) = Self::connect(&pg_config, tls.clone()).await?;
+        Self::verify_schema(&mut client).await?;
+
+        log::warn!(
+            "Storing sessions and keys in PostgreSQL database: {}",
+            pg_config.get_dbname().unwrap_or_default()
+        );
+        log::warn!("Please always double check this database and the connection to it are secure!");
+        log::warn!("(This warning can't be fixed; it's a friendly reminder.)");
+
+        Ok(PostgresStore {
+            id,
+            client: Arc::new(client),
+            notifications: Some(notifications),
+            generation: 0,
+            reconnecting: false,
+            signing_algs: vec![],
+            pg_config,
+            tls,
+            expire_sessions,
+            cache_policy,
+            limit_rules,
+            fetcher,
+            revalidations: Revalidations::default(),
+            key_manager: None,
+        })
+    }
+
+    /// Open a connection, and spawn a task to drive it.
+    ///
+    /// Notifications received on the connection are sent on the returned channel, which is closed
+    /// when the connection fails. Queries on the client then return errors.
+    async fn connect(
+        pg_config: &PgConfig,
+        tls: MakeTlsConnector,
+    ) -> Result<(Client, NotificationRecv), PgError> {
+        let (client, mut conn) = pg_config.connect(tls).await?;
+        let (tx, rx) = mpsc::unbounded_channel();
+        tokio::spawn(async move {
+            let mut messages = stream::poll_fn(move |cx| conn.poll_message(cx));
+            while let Some(message) = messages.next().await {
+                match message {
+                    Ok(AsyncMessage::Notification(notification)) => {
+                        let _ = tx.send(notification);
+                    }
+                    Ok(AsyncMessage::Notice(notice)) => {
+                        log::warn!("PostgreSQL notice: {}", notice);
+                    }
+                    Ok(_) => {}
+                    Err(err) => {
+                        log::error!("PostgreSQL connection failed: {}", err);
+                        return;
+                    }
+                }
+            }
+        });
+        Ok((client, rx))
+    }
+
+    /// Watch notifications on the main connection, and reconnect if the connection is lost.
+    fn spawn_watch(&self, me: Addr<Self>, mut notifications: NotificationRecv) {
+        let my_id = self.id.clone();
+        let generation = self.generation;
+        tokio::spawn(async move {
+            while let Some(notification) = notifications.recv().await {
+                let mut parts = notification.payload().splitn(2, ':');
+                let signing_alg = parts.next().unwrap_or_default();
+                let from_id = parts.next().unwrap_or_default();
+                if from_id == my_id {
+                    continue;
+                }
+                match signing_alg.parse() {
+                    Ok(signing_alg) => {
+                        me.send(RefreshKeys(signing_alg));
+                    }
+                    Err(_) => {
+                        log::warn!("Invalid key set notification: {}", notification.payload());
+                    }
+                }
+            }
+            log::error!("PostgreSQL connection was lost");
+            me.send(Reconnect(generation));
+        });
+    }
+
+    /// Listen for key set changes by other workers, and fetch current key sets.
+    fn subscribe_keys(&self, me: Addr<Self>) -> impl Future<Output = Result<(), PgError>> {
+        let client = self.client.clone();
+        let signing_algs = self.signing_algs.clone();
+        async move {
+            client
+                .batch_execute(&format!("LISTEN {}", KEYS_CHANNEL))
+                .await?;
+            for signing_alg in signing_algs {
+                me.send(RefreshKeys(signing_alg)).await;
+            }
+            Ok(())
+        }
+    }
+
+    async fn verify_schema(client: &mut Client) -> Result<(), PgError> {
+        let tx = client.transaction().await?;
+        // Workers may be starting at the same time.
+        tx.execute("SELECT pg_advisory_xact_lock($1)", &[&SCHEMA_LOCK_ID])
+            .await?;
+        tx.batch_execute("CREATE TABLE IF NOT EXISTS schema_version (version INTEGER NOT NULL)")
+            .await?;
+        let version: Option<i32> = tx
+            .query_opt("SELECT version FROM schema_version LIMIT 1", &[])
+            .await?
+            .map(|row| row.get(0));
+        match version {
+            None => Self::init_schema(&tx).await?,
+            Some(SCHEMA_VERSION) => {}
+            Some(version) => panic!(
+                "The PostgreSQL database has an unknown version: {}",
+                version
+            ),
+        }
+        tx.commit().await
+    }
+
+    async fn init_schema(tx: &Transaction<'_>) -> Result<(), PgError> {
+        tx.batch_execute(
+            "
+            CREATE TABLE sessions (
+                id TEXT NOT NULL PRIMARY KEY,
+                data TEXT NOT NULL,
+                expires BIGINT NOT NULL
+            );
+            CREATE INDEX sessions_expires ON sessions (expires);
+
+            CREATE TABLE challenges (
+                id TEXT NOT NULL PRIMARY KEY,
+                data TEXT NOT NULL,
+                expires BIGINT NOT NULL
+            );
+            CREATE INDEX challenges_expires ON challenges (expires);
+
+            CREATE TABLE cache_entries (
+                url TEXT NOT NULL PRIMARY KEY,
+                data TEXT NOT NULL,
+                etag TEXT,
+                last_modified TEXT,
+                fresh_until BIGINT NOT NULL,
+                stale_until BIGINT NOT NULL,
+                error_until BIGINT NOT NULL,
+                expires BIGINT NOT NULL
+            );
+            CREATE INDEX cache_entries_expires ON cache_entries (expires);
+
+            CREATE TABLE rate_limits (
+                id TEXT NOT NULL PRIMARY KEY,
+                value BIGINT NOT NULL,
+                expires BIGINT NOT NULL
+            );
+            CREATE INDEX rate_limits_expires ON rate_limits (expires);
+
+            CREATE TABLE domain_validations (
+                domain TEXT NOT NULL PRIMARY KEY,
+                data TEXT NOT NULL,
+                expires BIGINT NOT NULL
+            );
+            CREATE INDEX domain_validations_expires ON domain_validations (expires);
+
+            CREATE TABLE key_sets (
+                signing_alg TEXT NOT NULL PRIMARY KEY,
+                key_set TEXT NOT NULL
+            );
+            ",
+        )
+        .await?;
+        tx.execute(
+            "INSERT INTO schema_version (version) VALUES ($1)",
+            &[&SCHEMA_VERSION],
+        )
+        .await?;
+        Ok(())
+    }
+}
+
+/// Fetch a key set.
+async fn fetch_key_set(client: &Client, signing_alg: SigningAlgorithm) -> Result<KeySet, PgError> {
+    let data: Option<String> = client
+        .query_opt(
+            "SELECT key_set FROM key_sets WHERE signing_alg = $1",
+            &[&signing_alg.as_str()],
+        )
+        .await?
+        .map(|row| row.get(0));
+    Ok(data.map_or_else(
+        || KeySet::empty(signing_alg),
+        |data| serde_json::from_str(&data).expect("Invalid key set JSON in PostgreSQL"),
+    ))
+}
+
+/// Save a key set, and notify other workers.
+///
+/// When called within a transaction, the notification is delivered on commit.
+async fn save_key_set<C: GenericClient>(
+    client: &C,
+    key_set: &KeySet,
+    my_id: &str,
+) -> Result<(), PgError> {
+    let data = serde_json::to_string(key_set).expect("Could not encode key set as JSON");
+    client
+        .execute(
+            "INSERT INTO key_sets (signing_alg, key_set) VALUES ($1, $2)
+            ON CONFLICT (signing_alg) DO UPDATE SET key_set = EXCLUDED.key_set",
+            &[&key_set.signing_alg.as_str(), &data],
+        )
+        .await?;
+    let payload = format!("{}:{}", key_set.signing_alg, my_id);
+    client
+        .execute("SELECT pg_notify($1, $2)", &[&KEYS_CHANNEL, &payload])
+        .await?;
+    Ok(())
+}
+
+/// Rotate keys while holding a lock on the key set row.
+async fn rotate_key_set(
+    pg_config: &PgConfig,
+    tls: MakeTlsConnector,
+    signing_alg: SigningAlgorithm,
+    key_manager: &Addr<RotatingKeys>,
+    my_id: &str,
+) -> Result<Option<KeySet>, PgError> {
+    // Use a separate connection, so the transaction doesn't block other queries.
+    let (mut client, _) = PostgresStore::connect(pg_config, tls).await?;
+    let tx = client.transaction().await?;
+    // Make sure there is a row we can lock.
+    let empty = serde_json::to_string(&KeySet::empty(signing_alg))
+        .expect("Could not encode key set as JSON");
+    tx.execute(
+        "INSERT INTO key_sets (signing_alg, key_set) VALUES ($1, $2) ON CONFLICT DO NOTHING",
+        &[&signing_alg.as_str(), &empty],
+    )
+    .await?;
+    let data: String = tx
+        .query_one(
+            "SELECT key_set FROM key_sets WHERE signing_alg = $1 FOR UPDATE",
+            &[&signing_alg.as_str()],
+        )
+        .await?
+        .get(0);
+    let key_set = serde_json::from_str(&data).expect("Invalid key set JSON in PostgreSQL");
+    if let Some(key_set) = key_manager.send(RotateKeys(key_set)).await {
+        save_key_set(&tx, &key_set, my_id).await?;
+        tx.commit().await?;
+        Ok(Some(key_set))
+    } else {
+        Ok(None)
+    }
+}
+
+impl Agent for PostgresStore {
+    fn started(&mut self, cx: Context<Self, AgentStarted>) {
+        let notifications = self
+            .notifications
+            .take()
+            .expect("PostgreSQL store was already started");
+        self.spawn_watch(cx.addr().clone(), notifications);
+
+        // Start the garbage collection loop.
+        let addr = cx.addr().clone();
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(Duration::from_secs(60));
+            loop {
+                interval.tick().await;
+                addr.send(Gc).await;
+            }
+        });
+        cx.reply(());
+    }
+}
+
+impl Handler<Gc> for PostgresStore {
+    fn handle(&mut self, _message: Gc, cx: Context<Self, Gc>) {
+        let client = self.client.clone();
+        cx.reply_later(async move {
+            let now = unix_timestamp() as i64;
+            for table in &[
+                "sessions",
+                "challenges",
+                "cache_entries",
+                "domain_validations",
+                "rate_limits",
+            ] {
+                client
+                    .execute(
+                        format!("DELETE FROM {} WHERE expires <= $1", table).as_str(),
+                        &[&now],
+                    )
+                    .await
+                    .unwrap_or_else(|err| {
+                        log::error!("PostgreSQL cleanup failed: {}", err);
+                        0
+                    });
+            }
+        });
+    }
+}
+
+impl Handler<Reconnect> for PostgresStore {
+    fn handle(&mut self, message: Reconnect, cx: Context<Self, Reconnect>) {
+        // Ignore failures of old connections, and requests while already reconnecting.
+        if self.reconnecting || message.0 != self.generation {
+            return cx.reply(());
+        }
+        self.reconnecting = true;
+        log::warn!("Reconnecting to PostgreSQL");
+
+        let me = cx.addr().clone();
+        let pg_config = self.pg_config.clone();
+        let tls = self.tls.clone();
+        tokio::spawn(async move {
+            let mut delay = Duration::from_secs(1);
+            loop {
+                match PostgresStore::connect(&pg_config, tls.clone()).await {
+                    Ok((client, notifications)) => {
+                        me.send(Connected(client, notifications));
+                        return;
+                    }
+                    Err(err) => log::error!("Could not reconnect to PostgreSQL: {}", err),
+                }
+                tokio::time::sleep(delay).await;
+                delay = std::cmp::min(delay * 2, Duration::from_secs(30));
+            }
+        });
+        cx.reply(());
+    }
+}
+
+impl Handler<Connected> for PostgresStore {
+    fn handle(&mut self, message: Connected, cx: Context<Self, Connected>) {
+        let Connected(client, notifications) = message;
+        self.client = Arc::new(client);
+        self.generation += 1;
+        self.reconnecting = false;
+        log::warn!("Reconnected to PostgreSQL");
+
+        self.spawn_watch(cx.addr().clone(), notifications);
+        if self.key_manager.is_none() {
+            return cx.reply(());
+        }
+        // We may have missed key set updates while disconnected.
+        let subscribe_fut = self.subscribe_keys(cx.addr().clone());
+        cx.reply_later(async move {
+            if let Err(err) = subscribe_fut.await {
+                log::error!("Failed to listen for key changes in PostgreSQL: {}", err);
+            }
+        });
+    }
+}
+
+impl Handler<SaveSession> for PostgresStore {
+    fn handle(&mut self, message: SaveSession, cx: Context<Self, SaveSession>) {
+        let client = self.client.clone();
+        let ttl = self.expire_sessions;
+        cx.reply_later(async move {
+            let expires = (unix_timestamp() + ttl.as_secs()) as i64;
+            let data = serde_json::to_string(&message.data)?;
+            client
+                .execute(
+                    "INSERT INTO sessions (id, data, expires) VALUES ($1, $2, $3)
+                    ON CONFLICT (id) DO UPDATE SET data = EXCLUDED.data, expires = EXCLUDED.expires",
+                    &[&message.session_id, &data, &expires],
+                )
+                .await?;
+            Ok(())
+        });
+    }
+}
+
+impl Handler<GetSession> for PostgresStore {
+    fn handle(&mut self, message: GetSession, cx: Context<Self, GetSession>) {
+        let client = self.client.clone();
+        cx.reply_later(async move {
+            let now = unix_timestamp() as i64;
+            let data: Option<String> = client
+                .query_opt(
+                    "SELECT data FROM sessions WHERE id = $1 AND expires > $2",
+                    &[&message.session_id, &now],
+                )
+                .await?
+                .map(|row| row.get(0));
+            if let Some(data) = data {
+                Ok(Some(serde_json::from_str(&data)?))
+            } else {
+                Ok(None)
+            }
+        });
+    }
+}
+
+impl Handler<DeleteSession> for PostgresStore {
+    fn handle(&mut self, message: DeleteSession, cx: Context<Self, DeleteSession>) {
+        let client = self.client.clone();
+        cx.reply_later(async move {
+            client
+                .execute("DELETE FROM sessions WHERE id = $1", &[&message.session_id])
+                .await?;
+            Ok(())
+        });
+    }
+}
+
+impl Handler<SaveChallenge> for PostgresStore {
+    fn handle(&mut self, message: SaveChallenge, cx: Context<Self, SaveChallenge>) {
+        let client = self.client.clone();
+        let ttl = self.expire_sessions;
+        cx.reply_later(async move {
+            let expires = (unix_timestamp() + ttl.as_secs()) as i64;
+            client
+                .execute(
+                    "INSERT INTO challenges (id, data, expires) VALUES ($1, $2, $3)
+                    ON CONFLICT (id) DO UPDATE SET data = EXCLUDED.data, expires = EXCLUDED.expires",
+                    &[&message.challenge_id, &message.data, &expires],
+                )
+                .await?;
+            Ok(())
+        });
+    }
+}
+
+impl Handler<TakeChallenge> for PostgresStore {
+    fn handle(&mut self, message: TakeChallenge, cx: Context<Self, TakeChallenge>) {
+        let client = self.client.clone();
+        cx.reply_later(async move {
+            let now = unix_timestamp() as i64;
+            let row = client
+                .query_opt(
+                    "DELETE FROM challenges WHERE id = $1 RETURNING data, expires",
+                    &[&message.challenge_id],
+                )
+                .await?;
+            Ok(row
+                .filter(|row| row.get::<_, i64>(1) > now)
+                .map(|row| row.get(0)))
+        });
+    }
+}
+
+impl Handler<GetDomainValidation> for PostgresStore {
+    fn handle(&mut self, message: GetDomainValidation, cx: Context<Self, GetDomainValidation>) {
+        let client = self.client.clone();
+        cx.reply_later(async move {
+            let now = unix_timestamp() as i64;
+            let data: Option<String> = client
+                .query_opt(
+                    "SELECT data FROM domain_validations WHERE domain = $1 AND expires > $2",
+                    &[&message.key, &now],
+                )
+                .await?
+                .map(|row| row.get(0));
+            if let Some(data) = data {
+                Ok(Some(serde_json::from_str(&data)?))
+            } else {
+                Ok(None)
+            }
+        });
+    }
+}
+
+impl Handler<SaveDomainValidation> for PostgresStore {
+    fn handle(&mut self, message: SaveDomainValidation, cx: Context<Self, SaveDomainValidation>) {
+        let client = self.client.clone();
+        cx.reply_later(async move {
+            let expires = (unix_timestamp() + message.ttl.as_secs()) as i64;
+            let data = serde_json::to_string(&message.validation)?;
+            client
+                .execute(
+                    "INSERT INTO domain_validations (domain, data, expires) VALUES ($1, $2, $3)
+                    ON CONFLICT (domain) DO UPDATE SET data = EXCLUDED.data, expires = EXCLUDED.expires",
+                    &[&message.key, &data, &expires],
+                )
+                .await?;
+            Ok(())
+        });
+    }
+}
+
+impl Handler<FetchUrlCached> for PostgresStore {
+    fn handle(&mut self, message: FetchUrlCached, cx: Context<Self, FetchUrlCached>) {
+        let client = self.client.clone();
+        let fetcher = self.fetcher.clone();
+        let policy = self.cache_policy;
+        let revalidations = self.revalidations.clone();
+        cx.reply_later(async move {
+            let cached = get_cache_entry(&client, &message.url).await?;
+            if let Some(cached) = cached.as_ref() {
+                match cached.state() {
+                    CacheState::Fresh => return Ok(cached.data.clone()),
+                    CacheState::Stale => {
+                        let stale = cached.clone();
+                        let FetchUrlCached { url, purpose } = message;
+                        revalidations.spawn(url.clone(), async move {
+                            let fresh =
+                                fetch_entry(&fetcher, policy, &url, purpose, Some(&stale)).await?;
+                            set_cache_entry(&client, &url, &fresh).await?;
+                            Ok(())
+                        });
+                        return Ok(cached.data.clone());
+                    }
+                    CacheState::Expired => {}
+                }
+            }
+
+            match fetch_entry(
+                &fetcher,
+                policy,
+                &message.url,
+                message.purpose,
+                cached.as_ref(),
+            )
+            .await
+            {
+                Ok(fresh) => {
+                    set_cache_entry(&client, &message.url, &fresh).await?;
+                    Ok(fresh.data)
+                }
+                Err(err) => Ok(stale_on_error(cached.as_ref(), &message.url, err)?),
+            }
+        });
+    }
+}
+
+/// Read a cache entry, if it has not expired.
+async fn get_cache_entry(client: &Client, url: &Url) -> Result<Option<CacheEntry>, PgError> {
+    let now = unix_timestamp() as i64;
+    let row = client
+        .query_opt(
+            "SELECT data, etag, last_modified, fresh_until, stale_until, error_until, expires
+            FROM cache_entries WHERE url = $1 AND expires > $2",
+            &[&url.as_str(), &now],
+        )
+        .await?;
+    Ok(row.map(|row| CacheEntry {
+        data: row.get(0),
+        validators: Validators {
+            etag: row.get(1),
+            last_modified: row.get(2),
+        },
+        fresh_until: row.get::<_, i64>(3) as u64,
+        stale_until: row.get::<_, i64>(4) as u64,
+        error_until: row.get::<_, i64>(5) as u64,
+        expires: row.get::<_, i64>(6) as u64,
+    }))
+}
+
+/// Write a cache entry.
+async fn set_cache_entry(client: &Client, url: &Url, entry: &CacheEntry) -> Result<(), PgError> {
+    client
+        .execute(
+            "INSERT INTO cache_entries
+                (url, data, etag, last_modified, fresh_until, stale_until, error_until, expires)
+            VALUES ($1, $2, $3, $4, $5, $6, $7, $8)
+            ON CONFLICT (url) DO UPDATE SET
+                data = EXCLUDED.data,
+                etag = EXCLUDED.etag,
+                last_modified = EXCLUDED.last_modified,
+                fresh_until = EXCLUDED.fresh_until,
+                stale_until = EXCLUDED.stale_until,
+                error_until = EXCLUDED.error_until,
+                expires = EXCLUDED.expires",
+            &[
+                &url.as_str(),
+                &entry.data,
+                &entry.validators.etag,
+                &entry.validators.last_modified,
+                &(entry.fresh_until as i64),
+                &(entry.stale_until as i64),
+                &(entry.error_until as i64),
+                &(entry.expires as i64),
+            ],
+        )
+        .await?;
+    Ok(())
+}
+
+/// Increment a fixed window limit, and test it.
+async fn incr_fixed_window(
+    client: &Client,
+    config: &LimitConfig,
+    id: &str,
+) -> Result<Option<LimitExceeded>, PgError> {
+    let now = unix_timestamp() as i64;
+    let window = config.window.as_secs() as i64;
+    let row = client
+        .query_one(
+            "INSERT INTO rate_limits (id, value, expires) VALUES ($1, 1, $2::BIGINT + $3::BIGINT)
+            ON CONFLICT (id) DO UPDATE SET
+                value = CASE WHEN rate_limits.expires <= $2 THEN 1
+                    ELSE rate_limits.value + 1 END,
+                expires = CASE WHEN rate_limits.expires <= $2 OR $4 THEN $2::BIGINT + $3::BIGINT
+                    ELSE rate_limits.expires END
+            RETURNING value, expires",
+            &[&id, &now, &window, &config.extend_window],
+        )
+        .await?;
+    let (count, expires): (i64, i64) = (row.get(0), row.get(1));
+    if count as usize <= config.max_count {
+        Ok(None)
+    } else {
+        let reset = Duration::from_secs((expires - now).max(0) as u64);
+        Ok(Some(LimitExceeded::new(config, reset)))
+    }
+}
+
+/// Increment a sliding window limit, and test it.
+async fn incr_sliding_window(
+    client: &Client,
+    config: &LimitConfig,
+    id: &str,
+) -> Result<Option<LimitExceeded>, PgError> {
+    let now = unix_duration();
+    let window = config.sliding_window(now);
+    // Round up, so we never clean up a counter early.
+    let expires = (now + window.ttl).as_secs() as i64 + 1;
+    let row = client
+        .query_one(
+            "WITH current AS (
+                INSERT INTO rate_limits (id, value, expires) VALUES ($1, 1, $3)
+                ON CONFLICT (id) DO UPDATE SET value = rate_limits.value + 1
+                RETURNING value
+            )
+            SELECT
+                (SELECT value FROM current),
+                COALESCE((SELECT value FROM rate_limits WHERE id = $2 AND expires > $4), 0)",
+            &[
+                &window.current_key(id, "|"),
+                &window.previous_key(id, "|"),
+                &expires,
+                &(now.as_secs() as i64),
+            ],
+        )
+        .await?;
+    let (current, previous): (i64, i64) = (row.get(0), row.get(1));
+    let (previous, current) = (previous as usize, current as usize);
+    if window.count(previous, current) <= config.max_count {
+        Ok(None)
+    } else {
+        let reset = window.reset(previous, current, config.max_count);
+        Ok(Some(LimitExceeded::new(config, reset)))
+    }
+}
+
+/// Fetch the arrival time of a token bucket.
+///
+/// The arrival time is stored in the value column, in microseconds since Unix epoch.
+async fn fetch_token_bucket(client: &Client, id: &str) -> Result<Option<i64>, PgError> {
+    Ok(client
+        .query_opt("SELECT value FROM rate_limits WHERE id = $1", &[&id])
+        .await?
+        .map(|row| row.get(0)))
+}
+
+/// Save the arrival time of a token bucket, unless it was changed concurrently.
+///
+/// Returns whether the save succeeded. A `tat` of `None` deletes the bucket.
+async fn save_token_bucket(
+    client: &Client,
+    id: &str,
+    old: Option<i64>,
+    tat: Option<Duration>,
+) -> Result<bool, PgError> {
+    let value = tat.map(|tat| tat.as_micros() as i64);
+    let expires = tat.map(|tat| tat.as_secs() as i64 + 1);
+    let rows =
+        match (old, value) {
+            (None, None) => return Ok(true),
+            (None, Some(value)) => {
+                client
+                    .execute(
+                        "INSERT INTO rate_limits (id, value, expires) VALUES ($1, $2, $3)
+                    ON CONFLICT DO NOTHING",
+                        &[&id, &value, &expires],
+                    )
+                    .await?
+            }
+            (Some(old), Some(value)) => client
+                .execute(
+                    "UPDATE rate_limits SET value = $2, expires = $3 WHERE id = $1 AND value = $4",
+                    &[&id, &value, &expires, &old],
+                )
+                .await?,
+            (Some(old), None) => {
+                client
+                    .execute(
+                        "DELETE FROM rate_limits WHERE id = $1 AND value = $2",
+                        &[&id, &old],
+                    )
+                    .await?
+            }
+        };
+    Ok(rows == 1)
+}
+
+/// Try to take a token from a token bucket limit.
+async fn take_token_bucket(
+    client: &Client,
+    config: &LimitConfig,
+    id: &str,
+) -> Result<Option<LimitExceeded>, PgError> {
+    loop {
+        let old = fetch_token_bucket(client, id).await?;
+        let tat = old.map(|tat| Duration::from_micros(tat as u64));
+        match config.bucket_take(tat, unix_duration()) {
+            Ok(tat) => {
+                if save_token_bucket(client, id, old, Some(tat)).await? {
+                    return Ok(None);
+                }
+            }
+            Err(reset) => return Ok(Some(LimitExceeded::new(config, reset))),
+        }
+    }
+}
+
+/// Return a token to a token bucket limit.
+async fn return_token_bucket(
+    client: &Client,
+    config: &LimitConfig,
+    id: &str,
+) -> Result<(), PgError> {
+    loop {
+        let old = match fetch_token_bucket(client, id).await? {
+            Some(old) => old,
+            None => return Ok(()),
+        };
+        let tat = Duration::from_micros(old as u64);
+        let tat = config.bucket_return(tat, unix_duration());
+        if save_token_bucket(client, id, Some(old), tat).await? {
+            return Ok(());
+        }
+    }
+}
+
+impl Handler<IncrAndTestLimits> for PostgresStore {
+    fn handle(&mut self, message: IncrAndTestLimits, cx: Context<Self, IncrAndTestLimits>) {
+        let client = self.client.clone();
+        let ops: Vec<_> = self
+            .limit_rules
+            .select(&message.input)
+            .map(|config| {
+                let id = message.input.build_key(config, "", "|");
+                (config.clone(), id)
+            })
+            .collect();
+        cx.reply_later(async move {
+            let results = future::try_join_all(ops.iter().map(|(config, id)| {
+                let client = &client;
+                async move {
+                    match config.algorithm {
+                        LimitAlgorithm::FixedWindow => incr_fixed_window(client, config, id).await,
+                        LimitAlgorithm::SlidingWindow => {
+                            incr_sliding_window(client, config, id).await
+                        }
+                        LimitAlgorithm::TokenBucket => take_token_bucket(client, config, id).await,
+                    }
+                }
+            }))
+            .await?;
+            Ok(LimitExceeded::most_severe(results.into_iter().flatten()))
+        });
+    }
+}
+
+impl Handler<DecrLimits> for PostgresStore {
+    fn handle(&mut self, message: DecrLimits, cx: Context<Self, DecrLimits>) {
+        let client = self.client.clone();
+        let ops: Vec<_> = self
+            .limit_rules
+            .select(&message.input)
+            .filter(|config| config.decr_complete)
+            .map(|config| {
+                let id = message.input.build_key(config, "", "|");
+                (config.clone(), id)
+            })
+            .collect();
+        cx.reply_later(async move {
+            future::try_join_all(ops.iter().map(|(config, id)| {
+                let client = &client;
+                async move {
+                    let now = unix_duration();
+                    match config.algorithm {
+                        LimitAlgorithm::FixedWindow => {
+                            client
+                                .execute(
+                                    "UPDATE rate_limits SET value = value - 1
+                                    WHERE id = $1 AND expires > $2 AND value > 0",
+                                    &[&id, &(now.as_secs() as i64)],
+                                )
+                                .await?;
+                            Ok(())
+                        }
+                        LimitAlgorithm::SlidingWindow => {
+                            // Decrement the first counter that is still alive.
+                            let window = config.sliding_window(now);
+                            client
+                                .execute(
+                                    "UPDATE rate_limits SET value = value - 1 WHERE id = (
+                                        SELECT id FROM rate_limits
+                                        WHERE id IN ($1, $2) AND expires > $3 AND value > 0
+                                        ORDER BY id = $1 DESC LIMIT 1
+                                    )",
+                                    &[
+                                        &window.current_key(id, "|"),
+                                        &window.previous_key(id, "|"),
+                                        &(now.as_secs() as i64),
+                                    ],
+                                )
+                                .await?;
+                            Ok(())
+                        }
+                        LimitAlgorithm::TokenBucket => {
+                            return_token_bucket(client, config, id).await
+                        }
+                    }
+                }
+            }))
+            .await?;
+            Ok(())
+        });
+    }
+}
+
+impl Handler<EnableRotatingKeys> for PostgresStore {
+    fn handle(&mut self, message: EnableRotatingKeys, cx: Context<Self, EnableRotatingKeys>) {
+        self.key_manager = Some(message.key_manager);
+        self.signing_algs = message.signing_algs.into_iter().collect();
+        let subscribe_fut = self.subscribe_keys(cx.addr().clone());
+        cx.reply_later(async move {
+            subscribe_fut
+                .await
+                .expect("Failed to listen for key changes in PostgreSQL");
+        });
+    }
+}
+
+impl Handler<RotateKeysLocked> for PostgresStore {
+    fn handle(&mut self, message: RotateKeysLocked, cx: Context<Self, RotateKeysLocked>) {
+        let pg_config = self.pg_config.clone();
+        let tls = self.tls.clone();
+        let my_id = self.id.clone();
+        let key_manager = self.key_manager.as_ref().unwrap().clone();
+        cx.reply_later(async move {
+            let key_set = rotate_key_set(&pg_config, tls, message.0, &key_manager, &my_id)
+                .await
+                .expect("Failed to rotate keys in PostgreSQL");
+            if let Some(key_set) = key_set {
+                key_manager.send(UpdateKeys(key_set)).await;
+            }
+        });
+    }
+}
+
+impl Handler<ImportKeySet> for PostgresStore {
+    fn handle(&mut self, message: ImportKeySet, cx: Context<Self, ImportKeySet>) {
+        let client = self.client.clone();
+        let my_id = self.id.clone();
+        cx.reply_later(async move {
+            save_key_set(&*client, &message.0, &my_id)
+                .await
+                .expect("Failed to save keys to PostgreSQL");
+        });
+    }
+}
+
+impl Handler<RefreshKeys> for PostgresStore {
+    fn handle(&mut self, message: RefreshKeys, cx: Context<Self, RefreshKeys>) {
+        if !self.signing_algs.contains(&message.0) {
+            return cx.reply(());
+        }
+        let client = self.client.clone();
+        let key_manager = self.key_manager.as_ref().unwrap().clone();
+        cx.reply_later(async move {
+            // On failure, keys are fetched again after reconnecting.
+            match fetch_key_set(&client, message.0).await {
+                Ok(key_set) => key_manager.send(UpdateKeys(key_set)).await,
+                Err(err) => log::error!("Failed to fetch keys from PostgreSQL: {}", err),
+            }
+        });
+    }
+}
+
+impl Handler<ExportData> for PostgresStore {
+    fn handle(&mut self, message: ExportData, cx: Context<Self, ExportData>) {
+        let client = self.client.clone();
+        cx.reply_later(async move {
+            let now = unix_timestamp() as i64;
+            let mut data = StoreData::default();
+            for row in client.query("SELECT key_set FROM key_sets", &[]).await? {
+                data.key_sets.push(serde_json::from_str(row.get(0))?);
+            }
+            if message.sessions {
+                let rows = client
+                    .query(
+                        "SELECT id, data, expires FROM sessions WHERE expires > $1",
+                        &[&now],
+                    )
+                    .await?;
+                for row in rows {
+                    data.sessions.push(StoreEntry {
+                        id: row.get(0),
+                        value: serde_json::from_str(row.get(1))?,
+                        expires: Duration::from_secs(row.get::<_, i64>(2) as u64),
+                    });
+                }
+            }
+            if message.limits {
+                let rows = client
+                    .query(
+                        "SELECT id, value, expires FROM rate_limits WHERE expires > $1",
+                        &[&now],
+                    )
+                    .await?;
+                for row in rows {
+                    data.limits.push(StoreEntry {
+                        id: row.get(0),
+                        value: row.get(1),
+                        expires: Duration::from_secs(row.get::<_, i64>(2) as u64),
+                    });
+                }
+            }
+            Ok(data)
+        });
+    }
+}
+
+impl Handler<ImportData> for PostgresStore {
+    fn handle(&mut self, message: ImportData, cx: Context<Self, ImportData>) {
+        let pg_config = self.pg_config.clone();
+        let tls = self.tls.clone();
+        let my_id = self.id.clone();
+        cx.reply_later(async move {
+            // Use a separate connection, so the transaction doesn't block other queries.
+            let (mut client, _) = PostgresStore::connect(&pg_config, tls).await?;
+            let tx = client.transaction().await?;
+            for key_set in &message.0.key_sets {
+                save_key_set(&tx, key_set, &my_id).await?;
+            }
+            for entry in &message.0.sessions {
+                let data = serde_json::to_string(&entry.value)?;
+                tx.execute(
+                    "INSERT INTO sessions (id, data, expires) VALUES ($1, $2, $3)
+                    ON CONFLICT (id) DO UPDATE SET data = EXCLUDED.data, expires = EXCLUDED.expires",
+                    &[&entry.id, &data, &(entry.expires_secs() as i64)],
+                )
+                .await?;
+            }
+            for entry in &message.0.limits {
+                tx.execute(
+                    "INSERT INTO rate_limits (id, value, expires) VALUES ($1, $2, $3)
+                    ON CONFLICT (id) DO UPDATE SET value = EXCLUDED.value, expires = EXCLUDED.expires",
+                    &[&entry.id, &entry.value, &(entry.expires_secs() as i64)],
+                )
+                .await?;
+            }
+            tx.commit().await?;
+            Ok(())
+        });
+    }
+}
+
+impl StoreSender for Addr<PostgresStore> {}
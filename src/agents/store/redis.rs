@@ -1,22 +1,24 @@
+use crate::agents::store::cache::{fetch_entry, stale_on_error, Revalidations};
 use crate::agents::*;
-use crate::config::LimitConfig;
+use crate::config::{LimitAlgorithm, LimitExceeded, LimitRules};
 use crate::crypto::SigningAlgorithm;
 use crate::utils::{
     agent::*,
-    redis::{locking, pubsub},
-    BoxError, SecureRandom,
+    redis::{locking, parse_url, pubsub, sentinel::Sentinel},
+    unix_duration, BoxError, SecureRandom,
 };
 use ::redis::{
     aio::MultiplexedConnection as RedisConn, pipe, AsyncCommands, Client as RedisClient,
-    IntoConnectionInfo, RedisResult, Script,
+    ConnectionInfo, RedisResult, Script,
 };
-use futures_util::future;
-use std::{convert::identity, sync::Arc, time::Duration};
+use futures_util::future::{self, Future};
+use std::{fmt::Display, sync::Arc, time::Duration};
+use tokio::sync::broadcast::error::RecvError;
 
 /// Internal message used to lock a key set.
 struct LockKeys(SigningAlgorithm);
 impl Message for LockKeys {
-    type Reply = locking::LockGuard;
+    type Reply = RedisResult<locking::LockGuard>;
 }
 
 /// Internal message used to fetch a key set.
@@ -37,55 +39,125 @@ impl Message for UpdateKeysLocked {
     type Reply = ();
 }
 
+/// Internal message used to request a new connection.
+///
+/// Contains the generation of the connection that failed, or `None` if Sentinel announced a
+/// failover.
+struct Reconnect(Option<u64>);
+impl Message for Reconnect {
+    type Reply = ();
+}
+
+/// Internal message used to install a new connection.
+struct Connected(Connections);
+impl Message for Connected {
+    type Reply = ();
+}
+
+/// A set of connections to the same Redis server.
+struct Connections {
+    conn: RedisConn,
+    pubsub: pubsub::Subscriber,
+    locking: locking::LockClient,
+}
+
+impl Connections {
+    /// Connect to Redis, discovering the master first if Sentinel is used.
+    async fn open(
+        info: &ConnectionInfo,
+        sentinel: Option<&Sentinel>,
+        rng: SecureRandom,
+    ) -> RedisResult<Self> {
+        let info = match sentinel {
+            Some(sentinel) => sentinel.discover(info).await?,
+            None => info.clone(),
+        };
+        let pubsub = pubsub::connect(&info).await?;
+        let conn = RedisClient::open(info)?
+            .get_multiplexed_tokio_connection()
+            .await?;
+        let locking = locking::LockClient::new(conn.clone(), pubsub.clone(), rng);
+        Ok(Connections {
+            conn,
+            pubsub,
+            locking,
+        })
+    }
+}
+
 /// Store implementation using Redis.
 pub struct RedisStore {
     /// A random unique ID for ourselves.
     id: Arc<[u8]>,
+    /// Connection info from configuration.
+    info: ConnectionInfo,
+    /// Sentinel used to discover the master, if configured.
+    sentinel: Option<Sentinel>,
+    /// Randomness used by the locking client.
+    rng: SecureRandom,
     /// The connection.
     conn: RedisConn,
     /// Pubsub client.
     pubsub: pubsub::Subscriber,
     /// Locking client.
     locking: locking::LockClient,
+    /// Incremented every time we reconnect.
+    generation: u64,
+    /// Whether a reconnect is in progress.
+    reconnecting: bool,
+    /// Signing algorithms for which we track key set updates.
+    signing_algs: Vec<SigningAlgorithm>,
     /// TTL of session keys
     expire_sessions: Duration,
-    /// TTL of cache keys
-    expire_cache: Duration,
+    /// Cache timing configuration.
+    cache_policy: CachePolicy,
     /// The agent used for fetching on cache miss.
     fetcher: Addr<FetchAgent>,
+    /// Background revalidations in progress.
+    revalidations: Revalidations,
     /// Key manager if rotating keys are enabled.
     key_manager: Option<Addr<RotatingKeys>>,
-    /// Script used to increment a limit.
+    /// Script used to increment a fixed window limit.
     incr_limit_script: Arc<Script>,
+    /// Script used to increment a sliding window limit.
+    incr_sliding_script: Arc<Script>,
+    /// Script used to take a token from a token bucket limit.
+    take_bucket_script: Arc<Script>,
     /// Script used to decrement a limit.
     decr_limit_script: Arc<Script>,
+    /// Script used to return a token to a token bucket limit.
+    return_bucket_script: Arc<Script>,
     /// Rate limit configuration.
-    limit_configs: Vec<LimitConfig>,
+    limit_rules: LimitRules,
+    /// Prefix applied to all keys, channels and locks.
+    key_prefix: String,
 }
 
 impl RedisStore {
+    #[allow(clippy::too_many_arguments)]
     pub async fn new(
-        mut url: String,
+        url: String,
+        sentinel: Option<Sentinel>,
+        key_prefix: String,
         expire_sessions: Duration,
-        expire_cache: Duration,
-        limit_configs: Vec<LimitConfig>,
+        cache_policy: CachePolicy,
+        limit_rules: LimitRules,
         fetcher: Addr<FetchAgent>,
         rng: SecureRandom,
     ) -> RedisResult<Self> {
-        if url.starts_with("http://") {
-            url = url.replace("http://", "redis://");
-        } else if !url.starts_with("redis://") {
-            url = format!("redis://{}", &url);
-        }
         let id = rng.generate_async(16).await.into();
-        let info = url.as_str().into_connection_info()?;
-        let pubsub = pubsub::connect(&info).await?;
-        let conn = RedisClient::open(info)?
-            .get_multiplexed_tokio_connection()
-            .await?;
-        let locking = locking::LockClient::new(conn.clone(), pubsub.clone(), rng);
+        let info = parse_url(&url)?;
+        let Connections {
+            conn,
+            pubsub,
+            locking,
+        } = Connections::open(&info, sentinel.as_ref(), rng.clone()).await?;
 
-        log::warn!("Storing sessions and keys in Redis at {}", url);
+        if sentinel.is_some() {
+            log::warn!("Storing sessions and keys in Redis using Sentinel");
+        } else {
+            log::warn!("Storing sessions and keys in Redis at {}", info.addr);
+        }
         log::warn!("Please always double check this Redis and the connection to it are secure!");
         log::warn!("(This warning can't be fixed; it's a friendly reminder.)");
 
@@ -95,65 +167,283 @@ impl RedisStore {
             if count == 1 or ARGV[2] == 'true' then
                 redis.call('expire', KEYS[1], ARGV[1])
             end
-            return count
+            return {count, redis.call('pttl', KEYS[1])}
             ",
         ));
 
+        let incr_sliding_script = Arc::new(Script::new(
+            r"
+            local count = redis.call('incr', KEYS[1])
+            if count == 1 then
+                redis.call('pexpire', KEYS[1], ARGV[1])
+            end
+            local previous = tonumber(redis.call('get', KEYS[2]) or '0')
+            return {count, previous}
+            ",
+        ));
+
+        // Token bucket arrival times are stored in microseconds since Unix epoch.
+        let take_bucket_script = Arc::new(Script::new(
+            r"
+            local now = tonumber(ARGV[1])
+            local tat = tonumber(redis.call('get', KEYS[1]) or ARGV[1])
+            tat = math.max(tat, now) + tonumber(ARGV[2])
+            local wait = tat - now - tonumber(ARGV[3])
+            if wait > 0 then
+                return wait
+            end
+            local ttl = math.max(math.ceil((tat - now) / 1000), 1)
+            redis.call('set', KEYS[1], string.format('%d', tat), 'px', ttl)
+            return 0
+            ",
+        ));
+
+        // Decrements the first of the given keys that exists.
         let decr_limit_script = Arc::new(Script::new(
             r"
-            local count = redis.call('decr', KEYS[1])
-            if count <= 0 then
-                redis.call('del', KEYS[1])
+            for _, key in ipairs(KEYS) do
+                local count = tonumber(redis.call('get', key))
+                if count then
+                    if count <= 1 then
+                        redis.call('del', key)
+                    else
+                        redis.call('decr', key)
+                    end
+                    return
+                end
+            end
+            ",
+        ));
+
+        let return_bucket_script = Arc::new(Script::new(
+            r"
+            local tat = tonumber(redis.call('get', KEYS[1]))
+            if tat then
+                local now = tonumber(ARGV[1])
+                tat = tat - tonumber(ARGV[2])
+                if tat <= now then
+                    redis.call('del', KEYS[1])
+                else
+                    local ttl = math.max(math.ceil((tat - now) / 1000), 1)
+                    redis.call('set', KEYS[1], string.format('%d', tat), 'px', ttl)
+                end
             end
             ",
         ));
 
         Ok(RedisStore {
             id,
+            info,
+            sentinel,
+            rng,
             conn,
             pubsub,
             locking,
+            generation: 0,
+            reconnecting: false,
+            signing_algs: vec![],
             expire_sessions,
-            expire_cache,
+            cache_policy,
             fetcher,
+            revalidations: Revalidations::default(),
             key_manager: None,
             incr_limit_script,
+            incr_sliding_script,
+            take_bucket_script,
             decr_limit_script,
-            limit_configs,
+            return_bucket_script,
+            limit_rules,
+            key_prefix,
         })
     }
 
-    fn format_session_key(session_id: &str) -> String {
-        format!("session:{}", session_id)
+    /// Apply the configured prefix to a key or channel name.
+    fn prefixed(&self, name: impl Display) -> String {
+        format!("{}{}", self.key_prefix, name)
     }
-}
 
-impl Agent for RedisStore {
-    fn started(&mut self, cx: Context<Self, AgentStarted>) {
-        // Ping Redis at an interval.
+    fn format_session_key(&self, session_id: &str) -> String {
+        self.prefixed(format_args!("session:{}", session_id))
+    }
+
+    fn format_challenge_key(&self, challenge_id: &str) -> String {
+        self.prefixed(format_args!("challenge:{}", challenge_id))
+    }
+
+    fn format_domain_validation_key(&self, key: &str) -> String {
+        self.prefixed(format_args!("dns:{}", key))
+    }
+
+    /// Ping Redis at an interval, and reconnect if the connection is lost.
+    fn spawn_ping(&self, me: Addr<Self>) {
         let mut conn = self.conn.clone();
+        let pubsub = self.pubsub.clone();
+        let generation = self.generation;
         tokio::spawn(async move {
             let mut interval = tokio::time::interval(Duration::from_secs(20));
             // Ignore the first (immediate) tick.
             interval.tick().await;
             loop {
                 interval.tick().await;
-                let _res: String = ::redis::cmd("PING")
-                    .query_async(&mut conn)
-                    .await
-                    .expect("Redis ping failed");
+                let res: RedisResult<String> = ::redis::cmd("PING").query_async(&mut conn).await;
+                if let Err(err) = res {
+                    log::error!("Redis ping failed: {}", err);
+                } else if pubsub.is_closed() {
+                    log::error!("Redis pubsub connection was lost");
+                } else {
+                    continue;
+                }
+                me.send(Reconnect(Some(generation)));
+                return;
+            }
+        });
+    }
+
+    /// Reconnect when Sentinel announces a failover of our master.
+    fn spawn_sentinel_watch(&self, me: Addr<Self>) {
+        let sentinel = match self.sentinel {
+            Some(ref sentinel) => sentinel.clone(),
+            None => return,
+        };
+        tokio::spawn(async move {
+            loop {
+                match sentinel.watch().await {
+                    Ok(mut rx) => loop {
+                        match rx.recv().await {
+                            Ok(data) => {
+                                // Format: `<master name> <old ip> <old port> <new ip> <new port>`
+                                let name = data.split(|c| *c == b' ').next();
+                                if name == Some(sentinel.master_name().as_bytes()) {
+                                    log::warn!("Redis sentinel announced a failover");
+                                    me.send(Reconnect(None));
+                                }
+                            }
+                            Err(RecvError::Lagged(_)) => {
+                                me.send(Reconnect(None));
+                            }
+                            Err(RecvError::Closed) => {
+                                log::error!("Redis sentinel connection was lost");
+                                break;
+                            }
+                        }
+                    },
+                    Err(err) => log::error!("Could not watch Redis sentinels: {}", err),
+                }
+                tokio::time::sleep(Duration::from_secs(1)).await;
+            }
+        });
+    }
+
+    /// Subscribe to key set changes by other workers, and fetch current key sets.
+    fn subscribe_keys(&self, me: Addr<Self>) -> impl Future<Output = ()> {
+        let my_id = self.id.clone();
+        let mut pubsub = self.pubsub.clone();
+        let generation = self.generation;
+        let chans: Vec<_> = self
+            .signing_algs
+            .iter()
+            .map(|&signing_alg| {
+                let chan = self.prefixed(format_args!("keys-updated:{}", signing_alg));
+                (signing_alg, chan.into_bytes())
+            })
+            .collect();
+        async move {
+            for (signing_alg, chan) in chans {
+                let mut sub = match pubsub.subscribe(chan).await {
+                    Ok(sub) => sub,
+                    Err(err) => {
+                        log::error!("Redis keys subscription failed: {}", err);
+                        me.send(Reconnect(Some(generation)));
+                        return;
+                    }
+                };
+                let me2 = me.clone();
+                let my_id2 = my_id.clone();
+                tokio::spawn(async move {
+                    loop {
+                        match sub.recv().await {
+                            Ok(from_id) if from_id.as_slice() == my_id2.as_ref() => {}
+                            Ok(_) | Err(RecvError::Lagged(_)) => {
+                                me2.send(UpdateKeysLocked(signing_alg));
+                            }
+                            Err(RecvError::Closed) => {
+                                me2.send(Reconnect(Some(generation)));
+                                return;
+                            }
+                        }
+                    }
+                });
+                // Fetch current keys.
+                me.send(UpdateKeysLocked(signing_alg)).await;
+            }
+        }
+    }
+}
+
+impl Agent for RedisStore {
+    fn started(&mut self, cx: Context<Self, AgentStarted>) {
+        self.spawn_ping(cx.addr().clone());
+        self.spawn_sentinel_watch(cx.addr().clone());
+        cx.reply(());
+    }
+}
+
+impl Handler<Reconnect> for RedisStore {
+    fn handle(&mut self, message: Reconnect, cx: Context<Self, Reconnect>) {
+        // Ignore failures of old connections, and requests while already reconnecting.
+        if self.reconnecting || message.0.map_or(false, |gen| gen != self.generation) {
+            return cx.reply(());
+        }
+        self.reconnecting = true;
+        log::warn!("Reconnecting to Redis");
+
+        let me = cx.addr().clone();
+        let info = self.info.clone();
+        let sentinel = self.sentinel.clone();
+        let rng = self.rng.clone();
+        tokio::spawn(async move {
+            loop {
+                match Connections::open(&info, sentinel.as_ref(), rng.clone()).await {
+                    Ok(conns) => {
+                        me.send(Connected(conns));
+                        return;
+                    }
+                    Err(err) => log::error!("Could not reconnect to Redis: {}", err),
+                }
+                tokio::time::sleep(Duration::from_secs(1)).await;
             }
         });
         cx.reply(());
     }
 }
 
+impl Handler<Connected> for RedisStore {
+    fn handle(&mut self, message: Connected, cx: Context<Self, Connected>) {
+        let Connections {
+            conn,
+            pubsub,
+            locking,
+        } = message.0;
+        self.conn = conn;
+        self.pubsub = pubsub;
+        self.locking = locking;
+        self.generation += 1;
+        self.reconnecting = false;
+        log::warn!("Reconnected to Redis");
+
+        self.spawn_ping(cx.addr().clone());
+        // We may have missed key set updates while disconnected.
+        let subscribe_fut = self.subscribe_keys(cx.addr().clone());
+        cx.reply_later(subscribe_fut);
+    }
+}
+
 impl Handler<SaveSession> for RedisStore {
     fn handle(&mut self, message: SaveSession, cx: Context<Self, SaveSession>) {
         let mut conn = self.conn.clone();
         let ttl = self.expire_sessions;
+        let key = self.format_session_key(&message.session_id);
         cx.reply_later(async move {
-            let key = Self::format_session_key(&message.session_id);
             let data = serde_json::to_string(&message.data)?;
             conn.set_ex(&key, data, ttl.as_secs() as usize).await?;
             Ok(())
@@ -164,8 +454,8 @@ impl Handler<SaveSession> for RedisStore {
 impl Handler<GetSession> for RedisStore {
     fn handle(&mut self, message: GetSession, cx: Context<Self, GetSession>) {
         let mut conn = self.conn.clone();
+        let key = self.format_session_key(&message.session_id);
         cx.reply_later(async move {
-            let key = Self::format_session_key(&message.session_id);
             let data: Option<String> = conn.get(&key).await?;
             if let Some(data) = data {
                 Ok(Some(serde_json::from_str(&data)?))
@@ -179,68 +469,228 @@ impl Handler<GetSession> for RedisStore {
 impl Handler<DeleteSession> for RedisStore {
     fn handle(&mut self, message: DeleteSession, cx: Context<Self, DeleteSession>) {
         let mut conn = self.conn.clone();
+        let key = self.format_session_key(&message.session_id);
         cx.reply_later(async move {
-            let key = Self::format_session_key(&message.session_id);
             conn.del(&key).await?;
             Ok(())
         });
     }
 }
 
+impl Handler<SaveChallenge> for RedisStore {
+    fn handle(&mut self, message: SaveChallenge, cx: Context<Self, SaveChallenge>) {
+        let mut conn = self.conn.clone();
+        let ttl = self.expire_sessions;
+        let key = self.format_challenge_key(&message.challenge_id);
+        cx.reply_later(async move {
+            conn.set_ex::<_, _, ()>(&key, message.data, ttl.as_secs() as usize)
+                .await?;
+            Ok(())
+        });
+    }
+}
+
+impl Handler<TakeChallenge> for RedisStore {
+    fn handle(&mut self, message: TakeChallenge, cx: Context<Self, TakeChallenge>) {
+        let mut conn = self.conn.clone();
+        let key = self.format_challenge_key(&message.challenge_id);
+        cx.reply_later(async move {
+            let (data,): (Option<String>,) = pipe()
+                .atomic()
+                .get(&key)
+                .del(&key)
+                .ignore()
+                .query_async(&mut conn)
+                .await?;
+            Ok(data)
+        });
+    }
+}
+
+impl Handler<GetDomainValidation> for RedisStore {
+    fn handle(&mut self, message: GetDomainValidation, cx: Context<Self, GetDomainValidation>) {
+        let mut conn = self.conn.clone();
+        let key = self.format_domain_validation_key(&message.key);
+        cx.reply_later(async move {
+            let data: Option<String> = conn.get(&key).await?;
+            if let Some(data) = data {
+                Ok(Some(serde_json::from_str(&data)?))
+            } else {
+                Ok(None)
+            }
+        });
+    }
+}
+
+impl Handler<SaveDomainValidation> for RedisStore {
+    fn handle(&mut self, message: SaveDomainValidation, cx: Context<Self, SaveDomainValidation>) {
+        let mut conn = self.conn.clone();
+        let key = self.format_domain_validation_key(&message.key);
+        cx.reply_later(async move {
+            let data = serde_json::to_string(&message.validation)?;
+            conn.set_ex::<_, _, ()>(&key, data, message.ttl.as_secs() as usize)
+                .await?;
+            Ok(())
+        });
+    }
+}
+
 impl Handler<FetchUrlCached> for RedisStore {
     fn handle(&mut self, message: FetchUrlCached, cx: Context<Self, FetchUrlCached>) {
         let mut conn = self.conn.clone();
         let mut locking = self.locking.clone();
         let fetcher = self.fetcher.clone();
-        let expire_cache = self.expire_cache;
+        let policy = self.cache_policy;
+        let revalidations = self.revalidations.clone();
+        let key = self.prefixed(format_args!("cache:{}", message.url));
+        let lock_key = self.prefixed(format_args!("lock:cache:{}", message.url));
         cx.reply_later(async move {
-            let key = format!("cache:{}", message.url);
-            let _lock = locking.lock(format!("lock:{}", key).as_bytes()).await;
-            if let Some(data) = conn.get(key).await? {
-                Ok(data)
-            } else {
-                let key = message.url.as_str().to_owned();
-                let result = fetcher
-                    .send(FetchUrl::get(&message.url, message.metric))
-                    .await?;
-                let ttl = std::cmp::max(expire_cache, result.max_age);
-                conn.set_ex(key, result.data.clone(), ttl.as_secs() as usize)
-                    .await?;
-                Ok(result.data)
+            let cached = get_cache_entry(&mut conn, &key).await?;
+            if let Some(cached) = cached {
+                match cached.state() {
+                    CacheState::Fresh => return Ok(cached.data),
+                    CacheState::Stale => {
+                        let data = cached.data.clone();
+                        let stale = cached;
+                        let FetchUrlCached { url, purpose } = message;
+                        revalidations.spawn(url.clone(), async move {
+                            let _lock = locking.lock(lock_key.as_bytes()).await?;
+                            // Another worker may have revalidated while we waited for the lock.
+                            let cached = get_cache_entry(&mut conn, &key).await?;
+                            if let Some(CacheState::Fresh) = cached.as_ref().map(CacheEntry::state)
+                            {
+                                return Ok(());
+                            }
+                            let cached = cached.unwrap_or(stale);
+                            let fresh =
+                                fetch_entry(&fetcher, policy, &url, purpose, Some(&cached)).await?;
+                            set_cache_entry(&mut conn, &key, &fresh).await
+                        });
+                        return Ok(data);
+                    }
+                    CacheState::Expired => {}
+                }
+            }
+
+            let _lock = locking.lock(lock_key.as_bytes()).await?;
+            let cached = get_cache_entry(&mut conn, &key).await?;
+            if let Some(cached) = cached.as_ref() {
+                if cached.state() != CacheState::Expired {
+                    return Ok(cached.data.clone());
+                }
+            }
+            match fetch_entry(
+                &fetcher,
+                policy,
+                &message.url,
+                message.purpose,
+                cached.as_ref(),
+            )
+            .await
+            {
+                Ok(fresh) => {
+                    set_cache_entry(&mut conn, &key, &fresh).await?;
+                    Ok(fresh.data)
+                }
+                Err(err) => Ok(stale_on_error(cached.as_ref(), &message.url, err)?),
             }
         });
     }
 }
 
+/// Read a cache entry. Values that cannot be parsed are treated as missing.
+async fn get_cache_entry(conn: &mut RedisConn, key: &str) -> RedisResult<Option<CacheEntry>> {
+    let data: Option<String> = conn.get(key).await?;
+    Ok(data.and_then(|data| serde_json::from_str(&data).ok()))
+}
+
+/// Write a cache entry, which expires when it can be removed.
+async fn set_cache_entry(
+    conn: &mut RedisConn,
+    key: &str,
+    entry: &CacheEntry,
+) -> Result<(), BoxError> {
+    let data = serde_json::to_string(entry)?;
+    let ttl = std::cmp::max(entry.ttl().as_secs(), 1);
+    conn.set_ex(key, data, ttl as usize).await?;
+    Ok(())
+}
+
 impl Handler<IncrAndTestLimits> for RedisStore {
     fn handle(&mut self, message: IncrAndTestLimits, cx: Context<Self, IncrAndTestLimits>) {
         let conn = self.conn.clone();
-        let script = self.incr_limit_script.clone();
+        let incr_limit_script = self.incr_limit_script.clone();
+        let incr_sliding_script = self.incr_sliding_script.clone();
+        let take_bucket_script = self.take_bucket_script.clone();
+        let key_prefix = self.prefixed("rate-limit:");
         let ops: Vec<_> = self
-            .limit_configs
-            .iter()
+            .limit_rules
+            .select(&message.input)
             .map(|config| {
-                let key = message.input.build_key(&config, "rate-limit:", "|");
+                let key = message.input.build_key(config, &key_prefix, "|");
                 (config.clone(), key)
             })
             .collect();
         cx.reply_later(async move {
             let results = future::try_join_all(ops.into_iter().map(|(config, key)| {
                 let mut conn = conn.clone();
-                let script = script.clone();
+                let incr_limit_script = incr_limit_script.clone();
+                let incr_sliding_script = incr_sliding_script.clone();
+                let take_bucket_script = take_bucket_script.clone();
                 async move {
-                    let count: usize = script
-                        .prepare_invoke()
-                        .key(key)
-                        .arg(config.window.as_secs())
-                        .arg(config.extend_window)
-                        .invoke_async(&mut conn)
-                        .await?;
-                    Ok::<_, BoxError>(count <= config.max_count)
+                    match config.algorithm {
+                        LimitAlgorithm::FixedWindow => {
+                            let (count, ttl): (usize, i64) = incr_limit_script
+                                .prepare_invoke()
+                                .key(key)
+                                .arg(config.window.as_secs())
+                                .arg(config.extend_window)
+                                .invoke_async(&mut conn)
+                                .await?;
+                            if count <= config.max_count {
+                                return Ok::<_, BoxError>(None);
+                            }
+                            let reset = Duration::from_millis(ttl.max(0) as u64);
+                            Ok(Some(LimitExceeded::new(&config, reset)))
+                        }
+                        LimitAlgorithm::SlidingWindow => {
+                            let window = config.sliding_window(unix_duration());
+                            let (current, previous): (usize, usize) = incr_sliding_script
+                                .prepare_invoke()
+                                .key(window.current_key(&key, "|"))
+                                .key(window.previous_key(&key, "|"))
+                                .arg(window.ttl.as_millis() as u64)
+                                .invoke_async(&mut conn)
+                                .await?;
+                            if window.count(previous, current) <= config.max_count {
+                                return Ok(None);
+                            }
+                            let reset = window.reset(previous, current, config.max_count);
+                            Ok(Some(LimitExceeded::new(&config, reset)))
+                        }
+                        LimitAlgorithm::TokenBucket => {
+                            if config.max_count == 0 {
+                                return Ok(Some(LimitExceeded::new(&config, config.window)));
+                            }
+                            let wait: u64 = take_bucket_script
+                                .prepare_invoke()
+                                .key(key)
+                                .arg(unix_duration().as_micros() as u64)
+                                .arg(config.bucket_interval().as_micros() as u64)
+                                .arg(config.window.as_micros() as u64)
+                                .invoke_async(&mut conn)
+                                .await?;
+                            if wait == 0 {
+                                return Ok(None);
+                            }
+                            let reset = Duration::from_micros(wait);
+                            Ok(Some(LimitExceeded::new(&config, reset)))
+                        }
+                    }
                 }
             }))
             .await?;
-            Ok(results.into_iter().all(identity))
+            Ok(LimitExceeded::most_severe(results.into_iter().flatten()))
         });
     }
 }
@@ -248,28 +698,51 @@ impl Handler<IncrAndTestLimits> for RedisStore {
 impl Handler<DecrLimits> for RedisStore {
     fn handle(&mut self, message: DecrLimits, cx: Context<Self, DecrLimits>) {
         let conn = self.conn.clone();
-        let script = self.decr_limit_script.clone();
-        let keys: Vec<_> = self
-            .limit_configs
-            .iter()
-            .filter_map(|config| {
-                if config.decr_complete {
-                    Some(message.input.build_key(&config, "rate-limit:", "|"))
-                } else {
-                    None
-                }
+        let decr_limit_script = self.decr_limit_script.clone();
+        let return_bucket_script = self.return_bucket_script.clone();
+        let key_prefix = self.prefixed("rate-limit:");
+        let ops: Vec<_> = self
+            .limit_rules
+            .select(&message.input)
+            .filter(|config| config.decr_complete)
+            .map(|config| {
+                let key = message.input.build_key(config, &key_prefix, "|");
+                (config.clone(), key)
             })
             .collect();
         cx.reply_later(async move {
-            let _unused: Vec<()> = future::try_join_all(keys.into_iter().map(|key| {
+            let _unused: Vec<()> = future::try_join_all(ops.into_iter().map(|(config, key)| {
                 let mut conn = conn.clone();
-                let script = script.clone();
+                let decr_limit_script = decr_limit_script.clone();
+                let return_bucket_script = return_bucket_script.clone();
                 async move {
-                    script
-                        .prepare_invoke()
-                        .key(key)
-                        .invoke_async(&mut conn)
-                        .await
+                    match config.algorithm {
+                        LimitAlgorithm::FixedWindow => {
+                            decr_limit_script
+                                .prepare_invoke()
+                                .key(key)
+                                .invoke_async(&mut conn)
+                                .await
+                        }
+                        LimitAlgorithm::SlidingWindow => {
+                            let window = config.sliding_window(unix_duration());
+                            decr_limit_script
+                                .prepare_invoke()
+                                .key(window.current_key(&key, "|"))
+                                .key(window.previous_key(&key, "|"))
+                                .invoke_async(&mut conn)
+                                .await
+                        }
+                        LimitAlgorithm::TokenBucket => {
+                            return_bucket_script
+                                .prepare_invoke()
+                                .key(key)
+                                .arg(unix_duration().as_micros() as u64)
+                                .arg(config.bucket_interval().as_micros() as u64)
+                                .invoke_async(&mut conn)
+                                .await
+                        }
+                    }
                 }
             }))
             .await?;
@@ -280,30 +753,10 @@ impl Handler<DecrLimits> for RedisStore {
 
 impl Handler<EnableRotatingKeys> for RedisStore {
     fn handle(&mut self, message: EnableRotatingKeys, cx: Context<Self, EnableRotatingKeys>) {
-        let me = cx.addr().clone();
-        let my_id = self.id.clone();
-        let mut pubsub = self.pubsub.clone();
-        self.key_manager = Some(message.key_manager.clone());
-        cx.reply_later(async move {
-            for signing_alg in &message.signing_algs {
-                let signing_alg = *signing_alg;
-                // Listen for key changes by other workers.
-                let chan = format!("keys-updated:{}", signing_alg).into_bytes();
-                let mut sub = pubsub.subscribe(chan).await;
-                let me2 = me.clone();
-                let my_id2 = my_id.clone();
-                tokio::spawn(async move {
-                    loop {
-                        let from_id = sub.recv().await.expect("Redis keys subscription failed");
-                        if from_id.as_slice() != my_id2.as_ref() {
-                            me2.send(UpdateKeysLocked(signing_alg));
-                        }
-                    }
-                });
-                // Fetch current keys.
-                me.send(UpdateKeysLocked(signing_alg)).await;
-            }
-        });
+        self.key_manager = Some(message.key_manager);
+        self.signing_algs = message.signing_algs.into_iter().collect();
+        let subscribe_fut = self.subscribe_keys(cx.addr().clone());
+        cx.reply_later(subscribe_fut);
     }
 }
 
@@ -312,7 +765,10 @@ impl Handler<RotateKeysLocked> for RedisStore {
         let me = cx.addr().clone();
         let key_manager = self.key_manager.as_ref().unwrap().clone();
         cx.reply_later(async move {
-            let lock = me.send(LockKeys(message.0)).await;
+            let lock = me
+                .send(LockKeys(message.0))
+                .await
+                .expect("Failed to lock keys in Redis");
             let key_set = me
                 .send(FetchKeys(message.0))
                 .await
@@ -342,7 +798,7 @@ impl Handler<ImportKeySet> for RedisStore {
 impl Handler<LockKeys> for RedisStore {
     fn handle(&mut self, message: LockKeys, cx: Context<Self, LockKeys>) {
         let mut locking = self.locking.clone();
-        let lock_key = format!("lock:keys:{}", message.0);
+        let lock_key = self.prefixed(format_args!("lock:keys:{}", message.0));
         cx.reply_later(async move { locking.lock(lock_key.as_bytes()).await });
     }
 }
@@ -351,7 +807,7 @@ impl Handler<FetchKeys> for RedisStore {
     fn handle(&mut self, message: FetchKeys, cx: Context<Self, FetchKeys>) {
         let mut conn = self.conn.clone();
         let signing_alg = message.0;
-        let db_key = format!("keys:{}", signing_alg);
+        let db_key = self.prefixed(format_args!("keys:{}", signing_alg));
         cx.reply_later(async move {
             let key_set: Option<String> = conn.get(db_key).await?;
             let key_set = key_set.map_or_else(
@@ -367,12 +823,13 @@ impl Handler<SaveKeys> for RedisStore {
     fn handle(&mut self, message: SaveKeys, cx: Context<Self, SaveKeys>) {
         let mut conn = self.conn.clone();
         let signing_alg = message.0.signing_alg;
-        let db_key = format!("keys:{}", signing_alg);
+        let db_key = self.prefixed(format_args!("keys:{}", signing_alg));
         let data = serde_json::to_string(&message.0).expect("Could not encode key set as JSON");
         let mut pipe = pipe();
-        pipe.atomic()
-            .set(db_key, data)
-            .publish(format!("keys-updated:{}", signing_alg), &self.id[..]);
+        pipe.atomic().set(db_key, data).publish(
+            self.prefixed(format_args!("keys-updated:{}", signing_alg)),
+            &self.id[..],
+        );
         cx.reply_later(async move { pipe.query_async(&mut conn).await });
     }
 }
@@ -383,7 +840,10 @@ impl Handler<UpdateKeysLocked> for RedisStore {
         let key_manager = self.key_manager.as_ref().unwrap().clone();
         cx.reply_later(async move {
             let key_set = {
-                let _lock = me.send(LockKeys(message.0)).await;
+                let _lock = me
+                    .send(LockKeys(message.0))
+                    .await
+                    .expect("Failed to lock keys in Redis");
                 me.send(FetchKeys(message.0))
                     .await
                     .expect("Failed to fetch keys from Redis")
@@ -393,4 +853,126 @@ impl Handler<UpdateKeysLocked> for RedisStore {
     }
 }
 
+impl Handler<ExportData> for RedisStore {
+    fn handle(&mut self, message: ExportData, cx: Context<Self, ExportData>) {
+        let mut conn = self.conn.clone();
+        let key_prefix = self.key_prefix.clone();
+        cx.reply_later(async move {
+            let mut data = StoreData::default();
+            for signing_alg in SigningAlgorithm::ALL {
+                let key_set: Option<String> = conn
+                    .get(format!("{}keys:{}", key_prefix, signing_alg))
+                    .await?;
+                if let Some(key_set) = key_set {
+                    data.key_sets.push(serde_json::from_str(&key_set)?);
+                }
+            }
+            if message.sessions {
+                for (id, value, expires) in scan_values(&mut conn, &key_prefix, "session:").await? {
+                    data.sessions.push(StoreEntry {
+                        id,
+                        value: serde_json::from_str(&value)?,
+                        expires,
+                    });
+                }
+            }
+            if message.limits {
+                for (id, value, expires) in
+                    scan_values(&mut conn, &key_prefix, "rate-limit:").await?
+                {
+                    data.limits.push(StoreEntry {
+                        id,
+                        value: value.parse()?,
+                        expires,
+                    });
+                }
+            }
+            Ok(data)
+        });
+    }
+}
+
+impl Handler<ImportData> for RedisStore {
+    fn handle(&mut self, message: ImportData, cx: Context<Self, ImportData>) {
+        let me = cx.addr().clone();
+        let mut conn = self.conn.clone();
+        let mut pipe = pipe();
+        pipe.atomic();
+        for entry in &message.0.sessions {
+            if let Some(ttl) = entry.ttl() {
+                let data = match serde_json::to_string(&entry.value) {
+                    Ok(data) => data,
+                    Err(err) => return cx.reply(Err(err.into())),
+                };
+                let key = self.format_session_key(&entry.id);
+                pipe.pset_ex(key, data, ttl.as_millis() as usize).ignore();
+            }
+        }
+        for entry in &message.0.limits {
+            if let Some(ttl) = entry.ttl() {
+                let key = self.prefixed(format_args!("rate-limit:{}", entry.id));
+                pipe.pset_ex(key, entry.value, ttl.as_millis() as usize)
+                    .ignore();
+            }
+        }
+        cx.reply_later(async move {
+            pipe.query_async::<_, ()>(&mut conn).await?;
+            for key_set in message.0.key_sets {
+                me.send(SaveKeys(key_set)).await?;
+            }
+            Ok(())
+        });
+    }
+}
+
+/// Find keys with the given prefix, and fetch their string values and expiry times.
+///
+/// Returned IDs have the prefix stripped. Keys without an expiry time are skipped.
+async fn scan_values(
+    conn: &mut RedisConn,
+    key_prefix: &str,
+    name: &str,
+) -> RedisResult<Vec<(String, String, Duration)>> {
+    let prefix = format!("{}{}", key_prefix, name);
+    let pattern: String = prefix
+        .chars()
+        .flat_map(|c| match c {
+            '*' | '?' | '[' | ']' | '\\' => vec!['\\', c],
+            _ => vec![c],
+        })
+        .chain(std::iter::once('*'))
+        .collect();
+
+    let mut keys: Vec<String> = Vec::new();
+    let mut cursor = 0_u64;
+    loop {
+        let (next, batch): (u64, Vec<String>) = ::redis::cmd("SCAN")
+            .arg(cursor)
+            .arg("MATCH")
+            .arg(&pattern)
+            .arg("COUNT")
+            .arg(1000)
+            .query_async(conn)
+            .await?;
+        keys.extend(batch);
+        if next == 0 {
+            break;
+        }
+        cursor = next;
+    }
+    keys.sort();
+    keys.dedup();
+
+    let mut result = Vec::with_capacity(keys.len());
+    for key in keys {
+        let (value, pttl): (Option<String>, i64) =
+            pipe().get(&key).pttl(&key).query_async(conn).await?;
+        if let (Some(value), true) = (value, pttl > 0) {
+            let expires = unix_duration() + Duration::from_millis(pttl as u64);
+            result.push((key[prefix.len()..].to_owned(), value, expires));
+        }
+    }
+    Ok(result)
+}
+
 impl StoreSender for Addr<RedisStore> {}
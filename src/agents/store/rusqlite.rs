@@ -1,12 +1,15 @@
+use crate::agents::store::cache::{fetch_entry, stale_on_error, Revalidations};
 use crate::agents::*;
-use crate::config::LimitConfig;
+use crate::config::{LimitAlgorithm, LimitConfig, LimitExceeded, LimitRules};
 use crate::crypto::SigningAlgorithm;
-use crate::utils::{agent::*, unix_timestamp};
-use ::rusqlite::{Connection, Error as SqlError, OptionalExtension, ToSql};
-use std::path::PathBuf;
+use crate::utils::{agent::*, sqlite::Pool, unix_duration, unix_timestamp};
+use ::rusqlite::{
+    Connection, Error as SqlError, OpenFlags, OptionalExtension, ToSql, Transaction,
+    TransactionBehavior,
+};
+use std::path::{Path, PathBuf};
 use std::time::Duration;
 use tokio::task::spawn_blocking;
-use url::Url;
 
 macro_rules! params {
     ($($list:expr),*) => (
@@ -17,40 +20,101 @@ macro_rules! params {
 /// Database file `application_id` value. 'Prtr' in hex.
 const APP_ID: u32 = 0x5072_7472;
 
+/// Schema migrations, in order.
+///
+/// The database `user_version` is the number of migrations applied. Existing entries must never
+/// change; add a new entry to change the schema.
+const MIGRATIONS: &[&str] = &[
+    // Version 1: initial schema.
+    "
+    CREATE TABLE sessions (
+        id TEXT NOT NULL PRIMARY KEY,
+        data TEXT NOT NULL,
+        expires INTEGER NOT NULL
+    );
+    CREATE INDEX sessions_expires ON sessions (expires);
+
+    CREATE TABLE cache_entries (
+        url TEXT NOT NULL PRIMARY KEY,
+        data TEXT NOT NULL,
+        expires INTEGER NOT NULL
+    );
+    CREATE INDEX cache_entries_expires ON cache_entries (expires);
+
+    CREATE TABLE rate_limits (
+        id TEXT NOT NULL PRIMARY KEY,
+        value INTEGER NOT NULL,
+        expires INTEGER NOT NULL
+    );
+    CREATE INDEX rate_limits_expires ON rate_limits (expires);
+
+    CREATE TABLE key_sets (
+        signing_alg TEXT NOT NULL PRIMARY KEY,
+        key_set TEXT NOT NULL
+    );
+    ",
+    // Version 2: proof-of-work challenges.
+    "
+    CREATE TABLE challenges (
+        id TEXT NOT NULL PRIMARY KEY,
+        data TEXT NOT NULL,
+        expires INTEGER NOT NULL
+    );
+    CREATE INDEX challenges_expires ON challenges (expires);
+    ",
+    // Version 3: cache revalidation.
+    "
+    ALTER TABLE cache_entries ADD COLUMN etag TEXT;
+    ALTER TABLE cache_entries ADD COLUMN last_modified TEXT;
+    ALTER TABLE cache_entries ADD COLUMN fresh_until INTEGER NOT NULL DEFAULT 0;
+    ALTER TABLE cache_entries ADD COLUMN stale_until INTEGER NOT NULL DEFAULT 0;
+    ALTER TABLE cache_entries ADD COLUMN error_until INTEGER NOT NULL DEFAULT 0;
+    ",
+    // Version 4: domain validation cache.
+    "
+    CREATE TABLE domain_validations (
+        domain TEXT NOT NULL PRIMARY KEY,
+        data TEXT NOT NULL,
+        expires INTEGER NOT NULL
+    );
+    CREATE INDEX domain_validations_expires ON domain_validations (expires);
+    ",
+];
+
+/// Number of read-only connections in the pool.
+const READ_CONNECTIONS: usize = 4;
+
+/// Options for `RusqliteStore`.
+#[derive(Clone, Debug)]
+pub struct RusqliteOptions {
+    /// Whether to enable WAL journal mode.
+    pub wal: bool,
+    /// How long to wait for locks held by other connections.
+    pub busy_timeout: Duration,
+    /// Whether to copy the database file before applying migrations.
+    pub backup_before_migrate: bool,
+}
+
 /// Message sent at an interval to collect garbage.
 struct Gc;
 impl Message for Gc {
     type Reply = ();
 }
 
-/// Message used internally to save a cache entry.
-struct SaveCache {
-    url: Url,
-    data: String,
-    expires: i64,
-}
-impl Message for SaveCache {
-    type Reply = Result<(), SqlError>;
-}
-
-/// Message used internally to save a key set.
-struct SaveKeys(KeySet);
-impl Message for SaveKeys {
-    type Reply = Result<(), SqlError>;
-}
-
 /// Store implementation using `rusqlite`.
 pub struct RusqliteStore {
     /// TTL of session keys
     expire_sessions: Duration,
-    /// TTL of cache keys
-    expire_cache: Duration,
+    /// Cache timing configuration.
+    cache_policy: CachePolicy,
     /// Rate limit configuration.
-    limit_configs: Vec<LimitConfig>,
-    /// SQLite connection.
-    conn: Connection,
+    limit_rules: LimitRules,
+    /// SQLite connection pool.
+    pool: Pool,
     /// The agent used for fetching on cache miss.
     fetcher: Addr<FetchAgent>,
+    /// Background revalidations in progress.
+    revalidations: Revalidations,
     /// Key manager if rotating keys are enabled.
     key_manager: Option<Addr<RotatingKeys>>,
 }
@@ -58,16 +122,27 @@ pub struct RusqliteStore {
 impl RusqliteStore {
     pub async fn new(
         sqlite_db: PathBuf,
+        options: RusqliteOptions,
         expire_sessions: Duration,
-        expire_cache: Duration,
-        limit_configs: Vec<LimitConfig>,
+        cache_policy: CachePolicy,
+        limit_rules: LimitRules,
         fetcher: Addr<FetchAgent>,
     ) -> Result<Self, SqlError> {
         spawn_blocking(move || {
-            let conn = Connection::open(&sqlite_db)?;
-            conn.busy_timeout(Duration::from_millis(500))?;
+            let mut conn = Connection::open(&sqlite_db)?;
+            Self::configure(&conn, &options)?;
             Self::verify_app_id(&conn)?;
-            Self::verify_schema(&conn)?;
+            Self::migrate(&mut conn, &sqlite_db, &options)?;
+            let readers = (0..READ_CONNECTIONS)
+                .map(|_| {
+                    let reader = Connection::open_with_flags(
+                        &sqlite_db,
+                        OpenFlags::SQLITE_OPEN_READ_ONLY | OpenFlags::SQLITE_OPEN_NO_MUTEX,
+                    )?;
+                    reader.busy_timeout(options.busy_timeout)?;
+                    Ok(reader)
+                })
+                .collect::<Result<_, SqlError>>()?;
             log::warn!(
                 "Storing sessions and keys in SQLite at: {}",
                 sqlite_db.display()
@@ -76,10 +151,11 @@ impl RusqliteStore {
             log::warn!("(This warning can't be fixed; it's a friendly reminder.)");
             Ok(RusqliteStore {
                 expire_sessions,
-                expire_cache,
-                limit_configs,
-                conn,
+                cache_policy,
+                limit_rules,
+                pool: Pool::new(conn, readers),
                 fetcher,
+                revalidations: Revalidations::default(),
                 key_manager: None,
             })
         })
@@ -111,73 +187,96 @@ impl RusqliteStore {
         Ok(())
     }
 
-    fn verify_schema(conn: &Connection) -> Result<(), SqlError> {
-        let user_version: u32 =
-            conn.query_row("SELECT * FROM pragma_user_version()", [], |row| row.get(0))?;
-        match user_version {
-            0 => Self::init_schema(conn),
-            1 => Ok(()),
-            _ => panic!(
-                "The SQLite database has an unknown version: {}",
-                user_version
-            ),
+    fn configure(conn: &Connection, options: &RusqliteOptions) -> Result<(), SqlError> {
+        conn.busy_timeout(options.busy_timeout)?;
+        if options.wal {
+            let mode: String = conn.query_row("PRAGMA journal_mode = WAL", [], |row| row.get(0))?;
+            if mode.eq_ignore_ascii_case("wal") {
+                conn.execute_batch("PRAGMA synchronous = NORMAL")?;
+            } else {
+                log::warn!(
+                    "Could not enable SQLite WAL mode, journal mode is: {}",
+                    mode
+                );
+            }
         }
+        Ok(())
     }
 
-    fn init_schema(conn: &Connection) -> Result<(), SqlError> {
-        conn.execute_batch(
-            "
-            BEGIN;
-
-            CREATE TABLE sessions (
-                id TEXT NOT NULL PRIMARY KEY,
-                data TEXT NOT NULL,
-                expires INTEGER NOT NULL
-            );
-            CREATE INDEX sessions_expires ON sessions (expires);
-
-            CREATE TABLE cache_entries (
-                url TEXT NOT NULL PRIMARY KEY,
-                data TEXT NOT NULL,
-                expires INTEGER NOT NULL
-            );
-            CREATE INDEX cache_entries_expires ON cache_entries (expires);
-
-            CREATE TABLE rate_limits (
-                id TEXT NOT NULL PRIMARY KEY,
-                value INTEGER NOT NULL,
-                expires INTEGER NOT NULL
-            );
-            CREATE INDEX rate_limits_expires ON rate_limits (expires);
+    fn migrate(
+        conn: &mut Connection,
+        sqlite_db: &Path,
+        options: &RusqliteOptions,
+    ) -> Result<(), SqlError> {
+        let latest = MIGRATIONS.len() as u32;
+        let user_version = Self::user_version(conn)?;
+        assert!(
+            user_version <= latest,
+            "The SQLite database has version {}, but this broker only supports up to {}",
+            user_version,
+            latest
+        );
+        if user_version == latest {
+            return Ok(());
+        }
 
-            CREATE TABLE key_sets (
-                signing_alg TEXT NOT NULL PRIMARY KEY,
-                key_set TEXT NOT NULL
+        if options.backup_before_migrate && user_version != 0 {
+            let mut backup_path = sqlite_db.as_os_str().to_owned();
+            backup_path.push(format!(".v{}-{}.bak", user_version, unix_timestamp()));
+            log::warn!(
+                "Backing up SQLite database to: {}",
+                Path::new(&backup_path).display()
             );
+            conn.execute("VACUUM INTO ?1", params![&backup_path.to_string_lossy()])?;
+        }
 
-            PRAGMA user_version = 1;
-            COMMIT;
-            ",
-        )?;
-        Ok(())
+        // The version is checked again in each transaction, in case another process is migrating
+        // the same database file concurrently.
+        loop {
+            let tx = conn.transaction_with_behavior(TransactionBehavior::Immediate)?;
+            let user_version = Self::user_version(&tx)?;
+            let migration = match MIGRATIONS.get(user_version as usize) {
+                Some(migration) => migration,
+                None => return Ok(()),
+            };
+            log::info!("Migrating SQLite database to version {}", user_version + 1);
+            tx.execute_batch(migration)?;
+            // Note: can't use parameter binding in pragma.
+            tx.execute_batch(&format!("PRAGMA user_version = {}", user_version + 1))?;
+            tx.commit()?;
+        }
     }
 
-    fn get_key_set(&mut self, signing_alg: SigningAlgorithm) -> KeySet {
-        self.conn
-            .query_row(
-                "SELECT key_set FROM key_sets WHERE signing_alg = ?1 LIMIT 1",
-                params![&signing_alg.as_str()],
-                |row| row.get(0),
-            )
-            .optional()
-            .expect("Could not fetch keys from SQLite")
-            .map_or_else(
-                || KeySet::empty(signing_alg),
-                |data: String| serde_json::from_str(&data).expect("Invalid key set JSON in SQLite"),
-            )
+    fn user_version(conn: &Connection) -> Result<u32, SqlError> {
+        conn.query_row("SELECT * FROM pragma_user_version()", [], |row| row.get(0))
     }
 }
 
+/// Fetch a key set, or create an empty one.
+fn get_key_set(conn: &Connection, signing_alg: SigningAlgorithm) -> KeySet {
+    conn.query_row(
+        "SELECT key_set FROM key_sets WHERE signing_alg = ?1 LIMIT 1",
+        params![&signing_alg.as_str()],
+        |row| row.get(0),
+    )
+    .optional()
+    .expect("Could not fetch keys from SQLite")
+    .map_or_else(
+        || KeySet::empty(signing_alg),
+        |data: String| serde_json::from_str(&data).expect("Invalid key set JSON in SQLite"),
+    )
+}
+
+/// Save a key set.
+fn save_key_set(conn: &Connection, key_set: &KeySet) -> Result<(), SqlError> {
+    let data = serde_json::to_string(key_set).expect("Could not encode key set as JSON");
+    conn.execute(
+        "REPLACE INTO key_sets (signing_alg, key_set) VALUES (?1, ?2)",
+        params![&key_set.signing_alg.as_str(), &data],
+    )?;
+    Ok(())
+}
+
 impl Agent for RusqliteStore {
     fn started(&mut self, cx: Context<Self, AgentStarted>) {
         // Start the garbage collection loop.
@@ -195,29 +294,40 @@ impl Agent for RusqliteStore {
 
 impl Handler<Gc> for RusqliteStore {
     fn handle(&mut self, _message: Gc, cx: Context<Self, Gc>) {
-        let now = unix_timestamp() as i64;
-        self.conn
-            .execute("DELETE FROM sessions WHERE expires <= ?1", [now])
-            .expect("session cleanup failed");
-        self.conn
-            .execute("DELETE FROM cache_entries WHERE expires <= ?1", [now])
-            .expect("cache cleanup failed");
-        self.conn
-            .execute("DELETE FROM rate_limits WHERE expires <= ?1", [now])
-            .expect("rate limits cleanup failed");
-        cx.reply(())
+        let pool = self.pool.clone();
+        cx.reply_later(async move {
+            pool.write(|conn| {
+                let now = unix_timestamp() as i64;
+                conn.execute("DELETE FROM sessions WHERE expires <= ?1", [now])
+                    .expect("session cleanup failed");
+                conn.execute("DELETE FROM challenges WHERE expires <= ?1", [now])
+                    .expect("challenge cleanup failed");
+                conn.execute("DELETE FROM cache_entries WHERE expires <= ?1", [now])
+                    .expect("cache cleanup failed");
+                conn.execute("DELETE FROM domain_validations WHERE expires <= ?1", [now])
+                    .expect("domain validation cleanup failed");
+                conn.execute("DELETE FROM rate_limits WHERE expires <= ?1", [now])
+                    .expect("rate limits cleanup failed");
+            })
+            .await;
+        });
     }
 }
 
 impl Handler<SaveSession> for RusqliteStore {
     fn handle(&mut self, message: SaveSession, cx: Context<Self, SaveSession>) {
-        cx.reply_with(move || {
-            let expires = (unix_timestamp() + self.expire_sessions.as_secs()) as i64;
+        let pool = self.pool.clone();
+        let expire_sessions = self.expire_sessions;
+        cx.reply_later(async move {
             let data = serde_json::to_string(&message.data)?;
-            self.conn.execute(
-                "REPLACE INTO sessions (id, data, expires) VALUES (?1, ?2, ?3)",
-                params![&message.session_id, &data, &expires],
-            )?;
+            pool.write(move |conn| {
+                let expires = (unix_timestamp() + expire_sessions.as_secs()) as i64;
+                conn.execute(
+                    "REPLACE INTO sessions (id, data, expires) VALUES (?1, ?2, ?3)",
+                    params![&message.session_id, &data, &expires],
+                )
+            })
+            .await?;
             Ok(())
         });
     }
@@ -225,16 +335,19 @@ impl Handler<SaveSession> for RusqliteStore {
 
 impl Handler<GetSession> for RusqliteStore {
     fn handle(&mut self, message: GetSession, cx: Context<Self, GetSession>) {
-        cx.reply_with(move || {
-            let now = unix_timestamp() as i64;
-            let data: Option<String> = self
-                .conn
-                .query_row(
-                    "SELECT data FROM sessions WHERE id = ?1 AND expires > ?2 LIMIT 1",
-                    params![&message.session_id, &now],
-                    |row| row.get(0),
-                )
-                .optional()?;
+        let pool = self.pool.clone();
+        cx.reply_later(async move {
+            let data: Option<String> = pool
+                .read(move |conn| {
+                    let now = unix_timestamp() as i64;
+                    conn.query_row(
+                        "SELECT data FROM sessions WHERE id = ?1 AND expires > ?2 LIMIT 1",
+                        params![&message.session_id, &now],
+                        |row| row.get(0),
+                    )
+                    .optional()
+                })
+                .await??;
             if let Some(data) = data {
                 let data = serde_json::from_str(&data)?;
                 Ok(Some(data))
@@ -247,137 +360,444 @@ impl Handler<GetSession> for RusqliteStore {
 
 impl Handler<DeleteSession> for RusqliteStore {
     fn handle(&mut self, message: DeleteSession, cx: Context<Self, DeleteSession>) {
-        cx.reply_with(move || {
-            self.conn
-                .execute("DELETE FROM sessions WHERE id = ?1", &[&message.session_id])?;
+        let pool = self.pool.clone();
+        cx.reply_later(async move {
+            pool.write(move |conn| {
+                conn.execute("DELETE FROM sessions WHERE id = ?1", &[&message.session_id])
+            })
+            .await?;
             Ok(())
         });
     }
 }
 
-impl Handler<FetchUrlCached> for RusqliteStore {
-    fn handle(&mut self, message: FetchUrlCached, cx: Context<Self, FetchUrlCached>) {
-        // TODO: Add locking to coordinate multiple fetches for the same resource.
-        let now = unix_timestamp() as i64;
-        let data: Result<Option<String>, SqlError> = self
-            .conn
-            .query_row(
-                "SELECT data FROM cache_entries WHERE url = ?1 AND expires > ?2 LIMIT 1",
-                params![&message.url.as_str(), &now],
-                |row| row.get(0),
-            )
-            .optional();
-        match data {
-            Err(e) => return cx.reply(Err(e.into())),
-            Ok(Some(data)) => return cx.reply(Ok(data)),
-            Ok(None) => {}
-        }
-        let me = cx.addr().clone();
-        let fetcher = self.fetcher.clone();
-        let expire_cache = self.expire_cache;
+impl Handler<SaveChallenge> for RusqliteStore {
+    fn handle(&mut self, message: SaveChallenge, cx: Context<Self, SaveChallenge>) {
+        let pool = self.pool.clone();
+        let expire_sessions = self.expire_sessions;
         cx.reply_later(async move {
-            let result = fetcher
-                .send(FetchUrl::get(&message.url, message.metric))
-                .await?;
-            let ttl = std::cmp::max(expire_cache, result.max_age);
-            me.send(SaveCache {
-                url: message.url,
-                data: result.data.clone(),
-                expires: (unix_timestamp() + ttl.as_secs()) as i64,
+            pool.write(move |conn| {
+                let expires = (unix_timestamp() + expire_sessions.as_secs()) as i64;
+                conn.execute(
+                    "REPLACE INTO challenges (id, data, expires) VALUES (?1, ?2, ?3)",
+                    params![&message.challenge_id, &message.data, &expires],
+                )
             })
             .await?;
-            Ok(result.data)
+            Ok(())
         });
     }
 }
 
-impl Handler<SaveCache> for RusqliteStore {
-    fn handle(&mut self, message: SaveCache, cx: Context<Self, SaveCache>) {
-        cx.reply_with(move || {
-            self.conn.execute(
-                "REPLACE INTO cache_entries (url, data, expires) VALUES (?1, ?2, ?3)",
-                params![&message.url.as_str(), &message.data, &message.expires],
-            )?;
+impl Handler<TakeChallenge> for RusqliteStore {
+    fn handle(&mut self, message: TakeChallenge, cx: Context<Self, TakeChallenge>) {
+        let pool = self.pool.clone();
+        cx.reply_later(async move {
+            let data = pool
+                .write(move |conn| {
+                    let now = unix_timestamp() as i64;
+                    let tx = conn.transaction_with_behavior(TransactionBehavior::Immediate)?;
+                    let data: Option<String> = tx
+                        .query_row(
+                            "SELECT data FROM challenges WHERE id = ?1 AND expires > ?2 LIMIT 1",
+                            params![&message.challenge_id, &now],
+                            |row| row.get(0),
+                        )
+                        .optional()?;
+                    tx.execute(
+                        "DELETE FROM challenges WHERE id = ?1",
+                        params![&message.challenge_id],
+                    )?;
+                    tx.commit()?;
+                    Ok::<_, SqlError>(data)
+                })
+                .await?;
+            Ok(data)
+        });
+    }
+}
+
+impl Handler<GetDomainValidation> for RusqliteStore {
+    fn handle(&mut self, message: GetDomainValidation, cx: Context<Self, GetDomainValidation>) {
+        let pool = self.pool.clone();
+        cx.reply_later(async move {
+            let data: Option<String> = pool
+                .read(move |conn| {
+                    let now = unix_timestamp() as i64;
+                    conn.query_row(
+                        "SELECT data FROM domain_validations WHERE domain = ?1 AND expires > ?2",
+                        params![&message.key, &now],
+                        |row| row.get(0),
+                    )
+                    .optional()
+                })
+                .await??;
+            if let Some(data) = data {
+                Ok(Some(serde_json::from_str(&data)?))
+            } else {
+                Ok(None)
+            }
+        });
+    }
+}
+
+impl Handler<SaveDomainValidation> for RusqliteStore {
+    fn handle(&mut self, message: SaveDomainValidation, cx: Context<Self, SaveDomainValidation>) {
+        let pool = self.pool.clone();
+        cx.reply_later(async move {
+            let data = serde_json::to_string(&message.validation)?;
+            pool.write(move |conn| {
+                let expires = (unix_timestamp() + message.ttl.as_secs()) as i64;
+                conn.execute(
+                    "REPLACE INTO domain_validations (domain, data, expires) VALUES (?1, ?2, ?3)",
+                    params![&message.key, &data, &expires],
+                )
+            })
+            .await?;
             Ok(())
         });
     }
 }
 
+impl Handler<FetchUrlCached> for RusqliteStore {
+    fn handle(&mut self, message: FetchUrlCached, cx: Context<Self, FetchUrlCached>) {
+        // TODO: Add locking to coordinate multiple fetches for the same resource.
+        let pool = self.pool.clone();
+        let fetcher = self.fetcher.clone();
+        let policy = self.cache_policy;
+        let revalidations = self.revalidations.clone();
+        cx.reply_later(async move {
+            let url = message.url.as_str().to_owned();
+            let cached = pool.read(move |conn| get_cache_entry(conn, &url)).await??;
+            if let Some(cached) = cached.as_ref() {
+                match cached.state() {
+                    CacheState::Fresh => return Ok(cached.data.clone()),
+                    CacheState::Stale => {
+                        let stale = cached.clone();
+                        let FetchUrlCached { url, purpose } = message;
+                        revalidations.spawn(url.clone(), async move {
+                            let fresh =
+                                fetch_entry(&fetcher, policy, &url, purpose, Some(&stale)).await?;
+                            pool.write(move |conn| set_cache_entry(conn, url.as_str(), &fresh))
+                                .await?;
+                            Ok(())
+                        });
+                        return Ok(cached.data.clone());
+                    }
+                    CacheState::Expired => {}
+                }
+            }
+
+            match fetch_entry(
+                &fetcher,
+                policy,
+                &message.url,
+                message.purpose,
+                cached.as_ref(),
+            )
+            .await
+            {
+                Ok(fresh) => {
+                    let url = message.url.as_str().to_owned();
+                    let data = fresh.data.clone();
+                    pool.write(move |conn| set_cache_entry(conn, &url, &fresh))
+                        .await?;
+                    Ok(data)
+                }
+                Err(err) => Ok(stale_on_error(cached.as_ref(), &message.url, err)?),
+            }
+        });
+    }
+}
+
+/// Read a cache entry, if it has not expired.
+fn get_cache_entry(conn: &Connection, url: &str) -> Result<Option<CacheEntry>, SqlError> {
+    let now = unix_timestamp() as i64;
+    conn.query_row(
+        "SELECT data, etag, last_modified, fresh_until, stale_until, error_until, expires
+        FROM cache_entries WHERE url = ?1 AND expires > ?2 LIMIT 1",
+        params![&url, &now],
+        |row| {
+            Ok(CacheEntry {
+                data: row.get(0)?,
+                validators: Validators {
+                    etag: row.get(1)?,
+                    last_modified: row.get(2)?,
+                },
+                fresh_until: row.get::<_, i64>(3)? as u64,
+                stale_until: row.get::<_, i64>(4)? as u64,
+                error_until: row.get::<_, i64>(5)? as u64,
+                expires: row.get::<_, i64>(6)? as u64,
+            })
+        },
+    )
+    .optional()
+}
+
+/// Write a cache entry.
+fn set_cache_entry(conn: &Connection, url: &str, entry: &CacheEntry) -> Result<usize, SqlError> {
+    conn.execute(
+        "REPLACE INTO cache_entries
+            (url, data, etag, last_modified, fresh_until, stale_until, error_until, expires)
+        VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8)",
+        params![
+            &url,
+            &entry.data,
+            &entry.validators.etag,
+            &entry.validators.last_modified,
+            &(entry.fresh_until as i64),
+            &(entry.stale_until as i64),
+            &(entry.error_until as i64),
+            &(entry.expires as i64)
+        ],
+    )
+}
+
+/// Increment a fixed window limit, and test it.
+fn incr_fixed_window(
+    tx: &Transaction,
+    config: &LimitConfig,
+    id: &str,
+) -> Result<Option<LimitExceeded>, SqlError> {
+    let now = unix_timestamp() as i64;
+    let window = config.window.as_secs() as i64;
+    tx.execute(
+        "DELETE FROM rate_limits WHERE id = ?1 AND expires <= ?2",
+        params![&id, &now],
+    )?;
+    if config.extend_window {
+        tx.execute(
+            "INSERT INTO rate_limits (id, value, expires) VALUES (?1, 1, ?2 + ?3)
+            ON CONFLICT(id) DO UPDATE SET value = value + 1, expires = ?2 + ?3",
+            params![&id, &now, &window],
+        )?;
+    } else {
+        tx.execute(
+            "INSERT INTO rate_limits (id, value, expires) VALUES (?1, 1, ?2 + ?3)
+            ON CONFLICT(id) DO UPDATE SET value = value + 1",
+            params![&id, &now, &window],
+        )?;
+    }
+    let (count, expires): (i64, i64) = tx.query_row(
+        "SELECT value, expires FROM rate_limits WHERE id = ?1 LIMIT 1",
+        params![&id],
+        |row| Ok((row.get(0)?, row.get(1)?)),
+    )?;
+    if count as usize <= config.max_count {
+        Ok(None)
+    } else {
+        let reset = Duration::from_secs((expires - now).max(0) as u64);
+        Ok(Some(LimitExceeded::new(config, reset)))
+    }
+}
+
+/// Increment a sliding window limit, and test it.
+fn incr_sliding_window(
+    tx: &Transaction,
+    config: &LimitConfig,
+    id: &str,
+) -> Result<Option<LimitExceeded>, SqlError> {
+    let now = unix_duration();
+    let window = config.sliding_window(now);
+    let current_id = window.current_key(id, "|");
+    let previous_id = window.previous_key(id, "|");
+    // Round up, so we never clean up a counter early.
+    let expires = (now + window.ttl).as_secs() as i64 + 1;
+    tx.execute(
+        "INSERT INTO rate_limits (id, value, expires) VALUES (?1, 1, ?2)
+        ON CONFLICT(id) DO UPDATE SET value = value + 1",
+        params![&current_id, &expires],
+    )?;
+    let current: i64 = tx.query_row(
+        "SELECT value FROM rate_limits WHERE id = ?1 LIMIT 1",
+        params![&current_id],
+        |row| row.get(0),
+    )?;
+    let previous: Option<i64> = tx
+        .query_row(
+            "SELECT value FROM rate_limits WHERE id = ?1 AND expires > ?2 LIMIT 1",
+            params![&previous_id, &(now.as_secs() as i64)],
+            |row| row.get(0),
+        )
+        .optional()?;
+    let (previous, current) = (previous.unwrap_or(0) as usize, current as usize);
+    if window.count(previous, current) <= config.max_count {
+        Ok(None)
+    } else {
+        let reset = window.reset(previous, current, config.max_count);
+        Ok(Some(LimitExceeded::new(config, reset)))
+    }
+}
+
+/// Try to take a token from a token bucket limit.
+///
+/// The arrival time of the bucket is stored in the value column, in microseconds since Unix epoch.
+fn take_token_bucket(
+    tx: &Transaction,
+    config: &LimitConfig,
+    id: &str,
+) -> Result<Option<LimitExceeded>, SqlError> {
+    let now = unix_duration();
+    let tat: Option<i64> = tx
+        .query_row(
+            "SELECT value FROM rate_limits WHERE id = ?1 LIMIT 1",
+            params![&id],
+            |row| row.get(0),
+        )
+        .optional()?;
+    let tat = tat.map(|tat| Duration::from_micros(tat as u64));
+    match config.bucket_take(tat, now) {
+        Ok(tat) => {
+            save_token_bucket(tx, id, tat)?;
+            Ok(None)
+        }
+        Err(reset) => Ok(Some(LimitExceeded::new(config, reset))),
+    }
+}
+
+/// Save the arrival time of a token bucket.
+fn save_token_bucket(tx: &Transaction, id: &str, tat: Duration) -> Result<(), SqlError> {
+    let value = tat.as_micros() as i64;
+    let expires = tat.as_secs() as i64 + 1;
+    tx.execute(
+        "REPLACE INTO rate_limits (id, value, expires) VALUES (?1, ?2, ?3)",
+        params![&id, &value, &expires],
+    )?;
+    Ok(())
+}
+
 impl Handler<IncrAndTestLimits> for RusqliteStore {
     fn handle(&mut self, message: IncrAndTestLimits, cx: Context<Self, IncrAndTestLimits>) {
-        cx.reply_with(move || {
-            let mut ok = true;
-            for config in &self.limit_configs {
-                let id = message.input.build_key(&config, "", "|");
-                let now = unix_timestamp() as i64;
-                let window = config.window.as_secs() as i64;
-                let tx = self.conn.transaction()?;
-                tx.execute(
-                    "DELETE FROM rate_limits WHERE id = ?1 AND expires <= ?2",
-                    params![&id, &now],
-                )?;
-                if config.extend_window {
-                    tx.execute(
-                        "INSERT INTO rate_limits (id, value, expires) VALUES (?1, 1, ?2 + ?3)
-                        ON CONFLICT(id) DO UPDATE SET value = value + 1, expires = ?2 + ?3",
-                        params![&id, &now, &window],
-                    )?;
-                } else {
-                    tx.execute(
-                        "INSERT INTO rate_limits (id, value, expires) VALUES (?1, 1, ?2 + ?3)
-                        ON CONFLICT(id) DO UPDATE SET value = value + 1",
-                        params![&id, &now, &window],
-                    )?;
+        let pool = self.pool.clone();
+        let ops: Vec<_> = self
+            .limit_rules
+            .select(&message.input)
+            .map(|config| (config.clone(), message.input.build_key(config, "", "|")))
+            .collect();
+        cx.reply_later(async move {
+            let exceeded = pool
+                .write(move |conn| {
+                    let mut exceeded = Vec::new();
+                    for (config, id) in &ops {
+                        let tx = conn.transaction_with_behavior(TransactionBehavior::Immediate)?;
+                        let result = match config.algorithm {
+                            LimitAlgorithm::FixedWindow => incr_fixed_window(&tx, config, id)?,
+                            LimitAlgorithm::SlidingWindow => incr_sliding_window(&tx, config, id)?,
+                            LimitAlgorithm::TokenBucket => take_token_bucket(&tx, config, id)?,
+                        };
+                        tx.commit()?;
+                        exceeded.extend(result);
+                    }
+                    Ok::<_, SqlError>(exceeded)
+                })
+                .await?;
+            Ok(LimitExceeded::most_severe(exceeded))
+        });
+    }
+}
+
+/// Decrement a limit.
+fn decr_limit(tx: &Transaction, config: &LimitConfig, id: &str) -> Result<(), SqlError> {
+    match config.algorithm {
+        LimitAlgorithm::FixedWindow => {
+            let now = unix_timestamp() as i64;
+            tx.execute(
+                "DELETE FROM rate_limits WHERE id = ?1 AND (expires <= ?2 OR value <= 1)",
+                params![&id, &now],
+            )?;
+            tx.execute(
+                "UPDATE rate_limits SET value = value - 1 WHERE id = ?1",
+                params![&id],
+            )?;
+        }
+        LimitAlgorithm::SlidingWindow => {
+            let now = unix_duration();
+            let window = config.sliding_window(now);
+            // Decrement the first counter that is still alive.
+            for id in &[window.current_key(id, "|"), window.previous_key(id, "|")] {
+                let value: Option<i64> = tx
+                    .query_row(
+                        "SELECT value FROM rate_limits WHERE id = ?1 AND expires > ?2 LIMIT 1",
+                        params![id, &(now.as_secs() as i64)],
+                        |row| row.get(0),
+                    )
+                    .optional()?;
+                if let Some(value) = value {
+                    if value <= 1 {
+                        tx.execute("DELETE FROM rate_limits WHERE id = ?1", params![id])?;
+                    } else {
+                        tx.execute(
+                            "UPDATE rate_limits SET value = value - 1 WHERE id = ?1",
+                            params![id],
+                        )?;
+                    }
+                    break;
                 }
-                let count: i64 = tx.query_row(
+            }
+        }
+        LimitAlgorithm::TokenBucket => {
+            let tat: Option<i64> = tx
+                .query_row(
                     "SELECT value FROM rate_limits WHERE id = ?1 LIMIT 1",
                     params![&id],
                     |row| row.get(0),
-                )?;
-                tx.commit()?;
-                ok = ok && count as usize <= config.max_count;
+                )
+                .optional()?;
+            if let Some(tat) = tat {
+                let tat = Duration::from_micros(tat as u64);
+                match config.bucket_return(tat, unix_duration()) {
+                    Some(tat) => save_token_bucket(tx, id, tat)?,
+                    None => {
+                        tx.execute("DELETE FROM rate_limits WHERE id = ?1", params![&id])?;
+                    }
+                }
             }
-            Ok(ok)
-        })
+        }
     }
+    Ok(())
 }
 
 impl Handler<DecrLimits> for RusqliteStore {
     fn handle(&mut self, message: DecrLimits, cx: Context<Self, DecrLimits>) {
-        cx.reply_with(move || {
-            for config in &self.limit_configs {
-                if !config.decr_complete {
-                    continue;
+        let pool = self.pool.clone();
+        let ops: Vec<_> = self
+            .limit_rules
+            .select(&message.input)
+            .filter(|config| config.decr_complete)
+            .map(|config| (config.clone(), message.input.build_key(config, "", "|")))
+            .collect();
+        cx.reply_later(async move {
+            pool.write(move |conn| {
+                for (config, id) in &ops {
+                    let tx = conn.transaction_with_behavior(TransactionBehavior::Immediate)?;
+                    decr_limit(&tx, config, id)?;
+                    tx.commit()?;
                 }
-                let id = message.input.build_key(&config, "", "|");
-                let expires = (unix_timestamp() + config.window.as_secs()) as i64;
-                let tx = self.conn.transaction()?;
-                tx.execute(
-                    "DELETE FROM rate_limits WHERE id = ?1 AND (expires <= ?2 OR value <= 1)",
-                    params![&id, &expires],
-                )?;
-                tx.execute(
-                    "UPDATE rate_limits SET value = value - 1 WHERE id = ?1",
-                    params![&id],
-                )?;
-                tx.commit()?;
-            }
+                Ok::<_, SqlError>(())
+            })
+            .await?;
             Ok(())
-        })
+        });
     }
 }
 
 impl Handler<EnableRotatingKeys> for RusqliteStore {
     fn handle(&mut self, message: EnableRotatingKeys, cx: Context<Self, EnableRotatingKeys>) {
         self.key_manager = Some(message.key_manager.clone());
-        let mut update_msgs = Vec::with_capacity(message.signing_algs.len());
-        for signing_alg in &message.signing_algs {
-            let key_set = self.get_key_set(*signing_alg);
-            update_msgs.push(UpdateKeys(key_set.clone()));
-        }
+        let pool = self.pool.clone();
         cx.reply_later(async move {
-            for update_msg in update_msgs {
-                message.key_manager.send(update_msg).await;
+            let signing_algs = message.signing_algs;
+            let key_sets = pool
+                .read(move |conn| {
+                    signing_algs
+                        .into_iter()
+                        .map(|signing_alg| get_key_set(conn, signing_alg))
+                        .collect::<Vec<_>>()
+                })
+                .await
+                .expect("Could not fetch keys from SQLite");
+            for key_set in key_sets {
+                message.key_manager.send(UpdateKeys(key_set)).await;
             }
         });
     }
@@ -385,12 +805,17 @@ impl Handler<EnableRotatingKeys> for RusqliteStore {
 
 impl Handler<RotateKeysLocked> for RusqliteStore {
     fn handle(&mut self, message: RotateKeysLocked, cx: Context<Self, RotateKeysLocked>) {
-        let me = cx.addr().clone();
-        let key_set = self.get_key_set(message.0);
+        let pool = self.pool.clone();
         let key_manager = self.key_manager.as_ref().unwrap().clone();
         cx.reply_later(async move {
+            let signing_alg = message.0;
+            let key_set = pool
+                .read(move |conn| get_key_set(conn, signing_alg))
+                .await
+                .expect("Could not fetch keys from SQLite");
             if let Some(key_set) = key_manager.send(RotateKeys(key_set)).await {
-                me.send(SaveKeys(key_set.clone()))
+                let key_set2 = key_set.clone();
+                pool.write(move |conn| save_key_set(conn, &key_set2))
                     .await
                     .expect("Could not save keys to SQLite");
                 key_manager.send(UpdateKeys(key_set)).await;
@@ -401,27 +826,141 @@ impl Handler<RotateKeysLocked> for RusqliteStore {
 
 impl Handler<ImportKeySet> for RusqliteStore {
     fn handle(&mut self, message: ImportKeySet, cx: Context<Self, ImportKeySet>) {
-        let me = cx.addr().clone();
+        let pool = self.pool.clone();
         cx.reply_later(async move {
-            me.send(SaveKeys(message.0))
+            pool.write(move |conn| save_key_set(conn, &message.0))
                 .await
                 .expect("Could not save keys to SQLite");
         });
     }
 }
 
-impl Handler<SaveKeys> for RusqliteStore {
-    fn handle(&mut self, message: SaveKeys, cx: Context<Self, SaveKeys>) {
-        let key_set = message.0;
-        cx.reply_with(move || {
-            let data = serde_json::to_string(&key_set).expect("Could not encode key set as JSON");
-            self.conn.execute(
-                "REPLACE INTO key_sets (signing_alg, key_set) VALUES (?1, ?2)",
-                params![&key_set.signing_alg.as_str(), &data],
-            )?;
-            Ok(())
+impl Handler<ExportData> for RusqliteStore {
+    fn handle(&mut self, message: ExportData, cx: Context<Self, ExportData>) {
+        let pool = self.pool.clone();
+        cx.reply_later(async move {
+            pool.read(move |conn| {
+                let now = unix_timestamp() as i64;
+                let mut data = StoreData::default();
+
+                let mut stmt = conn.prepare("SELECT key_set FROM key_sets")?;
+                let mut rows = stmt.query([])?;
+                while let Some(row) = rows.next()? {
+                    let key_set: String = row.get(0)?;
+                    data.key_sets.push(serde_json::from_str(&key_set)?);
+                }
+
+                if message.sessions {
+                    let mut stmt =
+                        conn.prepare("SELECT id, data, expires FROM sessions WHERE expires > ?1")?;
+                    let mut rows = stmt.query([now])?;
+                    while let Some(row) = rows.next()? {
+                        let session: String = row.get(1)?;
+                        data.sessions.push(StoreEntry {
+                            id: row.get(0)?,
+                            value: serde_json::from_str(&session)?,
+                            expires: Duration::from_secs(row.get::<_, i64>(2)? as u64),
+                        });
+                    }
+                }
+
+                if message.limits {
+                    let mut stmt = conn
+                        .prepare("SELECT id, value, expires FROM rate_limits WHERE expires > ?1")?;
+                    let mut rows = stmt.query([now])?;
+                    while let Some(row) = rows.next()? {
+                        data.limits.push(StoreEntry {
+                            id: row.get(0)?,
+                            value: row.get(1)?,
+                            expires: Duration::from_secs(row.get::<_, i64>(2)? as u64),
+                        });
+                    }
+                }
+
+                Ok(data)
+            })
+            .await?
+        });
+    }
+}
+
+impl Handler<ImportData> for RusqliteStore {
+    fn handle(&mut self, message: ImportData, cx: Context<Self, ImportData>) {
+        let pool = self.pool.clone();
+        cx.reply_later(async move {
+            pool.write(move |conn| {
+                let tx = conn.transaction_with_behavior(TransactionBehavior::Immediate)?;
+                for key_set in &message.0.key_sets {
+                    save_key_set(&tx, key_set)?;
+                }
+                for entry in &message.0.sessions {
+                    let data = serde_json::to_string(&entry.value)?;
+                    tx.execute(
+                        "REPLACE INTO sessions (id, data, expires) VALUES (?1, ?2, ?3)",
+                        params![&entry.id, &data, &(entry.expires_secs() as i64)],
+                    )?;
+                }
+                for entry in &message.0.limits {
+                    tx.execute(
+                        "REPLACE INTO rate_limits (id, value, expires) VALUES (?1, ?2, ?3)",
+                        params![&entry.id, &entry.value, &(entry.expires_secs() as i64)],
+                    )?;
+                }
+                tx.commit()?;
+                Ok(())
+            })
+            .await
         });
     }
 }
 
 impl StoreSender for Addr<RusqliteStore> {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_migrate() {
+        let dir = std::env::temp_dir().join(format!("portier-migrate-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("db.sqlite3");
+        let options = RusqliteOptions {
+            wal: true,
+            busy_timeout: Duration::from_secs(1),
+            backup_before_migrate: true,
+        };
+
+        // Create a version 1 database.
+        let conn = Connection::open(&path).unwrap();
+        conn.execute_batch(MIGRATIONS[0]).unwrap();
+        conn.execute_batch("PRAGMA user_version = 1").unwrap();
+        drop(conn);
+
+        let mut conn = Connection::open(&path).unwrap();
+        RusqliteStore::configure(&conn, &options).unwrap();
+        RusqliteStore::migrate(&mut conn, &path, &options).unwrap();
+        assert_eq!(
+            RusqliteStore::user_version(&conn).unwrap(),
+            MIGRATIONS.len() as u32
+        );
+        conn.execute(
+            "INSERT INTO challenges (id, data, expires) VALUES ('a', 'b', 0)",
+            [],
+        )
+        .unwrap();
+
+        // A backup of the version 1 database was made.
+        let backups: Vec<_> = std::fs::read_dir(&dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().to_string_lossy().into_owned())
+            .filter(|name| name.starts_with("db.sqlite3.v1-"))
+            .collect();
+        assert_eq!(backups.len(), 1);
+        let backup = Connection::open(dir.join(&backups[0])).unwrap();
+        assert_eq!(RusqliteStore::user_version(&backup).unwrap(), 1);
+
+        drop(conn);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,819 @@
+//! Conformance tests run against every store implementation.
+
+use crate::agents::*;
+use crate::bridges::{email::EmailBridgeData, BridgeData};
+use crate::config::{LimitConfig, LimitInput, LimitRules};
+use crate::crypto::{random_zbase32, SigningAlgorithm};
+use crate::email_address::EmailAddress;
+use crate::utils::{
+    agent::*, unix_duration, BoxFuture, DnsOutcome, DomainValidation, SecureRandom,
+};
+use crate::web::{ResponseMode, ReturnParams, Session, SessionData};
+use futures_util::future;
+use http::{header, StatusCode};
+use hyper::{
+    service::{make_service_fn, service_fn},
+    Body, Request, Response, Server,
+};
+use std::collections::HashMap;
+use std::convert::Infallible;
+use std::future::Future;
+use std::path::PathBuf;
+use std::pin::Pin;
+use std::sync::{
+    atomic::{AtomicUsize, Ordering},
+    Arc, Mutex,
+};
+use std::time::Duration;
+use tokio::time::sleep;
+use url::Url;
+
+/// Session and cache TTL used by stores under test.
+const TTL: Duration = Duration::from_secs(2);
+
+/// Time to wait for entries to expire. Some stores have a resolution of one second.
+const EXPIRE_WAIT: Duration = Duration::from_secs(4);
+
+/// Creates a store under test with the given rate limits.
+type MakeStore = Box<dyn Fn(LimitRules) -> BoxFuture<Arc<dyn StoreSender>> + Send + Sync>;
+
+/// Shared state for a test run.
+struct Harness {
+    make_store: MakeStore,
+    /// Unique string, so runs don't collide in stores that persist across runs.
+    run_id: String,
+    /// Whether stores created by `make_store` share a backend.
+    shared_backend: bool,
+    /// Whether the clock is paused, so expiry can be tested by advancing it.
+    paused_clock: bool,
+    /// Test HTTP server used for cache tests.
+    server_url: Url,
+    /// Number of requests made to the test HTTP server, by path.
+    server_hits: Arc<Mutex<HashMap<String, usize>>>,
+}
+
+impl Harness {
+    async fn new(make_store: MakeStore, shared_backend: bool, paused_clock: bool) -> Self {
+        let rng = SecureRandom::new().await;
+        let run_id = random_zbase32(16, &rng).await;
+
+        let server_hits = Arc::new(Mutex::new(HashMap::new()));
+        let hits = server_hits.clone();
+        let server = Server::bind(&([127, 0, 0, 1], 0).into()).serve(make_service_fn(move |_| {
+            let hits = hits.clone();
+            async move {
+                Ok::<_, Infallible>(service_fn(move |req: Request<Body>| {
+                    let count = {
+                        let mut hits = hits.lock().unwrap();
+                        let count = hits.entry(req.uri().path().to_owned()).or_insert(0);
+                        *count += 1;
+                        *count
+                    };
+                    async move { Ok::<_, Infallible>(serve_cache_test(&req, count)) }
+                }))
+            }
+        }));
+        let server_url = format!("http://{}/{}/", server.local_addr(), run_id)
+            .parse()
+            .unwrap();
+        tokio::spawn(server);
+
+        Harness {
+            make_store,
+            run_id,
+            shared_backend,
+            paused_clock,
+            server_url,
+            server_hits,
+        }
+    }
+
+    /// Fetch a test server path through the store cache.
+    async fn fetch_cached(&self, store: &Arc<dyn StoreSender>, path: &str) -> String {
+        store
+            .send(FetchUrlCached {
+                url: self.server_url.join(path).unwrap(),
+                purpose: FetchPurpose::Webfinger,
+            })
+            .await
+            .unwrap()
+    }
+
+    /// Number of requests made to a test server path.
+    fn server_hits(&self, path: &str) -> usize {
+        let path = self.server_url.join(path).unwrap();
+        let hits = self.server_hits.lock().unwrap();
+        hits.get(path.path()).copied().unwrap_or(0)
+    }
+
+    async fn store(&self, limits: &[&str]) -> Arc<dyn StoreSender> {
+        (self.make_store)(limit_rules(limits)).await
+    }
+
+    fn limit_input(&self, user: &str) -> LimitInput {
+        LimitInput {
+            email_addr: format!("{}-{}@example.com", user, self.run_id)
+                .parse()
+                .unwrap(),
+            origin: "https://rp.example.com".to_owned(),
+            ip: [127, 0, 0, 1].into(),
+        }
+    }
+
+    /// Wait for entries to expire, by advancing the clock if it is paused.
+    async fn wait_for_expiry(&self) {
+        if self.paused_clock {
+            tokio::time::advance(EXPIRE_WAIT).await;
+        } else {
+            sleep(EXPIRE_WAIT).await;
+        }
+    }
+
+    /// Run all tests.
+    async fn run(self) {
+        // Tests that wait for expiry run concurrently.
+        let store = self.store(&[]).await;
+        tokio::join!(
+            self.run_expiry_checks(&store),
+            self.run_cache_checks(&store)
+        );
+        self.check_keys(&store).await;
+        self.check_export_import().await;
+    }
+
+    /// Run tests that use `wait_for_expiry`.
+    async fn run_expiry_checks(&self, store: &Arc<dyn StoreSender>) {
+        let checks: Vec<Pin<Box<dyn Future<Output = ()> + '_>>> = vec![
+            Box::pin(self.check_sessions(store)),
+            Box::pin(self.check_challenges(store)),
+            Box::pin(self.check_domain_validations(store)),
+            Box::pin(self.check_fixed_window()),
+            Box::pin(self.check_fixed_window_expiry()),
+            Box::pin(self.check_sliding_window()),
+            Box::pin(self.check_token_bucket()),
+        ];
+        if self.paused_clock {
+            // Advancing the clock affects all tests, so run them one by one. This takes no time.
+            for check in checks {
+                check.await;
+            }
+        } else {
+            future::join_all(checks).await;
+        }
+    }
+
+    /// Run cache tests, concurrently. HTTP caching uses the system clock, so these always sleep.
+    async fn run_cache_checks(&self, store: &Arc<dyn StoreSender>) {
+        tokio::join!(
+            self.check_cache(store),
+            self.check_cache_revalidate(store),
+            self.check_cache_stale_while_revalidate(store),
+            self.check_cache_stale_if_error(store),
+        );
+    }
+
+    async fn check_sessions(&self, store: &Arc<dyn StoreSender>) {
+        let session_id = format!("session-{}", self.run_id);
+        let get = || {
+            store.send(GetSession {
+                session_id: session_id.clone(),
+            })
+        };
+        assert!(get().await.unwrap().is_none());
+
+        store
+            .send(SaveSession {
+                session_id: session_id.clone(),
+                data: test_session(),
+            })
+            .await
+            .unwrap();
+        let session = get().await.unwrap().expect("session not found");
+        assert_eq!(session.data.nonce, "test-nonce");
+
+        store
+            .send(DeleteSession {
+                session_id: session_id.clone(),
+            })
+            .await
+            .unwrap();
+        assert!(get().await.unwrap().is_none());
+
+        store
+            .send(SaveSession {
+                session_id: session_id.clone(),
+                data: test_session(),
+            })
+            .await
+            .unwrap();
+        self.wait_for_expiry().await;
+        assert!(get().await.unwrap().is_none(), "session did not expire");
+    }
+
+    async fn check_challenges(&self, store: &Arc<dyn StoreSender>) {
+        let save = |challenge_id: String| {
+            store.send(SaveChallenge {
+                challenge_id,
+                data: "test-data".to_owned(),
+            })
+        };
+        let take = |challenge_id: String| store.send(TakeChallenge { challenge_id });
+
+        let challenge_id = format!("challenge-{}", self.run_id);
+        save(challenge_id.clone()).await.unwrap();
+        assert_eq!(
+            take(challenge_id.clone()).await.unwrap().as_deref(),
+            Some("test-data")
+        );
+        assert!(take(challenge_id).await.unwrap().is_none());
+
+        let challenge_id = format!("challenge-expire-{}", self.run_id);
+        save(challenge_id.clone()).await.unwrap();
+        self.wait_for_expiry().await;
+        assert!(
+            take(challenge_id).await.unwrap().is_none(),
+            "challenge did not expire"
+        );
+    }
+
+    async fn check_domain_validations(&self, store: &Arc<dyn StoreSender>) {
+        let key = format!("test:{}.example.com.", self.run_id);
+        let get = || store.send(GetDomainValidation { key: key.clone() });
+        assert!(get().await.unwrap().is_none());
+
+        let validation = DomainValidation {
+            outcome: DnsOutcome::NoPublicIps,
+            disposable_mx: true,
+        };
+        store
+            .send(SaveDomainValidation {
+                key: key.clone(),
+                validation,
+                ttl: TTL,
+            })
+            .await
+            .unwrap();
+        assert_eq!(get().await.unwrap(), Some(validation));
+
+        self.wait_for_expiry().await;
+        assert!(
+            get().await.unwrap().is_none(),
+            "domain validation did not expire"
+        );
+    }
+
+    async fn check_cache(&self, store: &Arc<dyn StoreSender>) {
+        assert_eq!(self.fetch_cached(store, "plain").await, "1");
+        assert_eq!(self.fetch_cached(store, "plain").await, "1");
+        assert_eq!(self.server_hits("plain"), 1);
+
+        sleep(EXPIRE_WAIT).await;
+        assert_eq!(
+            self.fetch_cached(store, "plain").await,
+            "2",
+            "cache entry did not expire"
+        );
+        assert_eq!(self.server_hits("plain"), 2);
+    }
+
+    async fn check_cache_revalidate(&self, store: &Arc<dyn StoreSender>) {
+        assert_eq!(self.fetch_cached(store, "etag").await, "1");
+        sleep(EXPIRE_WAIT).await;
+        // The server responds `304 Not Modified`, so the cached body is kept.
+        assert_eq!(self.fetch_cached(store, "etag").await, "1");
+        assert_eq!(self.server_hits("etag"), 2);
+    }
+
+    async fn check_cache_stale_while_revalidate(&self, store: &Arc<dyn StoreSender>) {
+        assert_eq!(self.fetch_cached(store, "stale").await, "1");
+        sleep(EXPIRE_WAIT).await;
+        assert_eq!(
+            self.fetch_cached(store, "stale").await,
+            "1",
+            "stale entry was not used"
+        );
+        for _ in 0..50 {
+            if self.fetch_cached(store, "stale").await == "2" {
+                assert_eq!(self.server_hits("stale"), 2);
+                return;
+            }
+            sleep(Duration::from_millis(100)).await;
+        }
+        panic!("stale entry was not revalidated in the background");
+    }
+
+    async fn check_cache_stale_if_error(&self, store: &Arc<dyn StoreSender>) {
+        assert_eq!(self.fetch_cached(store, "error").await, "1");
+        sleep(EXPIRE_WAIT).await;
+        assert_eq!(
+            self.fetch_cached(store, "error").await,
+            "1",
+            "stale entry was not used after an error"
+        );
+        assert_eq!(self.server_hits("error"), 2);
+    }
+
+    async fn check_fixed_window(&self) {
+        let store = self.store(&["email:decr_complete:2/1h"]).await;
+        let input = self.limit_input("fixed");
+        let incr = || {
+            store.send(IncrAndTestLimits {
+                input: input.clone(),
+            })
+        };
+
+        assert!(incr().await.unwrap().is_none());
+        assert!(incr().await.unwrap().is_none());
+        let exceeded = incr().await.unwrap().expect("limit not exceeded");
+        assert_eq!(exceeded.max_count, 2);
+        assert!(exceeded.reset > Duration::from_secs(0));
+        assert!(exceeded.reset <= Duration::from_secs(3600));
+
+        // Completed requests are not counted.
+        store
+            .send(DecrLimits {
+                input: input.clone(),
+            })
+            .await
+            .unwrap();
+        store
+            .send(DecrLimits {
+                input: input.clone(),
+            })
+            .await
+            .unwrap();
+        assert!(incr().await.unwrap().is_none());
+        assert!(incr().await.unwrap().is_some());
+
+        // Other users are not affected.
+        let other = self.limit_input("fixed-other");
+        assert!(store
+            .send(IncrAndTestLimits { input: other })
+            .await
+            .unwrap()
+            .is_none());
+    }
+
+    async fn check_fixed_window_expiry(&self) {
+        let store = self.store(&["email:1/2s"]).await;
+        let input = self.limit_input("fixed-expiry");
+        let incr = || {
+            store.send(IncrAndTestLimits {
+                input: input.clone(),
+            })
+        };
+
+        assert!(incr().await.unwrap().is_none());
+        assert!(incr().await.unwrap().is_some());
+        // Without `decr_complete`, this has no effect.
+        store
+            .send(DecrLimits {
+                input: input.clone(),
+            })
+            .await
+            .unwrap();
+        assert!(incr().await.unwrap().is_some());
+
+        self.wait_for_expiry().await;
+        assert!(incr().await.unwrap().is_none(), "window did not expire");
+    }
+
+    async fn check_sliding_window(&self) {
+        let store = self.store(&["email:sliding:decr_complete:2/1h"]).await;
+        let input = self.limit_input("sliding");
+        let incr = || {
+            store.send(IncrAndTestLimits {
+                input: input.clone(),
+            })
+        };
+
+        assert!(incr().await.unwrap().is_none());
+        assert!(incr().await.unwrap().is_none());
+        let exceeded = incr().await.unwrap().expect("limit not exceeded");
+        assert_eq!(exceeded.max_count, 2);
+        assert!(exceeded.reset > Duration::from_secs(0));
+
+        store
+            .send(DecrLimits {
+                input: input.clone(),
+            })
+            .await
+            .unwrap();
+        store
+            .send(DecrLimits {
+                input: input.clone(),
+            })
+            .await
+            .unwrap();
+        assert!(incr().await.unwrap().is_none());
+    }
+
+    async fn check_token_bucket(&self) {
+        let store = self.store(&["email:bucket:decr_complete:2/1h"]).await;
+        let input = self.limit_input("bucket");
+        let incr = || {
+            store.send(IncrAndTestLimits {
+                input: input.clone(),
+            })
+        };
+
+        assert!(incr().await.unwrap().is_none());
+        assert!(incr().await.unwrap().is_none());
+        let exceeded = incr().await.unwrap().expect("limit not exceeded");
+        assert_eq!(exceeded.max_count, 2);
+        // A token is added every 30 minutes.
+        assert!(exceeded.reset > Duration::from_secs(0));
+        assert!(exceeded.reset <= Duration::from_secs(1800));
+
+        // Returning a token allows one more request.
+        store
+            .send(DecrLimits {
+                input: input.clone(),
+            })
+            .await
+            .unwrap();
+        assert!(incr().await.unwrap().is_none());
+        assert!(incr().await.unwrap().is_some());
+    }
+
+    async fn check_export_import(&self) {
+        const LIMITS: &[&str] = &["email:2/1h", "email:bucket:3/1h"];
+        let store = self.store(LIMITS).await;
+
+        // Export from the store under test into a memory store.
+        let session_id = format!("export-{}", self.run_id);
+        store
+            .send(SaveSession {
+                session_id: session_id.clone(),
+                data: test_session(),
+            })
+            .await
+            .unwrap();
+        let input = self.limit_input("export");
+        for _ in 0..2 {
+            assert!(store
+                .send(IncrAndTestLimits {
+                    input: input.clone(),
+                })
+                .await
+                .unwrap()
+                .is_none());
+        }
+
+        let mut data = store
+            .send(ExportData {
+                sessions: true,
+                limits: true,
+            })
+            .await
+            .unwrap();
+        // Keys were created in `check_keys`.
+        if self.shared_backend {
+            assert!(data
+                .key_sets
+                .iter()
+                .any(|key_set| key_set.signing_alg == SigningAlgorithm::EdDsa
+                    && key_set.current.is_some()));
+        }
+        data.sessions.retain(|entry| entry.id == session_id);
+        assert_eq!(data.sessions.len(), 1);
+        data.limits
+            .retain(|entry| entry.id.ends_with(input.email_addr.as_str()));
+        assert_eq!(data.limits.len(), 2);
+
+        let snapshot_path =
+            std::env::temp_dir().join(format!("portier-import-{}.json", self.run_id));
+        let options = MemoryOptions {
+            max_cache_entries: 0,
+            max_limit_entries: 0,
+            snapshot_path: Some(snapshot_path.clone()),
+            snapshot_interval: Duration::from_secs(300),
+            snapshot_sessions: false,
+        };
+        let fetcher = spawn_agent(FetchAgent::new(FetchOptions::default())).await;
+        let spawn_memory = || async {
+            let memory = MemoryStore::new(
+                options.clone(),
+                TTL,
+                test_cache_policy(TTL),
+                limit_rules(LIMITS),
+                fetcher.clone(),
+            )
+            .unwrap();
+            spawn_agent(memory).await
+        };
+        spawn_memory().await.send(ImportData(data)).await.unwrap();
+        // Imported sessions and limits are in the snapshot, even without `snapshot_sessions`.
+        let memory = spawn_memory().await;
+        assert!(memory
+            .send(GetSession { session_id })
+            .await
+            .unwrap()
+            .is_some());
+        // The fixed window counter was copied.
+        let exceeded = memory
+            .send(IncrAndTestLimits { input })
+            .await
+            .unwrap()
+            .expect("limits were not imported");
+        assert_eq!(exceeded.max_count, 2);
+        std::fs::remove_file(&snapshot_path).unwrap();
+
+        // Import into the store under test.
+        let session_id = format!("import-{}", self.run_id);
+        let input = self.limit_input("import");
+        let rules = limit_rules(LIMITS);
+        let expires = unix_duration() + Duration::from_secs(3600);
+        let data = StoreData {
+            key_sets: vec![],
+            sessions: vec![StoreEntry {
+                id: session_id.clone(),
+                value: test_session(),
+                expires,
+            }],
+            limits: vec![
+                StoreEntry {
+                    id: input.build_key(&rules.configs[0], "", "|"),
+                    value: 1,
+                    expires,
+                },
+                StoreEntry {
+                    id: input.build_key(&rules.configs[1], "", "|"),
+                    value: expires.as_micros() as i64,
+                    expires,
+                },
+            ],
+        };
+        store.send(ImportData(data)).await.unwrap();
+        assert!(store
+            .send(GetSession { session_id })
+            .await
+            .unwrap()
+            .is_some());
+        // The token bucket is empty.
+        let exceeded = store
+            .send(IncrAndTestLimits { input })
+            .await
+            .unwrap()
+            .expect("limits were not imported");
+        assert_eq!(exceeded.max_count, 3);
+    }
+
+    async fn check_keys(&self, store: &Arc<dyn StoreSender>) {
+        let rng = SecureRandom::new().await;
+        let spawn_key_manager = |store: Arc<dyn StoreSender>| {
+            spawn_agent(RotatingKeys::new(
+                store,
+                Duration::from_secs(86_400),
+                &[SigningAlgorithm::EdDsa],
+                vec![],
+                rng.clone(),
+            ))
+        };
+
+        let key_manager = spawn_key_manager(store.clone()).await;
+        let jwks = wait_for_jwks(&key_manager).await;
+        assert_eq!(jwks.len(), 2);
+
+        // Keys are not yet expired, so this should not change anything.
+        store.send(RotateKeysLocked(SigningAlgorithm::EdDsa)).await;
+        assert_eq!(key_manager.send(GetPublicJwks).await, jwks);
+
+        // Another store on the same backend sees the same keys.
+        if self.shared_backend {
+            let key_manager = spawn_key_manager(self.store(&[]).await).await;
+            assert_eq!(wait_for_jwks(&key_manager).await, jwks);
+        }
+    }
+}
+
+/// Test server response for cache tests, selected by the last path segment.
+fn serve_cache_test(req: &Request<Body>, count: usize) -> Response<Body> {
+    let mut res = Response::new(Body::from(count.to_string()));
+    let headers = res.headers_mut();
+    match req.uri().path().rsplit('/').next() {
+        Some("etag") => {
+            let etag = header::HeaderValue::from_static("\"v1\"");
+            if req.headers().get(header::IF_NONE_MATCH) == Some(&etag) {
+                *res.status_mut() = StatusCode::NOT_MODIFIED;
+                *res.body_mut() = Body::empty();
+            } else {
+                let value = header::HeaderValue::from_static("stale-if-error=60");
+                headers.insert(header::CACHE_CONTROL, value);
+                headers.insert(header::ETAG, etag);
+            }
+        }
+        Some("stale") => {
+            let value = header::HeaderValue::from_static("stale-while-revalidate=60");
+            headers.insert(header::CACHE_CONTROL, value);
+        }
+        Some("error") if count > 1 => *res.status_mut() = StatusCode::INTERNAL_SERVER_ERROR,
+        Some("error") => {
+            let value = header::HeaderValue::from_static("stale-if-error=60");
+            headers.insert(header::CACHE_CONTROL, value);
+        }
+        _ => {}
+    }
+    res
+}
+
+/// Cache policy used by stores under test, without stale periods.
+pub(super) fn test_cache_policy(ttl: Duration) -> CachePolicy {
+    CachePolicy {
+        ttl,
+        stale_while_revalidate: Duration::from_secs(0),
+        stale_if_error: Duration::from_secs(0),
+    }
+}
+
+fn limit_rules(limits: &[&str]) -> LimitRules {
+    let limits: Vec<LimitConfig> = limits.iter().map(|limit| limit.parse().unwrap()).collect();
+    LimitRules::new(limits, vec![], vec![])
+}
+
+/// Wait for a key manager to load keys.
+pub(super) async fn wait_for_jwks(key_manager: &Addr<RotatingKeys>) -> Vec<serde_json::Value> {
+    for _ in 0..100 {
+        let jwks = key_manager.send(GetPublicJwks).await;
+        if !jwks.is_empty() {
+            return jwks;
+        }
+        sleep(Duration::from_millis(50)).await;
+    }
+    panic!("key manager did not load keys");
+}
+
+pub(super) fn test_session() -> Session {
+    let email_addr: EmailAddress = "user@example.com".parse().unwrap();
+    Session {
+        data: SessionData {
+            original_ip: [127, 0, 0, 1].into(),
+            return_params: ReturnParams {
+                redirect_uri: "https://rp.example.com/".parse().unwrap(),
+                response_mode: ResponseMode::FormPost,
+                response_errors: true,
+                state: "test-state".to_owned(),
+            },
+            email: email_addr.as_str().to_owned(),
+            email_addr,
+            nonce: "test-nonce".to_owned(),
+            signing_alg: SigningAlgorithm::EdDsa,
+        },
+        bridge_data: BridgeData::Email(EmailBridgeData {
+            code: "test-code".to_owned(),
+        }),
+    }
+}
+
+/// Creates memory stores, with snapshots in the given directory, if any.
+async fn make_memory_store(snapshot_dir: Option<PathBuf>) -> MakeStore {
+    let fetcher = spawn_agent(FetchAgent::new(FetchOptions::default())).await;
+    let counter = AtomicUsize::new(0);
+    Box::new(move |limit_rules| {
+        let fetcher = fetcher.clone();
+        let snapshot_path = snapshot_dir
+            .as_ref()
+            .map(|dir| dir.join(format!("{}.json", counter.fetch_add(1, Ordering::SeqCst))));
+        Box::pin(async move {
+            let options = MemoryOptions {
+                max_cache_entries: 100,
+                max_limit_entries: 100,
+                snapshot_path,
+                snapshot_interval: Duration::from_secs(300),
+                snapshot_sessions: false,
+            };
+            let store =
+                MemoryStore::new(options, TTL, test_cache_policy(TTL), limit_rules, fetcher)
+                    .unwrap();
+            Arc::new(spawn_agent(store).await) as Arc<dyn StoreSender>
+        })
+    })
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_memory() {
+    let dir = std::env::temp_dir().join(format!("portier-memory-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    // Snapshots are needed to import data.
+    let harness = Harness::new(make_memory_store(Some(dir.clone())).await, false, false).await;
+    let store = harness.store(&[]).await;
+    harness.run_cache_checks(&store).await;
+    harness.check_keys(&store).await;
+    harness.check_export_import().await;
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+/// The memory store uses the Tokio clock, so expiry is tested with a paused clock.
+#[tokio::test]
+async fn test_memory_expiry() {
+    tokio::time::pause();
+    let harness = Harness::new(make_memory_store(None).await, false, true).await;
+    let store = harness.store(&[]).await;
+    harness.run_expiry_checks(&store).await;
+}
+
+#[cfg(feature = "rusqlite")]
+#[tokio::test(flavor = "multi_thread")]
+async fn test_rusqlite() {
+    let dir = std::env::temp_dir().join(format!("portier-store-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let fetcher = spawn_agent(FetchAgent::new(FetchOptions::default())).await;
+    let path = dir.join("db.sqlite3");
+    let make_store: MakeStore = Box::new(move |limit_rules| {
+        let fetcher = fetcher.clone();
+        let path = path.clone();
+        Box::pin(async move {
+            let options = RusqliteOptions {
+                wal: true,
+                busy_timeout: Duration::from_secs(5),
+                backup_before_migrate: false,
+            };
+            let store = RusqliteStore::new(
+                path,
+                options,
+                TTL,
+                test_cache_policy(TTL),
+                limit_rules,
+                fetcher,
+            )
+            .await
+            .unwrap();
+            Arc::new(spawn_agent(store).await) as Arc<dyn StoreSender>
+        })
+    });
+    Harness::new(make_store, true, false).await.run().await;
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+/// Runs against `BROKER_TEST_REDIS_URL`, or a local server, if available.
+#[cfg(feature = "redis")]
+#[tokio::test(flavor = "multi_thread")]
+async fn test_redis() {
+    let url =
+        std::env::var("BROKER_TEST_REDIS_URL").unwrap_or_else(|_| "redis://127.0.0.1/".to_owned());
+    let rng = SecureRandom::new().await;
+    let key_prefix = format!("portier-test-{}:", random_zbase32(16, &rng).await);
+    let fetcher = spawn_agent(FetchAgent::new(FetchOptions::default())).await;
+    if let Err(err) = RedisStore::new(
+        url.clone(),
+        None,
+        key_prefix.clone(),
+        TTL,
+        test_cache_policy(TTL),
+        LimitRules::default(),
+        fetcher.clone(),
+        rng.clone(),
+    )
+    .await
+    {
+        eprintln!("Skipping Redis store tests: {}", err);
+        return;
+    }
+    let make_store: MakeStore = Box::new(move |limit_rules| {
+        let (url, key_prefix) = (url.clone(), key_prefix.clone());
+        let (fetcher, rng) = (fetcher.clone(), rng.clone());
+        Box::pin(async move {
+            let store = RedisStore::new(
+                url,
+                None,
+                key_prefix,
+                TTL,
+                test_cache_policy(TTL),
+                limit_rules,
+                fetcher,
+                rng,
+            )
+            .await
+            .unwrap();
+            Arc::new(spawn_agent(store).await) as Arc<dyn StoreSender>
+        })
+    });
+    Harness::new(make_store, true, false).await.run().await;
+}
+
+/// Runs against `BROKER_TEST_POSTGRES_URL`, if set.
+#[cfg(feature = "postgres")]
+#[tokio::test(flavor = "multi_thread")]
+async fn test_postgres() {
+    let url = match std::env::var("BROKER_TEST_POSTGRES_URL") {
+        Ok(url) => url,
+        Err(_) => {
+            eprintln!("Skipping PostgreSQL store tests: BROKER_TEST_POSTGRES_URL not set");
+            return;
+        }
+    };
+    let rng = SecureRandom::new().await;
+    let fetcher = spawn_agent(FetchAgent::new(FetchOptions::default())).await;
+    let make_store: MakeStore = Box::new(move |limit_rules| {
+        let url = url.clone();
+        let (fetcher, rng) = (fetcher.clone(), rng.clone());
+        Box::pin(async move {
+            let store =
+                PostgresStore::new(url, TTL, test_cache_policy(TTL), limit_rules, fetcher, rng)
+                    .await
+                    .unwrap();
+            Arc::new(spawn_agent(store).await) as Arc<dyn StoreSender>
+        })
+    });
+    Harness::new(make_store, true, false).await.run().await;
+}
@@ -1,4 +1,4 @@
-use crate::agents::FetchUrlCached;
+use crate::agents::{FetchPurpose, FetchUrlCached};
 use crate::bridges::{complete_auth, BridgeData};
 use crate::crypto::{self, SigningAlgorithm};
 use crate::email_address::EmailAddress;
@@ -312,7 +312,7 @@ async fn fetch_config(
         .store
         .send(FetchUrlCached {
             url: config_url,
-            metric: &*metrics::AUTH_OIDC_FETCH_CONFIG_DURATION,
+            purpose: FetchPurpose::OidcConfig,
         })
         .await
         .map_err(|e| {
@@ -350,7 +350,7 @@ async fn fetch_config(
         .store
         .send(FetchUrlCached {
             url: provider_config.jwks_uri.clone(),
-            metric: &*metrics::AUTH_OIDC_FETCH_JWKS_DURATION,
+            purpose: FetchPurpose::OidcKeys,
         })
         .await
         .map_err(|e| {
@@ -1,6 +1,7 @@
-use super::{ConfigBuilder, LegacyLimitPerEmail, LimitConfig};
+use super::{ConfigBuilder, LegacyLimitPerEmail, LimitConfig, OriginList};
 use crate::config::StringList;
 use crate::crypto::SigningAlgorithm;
+use ipnetwork::IpNetwork;
 use serde::Deserialize;
 use std::borrow::ToOwned;
 use std::path::PathBuf;
@@ -16,13 +17,39 @@ pub struct EnvConfig {
     listen_port: Option<u16>,
     public_url: Option<String>,
     data_dir: Option<String>,
+    http_proxy: Option<String>,
+    no_proxy: Option<Vec<String>>,
+    fetch_public_ips_only: Option<bool>,
+    fetch_allowed_ips: Option<Vec<IpNetwork>>,
+    fetch_connect_timeout: Option<u64>,
+    fetch_timeout: Option<u64>,
+    fetch_max_redirects: Option<usize>,
+    fetch_max_size_webfinger: Option<usize>,
+    fetch_max_size_oidc_config: Option<usize>,
+    fetch_max_size_oidc_keys: Option<usize>,
+    fetch_max_size_mail_api: Option<usize>,
+    fetch_ca_bundle: Option<PathBuf>,
+    fetch_client_identity: Option<PathBuf>,
+    fetch_client_identity_password: Option<String>,
 
     allowed_origins: Option<StringList>,
     #[serde(default)]
+    allowed_addresses: StringList,
+    #[serde(default)]
+    blocked_addresses: StringList,
+    #[serde(default)]
     allowed_domains: StringList,
     #[serde(default)]
     blocked_domains: StringList,
+    #[serde(default)]
+    disposable_domains: StringList,
+    #[serde(default)]
+    disposable_mail_servers: StringList,
+    block_disposable: Option<bool>,
+    public_suffix_list: Option<PathBuf>,
     verify_with_resolver: Option<String>,
+    verify_dnssec: Option<bool>,
+    verify_cache_ttl: Option<u64>,
     verify_public_ip: Option<bool>,
     allowed_domains_only: Option<bool>,
 
@@ -32,15 +59,29 @@ pub struct EnvConfig {
     token_ttl: Option<u64>,
     session_ttl: Option<u64>,
     cache_ttl: Option<u64>,
+    cache_stale_while_revalidate: Option<u64>,
+    cache_stale_if_error: Option<u64>,
 
     keyfiles: Option<Vec<PathBuf>>,
     keytext: Option<String>,
     signing_algs: Option<Vec<SigningAlgorithm>>,
     generate_rsa_command: Option<String>,
 
+    postgres_url: Option<String>,
     redis_url: Option<String>,
+    redis_sentinel_master: Option<String>,
+    redis_sentinels: Option<Vec<String>>,
+    redis_key_prefix: Option<String>,
     sqlite_db: Option<PathBuf>,
+    sqlite_wal: Option<bool>,
+    sqlite_busy_timeout: Option<u64>,
+    sqlite_backup_before_migrate: Option<bool>,
     memory_storage: Option<bool>,
+    memory_max_cache_entries: Option<usize>,
+    memory_max_limit_entries: Option<usize>,
+    memory_snapshot_path: Option<PathBuf>,
+    memory_snapshot_interval: Option<u64>,
+    memory_snapshot_sessions: Option<bool>,
 
     from_name: Option<String>,
     from_address: Option<String>,
@@ -60,6 +101,7 @@ pub struct EnvConfig {
 
     limits: Option<Vec<LimitConfig>>,
     limit_per_email: Option<LegacyLimitPerEmail>,
+    challenge_difficulty: Option<u8>,
 
     google_client_id: Option<String>,
 
@@ -110,19 +152,99 @@ impl EnvConfig {
         if let Some(val) = parsed.data_dir {
             builder.data_dir = val;
         }
+        if let Some(val) = parsed.http_proxy {
+            builder.http_proxy = Some(val);
+        }
+        if let Some(val) = parsed.no_proxy {
+            builder.no_proxy = val;
+        }
+        if let Some(val) = parsed.fetch_public_ips_only {
+            builder.fetch_public_ips_only = val;
+        }
+        if let Some(val) = parsed.fetch_allowed_ips {
+            builder.fetch_allowed_ips = val;
+        }
+        if let Some(val) = parsed.fetch_connect_timeout {
+            builder.fetch_connect_timeout = Duration::from_secs(val);
+        }
+        if let Some(val) = parsed.fetch_timeout {
+            builder.fetch_timeout = Duration::from_secs(val);
+        }
+        if let Some(val) = parsed.fetch_max_redirects {
+            builder.fetch_max_redirects = val;
+        }
+        if let Some(val) = parsed.fetch_max_size_webfinger {
+            builder.fetch_max_sizes.webfinger = val;
+        }
+        if let Some(val) = parsed.fetch_max_size_oidc_config {
+            builder.fetch_max_sizes.oidc_config = val;
+        }
+        if let Some(val) = parsed.fetch_max_size_oidc_keys {
+            builder.fetch_max_sizes.oidc_keys = val;
+        }
+        if let Some(val) = parsed.fetch_max_size_mail_api {
+            builder.fetch_max_sizes.mail_api = val;
+        }
+        if let Some(val) = parsed.fetch_ca_bundle {
+            builder.fetch_tls.ca_bundle = Some(val);
+        }
+        if let Some(val) = parsed.fetch_client_identity {
+            builder.fetch_tls.client_identity = Some(val);
+        }
+        if let Some(val) = parsed.fetch_client_identity_password {
+            builder.fetch_tls.client_identity_password = val;
+        }
 
         if let Some(val) = parsed.allowed_origins {
-            let list = builder.allowed_origins.get_or_insert(vec![]);
+            let list = builder
+                .allowed_origins
+                .get_or_insert_with(OriginList::default);
             for (source, res) in val.iter_values() {
-                match res {
-                    Ok(data) => list.push(data.into_owned()),
+                let data = match res {
+                    Ok(data) => data,
                     Err(err) => panic!(
                         "IO error in BROKER_ALLOWED_ORIGINS entry {}: {}",
                         source, err
                     ),
+                };
+                if let Err(err) = list.add(data.as_ref()) {
+                    panic!(
+                        "Invalid BROKER_ALLOWED_ORIGINS entry {}: '{}': {}",
+                        source, data, err
+                    );
                 }
             }
         }
+        for (source, res) in parsed.allowed_addresses.iter_values() {
+            let data = match res {
+                Ok(data) => data,
+                Err(err) => panic!(
+                    "IO error in BROKER_ALLOWED_ADDRESSES entry {}: {}",
+                    source, err
+                ),
+            };
+            if let Err(err) = builder.address_validator.add_allowed_address(data.as_ref()) {
+                panic!(
+                    "Invalid BROKER_ALLOWED_ADDRESSES entry {}: '{}': {}",
+                    source, data, err
+                );
+            }
+        }
+        for (source, res) in parsed.blocked_addresses.iter_values() {
+            let data = match res {
+                Ok(data) => data,
+                Err(err) => panic!(
+                    "IO error in BROKER_BLOCKED_ADDRESSES entry {}: {}",
+                    source, err
+                ),
+            };
+            if let Err(err) = builder.address_validator.add_blocked_address(data.as_ref()) {
+                panic!(
+                    "Invalid BROKER_BLOCKED_ADDRESSES entry {}: '{}': {}",
+                    source, data, err
+                );
+            }
+        }
         for (source, res) in parsed.allowed_domains.iter_values() {
             let data = match res {
                 Ok(data) => data,
@@ -153,12 +275,65 @@ impl EnvConfig {
                 );
             }
         }
+        for (source, res) in parsed.disposable_domains.iter_values() {
+            let data = match res {
+                Ok(data) => data,
+                Err(err) => panic!(
+                    "IO error in BROKER_DISPOSABLE_DOMAINS entry {}: {}",
+                    source, err
+                ),
+            };
+            if let Err(err) = builder
+                .domain_validator
+                .add_disposable_domain(data.as_ref())
+            {
+                panic!(
+                    "Invalid BROKER_DISPOSABLE_DOMAINS entry {}: '{}': {}",
+                    source, data, err
+                );
+            }
+        }
+        for (source, res) in parsed.disposable_mail_servers.iter_values() {
+            let data = match res {
+                Ok(data) => data,
+                Err(err) => panic!(
+                    "IO error in BROKER_DISPOSABLE_MAIL_SERVERS entry {}: {}",
+                    source, err
+                ),
+            };
+            if let Err(err) = builder
+                .domain_validator
+                .add_disposable_mail_server(data.as_ref())
+            {
+                panic!(
+                    "Invalid BROKER_DISPOSABLE_MAIL_SERVERS entry {}: '{}': {}",
+                    source, data, err
+                );
+            }
+        }
+        if let Some(val) = parsed.block_disposable {
+            builder.domain_validator.block_disposable = val;
+        }
+        if let Some(val) = parsed.public_suffix_list {
+            if let Err(err) = builder
+                .domain_validator
+                .load_public_suffix_list(Some(val.as_path()))
+            {
+                panic!("Could not load BROKER_PUBLIC_SUFFIX_LIST: {}", err);
+            }
+        }
         if let Some(val) = parsed.verify_with_resolver {
             builder
                 .domain_validator
                 .set_resolver(Some(val.as_str()).filter(|s| !s.is_empty()))
                 .expect("Invalid BROKER_VERIFY_WITH_RESOLVER value");
         }
+        if let Some(val) = parsed.verify_dnssec {
+            builder.domain_validator.set_verify_dnssec(val);
+        }
+        if let Some(val) = parsed.verify_cache_ttl {
+            builder.domain_validator.verify_cache_ttl = Duration::from_secs(val);
+        }
         if let Some(val) = parsed.verify_public_ip {
             builder.domain_validator.verify_public_ip = val;
         }
@@ -184,6 +359,12 @@ impl EnvConfig {
         if let Some(val) = parsed.cache_ttl {
             builder.cache_ttl = Duration::from_secs(val);
         }
+        if let Some(val) = parsed.cache_stale_while_revalidate {
+            builder.cache_stale_while_revalidate = Duration::from_secs(val);
+        }
+        if let Some(val) = parsed.cache_stale_if_error {
+            builder.cache_stale_if_error = Duration::from_secs(val);
+        }
 
         if let Some(val) = parsed.keyfiles {
             builder.keyfiles = val;
@@ -198,15 +379,51 @@ impl EnvConfig {
             builder.generate_rsa_command = val.split_whitespace().map(ToOwned::to_owned).collect();
         }
 
+        if let Some(val) = parsed.postgres_url {
+            builder.postgres_url = Some(val);
+        }
         if let Some(val) = parsed.redis_url {
             builder.redis_url = Some(val);
         }
+        if let Some(val) = parsed.redis_sentinel_master {
+            builder.redis_sentinel_master = Some(val);
+        }
+        if let Some(val) = parsed.redis_sentinels {
+            builder.redis_sentinels = val;
+        }
+        if let Some(val) = parsed.redis_key_prefix {
+            builder.redis_key_prefix = val;
+        }
         if let Some(val) = parsed.sqlite_db {
             builder.sqlite_db = Some(val);
         }
+        if let Some(val) = parsed.sqlite_wal {
+            builder.sqlite_wal = val;
+        }
+        if let Some(val) = parsed.sqlite_busy_timeout {
+            builder.sqlite_busy_timeout = Duration::from_secs(val);
+        }
+        if let Some(val) = parsed.sqlite_backup_before_migrate {
+            builder.sqlite_backup_before_migrate = val;
+        }
         if let Some(val) = parsed.memory_storage {
             builder.memory_storage = val;
         }
+        if let Some(val) = parsed.memory_max_cache_entries {
+            builder.memory_max_cache_entries = val;
+        }
+        if let Some(val) = parsed.memory_max_limit_entries {
+            builder.memory_max_limit_entries = val;
+        }
+        if let Some(val) = parsed.memory_snapshot_path {
+            builder.memory_snapshot_path = Some(val);
+        }
+        if let Some(val) = parsed.memory_snapshot_interval {
+            builder.memory_snapshot_interval = Duration::from_secs(val);
+        }
+        if let Some(val) = parsed.memory_snapshot_sessions {
+            builder.memory_snapshot_sessions = val;
+        }
 
         if let Some(val) = parsed.from_name {
             builder.from_name = val;
@@ -253,6 +470,9 @@ impl EnvConfig {
             log::warn!("BROKER_LIMIT_PER_EMAIL is deprecated. Please use BROKER_LIMITS instead.");
             builder.limits = vec![val.0];
         }
+        if let Some(val) = parsed.challenge_difficulty {
+            builder.challenge_difficulty = val;
+        }
 
         if let Some(val) = parsed.google_client_id {
             builder.google_client_id = Some(val);
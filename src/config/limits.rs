@@ -1,6 +1,15 @@
 use crate::email_address::EmailAddress;
+use ipnetwork::{IpNetwork, Ipv4Network, Ipv6Network};
 use serde::de::{Deserialize, Deserializer, Error as DeError};
-use std::{net::IpAddr, num::ParseIntError, str::FromStr, time::Duration};
+use std::{
+    collections::HashSet,
+    convert::TryFrom,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr},
+    num::ParseIntError,
+    ops::{Add, Sub},
+    str::FromStr,
+    time::Duration,
+};
 use thiserror::Error;
 
 #[derive(Debug, Error, Eq, PartialEq)]
@@ -15,14 +24,43 @@ pub enum LimitConfigError {
     InvalidCount(ParseIntError),
     #[error("rate limit contains an invalid keyword: {0}")]
     InvalidKeyword(String),
+    #[error("rate limit keyword conflicts with another keyword: {0}")]
+    ConflictingKeyword(String),
+    #[error("rate limit keyword has an invalid prefix length: {0}")]
+    InvalidPrefix(String),
+}
+
+/// Algorithm used to count requests for a limit.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum LimitAlgorithm {
+    /// Count requests in a window that starts at the first request.
+    FixedWindow,
+    /// Count requests in a window that ends at the current request.
+    ///
+    /// This is approximated by keeping counters for fixed windows aligned to Unix epoch, and
+    /// weighing the count of the previous window by how much it overlaps the sliding window.
+    SlidingWindow,
+    /// A bucket that holds `max_count` tokens and refills at a steady rate over the window.
+    ///
+    /// The bucket is stored as its 'theoretical arrival time', the time at which it is completely
+    /// refilled. Requests that are refused do not take a token.
+    TokenBucket,
+}
+
+impl Default for LimitAlgorithm {
+    fn default() -> Self {
+        LimitAlgorithm::FixedWindow
+    }
 }
 
 /// Configuration for a type of rate limiting.
 #[derive(Clone, Debug, Default, Eq, PartialEq)]
 #[allow(clippy::struct_excessive_bools)]
 pub struct LimitConfig {
-    /// ID of the limit. Currently matches config index.
+    /// ID of the limit. Currently matches the index in `LimitRules::configs`.
     pub id: usize,
+    /// Index of the scope this limit applies to, or `None` if it is unscoped.
+    pub scope: Option<usize>,
     /// Whether to include the email address in the key.
     pub with_email_addr: bool,
     /// Whether to include the email domain in the key.
@@ -31,10 +69,18 @@ pub struct LimitConfig {
     pub with_origin: bool,
     /// Whether to include the user IP in the key.
     pub with_ip: bool,
+    /// Prefix length to mask version 4 IP addresses to, if not the full address.
+    pub ipv4_prefix: Option<u8>,
+    /// Prefix length to mask version 6 IP addresses to, if not the full address.
+    pub ipv6_prefix: Option<u8>,
     /// Whether to extend the time window on new hits.
     pub extend_window: bool,
     /// Whether to decrement the limit for completed requests.
     pub decr_complete: bool,
+    /// Whether to require a challenge instead of refusing requests.
+    pub challenge: bool,
+    /// Algorithm used to count requests.
+    pub algorithm: LimitAlgorithm,
     /// Maximum request count within the window before we refuse.
     pub max_count: usize,
     /// Timespan of the entire window, in seconds.
@@ -75,12 +121,17 @@ impl FromStr for LimitConfig {
 
         let mut config = LimitConfig {
             id: 0,
+            scope: None,
             with_email_addr: false,
             with_email_domain: false,
             with_origin: false,
             with_ip: false,
+            ipv4_prefix: None,
+            ipv6_prefix: None,
             extend_window: false,
             decr_complete: false,
+            challenge: false,
+            algorithm: LimitAlgorithm::FixedWindow,
             max_count,
             window: Duration::from_secs(window),
         };
@@ -93,19 +144,245 @@ impl FromStr for LimitConfig {
                 "origin" => config.with_origin = true,
                 "extend_window" => config.extend_window = true,
                 "decr_complete" => config.decr_complete = true,
+                "challenge" => config.challenge = true,
+                "sliding" | "bucket" => {
+                    if config.algorithm != LimitAlgorithm::FixedWindow {
+                        return Err(LimitConfigError::ConflictingKeyword(keyword.to_owned()));
+                    }
+                    config.algorithm = if keyword == "sliding" {
+                        LimitAlgorithm::SlidingWindow
+                    } else {
+                        LimitAlgorithm::TokenBucket
+                    };
+                }
                 _ => {
-                    return Err(LimitConfigError::InvalidKeyword(keyword.to_owned()));
+                    let (ip_keyword, prefix) = match keyword.find('/') {
+                        Some(idx) => (&keyword[..idx], &keyword[(idx + 1)..]),
+                        None => {
+                            return Err(LimitConfigError::InvalidKeyword(keyword.to_owned()));
+                        }
+                    };
+                    let (slot, max) = match ip_keyword {
+                        "ip" => (&mut config.ipv4_prefix, 32),
+                        "ip6" => (&mut config.ipv6_prefix, 128),
+                        _ => {
+                            return Err(LimitConfigError::InvalidKeyword(keyword.to_owned()));
+                        }
+                    };
+                    match prefix.parse() {
+                        Ok(prefix) if prefix <= max => *slot = Some(prefix),
+                        _ => return Err(LimitConfigError::InvalidPrefix(keyword.to_owned())),
+                    }
+                    config.with_ip = true;
                 }
             }
         }
 
+        if config.extend_window && config.algorithm != LimitAlgorithm::FixedWindow {
+            return Err(LimitConfigError::ConflictingKeyword(
+                "extend_window".to_owned(),
+            ));
+        }
+
         Ok(config)
     }
 }
 
+/// Unwrap IPv4-mapped addresses, as seen on dual-stack sockets.
+fn unmap_ip(ip: IpAddr) -> IpAddr {
+    match ip {
+        IpAddr::V6(ip) => match ip.segments() {
+            [0, 0, 0, 0, 0, 0xffff, hi, lo] => {
+                IpAddr::V4(Ipv4Addr::from(u32::from(hi) << 16 | u32::from(lo)))
+            }
+            _ => IpAddr::V6(ip),
+        },
+        ip @ IpAddr::V4(_) => ip,
+    }
+}
+
+impl LimitConfig {
+    /// Format an IP address for use in a key, masked to the configured prefix length.
+    pub fn format_ip(&self, ip: IpAddr) -> String {
+        match unmap_ip(ip) {
+            IpAddr::V4(ip) => self.format_ipv4(ip),
+            IpAddr::V6(ip) => self.format_ipv6(ip),
+        }
+    }
+
+    fn format_ipv4(&self, ip: Ipv4Addr) -> String {
+        match self.ipv4_prefix {
+            Some(prefix) if prefix < 32 => {
+                let net = Ipv4Network::new(ip, prefix).expect("invalid IPv4 prefix length");
+                format!("{}/{}", net.network(), prefix)
+            }
+            _ => ip.to_string(),
+        }
+    }
+
+    fn format_ipv6(&self, ip: Ipv6Addr) -> String {
+        match self.ipv6_prefix {
+            Some(prefix) if prefix < 128 => {
+                let net = Ipv6Network::new(ip, prefix).expect("invalid IPv6 prefix length");
+                format!("{}/{}", net.network(), prefix)
+            }
+            _ => ip.to_string(),
+        }
+    }
+
+    /// Determine the position of a sliding window at the given time since Unix epoch.
+    #[allow(clippy::cast_precision_loss)]
+    pub fn sliding_window(&self, now: Duration) -> SlidingWindow {
+        let window = std::cmp::max(self.window.as_millis() as u64, 1);
+        let now = now.as_millis() as u64;
+        let index = now / window;
+        let elapsed = now % window;
+        SlidingWindow {
+            index,
+            weight: 1.0 - elapsed as f64 / window as f64,
+            ttl: Duration::from_millis(2 * window - elapsed),
+            length: Duration::from_millis(window),
+        }
+    }
+
+    /// The time a single request keeps a token out of a token bucket.
+    pub fn bucket_interval(&self) -> Duration {
+        // `Duration` can only be divided by `u32`, but this is precise enough for larger counts.
+        let max_count = u32::try_from(self.max_count).unwrap_or(u32::MAX);
+        self.window / std::cmp::max(max_count, 1)
+    }
+
+    /// Try to take a token from a token bucket.
+    ///
+    /// Takes the stored arrival time of the bucket, if any, and the current time. Returns the new
+    /// arrival time to store, or if the bucket is empty, the time until a token is available.
+    pub fn bucket_take<T>(&self, tat: Option<T>, now: T) -> Result<T, Duration>
+    where
+        T: Copy + Ord + Add<Duration, Output = T> + Sub<Output = Duration>,
+    {
+        if self.max_count == 0 {
+            return Err(self.window);
+        }
+        let tat = tat.map_or(now, |tat| std::cmp::max(tat, now)) + self.bucket_interval();
+        match (tat - now).checked_sub(self.window) {
+            Some(wait) if wait > Duration::from_secs(0) => Err(wait),
+            _ => Ok(tat),
+        }
+    }
+
+    /// Return a token to a token bucket.
+    ///
+    /// Takes the stored arrival time of the bucket and the current time. Returns the new arrival
+    /// time to store, or `None` if the bucket is now full and can be removed.
+    pub fn bucket_return<T>(&self, tat: T, now: T) -> Option<T>
+    where
+        T: Copy + Ord + Add<Duration, Output = T> + Sub<Duration, Output = T>,
+    {
+        let interval = self.bucket_interval();
+        if tat <= now + interval {
+            None
+        } else {
+            Some(tat - interval)
+        }
+    }
+}
+
+/// Position in time of a sliding window.
+pub struct SlidingWindow {
+    /// Index of the current fixed window, counting from Unix epoch.
+    pub index: u64,
+    /// Weight of the previous window count, based on overlap with the sliding window.
+    pub weight: f64,
+    /// How long to keep the counter of the current fixed window.
+    pub ttl: Duration,
+    /// Length of the window.
+    pub length: Duration,
+}
+
+impl SlidingWindow {
+    /// Combine the counts of the previous and current fixed windows.
+    #[allow(
+        clippy::cast_precision_loss,
+        clippy::cast_possible_truncation,
+        clippy::cast_sign_loss
+    )]
+    pub fn count(&self, previous: usize, current: usize) -> usize {
+        current.saturating_add((previous as f64 * self.weight) as usize)
+    }
+
+    /// Estimate how long until the combined count drops to the maximum again.
+    #[allow(clippy::cast_precision_loss)]
+    pub fn reset(&self, previous: usize, current: usize, max_count: usize) -> Duration {
+        let length = self.length.as_secs_f64();
+        let secs = if current > max_count {
+            // Wait for the current window to end, and its count to decay as the previous window.
+            length * self.weight + length * (1.0 - max_count as f64 / current as f64)
+        } else if previous > 0 {
+            // Wait for the count of the previous window to decay.
+            length * (self.weight - (max_count - current) as f64 / previous as f64)
+        } else {
+            0.0
+        };
+        Duration::from_secs_f64(secs.max(0.0))
+    }
+
+    /// Build the key of the counter for the current fixed window.
+    pub fn current_key(&self, key: &str, sep: &str) -> String {
+        format!("{}{}{}", key, sep, self.index)
+    }
+
+    /// Build the key of the counter for the previous fixed window.
+    pub fn previous_key(&self, key: &str, sep: &str) -> String {
+        format!("{}{}{}", key, sep, self.index.wrapping_sub(1))
+    }
+}
+
 serde_from_str!(LimitConfig);
 
+/// Details of a rate limit that was exceeded.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct LimitExceeded {
+    /// Maximum count of the limit.
+    pub max_count: usize,
+    /// Time window of the limit.
+    pub window: Duration,
+    /// Time until the limit allows requests again.
+    pub reset: Duration,
+    /// Whether the request may continue after solving a challenge.
+    pub challenge: bool,
+}
+
+impl LimitExceeded {
+    pub fn new(config: &LimitConfig, reset: Duration) -> Self {
+        LimitExceeded {
+            max_count: config.max_count,
+            window: config.window,
+            reset,
+            challenge: config.challenge,
+        }
+    }
+
+    /// Pick the most severe of several exceeded limits.
+    ///
+    /// Limits that refuse requests outweigh limits that require a challenge. Otherwise, the limit
+    /// that takes the longest to reset is picked.
+    pub fn most_severe(iter: impl IntoIterator<Item = Self>) -> Option<Self> {
+        iter.into_iter().max_by_key(|e| (!e.challenge, e.reset))
+    }
+
+    /// Time until the limit allows requests again, in whole seconds rounded up.
+    pub fn retry_after_secs(&self) -> u64 {
+        let secs = self.reset.as_secs();
+        if self.reset.subsec_nanos() > 0 {
+            secs + 1
+        } else {
+            std::cmp::max(secs, 1)
+        }
+    }
+}
+
 /// Input values for limit operations.
+#[derive(Clone)]
 pub struct LimitInput {
     /// The email address of the user.
     pub email_addr: EmailAddress,
@@ -124,7 +401,7 @@ impl LimitInput {
         let mut result = format!("{}{}", prefix, config.id);
         if config.with_ip {
             result.push_str(sep);
-            result.push_str(&format!("{}", self.ip));
+            result.push_str(&config.format_ip(self.ip));
         }
         if config.with_email_addr {
             result.push_str(sep);
@@ -142,6 +419,78 @@ impl LimitInput {
     }
 }
 
+/// Relying party origins and email domains that have their own set of limits.
+#[derive(Clone, Debug, Default)]
+pub struct LimitScope {
+    /// Exact origins of relying parties.
+    pub origins: HashSet<String>,
+    /// Email domains, in ASCII form.
+    pub email_domains: HashSet<String>,
+}
+
+impl LimitScope {
+    /// Whether the given input falls within this scope.
+    pub fn matches(&self, input: &LimitInput) -> bool {
+        self.origins.contains(&input.origin)
+            || self.email_domains.contains(input.email_addr.domain())
+    }
+}
+
+/// The complete set of rate limits.
+///
+/// Requests that match a scope are subject only to the limits of the first matching scope.
+/// Other requests are subject to the unscoped limits. Requests from exempt networks are never
+/// limited.
+#[derive(Clone, Debug, Default)]
+pub struct LimitRules {
+    /// All limits, scoped and unscoped.
+    pub configs: Vec<LimitConfig>,
+    /// Scopes referenced by `LimitConfig::scope`.
+    pub scopes: Vec<LimitScope>,
+    /// Networks that are exempt from all limits.
+    pub exempt: Vec<IpNetwork>,
+}
+
+impl LimitRules {
+    /// Combine unscoped limits with scoped overrides, and assign IDs.
+    ///
+    /// Unscoped limits are numbered first, so their IDs match their index in configuration.
+    pub fn new(
+        limits: Vec<LimitConfig>,
+        overrides: Vec<(LimitScope, Vec<LimitConfig>)>,
+        exempt: Vec<IpNetwork>,
+    ) -> Self {
+        let mut configs = limits;
+        let mut scopes = Vec::with_capacity(overrides.len());
+        for (scope, limits) in overrides {
+            let scope_idx = scopes.len();
+            scopes.push(scope);
+            configs.extend(limits.into_iter().map(|mut limit| {
+                limit.scope = Some(scope_idx);
+                limit
+            }));
+        }
+        for (idx, limit) in configs.iter_mut().enumerate() {
+            limit.id = idx;
+        }
+        LimitRules {
+            configs,
+            scopes,
+            exempt,
+        }
+    }
+
+    /// Select the limits that apply to the given input.
+    pub fn select<'a>(&'a self, input: &LimitInput) -> impl Iterator<Item = &'a LimitConfig> {
+        let ip = unmap_ip(input.ip);
+        let exempt = self.exempt.iter().any(|net| net.contains(ip));
+        let scope = self.scopes.iter().position(|scope| scope.matches(input));
+        self.configs
+            .iter()
+            .filter(move |config| !exempt && config.scope == scope)
+    }
+}
+
 /// Wrapper structure to deserialize the old `limit_per_email` field.
 #[derive(Clone)]
 pub struct LegacyLimitPerEmail(pub LimitConfig);
@@ -155,7 +504,10 @@ impl<'de> Deserialize<'de> for LegacyLimitPerEmail {
 
 #[cfg(test)]
 mod tests {
-    use super::LimitConfig;
+    use super::{
+        LimitAlgorithm, LimitConfig, LimitConfigError, LimitExceeded, LimitInput, LimitRules,
+        LimitScope,
+    };
     use std::time::Duration;
 
     #[test]
@@ -206,5 +558,192 @@ mod tests {
                 ..Default::default()
             })
         );
+        assert_eq!(
+            "ip:sliding:20/min".parse(),
+            Ok(LimitConfig {
+                with_ip: true,
+                algorithm: LimitAlgorithm::SlidingWindow,
+                max_count: 20,
+                window: Duration::from_secs(60),
+                ..Default::default()
+            })
+        );
+        assert_eq!(
+            "email:bucket:decr_complete:5/15m".parse(),
+            Ok(LimitConfig {
+                with_email_addr: true,
+                algorithm: LimitAlgorithm::TokenBucket,
+                decr_complete: true,
+                max_count: 5,
+                window: Duration::from_secs(900),
+                ..Default::default()
+            })
+        );
+        assert_eq!(
+            "ip:challenge:10/h".parse(),
+            Ok(LimitConfig {
+                with_ip: true,
+                challenge: true,
+                max_count: 10,
+                window: Duration::from_secs(3600),
+                ..Default::default()
+            })
+        );
+        assert_eq!(
+            "ip:sliding:bucket:1/s".parse::<LimitConfig>(),
+            Err(LimitConfigError::ConflictingKeyword("sliding".to_owned()))
+        );
+        assert_eq!(
+            "ip:extend_window:sliding:1/s".parse::<LimitConfig>(),
+            Err(LimitConfigError::ConflictingKeyword(
+                "extend_window".to_owned()
+            ))
+        );
+    }
+
+    #[test]
+    fn test_parse_ip_prefix() {
+        assert_eq!(
+            "ip/24:ip6/56:10/s".parse(),
+            Ok(LimitConfig {
+                with_ip: true,
+                ipv4_prefix: Some(24),
+                ipv6_prefix: Some(56),
+                max_count: 10,
+                window: Duration::from_secs(1),
+                ..Default::default()
+            })
+        );
+        assert_eq!(
+            "ip/33:10/s".parse::<LimitConfig>(),
+            Err(LimitConfigError::InvalidPrefix("ip/33".to_owned()))
+        );
+        assert_eq!(
+            "ip6/x:10/s".parse::<LimitConfig>(),
+            Err(LimitConfigError::InvalidPrefix("ip6/x".to_owned()))
+        );
+        assert_eq!(
+            "email/24:10/s".parse::<LimitConfig>(),
+            Err(LimitConfigError::InvalidKeyword("email/24".to_owned()))
+        );
+    }
+
+    #[test]
+    fn test_format_ip() {
+        let config: LimitConfig = "ip:10/s".parse().unwrap();
+        let ip = "2001:db8:1:2:3::4".parse().unwrap();
+        assert_eq!(config.format_ip(ip), "2001:db8:1:2:3::4");
+
+        let config: LimitConfig = "ip/24:ip6/56:10/s".parse().unwrap();
+        let ip = "192.0.2.123".parse().unwrap();
+        assert_eq!(config.format_ip(ip), "192.0.2.0/24");
+        let ip = "::ffff:192.0.2.123".parse().unwrap();
+        assert_eq!(config.format_ip(ip), "192.0.2.0/24");
+        let ip = "2001:db8:1:2:3::4".parse().unwrap();
+        assert_eq!(config.format_ip(ip), "2001:db8:1::/56");
+    }
+
+    #[test]
+    fn test_select() {
+        let mut scope = LimitScope::default();
+        scope.origins.insert("https://staff.example.com".to_owned());
+        scope.email_domains.insert("example.org".to_owned());
+        let rules = LimitRules::new(
+            vec!["ip:10/s".parse().unwrap()],
+            vec![(scope, vec!["ip:100/s".parse().unwrap()])],
+            vec!["192.0.2.0/24".parse().unwrap()],
+        );
+        let select = |email: &str, origin: &str, ip: &str| {
+            let input = LimitInput {
+                email_addr: email.parse().unwrap(),
+                origin: origin.to_owned(),
+                ip: ip.parse().unwrap(),
+            };
+            rules
+                .select(&input)
+                .map(|config| (config.id, config.max_count))
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(
+            select("a@example.com", "https://example.com", "198.51.100.1"),
+            vec![(0, 10)]
+        );
+        assert_eq!(
+            select("a@example.com", "https://staff.example.com", "198.51.100.1"),
+            vec![(1, 100)]
+        );
+        assert_eq!(
+            select("a@example.org", "https://example.com", "198.51.100.1"),
+            vec![(1, 100)]
+        );
+        assert_eq!(
+            select("a@example.com", "https://example.com", "192.0.2.1"),
+            vec![]
+        );
+        assert_eq!(
+            select("a@example.com", "https://example.com", "::ffff:192.0.2.1"),
+            vec![]
+        );
+    }
+
+    #[test]
+    fn test_sliding_window() {
+        let config: LimitConfig = "10/10s".parse().unwrap();
+        let window = config.sliding_window(Duration::from_secs(25));
+        assert_eq!(window.current_key("k", "|"), "k|2");
+        assert_eq!(window.previous_key("k", "|"), "k|1");
+        assert_eq!(window.count(10, 3), 8);
+        assert_eq!(window.reset(10, 8, 10), Duration::from_secs(3));
+        assert_eq!(window.reset(0, 20, 10), Duration::from_secs(10));
+    }
+
+    #[test]
+    fn test_token_bucket() {
+        let config: LimitConfig = "2/10s".parse().unwrap();
+        let now = Duration::from_secs(100);
+        let tat = config.bucket_take(None, now).unwrap();
+        assert_eq!(tat, Duration::from_secs(105));
+        let tat = config.bucket_take(Some(tat), now).unwrap();
+        assert_eq!(tat, Duration::from_secs(110));
+        assert_eq!(
+            config.bucket_take(Some(tat), now),
+            Err(Duration::from_secs(5))
+        );
+        assert_eq!(
+            config.bucket_return(tat, now),
+            Some(Duration::from_secs(105))
+        );
+        assert_eq!(config.bucket_return(Duration::from_secs(105), now), None);
+
+        let config: LimitConfig = "4294967296/s".parse().unwrap();
+        assert_eq!(config.bucket_interval(), Duration::from_nanos(0));
+        assert!(config.bucket_take(None, now).is_ok());
+    }
+
+    #[test]
+    fn test_retry_after() {
+        let config: LimitConfig = "2/10s".parse().unwrap();
+        let exceeded = LimitExceeded::new(&config, Duration::from_millis(2500));
+        assert_eq!(exceeded.retry_after_secs(), 3);
+        let exceeded = LimitExceeded::new(&config, Duration::from_secs(0));
+        assert_eq!(exceeded.retry_after_secs(), 1);
+    }
+
+    #[test]
+    fn test_most_severe() {
+        let hard: LimitConfig = "2/10s".parse().unwrap();
+        let soft: LimitConfig = "challenge:1/10s".parse().unwrap();
+        let result = LimitExceeded::most_severe(vec![
+            LimitExceeded::new(&soft, Duration::from_secs(9)),
+            LimitExceeded::new(&hard, Duration::from_secs(2)),
+            LimitExceeded::new(&hard, Duration::from_secs(5)),
+        ])
+        .unwrap();
+        assert!(!result.challenge);
+        assert_eq!(result.reset, Duration::from_secs(5));
+        let result =
+            LimitExceeded::most_severe(vec![LimitExceeded::new(&soft, Duration::from_secs(9))]);
+        assert!(result.unwrap().challenge);
+        assert!(LimitExceeded::most_severe(vec![]).is_none());
     }
 }
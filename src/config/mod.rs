@@ -1,11 +1,13 @@
 mod env;
 mod i18n;
 mod limits;
+mod origins;
 mod string_list;
 mod templates;
 mod toml;
 
 pub use limits::*;
+pub use origins::*;
 pub use string_list::*;
 
 use self::env::EnvConfig;
@@ -13,15 +15,16 @@ use self::i18n::I18n;
 use self::templates::Templates;
 use self::toml::TomlConfig;
 use crate::agents::{
-    self, FetchAgent, KeyManagerSender, ManualKeys, ManualKeysError, RotatingKeys, SendMail,
-    StoreSender,
+    self, CachePolicy, FetchAgent, FetchOptions, FetchSizeLimits, IpFilter, KeyManagerSender,
+    LoadTlsError, ManualKeys, ManualKeysError, MemoryOptions, ParseProxyError, Proxy, RotatingKeys,
+    SendMail, StoreSender, TlsFiles, TlsOptions,
 };
 use crate::bridges::oidc::GOOGLE_IDP_ORIGIN;
 use crate::crypto::SigningAlgorithm;
 use crate::email_address::EmailAddress;
 use crate::utils::{
     agent::{spawn_agent, Addr, Sender},
-    DomainValidator, SecureRandom,
+    AddressValidator, DomainValidator, SecureRandom,
 };
 use crate::webfinger::{Link, ParseLinkError, Relation};
 use ipnetwork::IpNetwork;
@@ -35,6 +38,13 @@ use std::{
     time::Duration,
 };
 use thiserror::Error;
+use url::Url;
+
+#[cfg(feature = "rusqlite")]
+use crate::agents::RusqliteOptions;
+
+#[cfg(feature = "redis")]
+use crate::utils::redis::sentinel::Sentinel;
 
 /// Union of all possible error types seen while parsing.
 #[derive(Debug, Error)]
@@ -49,6 +59,12 @@ pub enum ConfigError {
     ManualKeys(#[from] ManualKeysError),
     #[error("domain override configuration error: {0}")]
     DomainOverride(#[from] ParseLinkError),
+    #[error("proxy configuration error: {0}")]
+    Proxy(#[from] ParseProxyError),
+    #[error("TLS configuration error: {0}")]
+    Tls(#[from] LoadTlsError),
+    #[error("pattern configuration error: {0}")]
+    Pattern(#[from] regex::Error),
 }
 
 impl From<&'static str> for ConfigError {
@@ -64,7 +80,9 @@ pub struct Config {
     pub listen_port: u16,
     pub public_url: String,
     pub trusted_proxies: Vec<IpNetwork>,
-    pub allowed_origins: Option<Vec<String>>,
+    pub allowed_origins: Option<OriginList>,
+    pub origin_policies: OriginPolicies,
+    pub address_validator: AddressValidator,
     pub domain_validator: DomainValidator,
 
     pub static_ttl: Duration,
@@ -76,6 +94,7 @@ pub struct Config {
     pub signing_algs: Vec<SigningAlgorithm>,
 
     pub store: Arc<dyn StoreSender>,
+    pub challenge_difficulty: u8,
     pub mailer: Box<dyn Sender<SendMail>>,
 
     pub google_client_id: Option<String>,
@@ -90,8 +109,8 @@ pub struct Config {
 /// Parameters for `StoreConfig::spawn_store`.
 struct StoreParams {
     session_ttl: Duration,
-    cache_ttl: Duration,
-    limit_configs: Vec<LimitConfig>,
+    cache_policy: CachePolicy,
+    limit_rules: LimitRules,
     fetcher: Addr<FetchAgent>,
     #[allow(dead_code)]
     rng: SecureRandom,
@@ -99,53 +118,121 @@ struct StoreParams {
 
 /// Store configuration is first translated into this intermediate enum.
 enum StoreConfig {
+    #[cfg(feature = "postgres")]
+    Postgres(String),
     #[cfg(feature = "redis")]
-    Redis(String),
+    Redis {
+        url: String,
+        sentinel: Option<Sentinel>,
+        key_prefix: String,
+    },
     #[cfg(feature = "rusqlite")]
-    Rusqlite(PathBuf),
-    Memory,
+    Rusqlite(PathBuf, RusqliteOptions),
+    Memory(MemoryOptions),
 }
 
 impl StoreConfig {
-    fn from_options(
-        redis_url: Option<String>,
-        sqlite_db: Option<PathBuf>,
-        memory_storage: bool,
-    ) -> Result<Self, ConfigError> {
-        match (redis_url, sqlite_db, memory_storage) {
+    fn from_builder(builder: &ConfigBuilder) -> Result<Self, ConfigError> {
+        match (
+            &builder.postgres_url,
+            &builder.redis_url,
+            &builder.sqlite_db,
+            builder.memory_storage,
+        ) {
+            #[cfg(feature = "postgres")]
+            (Some(postgres_url), None, None, false) => {
+                Ok(StoreConfig::Postgres(postgres_url.clone()))
+            }
+            #[cfg(not(feature = "postgres"))]
+            (Some(_), None, None, false) => {
+                Err("PostgreSQL storage requested, but this build does not support it.".into())
+            }
+
             #[cfg(feature = "redis")]
-            (Some(redis_url), None, false) => Ok(StoreConfig::Redis(redis_url)),
+            (None, Some(redis_url), None, false) => {
+                let sentinel = match builder.redis_sentinel_master {
+                    Some(ref master_name) => Some(
+                        Sentinel::new(master_name.clone(), &builder.redis_sentinels)
+                            .map_err(|_| "Invalid or missing redis_sentinels")?,
+                    ),
+                    None if !builder.redis_sentinels.is_empty() => {
+                        return Err("redis_sentinels requires redis_sentinel_master".into())
+                    }
+                    None => None,
+                };
+                Ok(StoreConfig::Redis {
+                    url: redis_url.clone(),
+                    sentinel,
+                    key_prefix: builder.redis_key_prefix.clone(),
+                })
+            }
             #[cfg(not(feature = "redis"))]
-            (Some(_), None, false) => {
+            (None, Some(_), None, false) => {
                 Err("Redis storage requested, but this build does not support it.".into())
             }
 
             #[cfg(feature = "rusqlite")]
-            (None, Some(sqlite_db), false) => Ok(StoreConfig::Rusqlite(sqlite_db)),
+            (None, None, Some(sqlite_db), false) => Ok(StoreConfig::Rusqlite(
+                sqlite_db.clone(),
+                RusqliteOptions {
+                    wal: builder.sqlite_wal,
+                    busy_timeout: builder.sqlite_busy_timeout,
+                    backup_before_migrate: builder.sqlite_backup_before_migrate,
+                },
+            )),
             #[cfg(not(feature = "rusqlite"))]
-            (None, Some(_), false) => {
+            (None, None, Some(_), false) => {
                 Err("SQLite storage requested, but this build does not support it.".into())
             }
 
-            (None, None, true) => Ok(StoreConfig::Memory),
+            (None, None, None, true) => Ok(StoreConfig::Memory(MemoryOptions {
+                max_cache_entries: builder.memory_max_cache_entries,
+                max_limit_entries: builder.memory_max_limit_entries,
+                snapshot_path: builder.memory_snapshot_path.clone(),
+                snapshot_interval: builder.memory_snapshot_interval,
+                snapshot_sessions: builder.memory_snapshot_sessions,
+            })),
 
-            (None, None, false) => {
-                Err("Must specify one of redis_url, sqlite_db or memory_storage".into())
-            }
+            (None, None, None, false) => Err(
+                "Must specify one of postgres_url, redis_url, sqlite_db or memory_storage".into(),
+            ),
 
-            _ => Err("Can only specify one of redis_url, sqlite_db or memory_storage".into()),
+            _ => Err(
+                "Can only specify one of postgres_url, redis_url, sqlite_db or memory_storage"
+                    .into(),
+            ),
         }
     }
 
     async fn spawn_store(self, params: StoreParams) -> Arc<dyn StoreSender> {
         match self {
+            #[cfg(feature = "postgres")]
+            StoreConfig::Postgres(postgres_url) => {
+                let store = agents::PostgresStore::new(
+                    postgres_url,
+                    params.session_ttl,
+                    params.cache_policy,
+                    params.limit_rules,
+                    params.fetcher,
+                    params.rng,
+                )
+                .await
+                .expect("unable to initialize PostgreSQL store");
+                Arc::new(spawn_agent(store).await)
+            }
             #[cfg(feature = "redis")]
-            StoreConfig::Redis(redis_url) => {
+            StoreConfig::Redis {
+                url,
+                sentinel,
+                key_prefix,
+            } => {
                 let store = agents::RedisStore::new(
-                    redis_url,
+                    url,
+                    sentinel,
+                    key_prefix,
                     params.session_ttl,
-                    params.cache_ttl,
-                    params.limit_configs,
+                    params.cache_policy,
+                    params.limit_rules,
                     params.fetcher,
                     params.rng,
                 )
@@ -154,25 +241,28 @@ impl StoreConfig {
                 Arc::new(spawn_agent(store).await)
             }
             #[cfg(feature = "rusqlite")]
-            StoreConfig::Rusqlite(sqlite_db) => {
+            StoreConfig::Rusqlite(sqlite_db, options) => {
                 let store = agents::RusqliteStore::new(
                     sqlite_db,
+                    options,
                     params.session_ttl,
-                    params.cache_ttl,
-                    params.limit_configs,
+                    params.cache_policy,
+                    params.limit_rules,
                     params.fetcher,
                 )
                 .await
                 .expect("unable to initialize SQLite store");
                 Arc::new(spawn_agent(store).await)
             }
-            StoreConfig::Memory => {
+            StoreConfig::Memory(options) => {
                 let store = agents::MemoryStore::new(
+                    options,
                     params.session_ttl,
-                    params.cache_ttl,
-                    params.limit_configs,
+                    params.cache_policy,
+                    params.limit_rules,
                     params.fetcher,
-                );
+                )
+                .expect("unable to initialize memory store");
                 Arc::new(spawn_agent(store).await)
             }
         }
@@ -333,14 +423,27 @@ impl MailerConfig {
     }
 }
 
+#[allow(clippy::struct_excessive_bools)]
 pub struct ConfigBuilder {
     pub listen_ip: String,
     pub listen_port: u16,
     pub public_url: Option<String>,
     pub trusted_proxies: Vec<IpNetwork>,
-    pub allowed_origins: Option<Vec<String>>,
+    pub allowed_origins: Option<OriginList>,
+    pub origin_policies: OriginPolicies,
+    pub address_validator: AddressValidator,
     pub domain_validator: DomainValidator,
     pub data_dir: String,
+    pub http_proxy: Option<String>,
+    pub no_proxy: Vec<String>,
+    pub fetch_public_ips_only: bool,
+    pub fetch_allowed_ips: Vec<IpNetwork>,
+    pub fetch_connect_timeout: Duration,
+    pub fetch_timeout: Duration,
+    pub fetch_max_redirects: usize,
+    pub fetch_max_sizes: FetchSizeLimits,
+    pub fetch_tls: TlsFiles,
+    pub fetch_origin_tls: Vec<(String, TlsFiles)>,
 
     pub static_ttl: Duration,
     pub discovery_ttl: Duration,
@@ -348,15 +451,29 @@ pub struct ConfigBuilder {
     pub token_ttl: Duration,
     pub session_ttl: Duration,
     pub cache_ttl: Duration,
+    pub cache_stale_while_revalidate: Duration,
+    pub cache_stale_if_error: Duration,
 
     pub keyfiles: Vec<PathBuf>,
     pub keytext: Option<String>,
     pub signing_algs: Vec<SigningAlgorithm>,
     pub generate_rsa_command: Vec<String>,
 
+    pub postgres_url: Option<String>,
     pub redis_url: Option<String>,
+    pub redis_sentinel_master: Option<String>,
+    pub redis_sentinels: Vec<String>,
+    pub redis_key_prefix: String,
     pub sqlite_db: Option<PathBuf>,
+    pub sqlite_wal: bool,
+    pub sqlite_busy_timeout: Duration,
+    pub sqlite_backup_before_migrate: bool,
     pub memory_storage: bool,
+    pub memory_max_cache_entries: usize,
+    pub memory_max_limit_entries: usize,
+    pub memory_snapshot_path: Option<PathBuf>,
+    pub memory_snapshot_interval: Duration,
+    pub memory_snapshot_sessions: bool,
 
     pub from_name: String,
     pub from_address: Option<String>,
@@ -375,6 +492,9 @@ pub struct ConfigBuilder {
     pub mailgun_domain: Option<String>,
 
     pub limits: Vec<LimitConfig>,
+    pub limit_overrides: Vec<(LimitScope, Vec<LimitConfig>)>,
+    pub limits_exempt: Vec<IpNetwork>,
+    pub challenge_difficulty: u8,
 
     pub google_client_id: Option<String>,
     pub domain_overrides: HashMap<String, Vec<Link>>,
@@ -391,8 +511,20 @@ impl ConfigBuilder {
                 .map(|v| v.parse().unwrap())
                 .collect(),
             allowed_origins: None,
+            origin_policies: OriginPolicies::default(),
+            address_validator: AddressValidator::new(),
             domain_validator: DomainValidator::new(),
             data_dir: String::new(),
+            http_proxy: None,
+            no_proxy: Vec::new(),
+            fetch_public_ips_only: true,
+            fetch_allowed_ips: Vec::new(),
+            fetch_connect_timeout: Duration::from_secs(10),
+            fetch_timeout: Duration::from_secs(30),
+            fetch_max_redirects: 0,
+            fetch_max_sizes: FetchSizeLimits::default(),
+            fetch_tls: TlsFiles::default(),
+            fetch_origin_tls: Vec::new(),
 
             static_ttl: Duration::from_secs(604_800),
             discovery_ttl: Duration::from_secs(604_800),
@@ -400,6 +532,8 @@ impl ConfigBuilder {
             token_ttl: Duration::from_secs(600),
             session_ttl: Duration::from_secs(900),
             cache_ttl: Duration::from_secs(3600),
+            cache_stale_while_revalidate: Duration::from_secs(300),
+            cache_stale_if_error: Duration::from_secs(86_400),
 
             keyfiles: Vec::new(),
             keytext: None,
@@ -409,9 +543,21 @@ impl ConfigBuilder {
                 .map(ToOwned::to_owned)
                 .collect(),
 
+            postgres_url: None,
             redis_url: None,
+            redis_sentinel_master: None,
+            redis_sentinels: Vec::new(),
+            redis_key_prefix: String::new(),
             sqlite_db: None,
+            sqlite_wal: true,
+            sqlite_busy_timeout: Duration::from_secs(5),
+            sqlite_backup_before_migrate: false,
             memory_storage: false,
+            memory_max_cache_entries: 10_000,
+            memory_max_limit_entries: 100_000,
+            memory_snapshot_path: None,
+            memory_snapshot_interval: Duration::from_secs(300),
+            memory_snapshot_sessions: false,
 
             from_name: "Portier".to_owned(),
             from_address: None,
@@ -439,6 +585,9 @@ impl ConfigBuilder {
             .iter()
             .map(|value| value.parse().unwrap())
             .collect::<Vec<_>>(),
+            limit_overrides: Vec::new(),
+            limits_exempt: Vec::new(),
+            challenge_difficulty: 16,
 
             google_client_id: None,
             domain_overrides: HashMap::new(),
@@ -461,6 +610,18 @@ impl ConfigBuilder {
             self.public_url = Some(format!("https://{}.herokuapp.com", val));
         }
 
+        if self.http_proxy.is_none() {
+            self.http_proxy = env_var("HTTPS_PROXY")
+                .or_else(|_| env_var("https_proxy"))
+                .ok()
+                .filter(|val| !val.is_empty());
+        }
+        if self.no_proxy.is_empty() {
+            if let Ok(val) = env_var("NO_PROXY").or_else(|_| env_var("no_proxy")) {
+                self.no_proxy = val.split(',').map(ToOwned::to_owned).collect();
+            }
+        }
+
         for var in &[
             "REDISTOGO_URL",
             "REDISGREEN_URL",
@@ -489,9 +650,71 @@ impl ConfigBuilder {
         self
     }
 
+    fn fetch_options(&self) -> Result<FetchOptions, ConfigError> {
+        let proxy = match self.http_proxy {
+            Some(ref url) => Some(Proxy::new(url, &self.no_proxy)?),
+            None => None,
+        };
+        let ip_filter = IpFilter {
+            public_only: self.fetch_public_ips_only,
+            allowed: self.fetch_allowed_ips.clone(),
+        };
+        let mut origin_tls = HashMap::new();
+        for (origin, files) in &self.fetch_origin_tls {
+            let url: Url = origin
+                .parse()
+                .map_err(|_| "invalid origin in fetch_origin_tls")?;
+            let host = match url.host_str() {
+                Some(host) if url.scheme() == "https" => host
+                    .trim_start_matches('[')
+                    .trim_end_matches(']')
+                    .to_owned(),
+                _ => return Err("fetch_origin_tls origins must be HTTPS URLs".into()),
+            };
+            let port = url.port_or_known_default().unwrap_or(443);
+            origin_tls.insert((host, port), TlsOptions::load(files)?);
+        }
+        Ok(FetchOptions {
+            proxy,
+            ip_filter,
+            connect_timeout: self.fetch_connect_timeout,
+            timeout: self.fetch_timeout,
+            max_redirects: self.fetch_max_redirects,
+            max_sizes: self.fetch_max_sizes,
+            tls: TlsOptions::load(&self.fetch_tls)?,
+            origin_tls,
+        })
+    }
+
+    fn cache_policy(&self) -> CachePolicy {
+        CachePolicy {
+            ttl: self.cache_ttl,
+            stale_while_revalidate: self.cache_stale_while_revalidate,
+            stale_if_error: self.cache_stale_if_error,
+        }
+    }
+
     pub async fn done(mut self) -> Result<Config, ConfigError> {
-        let store_config =
-            StoreConfig::from_options(self.redis_url, self.sqlite_db, self.memory_storage)?;
+        if self.challenge_difficulty > 32 {
+            return Err("challenge_difficulty must be at most 32".into());
+        }
+
+        self.address_validator.finish()?;
+        self.domain_validator.finish()?;
+
+        for policy in self.origin_policies.iter() {
+            if let Some(ref algs) = policy.signing_algs {
+                if algs.iter().any(|alg| !self.signing_algs.contains(alg)) {
+                    return Err(
+                        "origin_policies signing_algs must be a subset of signing_algs".into(),
+                    );
+                }
+            }
+        }
+
+        let store_config = StoreConfig::from_builder(&self)?;
+        let fetch_options = self.fetch_options()?;
+        let cache_policy = self.cache_policy();
         let mailer_config = MailerConfig::from_options(
             self.smtp_server,
             self.smtp_username,
@@ -504,19 +727,14 @@ impl ConfigBuilder {
             self.mailgun_domain,
         )?;
 
-        // Assign IDs to limit configs.
-        for (idx, limit) in self.limits.iter_mut().enumerate() {
-            limit.id = idx;
-        }
-
         // Child structs
         let rng = SecureRandom::new().await;
-        let fetcher = spawn_agent(FetchAgent::new()).await;
+        let fetcher = spawn_agent(FetchAgent::new(fetch_options)).await;
         let store = store_config
             .spawn_store(StoreParams {
                 session_ttl: self.session_ttl,
-                cache_ttl: self.cache_ttl,
-                limit_configs: self.limits,
+                cache_policy,
+                limit_rules: LimitRules::new(self.limits, self.limit_overrides, self.limits_exempt),
                 fetcher: fetcher.clone(),
                 rng: rng.clone(),
             })
@@ -574,6 +792,9 @@ impl ConfigBuilder {
             domain_overrides.insert(domain, links);
         }
 
+        let mut domain_validator = self.domain_validator;
+        domain_validator.set_cache(store.clone());
+
         let templates = Templates::new(&self.data_dir);
         let i18n = I18n::new(&self.data_dir);
         let mut res_dir: PathBuf = self.data_dir.into();
@@ -585,7 +806,9 @@ impl ConfigBuilder {
             public_url: self.public_url.expect("no public url configured"),
             trusted_proxies: self.trusted_proxies,
             allowed_origins: self.allowed_origins,
-            domain_validator: self.domain_validator,
+            origin_policies: self.origin_policies,
+            address_validator: self.address_validator,
+            domain_validator,
 
             static_ttl: self.static_ttl,
             discovery_ttl: self.discovery_ttl,
@@ -596,6 +819,7 @@ impl ConfigBuilder {
             signing_algs: self.signing_algs,
 
             store,
+            challenge_difficulty: self.challenge_difficulty,
             mailer,
 
             google_client_id: self.google_client_id,
@@ -609,15 +833,14 @@ impl ConfigBuilder {
     }
 
     pub async fn into_store(self) -> Result<Arc<dyn StoreSender>, ConfigError> {
-        let store_config =
-            StoreConfig::from_options(self.redis_url, self.sqlite_db, self.memory_storage)?;
-        let fetcher = spawn_agent(FetchAgent::new()).await;
+        let store_config = StoreConfig::from_builder(&self)?;
+        let fetcher = spawn_agent(FetchAgent::new(self.fetch_options()?)).await;
         let rng = SecureRandom::new().await;
         let store = store_config
             .spawn_store(StoreParams {
                 session_ttl: self.session_ttl,
-                cache_ttl: self.cache_ttl,
-                limit_configs: self.limits,
+                cache_policy: self.cache_policy(),
+                limit_rules: LimitRules::new(self.limits, self.limit_overrides, self.limits_exempt),
                 fetcher,
                 rng,
             })
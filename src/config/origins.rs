@@ -0,0 +1,356 @@
+use crate::crypto::SigningAlgorithm;
+use serde::Deserialize;
+use std::collections::hash_map::Entry;
+use std::collections::HashMap;
+use std::time::Duration;
+use thiserror::Error;
+use url::{Host, Origin, ParseError, Url};
+
+/// Bridge that a Relying Party requires for authentication.
+#[derive(Clone, Copy, Debug, Deserialize, Eq, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum RequiredBridge {
+    /// Always use the email loop, skipping discovery.
+    Email,
+    /// Only use an OpenID Connect provider found by discovery, without email loop fallback.
+    Oidc,
+}
+
+/// Settings for specific Relying Party origins.
+///
+/// Domain rules for the origins are part of `DomainValidator`, by index of the policy.
+#[derive(Clone, Debug, Default)]
+pub struct OriginPolicy {
+    /// Bridge to use for authentication, instead of the default discovery.
+    pub bridge: Option<RequiredBridge>,
+    /// Lifetime of tokens issued to the origin, instead of `token_ttl`.
+    pub token_ttl: Option<Duration>,
+    /// Signing algorithms allowed for the origin, a subset of `signing_algs`.
+    pub signing_algs: Option<Vec<SigningAlgorithm>>,
+    /// Whether to block disposable email providers, instead of `block_disposable`.
+    pub block_disposable: Option<bool>,
+}
+
+#[derive(Debug, Error, Eq, PartialEq)]
+pub enum OriginPatternError {
+    #[error("origin must start with `http://` or `https://`")]
+    Scheme,
+    #[error("origin must not contain a path, query, fragment or credentials")]
+    Extra,
+    #[error("invalid host: {0}")]
+    Host(ParseError),
+    #[error("wildcards are only supported as `*.` at the start of a domain")]
+    Wildcard,
+    #[error("invalid port or port range")]
+    Port,
+    #[error("pattern is already used by another policy")]
+    Duplicate,
+}
+
+/// Split an origin pattern into its lowercase scheme and the rest, which must not contain a path,
+/// query, fragment or credentials.
+fn split_scheme(pattern: &str) -> Result<(String, &str), OriginPatternError> {
+    let idx = pattern.find("://").ok_or(OriginPatternError::Scheme)?;
+    let (scheme, rest) = (pattern[..idx].to_ascii_lowercase(), &pattern[idx + 3..]);
+    if scheme != "http" && scheme != "https" {
+        return Err(OriginPatternError::Scheme);
+    }
+    if rest.contains(&['/', '?', '#', '@', '\\'][..]) {
+        return Err(OriginPatternError::Extra);
+    }
+    Ok((scheme, rest))
+}
+
+/// Inclusive range of ports.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+struct PortRange(u16, u16);
+
+impl PortRange {
+    fn parse(input: &str) -> Result<Self, OriginPatternError> {
+        let parse = |port: &str| port.parse().map_err(|_| OriginPatternError::Port);
+        let range = if input == "*" {
+            PortRange(1, u16::MAX)
+        } else if let Some(idx) = input.find('-') {
+            PortRange(parse(&input[..idx])?, parse(&input[idx + 1..])?)
+        } else {
+            let port = parse(input)?;
+            PortRange(port, port)
+        };
+        if range.0 == 0 || range.0 > range.1 {
+            return Err(OriginPatternError::Port);
+        }
+        Ok(range)
+    }
+
+    fn contains(self, port: u16) -> bool {
+        self.0 <= port && port <= self.1
+    }
+}
+
+/// A list of origin patterns, indexed for fast matching.
+///
+/// Origins are matched by their parsed scheme, host and port, never as strings, so credentials or
+/// differently encoded hosts cannot be used to match a pattern. Each pattern carries a value,
+/// which `OriginPolicies` uses to find the policy of an origin.
+#[derive(Clone, Debug, Default)]
+pub struct OriginList {
+    /// Exact origins, as scheme, host and port.
+    exact: HashMap<(String, String, u16), usize>,
+    /// Port ranges by scheme and host, like `localhost` for `http://localhost:8000-8999`.
+    hosts: HashMap<(String, String), Vec<(PortRange, usize)>>,
+    /// Port ranges by scheme and parent domain, like `example.com` for `https://*.example.com`.
+    wildcards: HashMap<(String, String), Vec<(PortRange, usize)>>,
+}
+
+impl OriginList {
+    /// Add a pattern to the list.
+    ///
+    /// This is an origin like `https://example.com`, where the host may start with `*.` to match
+    /// exactly one additional label, and the port may be a range like `8000-8999` or `*`.
+    pub fn add(&mut self, pattern: &str) -> Result<(), OriginPatternError> {
+        self.insert(pattern, 0).map(|_| ())
+    }
+
+    /// Add a pattern to the list with a value.
+    ///
+    /// Returns `false` if the pattern was already in the list, in which case it keeps its value.
+    fn insert(&mut self, pattern: &str, value: usize) -> Result<bool, OriginPatternError> {
+        let (scheme, rest) = split_scheme(pattern)?;
+        let default_port = if scheme == "https" { 443 } else { 80 };
+
+        // The port follows the last colon, unless that is part of an IPv6 address.
+        let (host, port) = match rest.rfind(':') {
+            Some(idx) if !rest[idx..].contains(']') => (&rest[..idx], Some(&rest[idx + 1..])),
+            _ => (rest, None),
+        };
+        let ports = match port {
+            Some(port) => PortRange::parse(port)?,
+            None => PortRange(default_port, default_port),
+        };
+
+        let (wildcard, host) = match host.strip_prefix("*.") {
+            Some(host) => (true, host),
+            None => (false, host),
+        };
+        if host.contains('*') {
+            return Err(OriginPatternError::Wildcard);
+        }
+        let host = Host::parse(host).map_err(OriginPatternError::Host)?;
+        if wildcard && !matches!(host, Host::Domain(_)) {
+            return Err(OriginPatternError::Wildcard);
+        }
+
+        let key = (scheme, host.to_string());
+        let ranges = if wildcard {
+            self.wildcards.entry(key).or_default()
+        } else if ports.0 == ports.1 {
+            return Ok(match self.exact.entry((key.0, key.1, ports.0)) {
+                Entry::Occupied(_) => false,
+                Entry::Vacant(entry) => {
+                    entry.insert(value);
+                    true
+                }
+            });
+        } else {
+            self.hosts.entry(key).or_default()
+        };
+        if ranges.iter().any(|(range, _)| *range == ports) {
+            return Ok(false);
+        }
+        ranges.push((ports, value));
+        Ok(true)
+    }
+
+    /// Whether an origin matches any of the patterns.
+    pub fn matches(&self, origin: &Origin) -> bool {
+        self.find(origin).is_some()
+    }
+
+    /// Find the value of the pattern matching an origin.
+    ///
+    /// Exact origins take precedence over port ranges, which take precedence over wildcards.
+    /// Otherwise, the pattern added first wins.
+    fn find(&self, origin: &Origin) -> Option<usize> {
+        let (scheme, host, port) = match origin {
+            Origin::Tuple(scheme, host, port) => (scheme, host, *port),
+            Origin::Opaque(_) => return None,
+        };
+        let host_str = host.to_string();
+        if let Some(value) = self.exact.get(&(scheme.clone(), host_str.clone(), port)) {
+            return Some(*value);
+        }
+
+        let in_ranges = |ranges: Option<&Vec<(PortRange, usize)>>| {
+            ranges.and_then(|ranges| {
+                ranges
+                    .iter()
+                    .find(|(range, _)| range.contains(port))
+                    .map(|(_, value)| *value)
+            })
+        };
+        if let Some(value) = in_ranges(self.hosts.get(&(scheme.clone(), host_str))) {
+            return Some(value);
+        }
+        if let Host::Domain(domain) = host {
+            // Wildcards match exactly one non-empty label.
+            if let Some(idx) = domain.find('.').filter(|idx| *idx > 0) {
+                let parent = domain[idx + 1..].to_owned();
+                return in_ranges(self.wildcards.get(&(scheme.clone(), parent)));
+            }
+        }
+
+        None
+    }
+}
+
+/// Policies for Relying Party origins, found by the same patterns as `OriginList`.
+#[derive(Clone, Debug, Default)]
+pub struct OriginPolicies {
+    patterns: OriginList,
+    policies: Vec<OriginPolicy>,
+}
+
+impl OriginPolicies {
+    /// Add a policy, returning its index.
+    pub fn add(&mut self, policy: OriginPolicy) -> usize {
+        self.policies.push(policy);
+        self.policies.len() - 1
+    }
+
+    /// Apply the policy at an index to origins matching a pattern.
+    ///
+    /// Patterns are the same as for `OriginList::add`, but may only be used once.
+    pub fn add_origin(&mut self, index: usize, pattern: &str) -> Result<(), OriginPatternError> {
+        assert!(index < self.policies.len());
+        if self.patterns.insert(pattern, index)? {
+            Ok(())
+        } else {
+            Err(OriginPatternError::Duplicate)
+        }
+    }
+
+    /// Find the policy for an origin, and its index.
+    pub fn find(&self, origin: &Origin) -> Option<(usize, &OriginPolicy)> {
+        self.patterns
+            .find(origin)
+            .map(|index| (index, &self.policies[index]))
+    }
+
+    /// Find the policy for a `client_id`, which is the ASCII serialization of an origin.
+    pub fn find_for_client_id(&self, client_id: &str) -> Option<(usize, &OriginPolicy)> {
+        let origin = Url::parse(client_id).ok()?.origin();
+        self.find(&origin)
+    }
+
+    /// Iterate all policies, in order of their index.
+    pub fn iter(&self) -> impl Iterator<Item = &OriginPolicy> {
+        self.policies.iter()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use url::Url;
+
+    fn origin(input: &str) -> Origin {
+        Url::parse(input).unwrap().origin()
+    }
+
+    #[test]
+    fn test_origin_list() {
+        let mut list = OriginList::default();
+        for pattern in &[
+            "https://example.com",
+            "HTTPS://Bücher.example",
+            "https://*.preview.example.com",
+            "http://localhost:8000-8999",
+            "http://[::1]:*",
+        ] {
+            list.add(pattern).unwrap();
+        }
+
+        for input in &[
+            "https://example.com",
+            "https://example.com:443/path",
+            "https://xn--bcher-kva.example",
+            "https://pr-123.preview.example.com",
+            "http://localhost:8080",
+            "http://[::1]:3000",
+        ] {
+            assert!(list.matches(&origin(input)), "{}", input);
+        }
+        for input in &[
+            "http://example.com",
+            "https://example.com:8443",
+            "https://preview.example.com",
+            "https://a.pr-123.preview.example.com",
+            "https://evil.com#.preview.example.com",
+            "https://pr-123.preview.example.com@evil.com",
+            "https://pr-123.preview.example.com.evil.com",
+            "http://localhost:9000",
+            "http://localhost",
+        ] {
+            assert!(!list.matches(&origin(input)), "{}", input);
+        }
+
+        for (pattern, err) in &[
+            ("example.com", OriginPatternError::Scheme),
+            ("ftp://example.com", OriginPatternError::Scheme),
+            ("https://example.com/", OriginPatternError::Extra),
+            ("https://user@example.com", OriginPatternError::Extra),
+            ("https://a.*.example.com", OriginPatternError::Wildcard),
+            ("https://*.127.0.0.1", OriginPatternError::Wildcard),
+            ("http://localhost:9000-8000", OriginPatternError::Port),
+            ("http://localhost:0", OriginPatternError::Port),
+        ] {
+            assert_eq!(list.add(pattern).as_ref(), Err(err), "{}", pattern);
+        }
+    }
+
+    #[test]
+    fn test_origin_policies() {
+        let mut policies = OriginPolicies::default();
+        let staff = policies.add(OriginPolicy::default());
+        let preview = policies.add(OriginPolicy::default());
+        policies
+            .add_origin(staff, "HTTPS://Staff.example.com")
+            .unwrap();
+        policies
+            .add_origin(staff, "http://localhost:8000-8999")
+            .unwrap();
+        policies
+            .add_origin(preview, "https://*.example.com")
+            .unwrap();
+
+        for (input, expected) in &[
+            ("https://staff.example.com", Some(staff)),
+            ("http://localhost:8080", Some(staff)),
+            ("https://pr-123.example.com", Some(preview)),
+            ("https://example.com", None),
+            ("http://localhost:9000", None),
+        ] {
+            assert_eq!(
+                policies.find(&origin(input)).map(|(index, _)| index),
+                *expected,
+                "{}",
+                input
+            );
+        }
+        assert_eq!(
+            policies
+                .find_for_client_id("https://xn--bcher-kva.example.com")
+                .map(|(index, _)| index),
+            Some(preview)
+        );
+
+        for pattern in &["https://staff.example.com:443", "https://*.example.com"] {
+            assert_eq!(
+                policies.add_origin(preview, pattern),
+                Err(OriginPatternError::Duplicate),
+                "{}",
+                pattern
+            );
+        }
+    }
+}
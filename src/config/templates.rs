@@ -36,6 +36,8 @@ impl Template {
 
 // Contains all templates we use in compiled form.
 pub struct Templates {
+    /// Page that solves a proof-of-work challenge before continuing.
+    pub challenge: Template,
     /// Page displayed when the confirmation email was sent.
     pub confirm_email: Template,
     /// Page displayed when the login_hint is missing.
@@ -55,6 +57,7 @@ pub struct Templates {
 impl Templates {
     pub fn new(data_dir: &str) -> Templates {
         Templates {
+            challenge: Template::compile(data_dir, "challenge"),
             confirm_email: Template::compile(data_dir, "confirm_email"),
             email_html: Template::compile(data_dir, "email_html"),
             email_text: Template::compile(data_dir, "email_text"),
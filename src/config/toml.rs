@@ -1,7 +1,12 @@
-use super::{ConfigBuilder, LegacyLimitPerEmail, LimitConfig};
+use super::{
+    ConfigBuilder, LegacyLimitPerEmail, LimitConfig, LimitScope, OriginList, OriginPolicy,
+    RequiredBridge,
+};
+use crate::agents::TlsFiles;
 use crate::config::StringList;
 use crate::crypto::SigningAlgorithm;
 use crate::webfinger::Link;
+use ipnetwork::IpNetwork;
 use serde::Deserialize;
 use std::collections::HashMap;
 use std::fs;
@@ -15,13 +20,41 @@ pub struct TomlConfig {
     listen_port: Option<u16>,
     public_url: Option<String>,
     data_dir: Option<String>,
+    http_proxy: Option<String>,
+    no_proxy: Option<Vec<String>>,
+    fetch_public_ips_only: Option<bool>,
+    fetch_allowed_ips: Option<Vec<IpNetwork>>,
+    fetch_connect_timeout: Option<u64>,
+    fetch_timeout: Option<u64>,
+    fetch_max_redirects: Option<usize>,
+    fetch_max_size_webfinger: Option<usize>,
+    fetch_max_size_oidc_config: Option<usize>,
+    fetch_max_size_oidc_keys: Option<usize>,
+    fetch_max_size_mail_api: Option<usize>,
+    fetch_ca_bundle: Option<PathBuf>,
+    fetch_client_identity: Option<PathBuf>,
+    fetch_client_identity_password: Option<String>,
+    fetch_origin_tls: Option<Vec<TomlOriginTls>>,
 
     allowed_origins: Option<StringList>,
     #[serde(default)]
+    allowed_addresses: StringList,
+    #[serde(default)]
+    blocked_addresses: StringList,
+    #[serde(default)]
     allowed_domains: StringList,
     #[serde(default)]
     blocked_domains: StringList,
+    #[serde(default)]
+    disposable_domains: StringList,
+    #[serde(default)]
+    disposable_mail_servers: StringList,
+    block_disposable: Option<bool>,
+    origin_policies: Option<Vec<TomlOriginPolicy>>,
+    public_suffix_list: Option<PathBuf>,
     verify_with_resolver: Option<String>,
+    verify_dnssec: Option<bool>,
+    verify_cache_ttl: Option<u64>,
     verify_public_ip: Option<bool>,
     allowed_domains_only: Option<bool>,
 
@@ -31,15 +64,29 @@ pub struct TomlConfig {
     token_ttl: Option<u64>,
     session_ttl: Option<u64>,
     cache_ttl: Option<u64>,
+    cache_stale_while_revalidate: Option<u64>,
+    cache_stale_if_error: Option<u64>,
 
     keyfiles: Option<Vec<PathBuf>>,
     keytext: Option<String>,
     signing_algs: Option<Vec<SigningAlgorithm>>,
     generate_rsa_command: Option<Vec<String>>,
 
+    postgres_url: Option<String>,
     redis_url: Option<String>,
+    redis_sentinel_master: Option<String>,
+    redis_sentinels: Option<Vec<String>>,
+    redis_key_prefix: Option<String>,
     sqlite_db: Option<PathBuf>,
+    sqlite_wal: Option<bool>,
+    sqlite_busy_timeout: Option<u64>,
+    sqlite_backup_before_migrate: Option<bool>,
     memory_storage: Option<bool>,
+    memory_max_cache_entries: Option<usize>,
+    memory_max_limit_entries: Option<usize>,
+    memory_snapshot_path: Option<PathBuf>,
+    memory_snapshot_interval: Option<u64>,
+    memory_snapshot_sessions: Option<bool>,
 
     from_name: Option<String>,
     from_address: Option<String>,
@@ -58,6 +105,10 @@ pub struct TomlConfig {
 
     limits: Option<Vec<LimitConfig>>,
     limit_per_email: Option<LegacyLimitPerEmail>,
+    limit_overrides: Option<Vec<TomlLimitOverride>>,
+    #[serde(default)]
+    limits_exempt: StringList,
+    challenge_difficulty: Option<u8>,
 
     google_client_id: Option<String>,
     domain_overrides: Option<HashMap<String, Vec<Link>>>,
@@ -72,6 +123,36 @@ pub struct TomlConfig {
     google: Option<TomlGoogleTable>,
 }
 
+#[derive(Deserialize)]
+struct TomlOriginTls {
+    origin: String,
+    ca_bundle: Option<PathBuf>,
+    client_identity: Option<PathBuf>,
+    client_identity_password: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct TomlOriginPolicy {
+    origins: Vec<String>,
+    #[serde(default)]
+    allowed_domains: StringList,
+    #[serde(default)]
+    blocked_domains: StringList,
+    bridge: Option<RequiredBridge>,
+    token_ttl: Option<u64>,
+    signing_algs: Option<Vec<SigningAlgorithm>>,
+    block_disposable: Option<bool>,
+}
+
+#[derive(Deserialize)]
+struct TomlLimitOverride {
+    #[serde(default)]
+    origins: Vec<String>,
+    #[serde(default)]
+    email_domains: Vec<String>,
+    limits: Vec<LimitConfig>,
+}
+
 #[derive(Deserialize)]
 struct TomlServerTable {
     listen_ip: Option<String>,
@@ -245,16 +326,100 @@ impl TomlConfig {
         if let Some(val) = parsed.data_dir {
             builder.data_dir = val;
         }
+        if let Some(val) = parsed.http_proxy {
+            builder.http_proxy = Some(val);
+        }
+        if let Some(val) = parsed.no_proxy {
+            builder.no_proxy = val;
+        }
+        if let Some(val) = parsed.fetch_public_ips_only {
+            builder.fetch_public_ips_only = val;
+        }
+        if let Some(val) = parsed.fetch_allowed_ips {
+            builder.fetch_allowed_ips = val;
+        }
+        if let Some(val) = parsed.fetch_connect_timeout {
+            builder.fetch_connect_timeout = Duration::from_secs(val);
+        }
+        if let Some(val) = parsed.fetch_timeout {
+            builder.fetch_timeout = Duration::from_secs(val);
+        }
+        if let Some(val) = parsed.fetch_max_redirects {
+            builder.fetch_max_redirects = val;
+        }
+        if let Some(val) = parsed.fetch_max_size_webfinger {
+            builder.fetch_max_sizes.webfinger = val;
+        }
+        if let Some(val) = parsed.fetch_max_size_oidc_config {
+            builder.fetch_max_sizes.oidc_config = val;
+        }
+        if let Some(val) = parsed.fetch_max_size_oidc_keys {
+            builder.fetch_max_sizes.oidc_keys = val;
+        }
+        if let Some(val) = parsed.fetch_max_size_mail_api {
+            builder.fetch_max_sizes.mail_api = val;
+        }
+        if let Some(val) = parsed.fetch_ca_bundle {
+            builder.fetch_tls.ca_bundle = Some(val);
+        }
+        if let Some(val) = parsed.fetch_client_identity {
+            builder.fetch_tls.client_identity = Some(val);
+        }
+        if let Some(val) = parsed.fetch_client_identity_password {
+            builder.fetch_tls.client_identity_password = val;
+        }
+        if let Some(entries) = parsed.fetch_origin_tls {
+            for entry in entries {
+                let files = TlsFiles {
+                    ca_bundle: entry.ca_bundle,
+                    client_identity: entry.client_identity,
+                    client_identity_password: entry.client_identity_password.unwrap_or_default(),
+                };
+                builder.fetch_origin_tls.push((entry.origin, files));
+            }
+        }
 
         if let Some(val) = parsed.allowed_origins {
-            let list = builder.allowed_origins.get_or_insert(vec![]);
+            let list = builder
+                .allowed_origins
+                .get_or_insert_with(OriginList::default);
             for (source, res) in val.iter_values() {
-                match res {
-                    Ok(data) => list.push(data.into_owned()),
+                let data = match res {
+                    Ok(data) => data,
                     Err(err) => panic!("IO error in allowed_origins entry {}: {}", source, err),
+                };
+                if let Err(err) = list.add(data.as_ref()) {
+                    panic!(
+                        "Invalid allowed_origins entry {}: '{}': {}",
+                        source, data, err
+                    );
                 }
             }
         };
+        for (source, res) in parsed.allowed_addresses.iter_values() {
+            let data = match res {
+                Ok(data) => data,
+                Err(err) => panic!("IO error in allowed_addresses entry {}: {}", source, err),
+            };
+            if let Err(err) = builder.address_validator.add_allowed_address(data.as_ref()) {
+                panic!(
+                    "Invalid allowed_addresses entry {}: '{}': {}",
+                    source, data, err
+                );
+            }
+        }
+        for (source, res) in parsed.blocked_addresses.iter_values() {
+            let data = match res {
+                Ok(data) => data,
+                Err(err) => panic!("IO error in blocked_addresses entry {}: {}", source, err),
+            };
+            if let Err(err) = builder.address_validator.add_blocked_address(data.as_ref()) {
+                panic!(
+                    "Invalid blocked_addresses entry {}: '{}': {}",
+                    source, data, err
+                );
+            }
+        }
         for (source, res) in parsed.allowed_domains.iter_values() {
             let data = match res {
                 Ok(data) => data,
@@ -279,18 +444,118 @@ impl TomlConfig {
                 );
             }
         }
+        for (source, res) in parsed.disposable_domains.iter_values() {
+            let data = match res {
+                Ok(data) => data,
+                Err(err) => panic!("IO error in disposable_domains entry {}: {}", source, err),
+            };
+            if let Err(err) = builder
+                .domain_validator
+                .add_disposable_domain(data.as_ref())
+            {
+                panic!(
+                    "Invalid disposable_domains entry {}: '{}': {}",
+                    source, data, err
+                );
+            }
+        }
+        for (source, res) in parsed.disposable_mail_servers.iter_values() {
+            let data = match res {
+                Ok(data) => data,
+                Err(err) => panic!(
+                    "IO error in disposable_mail_servers entry {}: {}",
+                    source, err
+                ),
+            };
+            if let Err(err) = builder
+                .domain_validator
+                .add_disposable_mail_server(data.as_ref())
+            {
+                panic!(
+                    "Invalid disposable_mail_servers entry {}: '{}': {}",
+                    source, data, err
+                );
+            }
+        }
+        if let Some(val) = parsed.block_disposable {
+            builder.domain_validator.block_disposable = val;
+        }
+        if let Some(val) = parsed.public_suffix_list {
+            if let Err(err) = builder
+                .domain_validator
+                .load_public_suffix_list(Some(val.as_path()))
+            {
+                panic!("Could not load public_suffix_list: {}", err);
+            }
+        }
         if let Some(val) = parsed.verify_with_resolver {
             builder
                 .domain_validator
                 .set_resolver(Some(val.as_str()).filter(|s| !s.is_empty()))
                 .expect("Invalid verify_with_resolver value");
         }
+        if let Some(val) = parsed.verify_dnssec {
+            builder.domain_validator.set_verify_dnssec(val);
+        }
+        if let Some(val) = parsed.verify_cache_ttl {
+            builder.domain_validator.verify_cache_ttl = Duration::from_secs(val);
+        }
         if let Some(val) = parsed.verify_public_ip {
             builder.domain_validator.verify_public_ip = val;
         }
         if let Some(val) = parsed.allowed_domains_only {
             builder.domain_validator.allowed_domains_only = val;
         }
+        for entry in parsed.origin_policies.unwrap_or_default() {
+            let policy = OriginPolicy {
+                bridge: entry.bridge,
+                token_ttl: entry.token_ttl.map(Duration::from_secs),
+                signing_algs: entry.signing_algs,
+                block_disposable: entry.block_disposable,
+            };
+            let index = builder.origin_policies.add(policy);
+            for origin in entry.origins {
+                if let Err(err) = builder.origin_policies.add_origin(index, &origin) {
+                    panic!("Invalid origin_policies origin '{}': {}", origin, err);
+                }
+            }
+            for (source, res) in entry.allowed_domains.iter_values() {
+                let data = match res {
+                    Ok(data) => data,
+                    Err(err) => panic!(
+                        "IO error in origin_policies allowed_domains entry {}: {}",
+                        source, err
+                    ),
+                };
+                if let Err(err) = builder
+                    .domain_validator
+                    .add_allowed_domain_for(index, data.as_ref())
+                {
+                    panic!(
+                        "Invalid origin_policies allowed_domains entry {}: '{}': {}",
+                        source, data, err
+                    );
+                }
+            }
+            for (source, res) in entry.blocked_domains.iter_values() {
+                let data = match res {
+                    Ok(data) => data,
+                    Err(err) => panic!(
+                        "IO error in origin_policies blocked_domains entry {}: {}",
+                        source, err
+                    ),
+                };
+                if let Err(err) = builder
+                    .domain_validator
+                    .add_blocked_domain_for(index, data.as_ref())
+                {
+                    panic!(
+                        "Invalid origin_policies blocked_domains entry {}: '{}': {}",
+                        source, data, err
+                    );
+                }
+            }
+        }
 
         if let Some(val) = parsed.static_ttl {
             builder.static_ttl = Duration::from_secs(val);
@@ -310,6 +575,12 @@ impl TomlConfig {
         if let Some(val) = parsed.cache_ttl {
             builder.cache_ttl = Duration::from_secs(val);
         }
+        if let Some(val) = parsed.cache_stale_while_revalidate {
+            builder.cache_stale_while_revalidate = Duration::from_secs(val);
+        }
+        if let Some(val) = parsed.cache_stale_if_error {
+            builder.cache_stale_if_error = Duration::from_secs(val);
+        }
 
         if let Some(mut val) = parsed.keyfiles {
             builder.keyfiles.append(&mut val);
@@ -324,15 +595,51 @@ impl TomlConfig {
             builder.generate_rsa_command = val;
         }
 
+        if let Some(val) = parsed.postgres_url {
+            builder.postgres_url = Some(val);
+        }
         if let Some(val) = parsed.redis_url {
             builder.redis_url = Some(val);
         }
+        if let Some(val) = parsed.redis_sentinel_master {
+            builder.redis_sentinel_master = Some(val);
+        }
+        if let Some(val) = parsed.redis_sentinels {
+            builder.redis_sentinels = val;
+        }
+        if let Some(val) = parsed.redis_key_prefix {
+            builder.redis_key_prefix = val;
+        }
         if let Some(val) = parsed.sqlite_db {
             builder.sqlite_db = Some(val);
         }
+        if let Some(val) = parsed.sqlite_wal {
+            builder.sqlite_wal = val;
+        }
+        if let Some(val) = parsed.sqlite_busy_timeout {
+            builder.sqlite_busy_timeout = Duration::from_secs(val);
+        }
+        if let Some(val) = parsed.sqlite_backup_before_migrate {
+            builder.sqlite_backup_before_migrate = val;
+        }
         if let Some(val) = parsed.memory_storage {
             builder.memory_storage = val;
         }
+        if let Some(val) = parsed.memory_max_cache_entries {
+            builder.memory_max_cache_entries = val;
+        }
+        if let Some(val) = parsed.memory_max_limit_entries {
+            builder.memory_max_limit_entries = val;
+        }
+        if let Some(val) = parsed.memory_snapshot_path {
+            builder.memory_snapshot_path = Some(val);
+        }
+        if let Some(val) = parsed.memory_snapshot_interval {
+            builder.memory_snapshot_interval = Duration::from_secs(val);
+        }
+        if let Some(val) = parsed.memory_snapshot_sessions {
+            builder.memory_snapshot_sessions = val;
+        }
 
         if let Some(val) = parsed.from_name {
             builder.from_name = val;
@@ -376,6 +683,38 @@ impl TomlConfig {
             log::warn!("TOML field 'limit_per_email' is deprecated. Please use 'limits' instead.");
             builder.limits = vec![val.0];
         }
+        if let Some(val) = parsed.limit_overrides {
+            for entry in val {
+                let mut scope = LimitScope::default();
+                scope.origins.extend(entry.origins);
+                for domain in entry.email_domains {
+                    match idna::domain_to_ascii(&domain) {
+                        Ok(domain) => scope.email_domains.insert(domain),
+                        Err(err) => panic!(
+                            "Invalid limit_overrides email domain '{}': {:?}",
+                            domain, err
+                        ),
+                    };
+                }
+                builder.limit_overrides.push((scope, entry.limits));
+            }
+        }
+        for (source, res) in parsed.limits_exempt.iter_values() {
+            let data = match res {
+                Ok(data) => data,
+                Err(err) => panic!("IO error in limits_exempt entry {}: {}", source, err),
+            };
+            match data.parse() {
+                Ok(net) => builder.limits_exempt.push(net),
+                Err(err) => panic!(
+                    "Invalid limits_exempt entry {}: '{}': {}",
+                    source, data, err
+                ),
+            }
+        }
+        if let Some(val) = parsed.challenge_difficulty {
+            builder.challenge_difficulty = val;
+        }
 
         if let Some(val) = parsed.google_client_id {
             builder.google_client_id = Some(val);
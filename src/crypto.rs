@@ -24,6 +24,9 @@ pub enum SigningAlgorithm {
 }
 
 impl SigningAlgorithm {
+    /// All supported algorithms.
+    pub const ALL: &'static [Self] = &[SigningAlgorithm::EdDsa, SigningAlgorithm::Rs256];
+
     /// Get the JWA string representation.
     pub fn as_str(self) -> &'static str {
         use SigningAlgorithm::*;
@@ -240,6 +243,11 @@ pub async fn create_jwt(
     signing_alg: SigningAlgorithm,
 ) -> Result<String, SignError> {
     let now = unix_duration();
+    let ttl = app
+        .origin_policies
+        .find_for_client_id(aud)
+        .and_then(|(_, policy)| policy.token_ttl)
+        .unwrap_or(app.token_ttl);
     app.key_manager
         .send(SignJws {
             payload: json!({
@@ -247,7 +255,7 @@ pub async fn create_jwt(
                 "email": email_addr.as_str(),
                 "email_verified": true,
                 "email_original": email,
-                "exp": (now + app.token_ttl).as_secs(),
+                "exp": (now + ttl).as_secs(),
                 "iat": now.as_secs(),
                 "iss": &app.public_url,
                 "sub": email_addr.as_str(),
@@ -1,3 +1,4 @@
+use crate::config::LimitExceeded;
 use crate::crypto::random_zbase32;
 use crate::utils::SecureRandom;
 use http::StatusCode;
@@ -17,7 +18,7 @@ pub enum BrokerError {
     /// Internal errors, which result in 500
     Internal(String),
     /// User was rate limited, results in 413
-    RateLimited,
+    RateLimited(LimitExceeded),
     /// User session not found, results in 400
     SessionExpired,
     /// Result status used by bridges to cancel a request
@@ -32,7 +33,7 @@ impl BrokerError {
             // User errors only at debug level.
             BrokerError::Input(_)
             | BrokerError::ProviderInput(_)
-            | BrokerError::RateLimited
+            | BrokerError::RateLimited(_)
             | BrokerError::SessionExpired
             | BrokerError::ProviderCancelled => {
                 debug!("{}", self);
@@ -66,7 +67,7 @@ impl BrokerError {
             }
             BrokerError::Provider(_) => StatusCode::SERVICE_UNAVAILABLE,
             BrokerError::Internal(_) => StatusCode::INTERNAL_SERVER_ERROR,
-            BrokerError::RateLimited => StatusCode::TOO_MANY_REQUESTS,
+            BrokerError::RateLimited(_) => StatusCode::TOO_MANY_REQUESTS,
             // Internal status that should never bubble this far
             BrokerError::ProviderCancelled => unreachable!(),
         }
@@ -78,7 +79,7 @@ impl BrokerError {
             BrokerError::Input(_) | BrokerError::SessionExpired => "invalid_request",
             BrokerError::Provider(_) | BrokerError::ProviderInput(_) => "temporarily_unavailable",
             BrokerError::Internal(_) => "server_error",
-            BrokerError::RateLimited => "access_denied",
+            BrokerError::RateLimited(_) => "access_denied",
             // Internal status that should never bubble this far
             BrokerError::ProviderCancelled => unreachable!(),
         }
@@ -94,7 +95,7 @@ impl fmt::Display for BrokerError {
             | BrokerError::Provider(ref description)
             | BrokerError::ProviderInput(ref description)
             | BrokerError::Internal(ref description) => description,
-            BrokerError::RateLimited => "too many requests",
+            BrokerError::RateLimited(_) => "too many requests",
             BrokerError::SessionExpired => "session has expired",
             BrokerError::ProviderCancelled => "bridge cancelled the request",
         })
@@ -1,17 +1,17 @@
-use crate::agents::{GetPublicJwks, IncrAndTestLimits};
-use crate::config::LimitInput;
-use crate::crypto::SigningAlgorithm;
+use crate::agents::{GetPublicJwks, IncrAndTestLimits, SaveChallenge, TakeChallenge};
+use crate::config::{LimitInput, OriginPolicy, RequiredBridge};
+use crate::crypto::{random_zbase32, SigningAlgorithm};
 use crate::email_address::EmailAddress;
 use crate::error::BrokerError;
-use crate::utils::DomainValidationError;
+use crate::utils::{pow, AddressCheck, DomainValidationError};
 use crate::validation::parse_redirect_uri;
 use crate::web::{html_response, json_response, Context, HandlerResult, ReturnParams};
 use crate::webfinger::{self, Relation};
 use crate::{bridges, metrics};
-use http::Method;
+use http::{Method, StatusCode};
 use log::info;
 use serde_json::{from_value, json, Value};
-use std::collections::HashSet;
+use std::collections::{HashMap, HashSet};
 use std::time::Duration;
 
 /// Request handler to return the OpenID Discovery document.
@@ -100,12 +100,16 @@ pub async fn auth(ctx: &mut Context) -> HandlerResult {
     });
 
     if let Some(ref whitelist) = ctx.app.allowed_origins {
-        if !whitelist.contains(&client_id) {
+        if !whitelist.matches(&redirect_uri_.origin()) {
             return Err(BrokerError::Input(
                 "the origin is not whitelisted".to_owned(),
             ));
         }
     }
+    let (policy_index, policy) = match ctx.app.origin_policies.find(&redirect_uri_.origin()) {
+        Some((index, policy)) => (Some(index), policy.clone()),
+        None => (None, OriginPolicy::default()),
+    };
 
     let nonce = try_get_input_param!(params, "nonce");
     if try_get_input_param!(params, "response_type") != "id_token" {
@@ -125,14 +129,18 @@ pub async fn auth(ctx: &mut Context) -> HandlerResult {
 
     // NOTE: This query parameter is non-standard.
     let signing_alg = try_get_input_param!(params, "id_token_signing_alg", "RS256".to_owned());
+    let signing_algs = policy
+        .signing_algs
+        .as_ref()
+        .unwrap_or(&ctx.app.signing_algs);
     let signing_alg = signing_alg
         .parse()
         .ok()
-        .filter(|alg| ctx.app.signing_algs.contains(alg))
+        .filter(|alg| signing_algs.contains(alg))
         .ok_or_else(|| {
             BrokerError::Input(format!(
                 "unsupported id_token_signing_alg, must be one of: {}",
-                SigningAlgorithm::format_list(&*ctx.app.signing_algs)
+                SigningAlgorithm::format_list(signing_algs)
             ))
         })?;
 
@@ -173,45 +181,106 @@ pub async fn auth(ctx: &mut Context) -> HandlerResult {
         BrokerError::Input(format!("login_hint is not a valid email address: {}", err))
     })?;
 
-    // Enforce rate limits.
-    match ctx
-        .app
-        .store
-        .send(IncrAndTestLimits {
-            input: LimitInput {
-                email_addr: email_addr.clone(),
-                origin: client_id.clone(),
-                ip: ctx.ip,
-            },
-        })
-        .await
-    {
-        Ok(true) => {}
-        Ok(false) => {
-            metrics::AUTH_LIMITED.inc();
-            return Err(BrokerError::RateLimited);
+    // A solved proof-of-work challenge is bound to the request it was issued for.
+    let challenge_data = format!("{}|{}|{}|{}", ctx.ip, client_id, email_addr, nonce);
+    let solved = match (params.remove("pow_challenge"), params.remove("pow_nonce")) {
+        (Some(challenge_id), Some(pow_nonce)) => {
+            verify_challenge(ctx, challenge_id, &pow_nonce, &challenge_data).await?
         }
-        Err(e) => {
-            return Err(BrokerError::Internal(format!(
-                "could not test rate limit: {}",
-                e
-            )))
+        _ => false,
+    };
+
+    // Enforce rate limits. The request was already counted when the challenge was issued, so a
+    // solved challenge skips this step.
+    if !solved {
+        let result = ctx
+            .app
+            .store
+            .send(IncrAndTestLimits {
+                input: LimitInput {
+                    email_addr: email_addr.clone(),
+                    origin: client_id.clone(),
+                    ip: ctx.ip,
+                },
+            })
+            .await;
+        match result {
+            Ok(None) => {}
+            Ok(Some(exceeded)) if exceeded.challenge => {
+                metrics::AUTH_CHALLENGED.inc();
+                return issue_challenge(ctx, challenge_data, &original_params).await;
+            }
+            Ok(Some(exceeded)) => {
+                metrics::AUTH_LIMITED.inc();
+                return Err(BrokerError::RateLimited(exceeded));
+            }
+            Err(e) => {
+                return Err(BrokerError::Internal(format!(
+                    "could not test rate limit: {}",
+                    e
+                )))
+            }
         }
     }
 
     // At this point, we've done all the local input verification.
     metrics::AUTH_REQUESTS.inc();
 
-    // Verify the email domain.
-    if let Err(err) = ctx.app.domain_validator.validate(email_addr.domain()).await {
-        err.apply_metric();
-        return Err(BrokerError::Input(
-            match err {
-                DomainValidationError::Blocked => "the domain of the email address is blocked",
-                _ => "the domain of the email address is invalid",
+    // Check the domain rules of the origin.
+    if let Some(policy_index) = policy_index {
+        if let Err(err) = ctx
+            .app
+            .domain_validator
+            .validate_for_policy(email_addr.domain(), policy_index)
+        {
+            err.apply_metric();
+            return Err(BrokerError::Input(
+                "the domain of the email address is not allowed for this site".to_owned(),
+            ));
+        }
+    }
+
+    // Check the email address lists. Allowed addresses skip domain verification.
+    match ctx.app.address_validator.check(&email_addr) {
+        AddressCheck::Allowed => {}
+        AddressCheck::Blocked => {
+            metrics::AUTH_ADDRESS_BLOCKED.inc();
+            return Err(BrokerError::Input(
+                "the email address is blocked".to_owned(),
+            ));
+        }
+        AddressCheck::Unlisted => {
+            // Verify the email domain.
+            let block_disposable = policy
+                .block_disposable
+                .unwrap_or(ctx.app.domain_validator.block_disposable);
+            if let Err(err) = ctx
+                .app
+                .domain_validator
+                .validate(email_addr.domain(), block_disposable)
+                .await
+            {
+                err.apply_metric();
+                if let DomainValidationError::Resolver(_) = err {
+                    return Err(BrokerError::Internal(format!(
+                        "could not validate the email domain: {}",
+                        err
+                    )));
+                }
+                return Err(BrokerError::Input(
+                    match err {
+                        DomainValidationError::Blocked => {
+                            "the domain of the email address is blocked"
+                        }
+                        DomainValidationError::Disposable => {
+                            "disposable email addresses are not allowed"
+                        }
+                        _ => "the domain of the email address is invalid",
+                    }
+                    .to_owned(),
+                ));
             }
-            .to_owned(),
-        ));
+        }
     }
 
     // Create the session with common data, but do not yet save it.
@@ -225,6 +294,10 @@ pub async fn auth(ctx: &mut Context) -> HandlerResult {
     )
     .await;
 
+    if policy.bridge == Some(RequiredBridge::Email) {
+        return bridges::email::auth(ctx, email_addr).await;
+    }
+
     // Discover the authentication endpoints based on the email domain.
     let discovery_future = async {
         let links = webfinger::query(&ctx.app, &email_addr).await?;
@@ -275,6 +348,96 @@ pub async fn auth(ctx: &mut Context) -> HandlerResult {
         }
     }
 
-    // Fall back to email loop auth.
+    // Fall back to email loop auth, unless the origin requires a provider.
+    if policy.bridge == Some(RequiredBridge::Oidc) {
+        return Err(BrokerError::Input(
+            "no identity provider was found for the email address, which this site requires"
+                .to_owned(),
+        ));
+    }
     bridges::email::auth(ctx, email_addr).await
 }
+
+/// Check a proof-of-work solution submitted with an authentication request.
+///
+/// Challenges are single-use, and only valid for the request they were issued for.
+async fn verify_challenge(
+    ctx: &Context,
+    challenge_id: String,
+    pow_nonce: &str,
+    challenge_data: &str,
+) -> Result<bool, BrokerError> {
+    let data = ctx
+        .app
+        .store
+        .send(TakeChallenge {
+            challenge_id: challenge_id.clone(),
+        })
+        .await
+        .map_err(|e| BrokerError::Internal(format!("could not load challenge: {}", e)))?;
+    let valid = data.as_deref() == Some(challenge_data)
+        && pow::verify(&challenge_id, pow_nonce, ctx.app.challenge_difficulty);
+    if !valid {
+        metrics::AUTH_CHALLENGE_FAILED.inc();
+    }
+    Ok(valid)
+}
+
+/// Respond with a proof-of-work challenge the client must solve before retrying.
+async fn issue_challenge(
+    ctx: &Context,
+    challenge_data: String,
+    params: &HashMap<String, String>,
+) -> HandlerResult {
+    let challenge_id = random_zbase32(20, &ctx.app.rng).await;
+    ctx.app
+        .store
+        .send(SaveChallenge {
+            challenge_id: challenge_id.clone(),
+            data: challenge_data,
+        })
+        .await
+        .map_err(|e| BrokerError::Internal(format!("could not save challenge: {}", e)))?;
+
+    let difficulty = ctx.app.challenge_difficulty.to_string();
+    let mut res = if ctx.want_json() {
+        json_response(
+            &json!({
+                "error": "challenge_required",
+                "error_description": "solve the proof-of-work challenge and retry the request",
+                "pow_challenge": challenge_id,
+                "pow_difficulty": ctx.app.challenge_difficulty,
+            }),
+            None,
+        )
+    } else {
+        let catalog = ctx.catalog();
+        let data = mustache::MapBuilder::new()
+            .insert_str("title", catalog.gettext("Verifying your browser"))
+            .insert_str(
+                "explanation",
+                catalog.gettext("We've received many login attempts from your network. Please wait a moment while your browser completes a security check."),
+            )
+            .insert_str(
+                "noscript",
+                catalog.gettext("JavaScript is required to complete this check."),
+            )
+            .insert_str("challenge", challenge_id)
+            .insert_str("difficulty", difficulty)
+            .insert_vec("params", |mut builder| {
+                for (name, value) in params {
+                    if name.starts_with("pow_") {
+                        continue;
+                    }
+                    builder = builder.push_map(|builder| {
+                        builder.insert_str("name", name).insert_str("value", value)
+                    });
+                }
+                builder
+            })
+            .build();
+        html_response(ctx.app.templates.challenge.render_data(&data))
+    };
+    *res.status_mut() = StatusCode::TOO_MANY_REQUESTS;
+    Ok(res)
+}
@@ -27,7 +27,7 @@ mod validation;
 mod web;
 mod webfinger;
 
-use crate::agents::{Expiring, ImportKeySet, KeySet};
+use crate::agents::{Expiring, ExportData, ImportData, ImportKeySet, KeySet, StoreSender};
 use crate::config::{ConfigBuilder, ConfigRc};
 use crate::crypto::SigningAlgorithm;
 use crate::utils::{
@@ -43,6 +43,7 @@ use std::{
     io::{Cursor, Read},
     net::SocketAddr,
     path::{Path, PathBuf},
+    sync::Arc,
     time::SystemTime,
 };
 
@@ -58,6 +59,7 @@ Portier Broker
 Usage:
   portier-broker [CONFIG]
   portier-broker [CONFIG] --import-key FILE
+  portier-broker migrate-store --from FROM --to TO [--sessions] [--limits] [--dry-run]
   portier-broker --version
   portier-broker --help
 
@@ -65,14 +67,25 @@ Options:
   --version          Print version information and exit
   --help             Print this help message and exit
   --import-key FILE  Import a PEM private key, for migrating to rotating keys
+  --from FROM        Configuration file of the store to copy data from
+  --to TO            Configuration file of the store to copy data to
+  --sessions         Also copy active sessions
+  --limits           Also copy rate limit state
+  --dry-run          Only report what would be copied
 "#;
 
 /// Holds parsed command line parameters.
 #[derive(Deserialize)]
-#[allow(non_snake_case)]
+#[allow(non_snake_case, clippy::struct_excessive_bools)]
 struct Args {
     arg_CONFIG: Option<PathBuf>,
+    cmd_migrate_store: bool,
     flag_import_key: Option<PathBuf>,
+    flag_from: Option<PathBuf>,
+    flag_to: Option<PathBuf>,
+    flag_sessions: bool,
+    flag_limits: bool,
+    flag_dry_run: bool,
 }
 
 /// The `main()` method. Will loop forever to serve HTTP requests.
@@ -93,6 +106,10 @@ async fn main() {
         .and_then(|docopt| docopt.deserialize())
         .unwrap_or_else(|e| e.exit());
 
+    if args.cmd_migrate_store {
+        migrate_store(&args).await;
+    }
+
     let mut builder = ConfigBuilder::new();
     if let Some(ref path) = args.arg_CONFIG {
         builder.update_from_file(path);
@@ -208,3 +225,50 @@ async fn import_key(builder: ConfigBuilder, file: &Path) {
     // (Currently, if a Redis store is simply dropped, the pubsub task panics.)
     std::process::exit(0);
 }
+
+async fn migrate_store(args: &Args) {
+    let from = args.flag_from.as_ref().unwrap();
+    let to = args.flag_to.as_ref().unwrap();
+
+    let data = open_store(from)
+        .await
+        .send(ExportData {
+            sessions: args.flag_sessions,
+            limits: args.flag_limits,
+        })
+        .await
+        .unwrap_or_else(|err| panic!("Could not read from store '{}': {}", from.display(), err));
+    eprintln!(
+        "Key sets: {}, sessions: {}, rate limit entries: {}",
+        data.key_sets.len(),
+        data.sessions.len(),
+        data.limits.len()
+    );
+
+    if args.flag_dry_run {
+        eprintln!("Dry run, nothing was written");
+    } else {
+        open_store(to)
+            .await
+            .send(ImportData(data))
+            .await
+            .unwrap_or_else(|err| panic!("Could not write to store '{}': {}", to.display(), err));
+        eprintln!("Successfully copied data to '{}'", to.display());
+    }
+
+    // TODO: This is a little hacky, but we don't have code to shutdown gracefully.
+    std::process::exit(0);
+}
+
+/// Open the store configured in a file, ignoring environment variables.
+async fn open_store(path: &Path) -> Arc<dyn StoreSender> {
+    let mut builder = ConfigBuilder::new();
+    builder.update_from_file(path);
+    builder.into_store().await.unwrap_or_else(|err| {
+        panic!(
+            "failed to build configuration '{}': {}",
+            path.display(),
+            err
+        )
+    })
+}
@@ -35,6 +35,21 @@ lazy_static::lazy_static! {
         "Number of rate-limited authentication requests"
     ).unwrap();
 
+    pub static ref AUTH_ADDRESS_BLOCKED: IntCounter = register_int_counter!(
+        "portier_auth_address_blocked",
+        "Number of authentication requests for blocked email addresses"
+    ).unwrap();
+
+    pub static ref AUTH_CHALLENGED: IntCounter = register_int_counter!(
+        "portier_auth_challenged",
+        "Number of authentication requests that were sent a proof-of-work challenge"
+    ).unwrap();
+
+    pub static ref AUTH_CHALLENGE_FAILED: IntCounter = register_int_counter!(
+        "portier_auth_challenge_failed",
+        "Number of authentication requests with an invalid proof-of-work solution"
+    ).unwrap();
+
     pub static ref AUTH_REQUESTS: IntCounter = register_int_counter!(
         "portier_auth_requests",
         "Number of authentication requests"
@@ -105,4 +120,32 @@ lazy_static::lazy_static! {
         DOMAIN_VALIDATION_ERROR.with_label_values(&["no_servers"]);
     pub static ref DOMAIN_VALIDATION_NO_PUBLIC_IPS: IntCounter =
         DOMAIN_VALIDATION_ERROR.with_label_values(&["no_public_ips"]);
+    pub static ref DOMAIN_VALIDATION_BOGUS: IntCounter =
+        DOMAIN_VALIDATION_ERROR.with_label_values(&["dnssec_bogus"]);
+    pub static ref DOMAIN_VALIDATION_DISPOSABLE: IntCounter =
+        DOMAIN_VALIDATION_ERROR.with_label_values(&["disposable"]);
+    pub static ref DOMAIN_VALIDATION_RESOLVER: IntCounter =
+        DOMAIN_VALIDATION_ERROR.with_label_values(&["resolver"]);
+
+    pub static ref FETCH_ERROR: IntCounterVec = register_int_counter_vec!(
+        "portier_fetch_error",
+        "Number of failed outgoing HTTP requests",
+        &["reason"]
+    ).unwrap();
+    pub static ref FETCH_ERROR_HTTP: IntCounter =
+        FETCH_ERROR.with_label_values(&["http"]);
+    pub static ref FETCH_ERROR_CONNECT_TIMEOUT: IntCounter =
+        FETCH_ERROR.with_label_values(&["connect_timeout"]);
+    pub static ref FETCH_ERROR_TIMEOUT: IntCounter =
+        FETCH_ERROR.with_label_values(&["timeout"]);
+    pub static ref FETCH_ERROR_BAD_STATUS: IntCounter =
+        FETCH_ERROR.with_label_values(&["bad_status"]);
+    pub static ref FETCH_ERROR_REDIRECT_LIMIT: IntCounter =
+        FETCH_ERROR.with_label_values(&["redirect_limit"]);
+    pub static ref FETCH_ERROR_INVALID_REDIRECT: IntCounter =
+        FETCH_ERROR.with_label_values(&["invalid_redirect"]);
+    pub static ref FETCH_ERROR_TOO_LARGE: IntCounter =
+        FETCH_ERROR.with_label_values(&["too_large"]);
+    pub static ref FETCH_ERROR_INVALID_BODY: IntCounter =
+        FETCH_ERROR.with_label_values(&["invalid_body"]);
 }
@@ -0,0 +1,169 @@
+use std::collections::HashSet;
+use thiserror::Error;
+
+use crate::email_address::{EmailAddress, ParseEmailError};
+use crate::utils::pattern_set::PatternSet;
+
+/// Errors produced when adding a rule to the address allow/block-lists.
+#[derive(Debug, Error)]
+pub enum AddressRuleError {
+    #[error("invalid email address: {0}")]
+    Invalid(#[from] ParseEmailError),
+    #[error("invalid pattern: {0}")]
+    Pattern(#[from] regex::Error),
+    #[error("patterns can only be followed by `@domain`")]
+    PatternSuffix,
+}
+
+/// Outcome of `AddressValidator::check`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum AddressCheck {
+    /// The address is in the allow-list, and skips domain validation.
+    Allowed,
+    /// The address is in the block-list.
+    Blocked,
+    /// The address is in neither list.
+    Unlisted,
+}
+
+/// A list of address rules, indexed for fast matching.
+#[derive(Default)]
+struct AddressList {
+    /// Normalized addresses, like `alice@example.com`.
+    exact: HashSet<String>,
+    /// Regular expressions for the local part, like `.*\+.*` for `/.*\+.*/`.
+    patterns: PatternSet,
+    /// Domains `patterns` are limited to, like `example.com` for `/.*\+.*/@example.com`.
+    pattern_domains: Vec<Option<String>>,
+}
+
+impl AddressList {
+    fn add(&mut self, rule: &str) -> Result<(), AddressRuleError> {
+        if let Some(rule) = rule.strip_prefix('/') {
+            if let Some(end) = rule.rfind('/') {
+                let (pattern, domain) = (&rule[..end], &rule[end + 1..]);
+                let domain = match domain.strip_prefix('@') {
+                    // Parse a dummy address to normalize the domain.
+                    Some(domain) => Some(
+                        format!("x@{}", domain)
+                            .parse::<EmailAddress>()?
+                            .domain()
+                            .to_owned(),
+                    ),
+                    None if domain.is_empty() => None,
+                    None => return Err(AddressRuleError::PatternSuffix),
+                };
+                self.patterns.add(pattern)?;
+                self.pattern_domains.push(domain);
+                return Ok(());
+            }
+        }
+
+        let addr: EmailAddress = rule.parse()?;
+        self.exact.insert(addr.into_string());
+        Ok(())
+    }
+
+    fn finish(&mut self) -> Result<(), regex::Error> {
+        self.patterns.finish()
+    }
+
+    fn matches(&self, addr: &EmailAddress) -> bool {
+        if self.exact.contains(addr.as_str()) {
+            return true;
+        }
+
+        self.patterns.matches(addr.local()).into_iter().any(|idx| {
+            self.pattern_domains[idx]
+                .as_ref()
+                .map_or(true, |domain| domain == addr.domain())
+        })
+    }
+}
+
+/// Validates email addresses against allow/block-lists.
+#[derive(Default)]
+pub struct AddressValidator {
+    /// Address rules to allow.
+    allowed_addresses: AddressList,
+    /// Address rules to block.
+    blocked_addresses: AddressList,
+}
+
+impl AddressValidator {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Add a rule to the list of allowed addresses.
+    ///
+    /// This is either an exact address, or a regular expression between slashes that matches the
+    /// local part, optionally followed by `@example.com` to limit it to a domain.
+    pub fn add_allowed_address(&mut self, rule: &str) -> Result<(), AddressRuleError> {
+        self.allowed_addresses.add(rule)
+    }
+
+    /// Add a rule to the list of blocked addresses.
+    ///
+    /// Rules are the same as for `add_allowed_address`.
+    pub fn add_blocked_address(&mut self, rule: &str) -> Result<(), AddressRuleError> {
+        self.blocked_addresses.add(rule)
+    }
+
+    /// Compile the patterns in both lists. Must be called after adding the last rule.
+    pub fn finish(&mut self) -> Result<(), regex::Error> {
+        self.allowed_addresses.finish()?;
+        self.blocked_addresses.finish()
+    }
+
+    /// Check a normalized address. The allow-list takes precedence.
+    pub fn check(&self, addr: &EmailAddress) -> AddressCheck {
+        if self.allowed_addresses.matches(addr) {
+            AddressCheck::Allowed
+        } else if self.blocked_addresses.matches(addr) {
+            AddressCheck::Blocked
+        } else {
+            AddressCheck::Unlisted
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_address_lists() {
+        let mut validator = AddressValidator::new();
+        validator
+            .add_allowed_address("Contractor@Gmail.com")
+            .unwrap();
+        validator
+            .add_allowed_address("/ops-.*/@example.com")
+            .unwrap();
+        validator.add_blocked_address("abuser@example.com").unwrap();
+        validator.add_blocked_address("/.*\\+.*/").unwrap();
+        assert!(matches!(
+            validator.add_blocked_address("/(/"),
+            Err(AddressRuleError::Pattern(_))
+        ));
+        assert!(matches!(
+            validator.add_blocked_address("/x/example.com"),
+            Err(AddressRuleError::PatternSuffix)
+        ));
+        assert!(matches!(
+            validator.add_blocked_address("nobody"),
+            Err(AddressRuleError::Invalid(_))
+        ));
+        validator.finish().unwrap();
+
+        let check = |addr: &str| validator.check(&addr.parse().unwrap());
+        assert_eq!(check("contractor@gmail.com"), AddressCheck::Allowed);
+        assert_eq!(check("OPS-alice@example.com"), AddressCheck::Allowed);
+        assert_eq!(check("ops-alice+tag@example.com"), AddressCheck::Allowed);
+        assert_eq!(check("ops-alice@example.org"), AddressCheck::Unlisted);
+        assert_eq!(check("abuser@example.com"), AddressCheck::Blocked);
+        assert_eq!(check("alice+tag@example.org"), AddressCheck::Blocked);
+        assert_eq!(check("alice@example.com"), AddressCheck::Unlisted);
+    }
+}
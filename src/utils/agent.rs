@@ -133,6 +133,18 @@ pub trait Agent: Send + Sized + 'static {
     }
 }
 
+/// Message loop for agents whose handlers never block.
+///
+/// Agents can call this from their `spawn_loop` implementation. Handlers are called directly,
+/// without `block_in_place`, so this also works on a single-threaded runtime.
+pub fn spawn_nonblocking_loop<A: Agent>(mut agent: A, mut rx: mpsc::Receiver<DispatchFn<A>>) {
+    tokio::spawn(async move {
+        while let Some(dispatch) = rx.recv().await {
+            dispatch(&mut agent);
+        }
+    });
+}
+
 /// Start the agent.
 ///
 /// This function starts the agent message loop, and waits for the `started` method to complete.
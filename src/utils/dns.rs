@@ -0,0 +1,333 @@
+use hyper::Uri;
+use std::io::{self, ErrorKind};
+use std::net::{IpAddr, SocketAddr, ToSocketAddrs};
+use std::time::Duration;
+use trust_dns_resolver::{
+    config::{NameServerConfig, Protocol, ResolverConfig, ResolverOpts},
+    error::{ResolveError, ResolveErrorKind},
+    proto::{
+        error::ProtoErrorKind,
+        op::ResponseCode,
+        rr::{RData, Record, RecordType},
+        xfer::DnsRequestOptions,
+    },
+    Name, TokioAsyncResolver,
+};
+
+/// Timeout for each query.
+const TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Path of DNS-over-HTTPS queries. This is the only path supported by `trust-dns`.
+const DNS_QUERY_PATH: &str = "/dns-query";
+
+/// Messages of the errors `trust-dns` produces when DNSSEC validation fails.
+const DNSSEC_FAILURES: &[&str] = &[
+    "could not validate negative response missing SOA",
+    "could not validate negative response with NSEC",
+    "exceeded max validation depth",
+    "no results to verify",
+    "Could not validate all DNSKEYs",
+    "self-signed dnskey is invalid",
+    "validation failed",
+    "revoked",
+    "is not a zone key",
+    "mismatched algorithm",
+];
+
+/// An upstream DNS server.
+#[derive(Clone, Debug)]
+pub struct DnsUpstream {
+    /// Either plain DNS over UDP, DNS-over-TLS or DNS-over-HTTPS.
+    protocol: Protocol,
+    addrs: Vec<SocketAddr>,
+    /// Name used to verify the server certificate, for DNS-over-TLS and DNS-over-HTTPS.
+    tls_dns_name: Option<String>,
+}
+
+impl DnsUpstream {
+    /// Parse an upstream from configuration.
+    ///
+    /// This is `host:port` for plain DNS, `tls://host[:port]` for DNS-over-TLS, or an
+    /// `https://host[:port]/dns-query` URL for DNS-over-HTTPS. Host names of upstreams are
+    /// resolved using the system resolver.
+    pub fn parse(value: &str) -> Result<Self, io::Error> {
+        let invalid = |msg: &str| io::Error::new(ErrorKind::InvalidInput, msg.to_owned());
+        let (protocol, default_port, uri) = if value.starts_with("https://") {
+            let uri: Uri = value
+                .parse()
+                .map_err(|_| invalid("invalid DNS-over-HTTPS URL"))?;
+            if uri.path() != DNS_QUERY_PATH || uri.query().is_some() {
+                return Err(invalid("DNS-over-HTTPS URL must have the path /dns-query"));
+            }
+            (Protocol::Https, 443, uri)
+        } else if value.starts_with("tls://") {
+            let uri: Uri = value
+                .parse()
+                .map_err(|_| invalid("invalid DNS-over-TLS address"))?;
+            (Protocol::Tls, 853, uri)
+        } else {
+            return Ok(DnsUpstream {
+                protocol: Protocol::Udp,
+                addrs: value.to_socket_addrs()?.collect(),
+                tls_dns_name: None,
+            });
+        };
+
+        let name = uri
+            .host()
+            .filter(|host| !host.is_empty())
+            .ok_or_else(|| invalid("DNS server address has no host"))?;
+        // Certificates are verified using `webpki`, which only supports names.
+        if name.starts_with('[') || name.parse::<IpAddr>().is_ok() {
+            return Err(invalid(
+                "DNS-over-TLS and DNS-over-HTTPS servers must be configured by host name",
+            ));
+        }
+        let port = uri.port_u16().unwrap_or(default_port);
+        Ok(DnsUpstream {
+            protocol,
+            addrs: (name, port).to_socket_addrs()?.collect(),
+            tls_dns_name: Some(name.to_owned()),
+        })
+    }
+}
+
+/// DNS client used for email domain validation.
+pub struct DnsClient {
+    resolver: TokioAsyncResolver,
+    dnssec: bool,
+}
+
+impl DnsClient {
+    /// Create a client for the upstream, optionally validating answers using DNSSEC.
+    pub fn new(upstream: &DnsUpstream, dnssec: bool) -> Self {
+        let mut cfg = ResolverConfig::new();
+        for &socket_addr in &upstream.addrs {
+            cfg.add_name_server(NameServerConfig {
+                socket_addr,
+                protocol: upstream.protocol,
+                tls_dns_name: upstream.tls_dns_name.clone(),
+                trust_nx_responses: true,
+                tls_config: None,
+            });
+        }
+
+        let opts = ResolverOpts {
+            // Email domains must always be FQDNs.
+            ndots: 0,
+            // Tighter timeouts and retries, because we're handling a user agent request.
+            timeout: TIMEOUT,
+            attempts: 1,
+            // Unless configured, trust the server, don't do DNSSEC ourselves.
+            validate: dnssec,
+            // Leave all caching to the server.
+            cache_size: 0,
+            // Per our config docs, using `/etc/hosts` would be surprising behaviour.
+            use_hosts_file: false,
+            ..ResolverOpts::default()
+        };
+
+        // Unwrap, because this currently doesn't appear to fail ever.
+        let resolver = TokioAsyncResolver::tokio(cfg, opts).unwrap();
+        DnsClient { resolver, dnssec }
+    }
+
+    /// Whether this client validates answers using DNSSEC.
+    pub fn is_validating(&self) -> bool {
+        self.dnssec
+    }
+
+    /// Start a session for a series of lookups.
+    pub fn session(&self) -> DnsSession {
+        DnsSession {
+            resolver: self.resolver.clone(),
+            min_ttl: None,
+        }
+    }
+}
+
+/// Whether a lookup error means DNSSEC validation failed.
+pub fn is_dnssec_failure(err: &ResolveError) -> bool {
+    match err.kind() {
+        ResolveErrorKind::Proto(err) => match err.kind() {
+            ProtoErrorKind::RrsigsNotPresent { .. } | ProtoErrorKind::Ring(_) => true,
+            ProtoErrorKind::Message(msg) => DNSSEC_FAILURES.contains(msg),
+            _ => false,
+        },
+        _ => false,
+    }
+}
+
+/// Whether a lookup error is an answer from the DNS server about the name.
+///
+/// Other errors mean the server could not be reached, or did not answer properly. This includes
+/// responses like `SERVFAIL`, which `trust-dns` also reports as `NoRecordsFound`.
+pub fn is_answer(err: &ResolveError) -> bool {
+    match err.kind() {
+        ResolveErrorKind::NoRecordsFound { response_code, .. } => {
+            *response_code == ResponseCode::NoError || *response_code == ResponseCode::NXDomain
+        }
+        _ => is_dnssec_failure(err),
+    }
+}
+
+/// A series of lookups against a `DnsClient`.
+pub struct DnsSession {
+    resolver: TokioAsyncResolver,
+    /// Lowest TTL of the answers so far.
+    min_ttl: Option<u32>,
+}
+
+impl DnsSession {
+    /// Lowest TTL of the answers in this session, in seconds.
+    ///
+    /// For answers without records, this is the negative caching TTL, if the server provided one.
+    /// Returns `None` if no TTLs were seen.
+    pub fn min_ttl(&self) -> Option<u32> {
+        self.min_ttl
+    }
+
+    fn add_ttl(&mut self, ttl: Option<u32>) {
+        if let Some(ttl) = ttl {
+            self.min_ttl = Some(self.min_ttl.map_or(ttl, |min| min.min(ttl)));
+        }
+    }
+
+    /// Look up the mail servers of a domain.
+    ///
+    /// Returns an empty list if the domain has no MX records.
+    pub async fn mx_lookup(&mut self, name: Name) -> Result<Vec<Name>, ResolveError> {
+        let rdata = self.lookup(name, RecordType::MX).await?;
+        Ok(rdata
+            .into_iter()
+            .filter_map(|rdata| match rdata {
+                RData::MX(mx) => Some(mx.exchange().clone()),
+                _ => None,
+            })
+            .collect())
+    }
+
+    /// Look up the IP addresses of a host.
+    ///
+    /// Returns an empty list if the host has no addresses.
+    pub async fn ip_lookup(&mut self, name: Name) -> Result<Vec<IpAddr>, ResolveError> {
+        // Assume mail servers still require IPv4, so try query only A-records first. This creates
+        // an edge case with `verify_public_ip`, where the mail server only has private IPv4, but
+        // public IPv6, yet we fail. We consider this extremely unlikely.
+        let mut rdata = self.lookup(name.clone(), RecordType::A).await?;
+        if rdata.is_empty() {
+            rdata = self.lookup(name, RecordType::AAAA).await?;
+        }
+        Ok(rdata
+            .into_iter()
+            .filter_map(|rdata| match rdata {
+                RData::A(ip) => Some(IpAddr::V4(ip)),
+                RData::AAAA(ip) => Some(IpAddr::V6(ip)),
+                _ => None,
+            })
+            .collect())
+    }
+
+    async fn lookup(
+        &mut self,
+        name: Name,
+        record_type: RecordType,
+    ) -> Result<Vec<RData>, ResolveError> {
+        let options = DnsRequestOptions {
+            use_edns: true,
+            ..DnsRequestOptions::default()
+        };
+        let (answers, negative_ttl) = match self.resolver.lookup(name, record_type, options).await {
+            Ok(lookup) => (lookup.record_iter().cloned().collect(), None),
+            Err(err) => match *err.kind() {
+                ResolveErrorKind::NoRecordsFound {
+                    response_code: ResponseCode::NoError,
+                    negative_ttl,
+                    ..
+                }
+                | ResolveErrorKind::NoRecordsFound {
+                    response_code: ResponseCode::NXDomain,
+                    negative_ttl,
+                    ..
+                } => (vec![], negative_ttl),
+                _ => return Err(err),
+            },
+        };
+        let answers: Vec<Record> = answers
+            .into_iter()
+            .filter(|record| record.rr_type() == record_type)
+            .collect();
+        if answers.is_empty() {
+            self.add_ttl(negative_ttl);
+        } else {
+            self.add_ttl(answers.iter().map(Record::ttl).min());
+        }
+        Ok(answers
+            .into_iter()
+            .map(|record| record.rdata().clone())
+            .collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use trust_dns_resolver::proto::{error::ProtoError, op::Query};
+
+    #[test]
+    fn test_parse_upstream() {
+        let upstream = DnsUpstream::parse("127.0.0.1:53").unwrap();
+        assert_eq!(upstream.protocol, Protocol::Udp);
+        assert_eq!(upstream.addrs, vec!["127.0.0.1:53".parse().unwrap()]);
+
+        let upstream = DnsUpstream::parse("tls://localhost").unwrap();
+        assert_eq!(upstream.protocol, Protocol::Tls);
+        assert_eq!(upstream.tls_dns_name.as_deref(), Some("localhost"));
+        assert!(upstream.addrs.iter().all(|addr| addr.port() == 853));
+
+        let upstream = DnsUpstream::parse("https://localhost:8443/dns-query").unwrap();
+        assert_eq!(upstream.protocol, Protocol::Https);
+        assert_eq!(upstream.tls_dns_name.as_deref(), Some("localhost"));
+        assert!(upstream.addrs.iter().all(|addr| addr.port() == 8443));
+
+        for value in &[
+            "tls://",
+            "tls://127.0.0.1",
+            "tls://[::1]",
+            "https://localhost/resolve",
+            "not an address",
+        ] {
+            assert!(DnsUpstream::parse(value).is_err(), "{}", value);
+        }
+    }
+
+    #[test]
+    fn test_is_dnssec_failure() {
+        let proto = |kind: ProtoErrorKind| ResolveError::from(ProtoError::from(kind));
+        let bogus = proto(ProtoErrorKind::Message("self-signed dnskey is invalid"));
+        assert!(is_dnssec_failure(&bogus));
+        assert!(is_answer(&bogus));
+
+        let unreachable = proto(ProtoErrorKind::Msg("h2 handshake error".to_owned()));
+        assert!(!is_dnssec_failure(&unreachable));
+        assert!(!is_answer(&unreachable));
+        assert!(!is_answer(&proto(ProtoErrorKind::Timeout)));
+    }
+
+    #[test]
+    fn test_is_answer() {
+        let no_records = |response_code: ResponseCode| {
+            ResolveError::from(ResolveErrorKind::NoRecordsFound {
+                query: Query::query(Name::root(), RecordType::MX),
+                soa: None,
+                negative_ttl: None,
+                response_code,
+                trusted: true,
+            })
+        };
+        assert!(is_answer(&no_records(ResponseCode::NoError)));
+        assert!(is_answer(&no_records(ResponseCode::NXDomain)));
+        assert!(!is_answer(&no_records(ResponseCode::ServFail)));
+        assert!(!is_answer(&no_records(ResponseCode::Refused)));
+    }
+}
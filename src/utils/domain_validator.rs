@@ -1,17 +1,35 @@
+use ring::digest;
+use serde::{Deserialize, Serialize};
 use std::{
-    collections::HashSet,
+    collections::{HashMap, HashSet},
     io,
-    net::{IpAddr, ToSocketAddrs},
+    net::{IpAddr, Ipv4Addr},
+    path::Path,
+    sync::Arc,
     time::Duration,
 };
 use thiserror::Error;
-use trust_dns_resolver::{
-    config::{LookupIpStrategy, NameServerConfig, Protocol, ResolverConfig, ResolverOpts},
-    proto::{error::ProtoError, rr::rdata::MX},
-    Name, TokioAsyncResolver,
-};
+use trust_dns_resolver::{error::ResolveError, proto::error::ProtoError, Name};
 
+use crate::agents::{GetDomainValidation, SaveDomainValidation, StoreSender};
 use crate::metrics;
+use crate::utils::base64url;
+use crate::utils::dns::{is_answer, is_dnssec_failure, DnsClient, DnsSession, DnsUpstream};
+use crate::utils::pattern_set::PatternSet;
+use crate::utils::public_suffix::{LoadSuffixListError, PublicSuffixList};
+
+/// Errors produced when adding a rule to the domain allow/block-lists.
+#[derive(Debug, Error)]
+pub enum DomainRuleError {
+    #[error("invalid domain name: {0}")]
+    Invalid(#[from] ProtoError),
+    #[error("invalid pattern: {0}")]
+    Pattern(#[from] regex::Error),
+    #[error("wildcards are only supported as `*.` at the start, use a pattern instead")]
+    Wildcard,
+    #[error("the rule matches every domain")]
+    Everything,
+}
 
 /// Errors produced by `DomainValidator::validate`.
 #[derive(Debug, Error)]
@@ -26,6 +44,12 @@ pub enum DomainValidationError {
     NoServers,
     #[error("none of the domain mail servers have public IP addresses")]
     NoPublicIps,
+    #[error("the DNS answers for the domain failed DNSSEC validation")]
+    Bogus,
+    #[error("the domain belongs to a disposable email provider")]
+    Disposable,
+    #[error("the DNS server failed: {0}")]
+    Resolver(Box<ResolveError>),
 }
 
 impl DomainValidationError {
@@ -37,97 +61,405 @@ impl DomainValidationError {
             Self::NullMx => metrics::DOMAIN_VALIDATION_NULL_MX.inc(),
             Self::NoServers => metrics::DOMAIN_VALIDATION_NO_SERVERS.inc(),
             Self::NoPublicIps => metrics::DOMAIN_VALIDATION_NO_PUBLIC_IPS.inc(),
+            Self::Bogus => metrics::DOMAIN_VALIDATION_BOGUS.inc(),
+            Self::Disposable => metrics::DOMAIN_VALIDATION_DISPOSABLE.inc(),
+            Self::Resolver(_) => metrics::DOMAIN_VALIDATION_RESOLVER.inc(),
+        }
+    }
+}
+
+/// Result of the mail server checks of a domain.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum DnsOutcome {
+    Valid,
+    NullMx,
+    NoServers,
+    NoPublicIps,
+}
+
+/// Outcome of the DNS checks of a domain, as cached in the store.
+///
+/// This does not depend on the Relying Party, so it can be shared between logins.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct DomainValidation {
+    pub outcome: DnsOutcome,
+    /// Whether the domain uses mail servers of a disposable email provider.
+    pub disposable_mx: bool,
+}
+
+impl DomainValidation {
+    fn into_result(self, block_disposable: bool) -> Result<(), DomainValidationError> {
+        if block_disposable && self.disposable_mx {
+            return Err(DomainValidationError::Disposable);
+        }
+        match self.outcome {
+            DnsOutcome::Valid => Ok(()),
+            DnsOutcome::NullMx => Err(DomainValidationError::NullMx),
+            DnsOutcome::NoServers => Err(DomainValidationError::NoServers),
+            DnsOutcome::NoPublicIps => Err(DomainValidationError::NoPublicIps),
+        }
+    }
+}
+
+/// Whether an IP address is in the public address space.
+///
+/// Rejects private, loopback, link-local (including cloud metadata endpoints), shared, broadcast,
+/// documentation, multicast, unspecified and unique local addresses.
+pub fn is_public_ip(ip: &IpAddr) -> bool {
+    // TODO: Once stabilized, use `is_global`, see: https://github.com/rust-lang/rust/issues/27709
+    match ip {
+        IpAddr::V4(ip) => {
+            let octets = ip.octets();
+            !ip.is_private()
+                && !ip.is_loopback()
+                && !ip.is_link_local()
+                && !ip.is_broadcast()
+                && !ip.is_documentation()
+                && !ip.is_multicast()
+                && !ip.is_unspecified()
+                // 0.0.0.0/8, "this network".
+                && octets[0] != 0
+                // 100.64.0.0/10, shared address space.
+                && !(octets[0] == 100 && (octets[1] & 0xc0) == 64)
+        }
+        IpAddr::V6(ip) => {
+            if let Some(ip) = ip.to_ipv4() {
+                // IPv4-mapped and IPv4-compatible addresses, except `::` and `::1`.
+                if !ip.is_unspecified() && ip.octets() != [0, 0, 0, 1] {
+                    return is_public_ip(&IpAddr::V4(ip));
+                }
+            }
+            let segments = ip.segments();
+            if segments[..6] == [0x64, 0xff9b, 0, 0, 0, 0] {
+                // 64:ff9b::/96, NAT64, which reaches the embedded IPv4 address.
+                let ip = Ipv4Addr::from(u32::from(segments[6]) << 16 | u32::from(segments[7]));
+                return is_public_ip(&IpAddr::V4(ip));
+            }
+            let first = segments[0];
+            !ip.is_multicast()
+                && !ip.is_loopback()
+                && !ip.is_unspecified()
+                // fc00::/7, unique local.
+                && (first & 0xfe00) != 0xfc00
+                // fe80::/10, link-local.
+                && (first & 0xffc0) != 0xfe80
+                // 2001:db8::/32, documentation.
+                && (first, segments[1]) != (0x2001, 0xdb8)
+        }
+    }
+}
+
+/// Parse a domain to a lowercase FQDN.
+fn parse_domain(domain: &str) -> Result<Name, ProtoError> {
+    let mut domain = Name::from_utf8(domain)?.to_lowercase();
+    domain.set_fqdn(true);
+    Ok(domain)
+}
+
+/// A list of domain rules, indexed for fast matching.
+#[derive(Default)]
+struct DomainList {
+    /// Exact domains, like `example.com`.
+    exact: HashSet<Name>,
+    /// Domains matched including subdomains, like `example.com` for `.example.com`.
+    suffixes: HashSet<Name>,
+    /// Domains matched only by subdomains, like `example.com` for `*.example.com`.
+    wildcards: HashSet<Name>,
+    /// Regular expressions, like `mx[0-9]+\.example\.com` for `/mx[0-9]+\.example\.com/`.
+    patterns: PatternSet,
+}
+
+impl DomainList {
+    fn add(&mut self, rule: &str) -> Result<(), DomainRuleError> {
+        if let Some(pattern) = rule
+            .strip_prefix('/')
+            .and_then(|rule| rule.strip_suffix('/'))
+        {
+            self.patterns.add(pattern)?;
+            return Ok(());
+        }
+
+        let (set, domain) = if let Some(domain) = rule.strip_prefix("*.") {
+            (&mut self.wildcards, domain)
+        } else if let Some(domain) = rule.strip_prefix('.') {
+            (&mut self.suffixes, domain)
+        } else {
+            (&mut self.exact, rule)
+        };
+        if domain.contains('*') {
+            return Err(DomainRuleError::Wildcard);
+        }
+        let domain = parse_domain(domain)?;
+        if domain.is_root() {
+            return Err(DomainRuleError::Everything);
+        }
+        set.insert(domain);
+        Ok(())
+    }
+
+    fn finish(&mut self) -> Result<(), regex::Error> {
+        self.patterns.finish()
+    }
+
+    fn is_empty(&self) -> bool {
+        self.exact.is_empty()
+            && self.suffixes.is_empty()
+            && self.wildcards.is_empty()
+            && self.patterns.is_empty()
+    }
+
+    /// Whether a lowercase FQDN matches, or its registrable domain matches an exact rule.
+    fn matches(&self, domain: &Name, registrable: Option<&Name>) -> bool {
+        if self.exact.contains(domain) || registrable.map_or(false, |r| self.exact.contains(r)) {
+            return true;
+        }
+
+        let num_labels = domain.num_labels();
+        for n in 1..=num_labels {
+            let base = domain.trim_to(n as usize);
+            if self.suffixes.contains(&base) || (n < num_labels && self.wildcards.contains(&base)) {
+                return true;
+            }
         }
+
+        let ascii = domain.to_ascii();
+        self.patterns.is_match(ascii.trim_end_matches('.'))
     }
 }
 
+/// Domain rules for a specific Relying Party origin.
+#[derive(Default)]
+struct OriginDomains {
+    allowed: DomainList,
+    blocked: DomainList,
+}
+
 /// Validates domains based on some configuration.
+#[allow(clippy::struct_excessive_bools)]
 pub struct DomainValidator {
-    /// Exact domains to allow.
-    allowed_domains: HashSet<Name>,
-    /// Exact domains to block.
-    blocked_domains: HashSet<Name>,
-    /// DNS resolver for email domain validation.
-    dns_resolver: Option<TokioAsyncResolver>,
+    /// Domain rules to allow.
+    allowed_domains: DomainList,
+    /// Domain rules to block.
+    blocked_domains: DomainList,
+    /// Domain rules for disposable email providers.
+    disposable_domains: DomainList,
+    /// Mail server rules for disposable email providers.
+    disposable_mail_servers: DomainList,
+    /// Domain rules of origin policies, by index of the policy.
+    origin_domains: HashMap<usize, OriginDomains>,
+    /// Public suffix list used to match exact rules by registrable domain.
+    public_suffix_list: Option<PublicSuffixList>,
+    /// DNS server for email domain validation.
+    dns_upstream: Option<DnsUpstream>,
+    /// Whether to validate DNS answers using DNSSEC.
+    verify_dnssec: bool,
+    /// DNS client for email domain validation.
+    dns_client: Option<DnsClient>,
+    /// Store used to cache DNS validation outcomes.
+    cache: Option<Arc<dyn StoreSender>>,
+    /// Digest of the rules in `disposable_mail_servers`.
+    disposable_mail_servers_digest: digest::Context,
+    /// Prefix of cache keys, derived from the settings that DNS validation outcomes depend on.
+    cache_prefix: String,
+    /// Maximum time to cache DNS validation outcomes. Zero disables caching.
+    pub verify_cache_ttl: Duration,
     /// Whether to ignore reserved IP addresses in DNS results.
     pub verify_public_ip: bool,
     /// Whether to treat anything not in the allow-list as blocked.
     pub allowed_domains_only: bool,
+    /// Whether to block disposable email providers, unless overridden by an origin policy.
+    pub block_disposable: bool,
 }
 
 impl DomainValidator {
     pub fn new() -> Self {
         Self {
-            allowed_domains: HashSet::new(),
-            blocked_domains: HashSet::new(),
-            dns_resolver: None,
+            allowed_domains: DomainList::default(),
+            blocked_domains: DomainList::default(),
+            disposable_domains: DomainList::default(),
+            disposable_mail_servers: DomainList::default(),
+            origin_domains: HashMap::new(),
+            public_suffix_list: None,
+            dns_upstream: None,
+            verify_dnssec: false,
+            dns_client: None,
+            cache: None,
+            disposable_mail_servers_digest: digest::Context::new(&digest::SHA256),
+            cache_prefix: String::new(),
+            verify_cache_ttl: Duration::from_secs(3600),
             verify_public_ip: true,
             allowed_domains_only: false,
+            block_disposable: true,
         }
     }
 
-    /// Add a domain to the set of allowed domains.
-    pub fn add_allowed_domain(&mut self, domain: &str) -> Result<(), ProtoError> {
-        let mut domain = Name::from_utf8(domain)?.to_lowercase();
-        domain.set_fqdn(true);
-        self.allowed_domains.insert(domain);
+    /// Add a rule to the list of allowed domains.
+    ///
+    /// This is either an exact domain, `*.example.com` to match subdomains, `.example.com` to
+    /// match the domain and subdomains, or a regular expression between slashes.
+    pub fn add_allowed_domain(&mut self, rule: &str) -> Result<(), DomainRuleError> {
+        self.allowed_domains.add(rule)
+    }
+
+    /// Add a rule to the list of blocked domains.
+    ///
+    /// Rules are the same as for `add_allowed_domain`.
+    pub fn add_blocked_domain(&mut self, rule: &str) -> Result<(), DomainRuleError> {
+        self.blocked_domains.add(rule)
+    }
+
+    /// Add a rule to the list of disposable email provider domains.
+    ///
+    /// Rules are the same as for `add_allowed_domain`.
+    pub fn add_disposable_domain(&mut self, rule: &str) -> Result<(), DomainRuleError> {
+        self.disposable_domains.add(rule)
+    }
+
+    /// Add a rule to the list of disposable email provider mail servers.
+    ///
+    /// Rules are the same as for `add_allowed_domain`, but are matched against MX records.
+    pub fn add_disposable_mail_server(&mut self, rule: &str) -> Result<(), DomainRuleError> {
+        self.disposable_mail_servers.add(rule)?;
+        self.disposable_mail_servers_digest.update(rule.as_bytes());
+        self.disposable_mail_servers_digest.update(b"\n");
         Ok(())
     }
 
-    /// Add a domain to the set of blocked domains.
-    pub fn add_blocked_domain(&mut self, domain: &str) -> Result<(), ProtoError> {
-        let mut domain = Name::from_utf8(domain)?.to_lowercase();
-        domain.set_fqdn(true);
-        self.blocked_domains.insert(domain);
+    /// Add a rule to the list of allowed domains for an origin policy.
+    ///
+    /// Once a policy has allowed domains, any other domain is blocked for its origins. Rules are
+    /// the same as for `add_allowed_domain`.
+    pub fn add_allowed_domain_for(
+        &mut self,
+        policy: usize,
+        rule: &str,
+    ) -> Result<(), DomainRuleError> {
+        self.origin_domains
+            .entry(policy)
+            .or_default()
+            .allowed
+            .add(rule)
+    }
+
+    /// Add a rule to the list of blocked domains for an origin policy.
+    ///
+    /// Rules are the same as for `add_allowed_domain`.
+    pub fn add_blocked_domain_for(
+        &mut self,
+        policy: usize,
+        rule: &str,
+    ) -> Result<(), DomainRuleError> {
+        self.origin_domains
+            .entry(policy)
+            .or_default()
+            .blocked
+            .add(rule)
+    }
+
+    /// Load a public suffix list, after which exact rules also match by registrable domain.
+    pub fn load_public_suffix_list(
+        &mut self,
+        path: Option<&Path>,
+    ) -> Result<(), LoadSuffixListError> {
+        self.public_suffix_list = path.map(PublicSuffixList::load).transpose()?;
         Ok(())
     }
 
-    /// Set the DNS resolver for email domain validation.
+    /// Set the DNS server for email domain validation.
+    ///
+    /// This is either `host:port` for plain DNS, `tls://host[:port]` for DNS-over-TLS, or an
+    /// `https://host[:port]/dns-query` URL for DNS-over-HTTPS.
     pub fn set_resolver(&mut self, addr: Option<&str>) -> Result<(), io::Error> {
-        let addr = match addr {
-            Some(addr) => addr,
-            None => {
-                self.dns_resolver = None;
-                return Ok(());
-            }
-        };
+        self.dns_upstream = addr.map(DnsUpstream::parse).transpose()?;
+        self.update_client();
+        Ok(())
+    }
 
-        let mut cfg = ResolverConfig::new();
-        for socket_addr in addr.to_socket_addrs()? {
-            cfg.add_name_server(NameServerConfig {
-                socket_addr,
-                protocol: Protocol::Udp,
-                tls_dns_name: None,
-                trust_nx_responses: true,
-            });
+    /// Set whether to validate DNS answers using DNSSEC.
+    ///
+    /// This rejects any domain that is not signed.
+    pub fn set_verify_dnssec(&mut self, verify_dnssec: bool) {
+        self.verify_dnssec = verify_dnssec;
+        self.update_client();
+    }
+
+    fn update_client(&mut self) {
+        self.dns_client = self
+            .dns_upstream
+            .as_ref()
+            .map(|upstream| DnsClient::new(upstream, self.verify_dnssec));
+    }
+
+    /// Compile the patterns in all domain lists. Must be called after adding the last rule.
+    pub fn finish(&mut self) -> Result<(), regex::Error> {
+        self.allowed_domains.finish()?;
+        self.blocked_domains.finish()?;
+        self.disposable_domains.finish()?;
+        self.disposable_mail_servers.finish()?;
+        for domains in self.origin_domains.values_mut() {
+            domains.allowed.finish()?;
+            domains.blocked.finish()?;
         }
 
-        let opts = ResolverOpts {
-            // Email domains must always be FQDNs.
-            ndots: 0,
-            // Tighter timeouts and retries, because we're handling a user agent request.
-            timeout: Duration::from_secs(5),
-            attempts: 1,
-            // Trust the server, don't do DNSSEC ourselves.
-            validate: false,
-            // Assume mail servers still require IPv4, so try query only A-records first.
-            // This creates an edge case with `verify_public_ip`, where the mail server only has
-            // private IPv4, but public IPv6, yet we fail. We consider this extremely unlikely.
-            ip_strategy: LookupIpStrategy::Ipv4thenIpv6,
-            // Leave all caching to the server.
-            cache_size: 0,
-            // Per our config docs, using `/etc/hosts` would be surprising behaviour.
-            use_hosts_file: false,
-            ..ResolverOpts::default()
-        };
+        // Cached outcomes are not shared between different settings, even with a shared store.
+        let mut ctx = self.disposable_mail_servers_digest.clone();
+        ctx.update(&[
+            u8::from(self.verify_public_ip),
+            u8::from(self.verify_dnssec),
+            u8::from(self.public_suffix_list.is_some()),
+        ]);
+        self.cache_prefix = base64url::encode(&ctx.finish().as_ref()[..12]);
+        Ok(())
+    }
+
+    /// Key of a domain in the cache of DNS validation outcomes.
+    fn cache_key(&self, domain: &Name) -> String {
+        format!("{}:{}", self.cache_prefix, domain)
+    }
+
+    /// Set the store used to cache DNS validation outcomes.
+    pub fn set_cache(&mut self, store: Arc<dyn StoreSender>) {
+        self.cache = Some(store);
+    }
+
+    fn registrable_domain(&self, domain: &Name) -> Option<Name> {
+        self.public_suffix_list
+            .as_ref()
+            .and_then(|list| list.registrable_domain(domain))
+    }
 
-        // Unwrap, because this currently doesn't appear to fail ever.
-        self.dns_resolver = Some(TokioAsyncResolver::tokio(cfg, opts).unwrap());
+    /// Check the domain rules of an origin policy.
+    ///
+    /// These only restrict the domains accepted for the origins of the policy, and are checked
+    /// separately from `validate`, because allowed email addresses skip the latter.
+    pub fn validate_for_policy(
+        &self,
+        domain: &str,
+        policy: usize,
+    ) -> Result<(), DomainValidationError> {
+        let rules = match self.origin_domains.get(&policy) {
+            Some(rules) => rules,
+            None => return Ok(()),
+        };
+        let domain = parse_domain(domain).map_err(DomainValidationError::Invalid)?;
+        let registrable = self.registrable_domain(&domain);
+        if (!rules.allowed.is_empty() && !rules.allowed.matches(&domain, registrable.as_ref()))
+            || rules.blocked.matches(&domain, registrable.as_ref())
+        {
+            return Err(DomainValidationError::Blocked);
+        }
         Ok(())
     }
 
-    /// Validate a domain.
-    pub async fn validate(&self, domain: &str) -> Result<(), DomainValidationError> {
+    /// Validate a domain, optionally blocking disposable email providers.
+    ///
+    /// This is normally `block_disposable`, unless overridden by an origin policy.
+    pub async fn validate(
+        &self,
+        domain: &str,
+        block_disposable: bool,
+    ) -> Result<(), DomainValidationError> {
         // Use trust-dns to do domain name validation. This does the punycode transform for us, as
         // well as validating there are no invalid characters or empty labels.
         let mut domain = Name::from_utf8(domain)
@@ -137,99 +469,315 @@ impl DomainValidator {
         domain.set_fqdn(true);
 
         // Short-circuit for allow/block-lists.
-        if self.allowed_domains.contains(&domain) {
+        let registrable = self.registrable_domain(&domain);
+        if self.allowed_domains.matches(&domain, registrable.as_ref()) {
             return Ok(());
         }
-        if self.allowed_domains_only || self.blocked_domains.contains(&domain) {
+        if self.allowed_domains_only || self.blocked_domains.matches(&domain, registrable.as_ref())
+        {
             return Err(DomainValidationError::Blocked);
         }
+        if block_disposable
+            && self
+                .disposable_domains
+                .matches(&domain, registrable.as_ref())
+        {
+            return Err(DomainValidationError::Disposable);
+        }
 
         // Validate with a resolver if requested.
-        if let Some(ref resolver) = self.dns_resolver {
-            // Start with just an MX lookup. The spec allows just A/AAAA records, but it's very
-            // likely a real mail domain has MX records.
-            let res = resolver.mx_lookup(domain.clone()).await;
-            let mut has_mx = false;
-            let mail_servers: Vec<&Name> = match res {
-                Ok(ref mx) => {
-                    has_mx = true;
-                    // Answers should always be FQDNs. We also ignore priority.
-                    mx.iter()
-                        .map(MX::exchange)
-                        .filter(|name| name.is_fqdn())
-                        .collect()
-                }
-                Err(err) => {
-                    log::debug!(
-                        "Falling back to A/AAAA lookup for domain '{}', because MX lookup failed: {}",
-                        domain,
-                        err
-                    );
-                    vec![&domain]
-                }
+        if let Some(ref client) = self.dns_client {
+            let validation = match self.cached_validation(&domain).await {
+                Some(validation) => validation,
+                None => self.check_dns(client, &domain).await?,
             };
+            validation.into_result(block_disposable)?;
+        }
 
-            // Check for a null MX record.
-            if mail_servers.len() == 1 && mail_servers[0].is_root() {
-                return Err(DomainValidationError::NullMx);
+        Ok(())
+    }
+
+    /// Fetch the cached DNS validation outcome of a domain.
+    async fn cached_validation(&self, domain: &Name) -> Option<DomainValidation> {
+        let store = self.cache.as_ref()?;
+        if self.verify_cache_ttl == Duration::from_secs(0) {
+            return None;
+        }
+        let message = GetDomainValidation {
+            key: self.cache_key(domain),
+        };
+        match store.send(message).await {
+            Ok(validation) => validation,
+            Err(err) => {
+                log::warn!("Could not fetch cached validation of '{}': {}", domain, err);
+                None
             }
+        }
+    }
 
-            // If we didn't find an MX record, do a regular IP lookup of the domain itself. Also do
-            // an IP lookup of mail servers if config is set to allow only public IP addresses.
-            if !has_mx || self.verify_public_ip {
-                let mut ok = false;
-                let mut has_private_ips = false;
-                for server in mail_servers {
-                    match resolver.lookup_ip(server.clone()).await {
-                        Ok(_) if !self.verify_public_ip => ok = true,
-                        Ok(ref ips) => {
-                            // TODO: Once stabilized, see: https://github.com/rust-lang/rust/issues/27709
-                            // In fact, we currently cannot check for IPv6 private address space. :(
-                            //ok = ips.iter().any(|ip| match ip {
-                            //    IpAddr::V4(ip) => ip.is_global(),
-                            //    IpAddr::V6(ip) => ip.is_unicast_global(),
-                            //});
-                            ok = ips.iter().any(|ip| match ip {
-                                IpAddr::V4(ip) => {
-                                    !ip.is_private()
-                                        && !ip.is_loopback()
-                                        && !ip.is_link_local()
-                                        && !ip.is_broadcast()
-                                        && !ip.is_documentation()
-                                        && !ip.is_multicast()
-                                        && !ip.is_unspecified()
-                                }
-                                IpAddr::V6(ip) => {
-                                    !ip.is_multicast() && !ip.is_loopback() && !ip.is_unspecified()
-                                }
-                            });
-                            if !ok {
-                                has_private_ips = true;
-                            }
-                        }
-                        Err(err) => {
-                            log::debug!(
-                                "Could not resolve mail server '{}' for domain '{}': {}",
-                                server,
-                                domain,
-                                err
-                            );
+    /// Check the mail servers of a domain, and cache the outcome if possible.
+    ///
+    /// Outcomes are cached for the lowest TTL of the DNS answers, up to `verify_cache_ttl`. They
+    /// are not cached if any lookup failed.
+    async fn check_dns(
+        &self,
+        client: &DnsClient,
+        domain: &Name,
+    ) -> Result<DomainValidation, DomainValidationError> {
+        let mut session = client.session();
+        let mut complete = true;
+        let validation = self
+            .lookup_mail_servers(client, &mut session, domain, &mut complete)
+            .await?;
+
+        let ttl = session
+            .min_ttl()
+            .map(|ttl| Duration::from_secs(ttl.into()).min(self.verify_cache_ttl))
+            .filter(|ttl| *ttl > Duration::from_secs(0));
+        if let (true, Some(store), Some(ttl)) = (complete, self.cache.as_ref(), ttl) {
+            let message = SaveDomainValidation {
+                key: self.cache_key(domain),
+                validation,
+                ttl,
+            };
+            if let Err(err) = store.send(message).await {
+                log::warn!("Could not cache validation of '{}': {}", domain, err);
+            }
+        }
+
+        Ok(validation)
+    }
+
+    /// Look up the mail servers of a domain and their addresses.
+    ///
+    /// Fails with `Resolver` if the DNS server could not answer a lookup, and sets `complete` to
+    /// false if the server answered a lookup with an error.
+    async fn lookup_mail_servers(
+        &self,
+        client: &DnsClient,
+        session: &mut DnsSession,
+        domain: &Name,
+        complete: &mut bool,
+    ) -> Result<DomainValidation, DomainValidationError> {
+        let is_bogus = |err: &_| client.is_validating() && is_dnssec_failure(err);
+        let validation = |outcome| DomainValidation {
+            outcome,
+            disposable_mx: false,
+        };
+
+        // Start with just an MX lookup. The spec allows just A/AAAA records, but it's very
+        // likely a real mail domain has MX records.
+        let res = session.mx_lookup(domain.clone()).await;
+        let mut has_mx = false;
+        let mail_servers: Vec<Name> = match res {
+            Ok(ref mx) if !mx.is_empty() => {
+                has_mx = true;
+                // Answers should always be FQDNs. We also ignore priority.
+                mx.iter().filter(|name| name.is_fqdn()).cloned().collect()
+            }
+            Ok(_) => {
+                log::debug!(
+                    "Falling back to A/AAAA lookup for domain '{}', because it has no MX records",
+                    domain
+                );
+                vec![domain.clone()]
+            }
+            Err(ref err) if is_bogus(err) => {
+                log::info!("DNSSEC validation failed for domain '{}': {}", domain, err);
+                return Err(DomainValidationError::Bogus);
+            }
+            Err(err) if !is_answer(&err) => {
+                return Err(DomainValidationError::Resolver(Box::new(err)))
+            }
+            Err(err) => {
+                log::debug!(
+                    "Falling back to A/AAAA lookup for domain '{}', because MX lookup failed: {}",
+                    domain,
+                    err
+                );
+                *complete = false;
+                vec![domain.clone()]
+            }
+        };
+
+        // Check for a null MX record.
+        if mail_servers.len() == 1 && mail_servers[0].is_root() {
+            return Ok(validation(DnsOutcome::NullMx));
+        }
+
+        // Check for mail servers of disposable email providers. Whether this blocks the login
+        // depends on the Relying Party, so we continue checking.
+        let disposable_mx = has_mx
+            && mail_servers.iter().any(|server| {
+                self.disposable_mail_servers
+                    .matches(server, self.registrable_domain(server).as_ref())
+            });
+        let validation = |outcome| DomainValidation {
+            outcome,
+            disposable_mx,
+        };
+
+        // If we didn't find an MX record, do a regular IP lookup of the domain itself. Also do
+        // an IP lookup of mail servers if config is set to allow only public IP addresses.
+        if !has_mx || self.verify_public_ip {
+            let mut ok = false;
+            let mut has_private_ips = false;
+            for server in mail_servers {
+                match session.ip_lookup(server.clone()).await {
+                    Ok(ref ips) if ips.is_empty() => {
+                        log::debug!(
+                            "Mail server '{}' for domain '{}' has no IP addresses",
+                            server,
+                            domain
+                        );
+                    }
+                    Ok(_) if !self.verify_public_ip => ok = true,
+                    Ok(ref ips) => {
+                        ok = ips.iter().any(is_public_ip);
+                        if !ok {
+                            has_private_ips = true;
                         }
                     }
-                    if ok {
-                        break;
+                    Err(ref err) if is_bogus(err) => {
+                        log::info!(
+                            "DNSSEC validation failed for mail server '{}' of domain '{}': {}",
+                            server,
+                            domain,
+                            err
+                        );
+                        return Err(DomainValidationError::Bogus);
+                    }
+                    Err(err) if !is_answer(&err) => {
+                        return Err(DomainValidationError::Resolver(Box::new(err)));
+                    }
+                    Err(err) => {
+                        log::debug!(
+                            "Could not resolve mail server '{}' for domain '{}': {}",
+                            server,
+                            domain,
+                            err
+                        );
+                        *complete = false;
                     }
                 }
-                if !ok {
-                    return Err(if has_private_ips {
-                        DomainValidationError::NoPublicIps
-                    } else {
-                        DomainValidationError::NoServers
-                    });
+                if ok {
+                    break;
                 }
             }
+            if !ok {
+                return Ok(validation(if has_private_ips {
+                    DnsOutcome::NoPublicIps
+                } else {
+                    DnsOutcome::NoServers
+                }));
+            }
         }
 
-        Ok(())
+        Ok(validation(DnsOutcome::Valid))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_domain_list() {
+        let mut list = DomainList::default();
+        for rule in &[
+            "exact.test",
+            "*.wildcard.test",
+            ".suffix.test",
+            "/mx[0-9]+\\.pattern\\.test/",
+        ] {
+            list.add(rule).unwrap();
+        }
+        list.finish().unwrap();
+        let matches = |domain: &str| list.matches(&parse_domain(domain).unwrap(), None);
+        for domain in &[
+            "exact.test",
+            "EXACT.test",
+            "a.wildcard.test",
+            "a.b.wildcard.test",
+            "suffix.test",
+            "a.suffix.test",
+            "mx1.pattern.test",
+        ] {
+            assert!(matches(domain), "{}", domain);
+        }
+        for domain in &[
+            "a.exact.test",
+            "wildcard.test",
+            "notsuffix.test",
+            "mx.pattern.test",
+            "mx1.pattern.test.example",
+        ] {
+            assert!(!matches(domain), "{}", domain);
+        }
+
+        let registrable = parse_domain("exact.test").unwrap();
+        assert!(list.matches(&parse_domain("a.exact.test").unwrap(), Some(&registrable)));
+    }
+
+    #[test]
+    fn test_invalid_domain_rules() {
+        let mut list = DomainList::default();
+        list.add("/mx[0-9]+\\.pattern\\.test/").unwrap();
+        assert!(matches!(
+            list.add("a.*.test"),
+            Err(DomainRuleError::Wildcard)
+        ));
+        assert!(matches!(list.add("."), Err(DomainRuleError::Everything)));
+        assert!(matches!(list.add("/(/"), Err(DomainRuleError::Pattern(_))));
+        list.finish().unwrap();
+        // A failed pattern does not affect earlier patterns.
+        assert!(list.matches(&parse_domain("mx1.pattern.test").unwrap(), None));
+    }
+
+    #[test]
+    fn test_origin_domains() {
+        let mut validator = DomainValidator::new();
+        let internal = 0;
+        validator
+            .add_allowed_domain_for(internal, ".company.test")
+            .unwrap();
+        validator
+            .add_blocked_domain_for(internal, "old.company.test")
+            .unwrap();
+
+        assert!(validator
+            .validate_for_policy("company.test", internal)
+            .is_ok());
+        assert!(validator
+            .validate_for_policy("mail.company.test", internal)
+            .is_ok());
+        for domain in &["old.company.test", "example.test"] {
+            assert!(
+                matches!(
+                    validator.validate_for_policy(domain, internal),
+                    Err(DomainValidationError::Blocked)
+                ),
+                "{}",
+                domain
+            );
+        }
+        assert!(validator.validate_for_policy("example.test", 1).is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_disposable() {
+        let mut validator = DomainValidator::new();
+        validator.add_disposable_domain(".throwaway.test").unwrap();
+        validator.add_allowed_domain("ok.throwaway.test").unwrap();
+
+        assert!(matches!(
+            validator.validate("mail.throwaway.test", true).await,
+            Err(DomainValidationError::Disposable)
+        ));
+        assert!(validator.validate("ok.throwaway.test", true).await.is_ok());
+        assert!(validator
+            .validate("mail.throwaway.test", false)
+            .await
+            .is_ok());
     }
 }
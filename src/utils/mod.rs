@@ -1,19 +1,27 @@
+mod address_validator;
 pub mod agent;
 pub mod base64url;
 mod delay_queue_task;
+pub mod dns;
 mod domain_validator;
 pub mod http;
 pub mod keys;
 pub mod logger;
+mod pattern_set;
 pub mod pem;
+pub mod pow;
+pub mod public_suffix;
 mod real_ip;
 #[cfg(feature = "redis")]
 pub mod redis;
 mod rng;
+#[cfg(feature = "rusqlite")]
+pub mod sqlite;
 mod time;
 
 use std::{error::Error, future::Future, pin::Pin};
 
+pub use address_validator::*;
 pub use delay_queue_task::*;
 pub use domain_validator::*;
 pub use real_ip::*;
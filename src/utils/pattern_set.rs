@@ -0,0 +1,82 @@
+use regex::{Error as RegexError, RegexBuilder, RegexSet, RegexSetBuilder};
+
+/// A set of regular expressions, matched case-insensitively against a whole input.
+///
+/// Patterns are checked one by one as they are added, but compiled together only once, by
+/// `finish`, which must be called before matching.
+#[derive(Default)]
+pub struct PatternSet {
+    /// Anchored patterns, like `^(?:mx[0-9]+)$` for `mx[0-9]+`.
+    patterns: Vec<String>,
+    /// Compiled `patterns`, set by `finish`.
+    set: Option<RegexSet>,
+}
+
+impl PatternSet {
+    /// Add a pattern, and return its index in the set.
+    pub fn add(&mut self, pattern: &str) -> Result<usize, RegexError> {
+        let pattern = format!("^(?:{})$", pattern);
+        // Compile by itself first, so errors are reported for the offending pattern.
+        RegexBuilder::new(&pattern).case_insensitive(true).build()?;
+        self.patterns.push(pattern);
+        self.set = None;
+        Ok(self.patterns.len() - 1)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.patterns.is_empty()
+    }
+
+    /// Compile the patterns added so far.
+    pub fn finish(&mut self) -> Result<(), RegexError> {
+        if self.set.is_none() && !self.patterns.is_empty() {
+            let set = RegexSetBuilder::new(&self.patterns)
+                .case_insensitive(true)
+                .build()?;
+            self.set = Some(set);
+        }
+        Ok(())
+    }
+
+    /// Indices of the patterns that match the input.
+    pub fn matches(&self, input: &str) -> Vec<usize> {
+        if self.patterns.is_empty() {
+            return Vec::new();
+        }
+        self.set
+            .as_ref()
+            .expect("PatternSet::finish must be called before matching")
+            .matches(input)
+            .into_iter()
+            .collect()
+    }
+
+    /// Whether any of the patterns match the input.
+    pub fn is_match(&self, input: &str) -> bool {
+        !self.patterns.is_empty()
+            && self
+                .set
+                .as_ref()
+                .expect("PatternSet::finish must be called before matching")
+                .is_match(input)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_pattern_set() {
+        let mut set = PatternSet::default();
+        assert!(!set.is_match("anything"));
+        assert_eq!(set.add("mx[0-9]+").unwrap(), 0);
+        assert_eq!(set.add("ops-.*").unwrap(), 1);
+        assert!(set.add("(").is_err());
+        set.finish().unwrap();
+
+        assert!(set.is_match("MX12"));
+        assert!(!set.is_match("mx12.example.com"));
+        assert_eq!(set.matches("ops-mx1"), vec![1]);
+    }
+}
@@ -0,0 +1,44 @@
+use ring::digest::{digest, SHA256};
+
+/// Verify a proof-of-work solution.
+///
+/// The solution is valid if the SHA-256 digest of `challenge:nonce` starts with at least
+/// `difficulty` zero bits.
+pub fn verify(challenge: &str, nonce: &str, difficulty: u8) -> bool {
+    let hash = digest(&SHA256, format!("{}:{}", challenge, nonce).as_bytes());
+    leading_zero_bits(hash.as_ref()) >= u32::from(difficulty)
+}
+
+/// Count the number of leading zero bits in the input.
+fn leading_zero_bits(data: &[u8]) -> u32 {
+    let mut count = 0;
+    for byte in data {
+        count += byte.leading_zeros();
+        if *byte != 0 {
+            break;
+        }
+    }
+    count
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{leading_zero_bits, verify};
+
+    #[test]
+    fn test_leading_zero_bits() {
+        assert_eq!(leading_zero_bits(&[0x80]), 0);
+        assert_eq!(leading_zero_bits(&[0x00, 0x10]), 11);
+        assert_eq!(leading_zero_bits(&[0x00, 0x00]), 16);
+    }
+
+    #[test]
+    fn test_verify() {
+        // SHA-256 of `test:90` starts with `0045`.
+        assert!(verify("test", "90", 8));
+        assert!(verify("test", "90", 9));
+        assert!(!verify("test", "90", 10));
+        assert!(!verify("test", "91", 8));
+        assert!(verify("test", "91", 0));
+    }
+}
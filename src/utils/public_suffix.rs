@@ -0,0 +1,124 @@
+use std::collections::HashSet;
+use std::fs::File;
+use std::io::{BufRead, BufReader, Error as IoError};
+use std::path::Path;
+use thiserror::Error;
+use trust_dns_resolver::{proto::error::ProtoError, Name};
+
+#[derive(Debug, Error)]
+pub enum LoadSuffixListError {
+    #[error("could not read the list: {0}")]
+    Io(#[from] IoError),
+    #[error("invalid rule on line {0}: {1}")]
+    Rule(usize, ProtoError),
+}
+
+/// Public suffix list, in the format of <https://publicsuffix.org/list/>.
+#[derive(Default)]
+pub struct PublicSuffixList {
+    /// Normal rules, like `co.uk`.
+    rules: HashSet<Name>,
+    /// Wildcard rules without the wildcard label, like `ck` for `*.ck`.
+    wildcards: HashSet<Name>,
+    /// Exception rules without the `!`, like `www.ck` for `!www.ck`.
+    exceptions: HashSet<Name>,
+}
+
+impl PublicSuffixList {
+    /// Load the list from a file.
+    pub fn load(path: &Path) -> Result<Self, LoadSuffixListError> {
+        Self::parse(BufReader::new(File::open(path)?))
+    }
+
+    /// Parse the list from a reader.
+    pub fn parse(reader: impl BufRead) -> Result<Self, LoadSuffixListError> {
+        let mut list = PublicSuffixList::default();
+        for (idx, line) in reader.lines().enumerate() {
+            let line = line?;
+            // Rules end at the first whitespace.
+            let rule = match line.split_whitespace().next() {
+                Some(rule) if !rule.starts_with("//") => rule,
+                _ => continue,
+            };
+            let parse = |rule: &str| -> Result<Name, LoadSuffixListError> {
+                let mut name = Name::from_utf8(rule)
+                    .map_err(|err| LoadSuffixListError::Rule(idx + 1, err))?
+                    .to_lowercase();
+                name.set_fqdn(true);
+                Ok(name)
+            };
+            if let Some(rule) = rule.strip_prefix('!') {
+                list.exceptions.insert(parse(rule)?);
+            } else if let Some(rule) = rule.strip_prefix("*.") {
+                list.wildcards.insert(parse(rule)?);
+            } else {
+                list.rules.insert(parse(rule)?);
+            }
+        }
+        Ok(list)
+    }
+
+    /// Number of labels in the public suffix of a lowercase FQDN.
+    fn suffix_len(&self, name: &Name) -> u8 {
+        let num_labels = name.num_labels();
+        // The implicit `*` rule.
+        let mut len = 1;
+        for n in 1..=num_labels {
+            let suffix = name.trim_to(n as usize);
+            if self.exceptions.contains(&suffix) {
+                return n - 1;
+            }
+            if self.rules.contains(&suffix) {
+                len = n;
+            }
+            if n < num_labels && self.wildcards.contains(&suffix) {
+                len = n + 1;
+            }
+        }
+        len
+    }
+
+    /// The registrable domain of a lowercase FQDN, or `None` if it is a public suffix itself.
+    pub fn registrable_domain(&self, name: &Name) -> Option<Name> {
+        let len = self.suffix_len(name);
+        if name.num_labels() > len {
+            Some(name.trim_to(len as usize + 1))
+        } else {
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_registrable_domain() {
+        let list = PublicSuffixList::parse(
+            &b"// Comment
+com
+co.uk
+*.ck
+!www.ck
+github.io  trailing text is ignored
+"[..],
+        )
+        .unwrap();
+        let registrable = |domain: &str| {
+            let mut name = Name::from_utf8(domain).unwrap();
+            name.set_fqdn(true);
+            list.registrable_domain(&name).map(|name| name.to_string())
+        };
+        assert_eq!(registrable("example.com").unwrap(), "example.com.");
+        assert_eq!(registrable("a.b.example.com").unwrap(), "example.com.");
+        assert_eq!(registrable("a.example.co.uk").unwrap(), "example.co.uk.");
+        assert_eq!(registrable("a.example.test").unwrap(), "example.test.");
+        assert_eq!(registrable("a.example.ck").unwrap(), "a.example.ck.");
+        assert_eq!(registrable("a.www.ck").unwrap(), "www.ck.");
+        assert_eq!(registrable("alice.github.io").unwrap(), "alice.github.io.");
+        assert_eq!(registrable("co.uk"), None);
+        assert_eq!(registrable("example.ck"), None);
+        assert_eq!(registrable("github.io"), None);
+    }
+}
@@ -2,6 +2,7 @@ use crate::utils::{redis::pubsub::Subscriber, SecureRandom};
 use futures_util::future::{self, FutureExt};
 use redis::{aio::MultiplexedConnection, RedisResult, Script, Value};
 use std::sync::Arc;
+use tokio::sync::broadcast::error::RecvError;
 use tokio::time::{interval, Duration};
 
 // TODO: Lock locally first, so multiple locks from the same machine are more efficient.
@@ -75,24 +76,36 @@ impl LockClient {
     ///
     /// Note that the given key *is* the lock, and the key may not otherwise be written to.
     /// (Unlike, say, file locking, where the lock is conceptually metadata on the file.)
-    pub async fn lock(&mut self, key: &[u8]) -> LockGuard {
+    pub async fn lock(&mut self, key: &[u8]) -> RedisResult<LockGuard> {
         let request = self.rng.generate_async(16).await;
-        let mut sub = self.pubsub.subscribe(key.to_vec()).await;
+        let mut sub = Some(self.pubsub.subscribe(key.to_vec()).await?);
         let mut retry = interval(Duration::from_secs(2));
         #[allow(clippy::mut_mut)]
         loop {
-            let retry_fut = retry.tick().fuse();
-            let sub_fut = sub.recv().fuse();
-            tokio::pin!(retry_fut, sub_fut);
-            future::select(retry_fut, sub_fut).await;
-            if self.try_lock(key, &request).await {
-                return self.make_guard(key, request);
+            let closed = if let Some(ref mut rx) = sub {
+                let retry_fut = retry.tick().fuse();
+                let sub_fut = rx.recv().fuse();
+                tokio::pin!(retry_fut, sub_fut);
+                matches!(
+                    future::select(retry_fut, sub_fut).await,
+                    future::Either::Right((Err(RecvError::Closed), _))
+                )
+            } else {
+                retry.tick().await;
+                false
+            };
+            if closed {
+                // The pubsub connection was lost, fall back to only polling.
+                sub = None;
+            }
+            if self.try_lock(key, &request).await? {
+                return Ok(self.make_guard(key, request));
             }
         }
     }
 
     /// Try to acquire a lock without waiting.
-    async fn try_lock(&mut self, key: &[u8], request: &[u8]) -> bool {
+    async fn try_lock(&mut self, key: &[u8], request: &[u8]) -> RedisResult<bool> {
         let value = redis::cmd("SET")
             .arg(key)
             .arg(request)
@@ -100,11 +113,10 @@ impl LockClient {
             .arg("px")
             .arg("30000")
             .query_async(&mut self.conn)
-            .await
-            .expect("Could not make Redis lock request");
+            .await?;
         match value {
-            Value::Nil => false,
-            Value::Okay => true,
+            Value::Nil => Ok(false),
+            Value::Okay => Ok(true),
             value => panic!("Unexpected lock result from Redis: {:?}", value),
         }
     }
@@ -1,9 +1,10 @@
 use futures_util::future::poll_fn;
+use native_tls::TlsConnector;
 use redis::{ConnectionAddr, ConnectionInfo, ErrorKind, RedisError, RedisResult, Value};
 use std::collections::hash_map::{Entry, HashMap};
 use std::future::Future;
 use std::io::Result as IoResult;
-use std::net::ToSocketAddrs;
+use std::net::{SocketAddr, ToSocketAddrs};
 use std::pin::Pin;
 use std::task::Poll;
 use tokio::io::{self, AsyncWriteExt};
@@ -95,10 +96,18 @@ async fn conn_loop(mut rx: ReadHalf, mut tx: WriteHalf, mut cmd: mpsc::Receiver<
     // Map of active subscriptions by channel name.
     let mut subs: HashMap<Vec<u8>, Sub> = HashMap::new();
 
+    // Whether `Subscriber` handles still exist.
+    let mut cmd_open = true;
+
     loop {
         // This specifically prioritizes processing commands over receiving.
         match poll_fn(|cx| {
-            if let Poll::Ready(res) = cmd.poll_recv(cx) {
+            let cmd_poll = if cmd_open {
+                cmd.poll_recv(cx)
+            } else {
+                Poll::Pending
+            };
+            if let Poll::Ready(res) = cmd_poll {
                 match res {
                     Some(cmd) => Poll::Ready(LoopEvent::Cmd(cmd)),
                     None => Poll::Ready(LoopEvent::CmdClosed),
@@ -130,15 +139,19 @@ async fn conn_loop(mut rx: ReadHalf, mut tx: WriteHalf, mut cmd: mpsc::Receiver<
                         tx: broadcast::channel(8).0,
                         pending: Some(vec![reply]),
                     });
-                    tx.write(&[b"SUBSCRIBE", &chan])
-                        .await
-                        .expect("Failed to send subscribe command to Redis");
+                    if let Err(err) = tx.write(&[b"SUBSCRIBE", &chan]).await {
+                        log::error!("Failed to send subscribe command to Redis: {}", err);
+                        return;
+                    }
                 }
             },
             LoopEvent::CmdClosed => {
-                // TODO: Stop reading from the command channel.
-                // This, plus an empty `subs`, means we can exit.
-                unimplemented!();
+                // No more subscriptions can be added, but existing ones remain active. Once those
+                // are all gone, we can exit.
+                cmd_open = false;
+                if subs.is_empty() {
+                    return;
+                }
             }
             LoopEvent::Interval => {
                 // Unsubscribe from channels that no longer have subscribers, or send a ping.
@@ -153,18 +166,23 @@ async fn conn_loop(mut rx: ReadHalf, mut tx: WriteHalf, mut cmd: mpsc::Receiver<
                     })
                     .collect();
                 if to_unsub.is_empty() {
-                    tx.write(&[b"PING"])
-                        .await
-                        .expect("Failed to send ping command to Redis");
+                    if let Err(err) = tx.write(&[b"PING"]).await {
+                        log::error!("Failed to send ping command to Redis: {}", err);
+                        return;
+                    }
                 } else {
                     for chan in &to_unsub {
                         subs.remove(chan);
                     }
+                    if !cmd_open && subs.is_empty() {
+                        return;
+                    }
                     let mut unsub_cmd: Vec<&[u8]> = vec![b"UNSUBSCRIBE"];
                     unsub_cmd.extend(to_unsub.iter().map(|chan| &chan[..]));
-                    tx.write(&unsub_cmd)
-                        .await
-                        .expect("Failed to send unsubscribe command to Redis");
+                    if let Err(err) = tx.write(&unsub_cmd).await {
+                        log::error!("Failed to send unsubscribe command to Redis: {}", err);
+                        return;
+                    }
                 }
             }
             LoopEvent::Read((res, mut rx)) => {
@@ -172,7 +190,13 @@ async fn conn_loop(mut rx: ReadHalf, mut tx: WriteHalf, mut cmd: mpsc::Receiver<
                     let res = rx.read().await;
                     (res, rx)
                 });
-                let value = res.expect("Failed to read from Redis");
+                let value = match res {
+                    Ok(value) => value,
+                    Err(err) => {
+                        log::error!("Failed to read from Redis: {}", err);
+                        return;
+                    }
+                };
                 let vec = match value {
                     // Note: If we have no subscriptions at all, we receive pongs as regular
                     // replies instead of events.
@@ -220,10 +244,17 @@ pub struct Subscriber {
 }
 
 impl Subscriber {
+    /// Whether the connection was lost.
+    pub fn is_closed(&self) -> bool {
+        self.cmd.is_closed()
+    }
+
     /// Subscribe to a channel.
     ///
     /// This function does not complete until the server has confirmed the subscription.
-    pub async fn subscribe(&mut self, chan: Vec<u8>) -> broadcast::Receiver<Vec<u8>> {
+    ///
+    /// When the connection is lost, the returned channel is closed.
+    pub async fn subscribe(&mut self, chan: Vec<u8>) -> RedisResult<RecvChan> {
         let (reply_tx, reply_rx) = oneshot::channel();
         let cmd = Cmd {
             chan,
@@ -231,10 +262,13 @@ impl Subscriber {
         };
         if self.cmd.send(cmd).await.is_ok() {
             if let Ok(rx) = reply_rx.await {
-                return rx;
+                return Ok(rx);
             }
         }
-        panic!("Tried to subscribe on closed pubsub connection");
+        Err(RedisError::from((
+            ErrorKind::IoError,
+            "Tried to subscribe on closed pubsub connection",
+        )))
     }
 }
 
@@ -246,28 +280,32 @@ pub async fn connect(info: &ConnectionInfo) -> RedisResult<Subscriber> {
         Box<dyn io::AsyncWrite + Unpin + Send>,
     ) = match *info.addr {
         ConnectionAddr::Tcp(ref host, port) => {
-            let socket_addr = {
-                let mut socket_addrs = (&host[..], port).to_socket_addrs()?;
-                match socket_addrs.next() {
-                    Some(socket_addr) => socket_addr,
-                    None => {
-                        return Err(RedisError::from((
-                            ErrorKind::InvalidClientConfig,
-                            "No address found for host",
-                        )));
-                    }
-                }
-            };
-
+            let socket_addr = get_socket_addr(host, port)?;
             let (rx, tx) = io::split(TcpStream::connect(&socket_addr).await?);
             (Box::new(rx), Box::new(tx))
         }
 
-        ConnectionAddr::TcpTls { .. } => {
-            return Err(RedisError::from((
-                ErrorKind::InvalidClientConfig,
-                "TLS connections not yet supported",
-            )))
+        ConnectionAddr::TcpTls {
+            ref host,
+            port,
+            insecure,
+        } => {
+            let socket_addr = get_socket_addr(host, port)?;
+            let tls_connector: tokio_native_tls::TlsConnector = if insecure {
+                TlsConnector::builder()
+                    .danger_accept_invalid_certs(true)
+                    .danger_accept_invalid_hostnames(true)
+                    .use_sni(false)
+                    .build()?
+            } else {
+                TlsConnector::new()?
+            }
+            .into();
+            let stream = tls_connector
+                .connect(host, TcpStream::connect(&socket_addr).await?)
+                .await?;
+            let (rx, tx) = io::split(stream);
+            (Box::new(rx), Box::new(tx))
         }
 
         #[cfg(unix)]
@@ -317,3 +355,11 @@ pub async fn connect(info: &ConnectionInfo) -> RedisResult<Subscriber> {
     tokio::spawn(conn_loop(rx, tx, cmd_rx));
     Ok(Subscriber { cmd: cmd_tx })
 }
+
+/// Resolve a host and port to a single socket address.
+fn get_socket_addr(host: &str, port: u16) -> RedisResult<SocketAddr> {
+    let mut socket_addrs = (host, port).to_socket_addrs()?;
+    socket_addrs.next().ok_or_else(|| {
+        RedisError::from((ErrorKind::InvalidClientConfig, "No address found for host"))
+    })
+}
@@ -0,0 +1,152 @@
+use crate::utils::redis::{parse_url, pubsub};
+use redis::{
+    aio::Connection, Client as RedisClient, ConnectionAddr, ConnectionInfo, ErrorKind, RedisError,
+    RedisResult, Value,
+};
+use std::future::Future;
+use tokio::time::{timeout, Duration};
+
+/// Timeout for connections made during discovery.
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Sentinel pubsub channel that announces failovers.
+const SWITCH_MASTER_CHAN: &[u8] = b"+switch-master";
+
+/// Discovers the current Redis master using Sentinel.
+///
+/// This struct can be cheaply cloned.
+#[derive(Clone)]
+pub struct Sentinel {
+    /// Name of the monitored master.
+    master_name: String,
+    /// Connection info for each of the sentinels.
+    sentinels: Vec<ConnectionInfo>,
+}
+
+impl Sentinel {
+    /// Create a new instance.
+    ///
+    /// Sentinels are specified as `host:port` pairs or Redis URLs.
+    pub fn new(master_name: String, sentinels: &[String]) -> RedisResult<Self> {
+        let sentinels = sentinels
+            .iter()
+            .map(|url| parse_url(url))
+            .collect::<RedisResult<Vec<_>>>()?;
+        if sentinels.is_empty() {
+            return Err(RedisError::from((
+                ErrorKind::InvalidClientConfig,
+                "No Redis sentinels configured",
+            )));
+        }
+        Ok(Sentinel {
+            master_name,
+            sentinels,
+        })
+    }
+
+    /// Name of the monitored master.
+    pub fn master_name(&self) -> &str {
+        &self.master_name
+    }
+
+    /// Ask the sentinels for the current master.
+    ///
+    /// The address in `info` is replaced with that of the master. Database, credentials and TLS
+    /// settings are kept.
+    pub async fn discover(&self, info: &ConnectionInfo) -> RedisResult<ConnectionInfo> {
+        for sentinel in &self.sentinels {
+            match self.query_sentinel(sentinel, info).await {
+                Ok(master) => {
+                    log::info!(
+                        "Redis sentinel {} reports master at {}",
+                        sentinel.addr,
+                        master.addr
+                    );
+                    return Ok(master);
+                }
+                Err(err) => log::warn!("Redis sentinel {} failed: {}", sentinel.addr, err),
+            }
+        }
+        Err(RedisError::from((
+            ErrorKind::IoError,
+            "None of the Redis sentinels could provide a master",
+        )))
+    }
+
+    /// Subscribe to failover announcements.
+    ///
+    /// The returned channel is closed if the connection to the sentinel is lost.
+    pub async fn watch(&self) -> RedisResult<pubsub::RecvChan> {
+        let mut last_err = None;
+        for sentinel in &self.sentinels {
+            let res = with_timeout(async {
+                let mut sub = pubsub::connect(sentinel).await?;
+                sub.subscribe(SWITCH_MASTER_CHAN.to_vec()).await
+            })
+            .await;
+            match res {
+                Ok(rx) => return Ok(rx),
+                Err(err) => {
+                    log::warn!("Redis sentinel {} failed: {}", sentinel.addr, err);
+                    last_err = Some(err);
+                }
+            }
+        }
+        Err(last_err.expect("No Redis sentinels configured"))
+    }
+
+    /// Ask a single sentinel for the current master, and verify its role.
+    async fn query_sentinel(
+        &self,
+        sentinel: &ConnectionInfo,
+        info: &ConnectionInfo,
+    ) -> RedisResult<ConnectionInfo> {
+        let mut conn = open(sentinel.clone()).await?;
+        let addr: Option<(String, u16)> = with_timeout(
+            redis::cmd("SENTINEL")
+                .arg("get-master-addr-by-name")
+                .arg(&self.master_name)
+                .query_async(&mut conn),
+        )
+        .await?;
+        let (host, port) = addr.ok_or_else(|| {
+            RedisError::from((
+                ErrorKind::ResponseError,
+                "Sentinel does not know the master",
+            ))
+        })?;
+
+        let mut master = info.clone();
+        master.addr = Box::new(match *info.addr {
+            ConnectionAddr::TcpTls { insecure, .. } => ConnectionAddr::TcpTls {
+                host,
+                port,
+                insecure,
+            },
+            _ => ConnectionAddr::Tcp(host, port),
+        });
+
+        // During a failover, sentinels may briefly report a master that has not been promoted.
+        let mut conn = open(master.clone()).await?;
+        let role: Vec<Value> = with_timeout(redis::cmd("ROLE").query_async(&mut conn)).await?;
+        match role.first() {
+            Some(Value::Data(role)) if role == b"master" => Ok(master),
+            _ => Err(RedisError::from((
+                ErrorKind::ResponseError,
+                "Reported master does not have the master role",
+            ))),
+        }
+    }
+}
+
+/// Open a regular connection.
+async fn open(info: ConnectionInfo) -> RedisResult<Connection> {
+    with_timeout(RedisClient::open(info)?.get_async_connection()).await
+}
+
+/// Apply `CONNECT_TIMEOUT` to a Redis operation.
+async fn with_timeout<T>(fut: impl Future<Output = RedisResult<T>>) -> RedisResult<T> {
+    timeout(CONNECT_TIMEOUT, fut)
+        .await
+        .unwrap_or_else(|_| Err(RedisError::from((ErrorKind::IoError, "Timed out"))))
+}
@@ -0,0 +1,122 @@
+use rusqlite::Connection;
+use std::sync::{Arc, Mutex};
+use thiserror::Error;
+use tokio::sync::{Mutex as AsyncMutex, Semaphore};
+use tokio::task::spawn_blocking;
+
+#[derive(Debug, Error)]
+pub enum PoolError {
+    #[error("no idle SQLite read connection")]
+    NoIdleConnection,
+}
+
+/// Returns a read-only connection to the pool when dropped, even if the operation panics.
+struct ReadGuard {
+    conn: Option<Connection>,
+    readers: Arc<Mutex<Vec<Connection>>>,
+}
+
+impl Drop for ReadGuard {
+    fn drop(&mut self) {
+        if let Some(conn) = self.conn.take() {
+            // Recover from poisoning, so the connection is not lost too.
+            let mut readers = match self.readers.lock() {
+                Ok(readers) => readers,
+                Err(err) => err.into_inner(),
+            };
+            readers.push(conn);
+        }
+    }
+}
+
+/// A pool of `rusqlite` connections, used on the blocking thread pool.
+///
+/// Writes are serialized on a single connection, while reads can run concurrently on a set of
+/// read-only connections. This struct can be cheaply cloned.
+#[derive(Clone)]
+pub struct Pool {
+    /// The connection used for writes.
+    writer: Arc<AsyncMutex<Connection>>,
+    /// Idle read-only connections.
+    readers: Arc<Mutex<Vec<Connection>>>,
+    /// Limits concurrent reads to the number of read-only connections.
+    read_permits: Arc<Semaphore>,
+}
+
+impl Pool {
+    /// Create a new instance.
+    ///
+    /// All connections must be opened on the same database file.
+    pub fn new(writer: Connection, readers: Vec<Connection>) -> Self {
+        assert!(!readers.is_empty(), "SQLite pool requires read connections");
+        Pool {
+            writer: Arc::new(AsyncMutex::new(writer)),
+            read_permits: Arc::new(Semaphore::new(readers.len())),
+            readers: Arc::new(Mutex::new(readers)),
+        }
+    }
+
+    /// Run a read-only operation.
+    pub async fn read<F, T>(&self, f: F) -> Result<T, PoolError>
+    where
+        F: FnOnce(&Connection) -> T + Send + 'static,
+        T: Send + 'static,
+    {
+        let _permit = self
+            .read_permits
+            .acquire()
+            .await
+            .expect("SQLite pool semaphore closed");
+        let readers = self.readers.clone();
+        spawn_blocking(move || {
+            let conn = match readers.lock() {
+                Ok(mut readers) => readers.pop(),
+                Err(err) => err.into_inner().pop(),
+            };
+            let guard = ReadGuard {
+                conn: Some(conn.ok_or(PoolError::NoIdleConnection)?),
+                readers,
+            };
+            Ok(f(guard.conn.as_ref().unwrap()))
+        })
+        .await
+        .unwrap()
+    }
+
+    /// Run an operation that may write.
+    ///
+    /// Transactions should be started with `TransactionBehavior::Immediate`. A deferred transaction
+    /// that reads before writing fails with `SQLITE_BUSY`, without waiting, if another process
+    /// wrote to the database in the meantime.
+    pub async fn write<F, T>(&self, f: F) -> T
+    where
+        F: FnOnce(&mut Connection) -> T + Send + 'static,
+        T: Send + 'static,
+    {
+        let mut conn = self.writer.clone().lock_owned().await;
+        spawn_blocking(move || f(&mut conn)).await.unwrap()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_read_panic_keeps_connection() {
+        let open = || Connection::open_in_memory().unwrap();
+        let pool = Pool::new(open(), vec![open()]);
+        let res = tokio::spawn({
+            let pool = pool.clone();
+            async move { pool.read(|_| panic!("read failed")).await }
+        })
+        .await;
+        assert!(res.is_err());
+        let value: i64 = pool
+            .read(|conn| conn.query_row("SELECT 1", [], |row| row.get(0)))
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(value, 1);
+    }
+}
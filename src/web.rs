@@ -1,6 +1,6 @@
 use crate::agents::{GetSession, SaveSession};
 use crate::bridges::BridgeData;
-use crate::config::ConfigRc;
+use crate::config::{ConfigRc, LimitExceeded};
 use crate::crypto::{self, SigningAlgorithm};
 use crate::email_address::EmailAddress;
 use crate::error::{BrokerError, BrokerResult};
@@ -324,15 +324,19 @@ async fn handle_error(ctx: &Context, err: BrokerError) -> Response {
     let reference = err.log(Some(&ctx.app.rng)).await;
 
     if ctx.want_json() {
-        let mut res = json_response(
-            &json!({
-                "error": err.oauth_error_code(),
-                "error_description": &format!("{}", err),
-                "reference": reference,
-            }),
-            None,
-        );
+        let mut body = json!({
+            "error": err.oauth_error_code(),
+            "error_description": &format!("{}", err),
+            "reference": reference,
+        });
+        if let BrokerError::RateLimited(ref exceeded) = err {
+            body["retry_after"] = exceeded.retry_after_secs().into();
+        }
+        let mut res = json_response(&body, None);
         *res.status_mut() = err.http_status_code();
+        if let BrokerError::RateLimited(ref exceeded) = err {
+            set_rate_limit_headers(&mut res, exceeded);
+        }
         return res;
     }
 
@@ -397,12 +401,13 @@ async fn handle_error(ctx: &Context, err: BrokerError) -> Response {
             *res.status_mut() = err.http_status_code();
             res
         }
-        (err @ BrokerError::RateLimited, _) => {
+        (BrokerError::RateLimited(ref exceeded), _) => {
             let mut res = html_response(ctx.app.templates.error.render(&[
                 ("intro", catalog.gettext("Too many login attempts.")),
-                ("explanation", catalog.gettext("We've received too many requests in a short amount of time. Please try again later.")),
+                ("explanation", &rate_limit_explanation(catalog, exceeded)),
             ]));
-            *res.status_mut() = err.http_status_code();
+            *res.status_mut() = StatusCode::TOO_MANY_REQUESTS;
+            set_rate_limit_headers(&mut res, exceeded);
             res
         }
         (err @ BrokerError::SessionExpired, _) => {
@@ -418,6 +423,46 @@ async fn handle_error(ctx: &Context, err: BrokerError) -> Response {
     }
 }
 
+/// Build a localized explanation of when a rate limited user can try again.
+fn rate_limit_explanation(catalog: &Catalog, exceeded: &LimitExceeded) -> String {
+    let secs = exceeded.retry_after_secs();
+    let (n, text) = if secs < 60 {
+        (secs, catalog.ngettext(
+            "We've received too many requests in a short amount of time. Please try again in {} second.",
+            "We've received too many requests in a short amount of time. Please try again in {} seconds.",
+            secs,
+        ))
+    } else if secs < 3600 {
+        let mins = (secs + 59) / 60;
+        (mins, catalog.ngettext(
+            "We've received too many requests in a short amount of time. Please try again in {} minute.",
+            "We've received too many requests in a short amount of time. Please try again in {} minutes.",
+            mins,
+        ))
+    } else {
+        let hours = (secs + 3599) / 3600;
+        (hours, catalog.ngettext(
+            "We've received too many requests in a short amount of time. Please try again in {} hour.",
+            "We've received too many requests in a short amount of time. Please try again in {} hours.",
+            hours,
+        ))
+    };
+    text.replace("{}", &n.to_string())
+}
+
+/// Mutate a response to set headers describing an exceeded rate limit.
+fn set_rate_limit_headers<B>(res: &mut hyper::Response<B>, exceeded: &LimitExceeded) {
+    let retry_after = exceeded.retry_after_secs().to_string();
+    res.header(hyper::header::RETRY_AFTER, retry_after.clone());
+    res.header("ratelimit-limit", exceeded.max_count.to_string());
+    res.header("ratelimit-remaining", "0");
+    res.header("ratelimit-reset", retry_after);
+    res.header(
+        "ratelimit-policy",
+        format!("{};w={}", exceeded.max_count, exceeded.window.as_secs()),
+    );
+}
+
 /// Mutate a response to set common headers.
 fn set_headers<B>(res: &mut hyper::Response<B>) {
     // Specify a tight content security policy. We need to be able to POST
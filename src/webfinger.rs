@@ -1,8 +1,7 @@
-use crate::agents::FetchUrlCached;
+use crate::agents::{FetchPurpose, FetchUrlCached};
 use crate::config::ConfigRc;
 use crate::email_address::EmailAddress;
 use crate::error::BrokerError;
-use crate::metrics;
 use serde::{Deserialize, Serialize};
 use std::fmt::{Display, Error as FmtError, Formatter};
 use std::str::FromStr;
@@ -123,7 +122,7 @@ pub async fn query(app: &ConfigRc, email_addr: &EmailAddress) -> Result<Vec<Link
         .store
         .send(FetchUrlCached {
             url,
-            metric: &*metrics::AUTH_WEBFINGER_DURATION,
+            purpose: FetchPurpose::Webfinger,
         })
         .await
         .map_err(|e| BrokerError::Provider(format!("webfinger request failed: {}", e)))?;
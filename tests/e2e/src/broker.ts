@@ -23,7 +23,8 @@ export default ({ mailbox }: { mailbox: Mailbox }): Broker => {
     BROKER_PUBLIC_URL: "http://localhost:44133",
     BROKER_FROM_ADDRESS: "portier@example.com",
     BROKER_LIMITS: "100000/s",
-    BROKER_ALLOWED_DOMAINS: "example.com"
+    BROKER_ALLOWED_DOMAINS: "example.com",
+    BROKER_FETCH_PUBLIC_IPS_ONLY: "false"
   };
 
   switch (TEST_STORE) {